@@ -0,0 +1,8 @@
+// fuzz_targets/ews_response.rs
+// EWS SOAP responses and the item converters: cargo +nightly fuzz run ews_response
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| davmail_core::fuzzing::ews_response(data));
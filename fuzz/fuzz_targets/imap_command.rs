@@ -0,0 +1,8 @@
+// fuzz_targets/imap_command.rs
+// IMAP command lines from clients: cargo +nightly fuzz run imap_command
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| davmail_core::fuzzing::imap_command(data));
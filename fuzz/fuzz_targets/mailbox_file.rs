@@ -0,0 +1,8 @@
+// fuzz_targets/mailbox_file.rs
+// .eml and mbox files of the import command: cargo +nightly fuzz run mailbox_file
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| davmail_core::fuzzing::mailbox_file(data));
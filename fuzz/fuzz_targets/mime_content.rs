@@ -0,0 +1,8 @@
+// fuzz_targets/mime_content.rs
+// MimeContent streamed out of GetItem responses: cargo +nightly fuzz run mime_content
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| davmail_core::fuzzing::mime_content(data));
@@ -0,0 +1,8 @@
+// fuzz_targets/search.rs
+// SEARCH criteria: cargo +nightly fuzz run search
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| davmail_core::fuzzing::search(data));
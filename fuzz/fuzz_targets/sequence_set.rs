@@ -0,0 +1,8 @@
+// fuzz_targets/sequence_set.rs
+// FETCH and STORE sequence sets: cargo +nightly fuzz run sequence_set
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| davmail_core::fuzzing::sequence_set(data));
@@ -0,0 +1,422 @@
+// admin.rs
+// Web dashboard and admin API on davmail.adminPort: listeners, connection counts, token status and recent errors,
+// plus the autodiscover and autoconfig documents of autoconfig.rs
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::net::ToSocketAddrs;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Utc};
+use config::Config;
+use futures_util::future::{self, Either};
+use log::{error, info, warn, Level, Record};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, watch};
+use crate::auth::{same_bytes, AccountStatus};
+use crate::autoconfig::{self, Autoconfig};
+use crate::exchange::metrics;
+use crate::logformat::push_json_string;
+
+pub const DEFAULT_ADMIN_BIND_ADDRESS: &str = "127.0.0.1";
+
+// davmail.adminBindAddress and davmail.adminPort, None without a port
+pub fn admin_address(config: &Config) -> Option<(String, u16)> {
+    let port = config.get_int("davmail.adminPort").ok().filter(|port| *port > 0)? as u16;
+    let bind_address = config.get_string("davmail.adminBindAddress").ok()
+        .filter(|address| !address.trim().is_empty())
+        .map_or_else(|| DEFAULT_ADMIN_BIND_ADDRESS.to_string(), |address| address.trim().to_string());
+    Some((bind_address, port))
+}
+
+// Something already answers on the admin port, most likely another instance with the same configuration
+pub fn port_in_use(config: &Config) -> bool {
+    let Some((bind_address, port)) = admin_address(config) else {
+        return false;
+    };
+    let host = match bind_address.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        address => address,
+    };
+    (host, port).to_socket_addrs()
+        .map(|mut addresses| addresses.any(|address| std::net::TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_ok()))
+        .unwrap_or(false)
+}
+// Warnings and errors kept for the dashboard
+const RECENT_ERRORS: usize = 50;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADER_LINES: usize = 100;
+// Longest request or header line, longer ones are refused like a garbled request
+const MAX_LINE_BYTES: u64 = 8 * 1024;
+// Autodiscover requests are a few hundred bytes, only they have a body worth reading
+const MAX_BODY_BYTES: usize = 16 * 1024;
+
+const DASHBOARD: &str = include_str!("admin/dashboard.html");
+
+static RECENT: Mutex<VecDeque<RecentError>> = Mutex::new(VecDeque::new());
+
+struct RecentError {
+    time: DateTime<Utc>,
+    level: Level,
+    target: String,
+    message: String,
+}
+
+// Called by the logger for every record it lets through
+pub fn remember(record: &Record) {
+    if record.level() > Level::Warn {
+        return;
+    }
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == RECENT_ERRORS {
+        recent.pop_front();
+    }
+    recent.push_back(RecentError {
+        time: Utc::now(),
+        level: record.level(),
+        target: record.target().to_string(),
+        message: record.args().to_string(),
+    });
+}
+
+// Carried out by the main thread, which owns the listeners
+pub enum AdminCommand {
+    Status,
+    Enable { profile: String, protocol: String },
+    Disable { profile: String, protocol: String },
+    Restart { profile: String, protocol: String },
+    Reload,
+}
+
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    // JSON for Status, a message for the actions
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
+
+// A protocol listener of a profile, running or not
+pub struct ListenerStatus {
+    pub profile: String,
+    pub protocol: String,
+    pub bind_addresses: Vec<String>,
+    pub port: u16,
+    pub tls: bool,
+    pub running: bool,
+    pub connections: usize,
+}
+
+pub fn status_json(listeners: &[ListenerStatus], accounts: &[AccountStatus]) -> String {
+    let mut json = String::from("{\"listeners\":[");
+    for (index, listener) in listeners.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"profile\":");
+        push_json_string(&mut json, &listener.profile);
+        json.push_str(",\"protocol\":");
+        push_json_string(&mut json, &listener.protocol);
+        json.push_str(",\"bindAddresses\":");
+        push_json_string(&mut json, &listener.bind_addresses.join(", "));
+        let _ = write!(json, ",\"port\":{},\"tls\":{},\"running\":{},\"connections\":{}}}",
+            listener.port, listener.tls, listener.running, listener.connections);
+    }
+    json.push_str("],\"accounts\":[");
+    for (index, account) in accounts.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"username\":");
+        push_json_string(&mut json, &account.username);
+        json.push_str(",\"clientId\":");
+        push_json_string(&mut json, &account.client_id);
+        json.push_str(",\"expiresAt\":");
+        push_json_string(&mut json, &DateTime::<Utc>::from(account.expires_at).to_rfc3339());
+        let expired = account.expires_at <= SystemTime::now();
+        let _ = write!(json, ",\"expired\":{},\"sessions\":{}", expired, account.sessions);
+        if let Some(last_error) = &account.last_error {
+            json.push_str(",\"lastError\":");
+            push_json_string(&mut json, last_error);
+        }
+        json.push('}');
+    }
+    json.push_str("],\"recentErrors\":[");
+    for (index, recent) in RECENT.lock().unwrap().iter().rev().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"time\":");
+        push_json_string(&mut json, &recent.time.to_rfc3339());
+        json.push_str(",\"level\":");
+        push_json_string(&mut json, recent.level.as_str());
+        json.push_str(",\"target\":");
+        push_json_string(&mut json, &recent.target);
+        json.push_str(",\"message\":");
+        push_json_string(&mut json, &recent.message);
+        json.push('}');
+    }
+    json.push_str("],\"ewsErrors\":[");
+    for (index, response) in metrics::recent_error_responses().iter().rev().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"time\":");
+        push_json_string(&mut json, &response.time.to_rfc3339());
+        json.push_str(",\"operation\":");
+        push_json_string(&mut json, response.operation);
+        let _ = write!(json, ",\"status\":{},\"error\":", response.status);
+        push_json_string(&mut json, &response.error);
+        json.push_str(",\"body\":");
+        push_json_string(&mut json, &response.body);
+        json.push('}');
+    }
+    json.push_str("]}");
+    json
+}
+
+pub struct AdminServer {
+    bind_address: String,
+    port: u16,
+    // HTTP basic authentication as "admin", required unless the server only listens on the loopback interface
+    password: Option<String>,
+    // Autodiscover and autoconfig documents, served without the password
+    autoconfig: Option<Autoconfig>,
+    requests: Arc<dyn Fn(AdminRequest) + Send + Sync>,
+    // Told once the port is bound or binding failed
+    bound: Option<oneshot::Sender<()>>,
+}
+
+struct HttpResponse {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl HttpResponse {
+    fn new(status: &'static str, content_type: &'static str, body: impl Into<String>) -> Self {
+        HttpResponse { status, content_type, body: body.into() }
+    }
+
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        HttpResponse::new(status, "text/plain; charset=utf-8", body)
+    }
+}
+
+impl AdminServer {
+    // davmail.adminPort, davmail.adminBindAddress (127.0.0.1) and davmail.adminPassword. None when
+    // no port is set, or when the server would be reachable from the network without a password.
+    pub fn from_config(config: &Config, requests: impl Fn(AdminRequest) + Send + Sync + 'static) -> Option<Self> {
+        let (bind_address, port) = admin_address(config)?;
+        let password = config.get_string("davmail.adminPassword").ok().filter(|password| !password.is_empty());
+        let loopback = bind_address == "localhost" || bind_address.parse::<std::net::IpAddr>().is_ok_and(|address| address.is_loopback());
+        if password.is_none() && !loopback {
+            error!("Not starting the admin server on {}, davmail.adminPassword is required off the loopback interface", bind_address);
+            return None;
+        }
+        let autoconfig = Autoconfig::from_config(config);
+        Some(AdminServer { bind_address, port, password, autoconfig, requests: Arc::new(requests), bound: None })
+    }
+
+    // Address a browser on this machine opens the dashboard at
+    pub fn url(&self) -> String {
+        match self.bind_address.as_str() {
+            "0.0.0.0" | "::" => format!("http://127.0.0.1:{}/", self.port),
+            address if address.contains(':') => format!("http://[{}]:{}/", address, self.port),
+            address => format!("http://{}:{}/", address, self.port),
+        }
+    }
+
+    // Sent once the port is bound or failed to bind
+    pub fn with_bound_signal(mut self, bound: oneshot::Sender<()>) -> Self {
+        self.bound = Some(bound);
+        self
+    }
+
+    pub async fn run(mut self, mut shutdown_signal: watch::Receiver<bool>) {
+        let listener = TcpListener::bind((self.bind_address.as_str(), self.port)).await;
+        if let Some(bound) = self.bound.take() {
+            let _ = bound.send(());
+        }
+        let listener = match listener {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind admin server to {} port {}: {}", self.bind_address, self.port, e);
+                return;
+            }
+        };
+        info!("Admin server listening on {}", self.url());
+        let server = Arc::new(self);
+        loop {
+            let accepted = match future::select(pin!(listener.accept()), pin!(shutdown_signal.wait_for(|shutdown| *shutdown))).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => break,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = server.handle(stream).await {
+                            warn!("Admin request failed: {}", e);
+                        }
+                    });
+                },
+                Err(e) => {
+                    error!("Error accepting admin connection: {}", e);
+                    break;
+                }
+            }
+        }
+        info!("Admin server stopped");
+    }
+
+    // One request per connection
+    async fn handle(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            let Some((request_line, headers)) = read_head(&mut stream).await? else {
+                return Ok(None);
+            };
+            let length = headers.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+                .and_then(|(_, value)| value.parse::<usize>().ok())
+                .filter(|length| *length <= MAX_BODY_BYTES)
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            Ok(Some((request_line, headers, String::from_utf8_lossy(&body).into_owned())))
+        }).await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out")))?;
+        let response = match request {
+            Some((request_line, headers, body)) => self.respond(&request_line, &headers, &body).await,
+            None => HttpResponse::text("400 Bad Request", "Bad request"),
+        };
+
+        let mut message = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+            response.status, response.content_type, response.body.len());
+        if response.status.starts_with("401") {
+            message.push_str("WWW-Authenticate: Basic realm=\"gatewayrs563 admin\"\r\n");
+        }
+        message.push_str("\r\n");
+        message.push_str(&response.body);
+        let stream = stream.get_mut();
+        stream.write_all(message.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    async fn respond(&self, request_line: &str, headers: &[(String, String)], body: &str) -> HttpResponse {
+        let header = |name: &str| headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+
+        if let Some(response) = self.autoconfig(request_line, header("Host"), body) {
+            return response;
+        }
+
+        // Without a password only the loopback interface is served. A page whose host name was rebound to
+        // 127.0.0.1 still sends its own Host header, which keeps it from reading the status.
+        if self.password.is_none() && !header("Host").map(autoconfig::host_name)
+            .is_some_and(|host| host == self.bind_address || host == "localhost" || host == "127.0.0.1") {
+            return HttpResponse::text("403 Forbidden", "Unknown host");
+        }
+        if let Some(password) = &self.password {
+            let expected = format!("Basic {}", STANDARD.encode(format!("admin:{}", password)));
+            if !header("Authorization").is_some_and(|authorization| same_bytes(authorization.as_bytes(), expected.as_bytes())) {
+                return HttpResponse::text("401 Unauthorized", "Authentication required");
+            }
+        }
+
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return HttpResponse::text("400 Bad Request", "Bad request");
+        };
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<String> = path.trim_matches('/').split('/')
+            .map(|segment| urlencoding::decode(segment).map_or_else(|_| segment.to_string(), |segment| segment.into_owned()))
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        let command = match (method, segments.as_slice()) {
+            ("GET", [""]) => return HttpResponse::new("200 OK", "text/html; charset=utf-8", DASHBOARD),
+            ("GET", ["api", "status"]) => AdminCommand::Status,
+            ("POST", ["api", "reload"]) => AdminCommand::Reload,
+            ("POST", ["api", "listeners", profile, protocol, action]) => {
+                let (profile, protocol) = (profile.to_string(), protocol.to_string());
+                match *action {
+                    "enable" => AdminCommand::Enable { profile, protocol },
+                    "disable" => AdminCommand::Disable { profile, protocol },
+                    "restart" => AdminCommand::Restart { profile, protocol },
+                    _ => return HttpResponse::text("404 Not Found", "Unknown action"),
+                }
+            },
+            _ => return HttpResponse::text("404 Not Found", "Not found"),
+        };
+        // Browsers only send this header from scripts of the same origin, so another site can't post a form here
+        if method == "POST" && header("X-Requested-With").is_none() {
+            return HttpResponse::text("403 Forbidden", "X-Requested-With header required");
+        }
+
+        let (reply, replied) = oneshot::channel();
+        (self.requests)(AdminRequest { command, reply });
+        match replied.await {
+            Ok(Ok(body)) if body.starts_with('{') => HttpResponse::new("200 OK", "application/json", body),
+            Ok(Ok(message)) => HttpResponse::text("200 OK", message),
+            Ok(Err(message)) => HttpResponse::text("409 Conflict", message),
+            Err(_) => HttpResponse::text("503 Service Unavailable", "Shutting down"),
+        }
+    }
+
+    // Documents mail clients ask for when pointed at the gateway's host name, None for other requests
+    fn autoconfig(&self, request_line: &str, host: Option<&str>, body: &str) -> Option<HttpResponse> {
+        let autoconfig = self.autoconfig.as_ref()?;
+        let mut parts = request_line.split(' ');
+        let (method, target) = (parts.next()?, parts.next()?);
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let host = host.map_or(self.bind_address.as_str(), autoconfig::host_name);
+        match (method, path.to_lowercase().as_str()) {
+            // Outlook POSTs its request, some clients only GET
+            ("POST" | "GET", "/autodiscover/autodiscover.xml") => {
+                let xml = autoconfig.autodiscover(host, autoconfig::requested_email(body));
+                Some(HttpResponse::new("200 OK", "text/xml; charset=utf-8", xml))
+            },
+            ("GET", "/mail/config-v1.1.xml" | "/.well-known/autoconfig/mail/config-v1.1.xml") => {
+                let email = query.split('&')
+                    .filter_map(|parameter| parameter.split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("emailaddress"))
+                    .and_then(|(_, value)| urlencoding::decode(value).ok());
+                let xml = autoconfig.thunderbird(host, email.as_deref());
+                Some(HttpResponse::new("200 OK", "text/xml; charset=utf-8", xml))
+            },
+            _ => None,
+        }
+    }
+}
+
+// Request line and headers, None for a connection closed or garbled before the blank line
+pub(crate) async fn read_head(stream: &mut BufReader<TcpStream>) -> std::io::Result<Option<(String, Vec<(String, String)>)>> {
+    let Some(request_line) = read_line(stream).await? else {
+        return Ok(None);
+    };
+    let mut headers = Vec::new();
+    loop {
+        let Some(line) = read_line(stream).await?.filter(|_| headers.len() <= MAX_HEADER_LINES) else {
+            return Ok(None);
+        };
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(Some((request_line.trim_end().to_string(), headers)))
+}
+
+// One line of at most MAX_LINE_BYTES, None at the end of the stream or for a longer line
+async fn read_line(stream: &mut BufReader<TcpStream>) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    (&mut *stream).take(MAX_LINE_BYTES).read_line(&mut line).await?;
+    Ok(Some(line).filter(|line| line.ends_with('\n')))
+}
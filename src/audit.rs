@@ -0,0 +1,142 @@
+// audit.rs
+// Opt-in audit log of what each user did: logins, logouts, folder selections and deletions, one JSON object
+// per line so shared gateways can feed it to whatever keeps their records.
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use chrono::Utc;
+use config::Config;
+use log::{error, info};
+
+use crate::logformat::push_json_string;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static AUDIT_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent<'a> {
+    Login { client_address: IpAddr, offline: bool },
+    // Refused by Exchange, the lockout, the user limits or a hook, `reason` tells which
+    LoginFailed { client_address: IpAddr, reason: &'a str },
+    Logout,
+    Select { folder: &'a str },
+    // Messages moved to Deleted Items
+    Delete { folder: &'a str, count: usize },
+    // Every message of the folder permanently deleted
+    EmptyFolder { folder: &'a str },
+}
+
+impl AuditEvent<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            AuditEvent::Login { .. } => "login",
+            AuditEvent::LoginFailed { .. } => "login_failed",
+            AuditEvent::Logout => "logout",
+            AuditEvent::Select { .. } => "select",
+            AuditEvent::Delete { .. } => "delete",
+            AuditEvent::EmptyFolder { .. } => "empty_folder",
+        }
+    }
+
+    // The event's own fields, each preceded by a comma
+    fn push_fields(&self, line: &mut String) {
+        match self {
+            AuditEvent::Login { client_address, offline } => {
+                push_field(line, "client", &client_address.to_string());
+                let _ = write!(line, ",\"offline\":{}", offline);
+            },
+            AuditEvent::LoginFailed { client_address, reason } => {
+                push_field(line, "client", &client_address.to_string());
+                push_field(line, "reason", reason);
+            },
+            AuditEvent::Logout => {},
+            AuditEvent::Select { folder } | AuditEvent::EmptyFolder { folder } => push_field(line, "folder", folder),
+            AuditEvent::Delete { folder, count } => {
+                push_field(line, "folder", folder);
+                let _ = write!(line, ",\"count\":{}", count);
+            },
+        }
+    }
+}
+
+// davmail.auditLogFile, appended to. Independent of the log level, records are written whenever the file is set.
+pub fn configure(config: &Config) {
+    let path = config.get_string("davmail.auditLogFile").ok().filter(|path| !path.trim().is_empty());
+    let file = path.and_then(|path| {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(path.trim()) {
+            Ok(file) => {
+                info!("Writing the audit log to {}", path.trim());
+                Some(file)
+            },
+            Err(e) => {
+                error!("Failed to open audit log {}: {}", path.trim(), e);
+                None
+            },
+        }
+    });
+    ENABLED.store(file.is_some(), Ordering::Relaxed);
+    *AUDIT_LOG.lock().unwrap() = file;
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// {"timestamp":"2025-03-28T10:00:00.000Z","protocol":"IMAP","user":"alice@example.com","event":"select","folder":"INBOX"}
+pub fn record(protocol: &str, user: &str, event: &AuditEvent) {
+    if !enabled() {
+        return;
+    }
+    let mut line = String::with_capacity(192);
+    line.push_str("{\"timestamp\":");
+    push_json_string(&mut line, &Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string());
+    push_field(&mut line, "protocol", protocol);
+    push_field(&mut line, "user", user);
+    push_field(&mut line, "event", event.name());
+    event.push_fields(&mut line);
+    line.push('}');
+
+    if let Some(file) = AUDIT_LOG.lock().unwrap().as_mut() {
+        if let Err(e) = writeln!(file, "{}", line) {
+            error!("Failed to write the audit log: {}", e);
+        }
+    }
+}
+
+fn push_field(line: &mut String, name: &str, value: &str) {
+    line.push(',');
+    push_json_string(line, name);
+    line.push(':');
+    push_json_string(line, value);
+}
+
+// Records the login and, when dropped with the connection, the logout, whichever way the connection ends
+pub struct AuditSession {
+    protocol: &'static str,
+    user: String,
+}
+
+impl AuditSession {
+    pub fn login(protocol: &'static str, user: &str, client_address: IpAddr, offline: bool) -> Self {
+        record(protocol, user, &AuditEvent::Login { client_address, offline });
+        AuditSession { protocol, user: user.to_string() }
+    }
+
+    pub fn record(&self, event: &AuditEvent) {
+        record(self.protocol, &self.user, event);
+    }
+}
+
+impl Drop for AuditSession {
+    fn drop(&mut self) {
+        record(self.protocol, &self.user, &AuditEvent::Logout);
+    }
+}
@@ -1,36 +1,44 @@
 // auth.rs
 // Authentication module for DavMail Rust
 use std::fmt;
+use reqwest::Client;
+
+use crate::error::Result;
 
 pub mod basicauth;
+pub mod ntlm;
 pub mod oauth2;
+pub mod tokenmanager;
+pub mod tokenstore;
 
 pub use basicauth::*;
+pub use ntlm::*;
 pub use oauth2::*;
+pub use tokenmanager::*;
+pub use tokenstore::*;
 
 
-// Auth provider trait to support multiple authentication methods
+/// Auth provider trait to support multiple authentication methods
 pub trait AuthProvider {
-    fn get_auth_header(&self) -> Result<String, Box<dyn std::error::Error>>;
+    fn get_auth_header(&self) -> Result<String>;
 }
 
-// Basic Auth implementation
+/// Basic Auth implementation
 pub struct BasicAuth {
-    pub username: &'static str,
-    pub password: &'static str,
+    credentials: Credentials,
 }
 
 // Want to have it in the module.....
 impl BasicAuth {
-    pub fn new(username: &'static str, password: &'static str) -> Self {
-        BasicAuth { username, password }
+    pub fn new(credentials: Credentials) -> Self {
+        BasicAuth { credentials }
     }
 }
 
 
 impl AuthProvider for BasicAuth {
-    fn get_auth_header(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let auth = format!("{}:{}", self.username, self.password);
+    fn get_auth_header(&self) -> Result<String> {
+        let auth = self.credentials.basic_token();
         let encoded = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, auth.as_bytes());
         Ok(format!("Basic {}", encoded))
     }
@@ -39,31 +47,50 @@ impl AuthProvider for BasicAuth {
 // Don't print the password in debug output
 impl fmt::Debug for BasicAuth {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Credentials")
-            .field("username", &self.username)
-            .field("password", &"[REDACTED]")
+        f.debug_struct("BasicAuth")
+            .field("username", &self.credentials.username)
+            .field("password", &self.credentials.password)
             .finish()
     }
 }
 
-// OAuth2 Auth implementation
+/// OAuth2 Auth implementation
 pub struct OAuth2Auth {
     client: OAuth2Client,
 }
 
 impl OAuth2Auth {
-    pub fn new(config: OAuth2Config) -> Result<Self, OAuth2Error> {
-        let client = OAuth2Client::new(config)?;
+    pub fn new(config: OAuth2Config, http_client: Client) -> Result<Self, OAuth2Error> {
+        let client = OAuth2Client::new(config, http_client)?;
         Ok(Self { client })
     }
+    
+    pub fn with_token_store(mut self, token_store: SharedTokenStore, username: &str) -> Self {
+        self.client = self.client.with_token_store(token_store, username);
+        self
+    }
+    
+    pub fn with_password_credentials(mut self, username: &str, password: &str) -> Self {
+        self.client = self.client.with_password_credentials(username, password);
+        self
+    }
+    
+    pub fn with_user_assertion(mut self, username: &str, assertion: &str) -> Self {
+        self.client = self.client.with_user_assertion(username, assertion);
+        self
+    }
+    
+    pub fn token_key(&self) -> TokenKey {
+        self.client.token_key()
+    }
 }
 
 impl AuthProvider for OAuth2Auth {
-    fn get_auth_header(&self) -> Result<String, Box<dyn std::error::Error>> {
+    fn get_auth_header(&self) -> Result<String> {
         // In a real implementation, this would be async
         // For synchronous API compatibility, we'd need to use tokio::runtime::Runtime
         // to block on the async operation
-        Err("OAuth2Auth.get_auth_header() requires async runtime, use async_get_auth_header() instead".into())
+        Err(OAuth2Error::ConfigError("OAuth2Auth.get_auth_header() requires async runtime, use async_get_auth_header() instead".to_string()).into())
     }
 }
 
@@ -73,4 +100,24 @@ impl OAuth2Auth {
         let token = self.client.get_token().await?;
         Ok(token.authorization_header())
     }
+    
+    // Current token, refreshed when it is about to expire
+    pub async fn async_get_token(&mut self) -> Result<OAuth2Token, OAuth2Error> {
+        self.client.get_token().await
+    }
+    
+    // Sign the user in through the browser, whatever grant the configuration selects
+    pub async fn async_acquire_token_interactive(&mut self) -> Result<OAuth2Token, OAuth2Error> {
+        self.client.acquire_token_interactive().await
+    }
+    
+    // Sign the user in with a code entered on another device, for machines without a browser
+    pub async fn async_acquire_token_device_code(&mut self, prompt: impl FnOnce(&DeviceCode)) -> Result<OAuth2Token, OAuth2Error> {
+        self.client.acquire_token_device_code(prompt).await
+    }
+    
+    // New token even if the current one hasn't expired yet
+    pub async fn async_renew_token(&mut self) -> Result<OAuth2Token, OAuth2Error> {
+        self.client.renew_token().await
+    }
 }
@@ -2,20 +2,79 @@
 // Authentication module for DavMail Rust
 
 use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+// Password or other secret, wiped from memory when dropped
+#[derive(Clone)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: String) -> Self {
+        SecretString(secret)
+    }
+
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+
+    // Whether `candidate` is the secret, without telling through the time taken where they differ
+    pub fn matches(&self, candidate: &str) -> bool {
+        same_bytes(self.0.as_bytes(), candidate.as_bytes())
+    }
+}
+
+// Compares in the same time wherever the first difference is
+pub fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        SecretString(secret.to_string())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
 
 #[derive(Clone)]
 pub struct Credentials {
     pub username: String,
-    pub password: String,
+    pub password: SecretString,
 }
 
+impl Credentials {
+    pub fn new(username: String, password: impl Into<SecretString>) -> Self {
+        Credentials { username, password: password.into() }
+    }
+
+    // user:password as sent in a Basic Authorization header, wiped once encoded
+    pub fn basic_token(&self) -> Zeroizing<String> {
+        Zeroizing::new(format!("{}:{}", self.username, self.password.expose_secret()))
+    }
+}
 
 // Don't print the password in debug output
 impl fmt::Debug for Credentials {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Credentials")
             .field("username", &self.username)
-            .field("password", &"[REDACTED]")
+            .field("password", &self.password)
             .finish()
     }
 }
@@ -0,0 +1,198 @@
+// auth/ntlm.rs
+// NTLMv2 authentication for on-premise Exchange servers
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::SystemTime;
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+
+use super::AuthProvider;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE | REQUEST_TARGET | NEGOTIATE_NTLM | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY | NEGOTIATE_TARGET_INFO | NEGOTIATE_128 | NEGOTIATE_56;
+
+// Windows FILETIME epoch (1601) is this many seconds before the Unix epoch
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NtlmError {
+    #[error("Invalid NTLM challenge: {0}")]
+    InvalidChallenge(String),
+}
+
+// NTLM authenticates the TCP connection rather than each request, so the HTTP client used
+// with it must keep a single connection per host and stay on HTTP/1.1
+pub struct NtlmAuth {
+    pub username: String,
+    pub password: String,
+    pub domain: String,
+    pub workstation: String,
+}
+
+impl NtlmAuth {
+    // Accepts DOMAIN\user, user@domain is passed as is since Exchange resolves UPNs itself
+    pub fn new(login: &str, password: &str) -> Self {
+        let (domain, username) = match login.split_once('\\') {
+            Some((domain, username)) => (domain, username),
+            None => ("", login),
+        };
+
+        NtlmAuth {
+            username: username.to_string(),
+            password: password.to_string(),
+            domain: domain.to_string(),
+            workstation: String::new(),
+        }
+    }
+
+    // Authorization header value opening the handshake (type 1 message)
+    pub fn negotiate_header(&self) -> String {
+        format!("NTLM {}", self.negotiate_token())
+    }
+
+    // Base64 type 1 message, without the scheme so it can also be sent as a Negotiate token
+    pub fn negotiate_token(&self) -> String {
+        let mut message = Vec::with_capacity(32);
+        message.extend_from_slice(SIGNATURE);
+        message.extend_from_slice(&1u32.to_le_bytes());
+        message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+        // Empty domain and workstation security buffers
+        message.extend_from_slice(&[0; 16]);
+
+        base64::Engine::encode(&base64::engine::general_purpose::STANDARD, message)
+    }
+
+    // Authorization header value answering the server's WWW-Authenticate challenge (type 3 message)
+    pub fn authenticate_header(&self, www_authenticate: &str) -> Result<String, NtlmError> {
+        let encoded = www_authenticate.trim().strip_prefix("NTLM ")
+            .ok_or_else(|| NtlmError::InvalidChallenge("not an NTLM challenge".to_string()))?;
+        Ok(format!("NTLM {}", self.authenticate_token(encoded)?))
+    }
+
+    // Base64 type 3 message answering a base64 type 2 challenge
+    pub fn authenticate_token(&self, encoded: &str) -> Result<String, NtlmError> {
+        let challenge = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, encoded.trim())
+            .map_err(|e| NtlmError::InvalidChallenge(e.to_string()))?;
+
+        if challenge.len() < 48 || &challenge[..8] != SIGNATURE || read_u32(&challenge, 8) != 2 {
+            return Err(NtlmError::InvalidChallenge("not a type 2 message".to_string()));
+        }
+        let flags = read_u32(&challenge, 20) & NEGOTIATE_FLAGS;
+        let server_challenge = &challenge[24..32];
+        let target_info = read_buffer(&challenge, 40)?;
+
+        // NTLMv2 responses, LM and NT proofs share the user hash and client challenge
+        let client_challenge = random_bytes();
+        let user_hash = self.ntlmv2_hash();
+
+        let mut blob = vec![0x01, 0x01, 0, 0, 0, 0, 0, 0];
+        blob.extend_from_slice(&timestamp().to_le_bytes());
+        blob.extend_from_slice(&client_challenge);
+        blob.extend_from_slice(&[0; 4]);
+        blob.extend_from_slice(target_info);
+        blob.extend_from_slice(&[0; 4]);
+
+        let mut nt_response = hmac_md5(&user_hash, &[server_challenge, &blob]);
+        nt_response.extend_from_slice(&blob);
+        let mut lm_response = hmac_md5(&user_hash, &[server_challenge, &client_challenge]);
+        lm_response.extend_from_slice(&client_challenge);
+
+        let domain = utf16le(&self.domain);
+        let user = utf16le(&self.username);
+        let workstation = utf16le(&self.workstation);
+
+        // Fixed header is 64 bytes, payloads follow in the order of their security buffers
+        let payloads: [&[u8]; 6] = [&lm_response, &nt_response, &domain, &user, &workstation, &[]];
+        let mut message = Vec::new();
+        message.extend_from_slice(SIGNATURE);
+        message.extend_from_slice(&3u32.to_le_bytes());
+        let mut offset = 64u32;
+        for payload in payloads {
+            message.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            message.extend_from_slice(&offset.to_le_bytes());
+            offset += payload.len() as u32;
+        }
+        message.extend_from_slice(&flags.to_le_bytes());
+        for payload in payloads {
+            message.extend_from_slice(payload);
+        }
+
+        Ok(base64::Engine::encode(&base64::engine::general_purpose::STANDARD, message))
+    }
+
+    fn ntlmv2_hash(&self) -> Vec<u8> {
+        let nt_hash = Md4::digest(utf16le(&self.password));
+        let identity = utf16le(&format!("{}{}", self.username.to_uppercase(), self.domain));
+        hmac_md5(&nt_hash, &[&identity])
+    }
+}
+
+impl AuthProvider for NtlmAuth {
+    // First leg of the handshake, the rest depends on the server challenge
+    fn get_auth_header(&self) -> crate::error::Result<String> {
+        Ok(self.negotiate_header())
+    }
+}
+
+// Don't print the password in debug output
+impl fmt::Debug for NtlmAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtlmAuth")
+            .field("username", &self.username)
+            .field("domain", &self.domain)
+            .field("password", &"[REDACTED]")
+            .finish()
+    }
+}
+
+fn read_u32(message: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([message[offset], message[offset + 1], message[offset + 2], message[offset + 3]])
+}
+
+// Payload of the security buffer (length, max length, offset) starting at `offset`
+fn read_buffer(message: &[u8], offset: usize) -> Result<&[u8], NtlmError> {
+    let length = u16::from_le_bytes([message[offset], message[offset + 1]]) as usize;
+    let start = read_u32(message, offset + 4) as usize;
+    message.get(start..start + length)
+        .ok_or_else(|| NtlmError::InvalidChallenge("security buffer out of bounds".to_string()))
+}
+
+fn utf16le(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Current time as a Windows FILETIME (100ns intervals since 1601)
+fn timestamp() -> u64 {
+    let unix = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    (unix.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000 + u64::from(unix.subsec_nanos() / 100)
+}
+
+// Client challenge, RandomState is seeded from the OS random source
+fn random_bytes() -> [u8; 8] {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(timestamp());
+    hasher.finish().to_le_bytes()
+}
@@ -1,13 +1,21 @@
 // auth/oauth2.rs
 // OAuth2 implementation for Exchange Web Services (EWS)
 
-use std::error::Error;
-use std::fmt;
-use std::time::{Duration, SystemTime};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use config::Config;
 use reqwest::Client;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, ACCEPT};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Serialize, Deserialize};
-use log::debug;
+use log::{debug, info, warn};
+
+use super::tokenstore::{SharedTokenStore, TokenKey, TokenStore};
 
 // OAuth2 configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,12 +49,97 @@ impl OAuth2Config {
         }
     }
     
+    // Application registration from davmail.oauth.*, None when no client id is configured
+    pub fn from_config(config: &Config) -> Option<Self> {
+        OAuth2Config::from_profile(config, DEFAULT_PROFILE_PREFIX)
+    }
+    
+    // Registration for the user's login domain, so one gateway can serve several tenants.
+    // davmail.oauth.profiles lists profile names, davmail.oauth.profile.<name>.domains the domains
+    // each one serves, other davmail.oauth.profile.<name>.* keys override davmail.oauth.*
+    pub fn for_user(config: &Config, username: &str) -> Option<Self> {
+        let domain = match username.rsplit_once('@') {
+            Some((_, domain)) => domain.to_lowercase(),
+            None => return OAuth2Config::from_config(config),
+        };
+        
+        let profiles = config.get_string("davmail.oauth.profiles").unwrap_or_default();
+        for profile in profiles.split(',').map(str::trim).filter(|profile| !profile.is_empty()) {
+            let prefix = format!("davmail.oauth.profile.{}", profile);
+            let domains = config.get_string(&format!("{}.domains", prefix)).unwrap_or_default();
+            if domains.split(',').any(|candidate| candidate.trim().eq_ignore_ascii_case(&domain)) {
+                debug!("Using OAuth2 profile {} for {}", profile, username);
+                return OAuth2Config::from_profile(config, &prefix);
+            }
+        }
+        
+        OAuth2Config::from_config(config)
+    }
+    
+    fn from_profile(config: &Config, prefix: &str) -> Option<Self> {
+        let get = |key: &str| config.get_string(&format!("{}.{}", prefix, key))
+            .or_else(|_| config.get_string(&format!("{}.{}", DEFAULT_PROFILE_PREFIX, key)));
+        
+        let client_id = get("clientId").ok().filter(|id| !id.is_empty())?;
+        let tenant_id = get("tenantId").unwrap_or_else(|_| "common".to_string());
+        let oauth2_config = OAuth2Config::new(
+            &tenant_id,
+            &client_id,
+            &get("clientSecret").unwrap_or_default(),
+            &get("redirectUri").unwrap_or_else(|_| DEFAULT_REDIRECT_URI.to_string()),
+            &get("scope").unwrap_or_else(|_| DEFAULT_DELEGATED_SCOPE.to_string()),
+        );
+
+        Some(match get("authority") {
+            Ok(authority) if !authority.is_empty() => oauth2_config.with_authority(&authority),
+            _ => oauth2_config,
+        })
+    }
+    
     pub fn with_authority(mut self, authority: &str) -> Self {
         self.authority = authority.to_string();
         self
     }
 }
 
+const DEFAULT_PROFILE_PREFIX: &str = "davmail.oauth";
+
+pub const DEFAULT_REDIRECT_URI: &str = "http://localhost:1965/";
+
+// Delegated EWS access, offline_access to get refresh tokens
+pub const DEFAULT_DELEGATED_SCOPE: &str = "https://outlook.office365.com/EWS.AccessAsUser.All offline_access";
+
+// How long the local redirect listener waits for the user to finish signing in
+const INTERACTIVE_LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+// How long a connection to the redirect listener may take to send its request line
+const REDIRECT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Device authorization response, what the user is told to do on another device
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceCode {
+    pub user_code: String,
+    device_code: String,
+    pub verification_uri: String,
+    // Seconds until the code can no longer be used
+    pub expires_in: u64,
+    #[serde(default = "default_poll_interval")]
+    interval: u64,
+    // Ready to show instructions, in the language of the tenant
+    pub message: String,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+// Token endpoint error while the user hasn't finished the device code sign-in yet
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
 // OAuth2 token response structure
 #[derive(Debug, Deserialize)]
 pub struct TokenResponse {
@@ -61,35 +154,20 @@ pub struct TokenResponse {
 }
 
 // OAuth2 error types
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum OAuth2Error {
-    RequestError(reqwest::Error),
+    #[error("OAuth2 request error: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("OAuth2 response error: {0}")]
     ResponseError(String),
+    #[error("OAuth2 parse error: {0}")]
     ParseError(String),
+    #[error("OAuth2 token expired")]
     TokenExpired,
+    #[error("OAuth2 configuration error: {0}")]
     ConfigError(String),
 }
 
-impl fmt::Display for OAuth2Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            OAuth2Error::RequestError(e) => write!(f, "OAuth2 request error: {}", e),
-            OAuth2Error::ResponseError(s) => write!(f, "OAuth2 response error: {}", s),
-            OAuth2Error::ParseError(s) => write!(f, "OAuth2 parse error: {}", s),
-            OAuth2Error::TokenExpired => write!(f, "OAuth2 token expired"),
-            OAuth2Error::ConfigError(s) => write!(f, "OAuth2 configuration error: {}", s),
-        }
-    }
-}
-
-impl Error for OAuth2Error {}
-
-impl From<reqwest::Error> for OAuth2Error {
-    fn from(error: reqwest::Error) -> Self {
-        OAuth2Error::RequestError(error)
-    }
-}
-
 // OAuth2 token with metadata
 #[derive(Debug, Clone)]
 pub struct OAuth2Token {
@@ -115,18 +193,14 @@ impl OAuth2Token {
     }
     
     pub fn is_expired(&self) -> bool {
-        match SystemTime::now().duration_since(self.expires_at) {
-            Ok(_) => true,  // Current time is after expiry time
-            Err(_) => false, // Current time is before expiry time
-        }
+        // Current time is after expiry time
+        SystemTime::now().duration_since(self.expires_at).is_ok()
     }
     
     pub fn is_expiring_soon(&self, buffer_seconds: u64) -> bool {
         let buffer = Duration::from_secs(buffer_seconds);
-        match SystemTime::now().duration_since(self.expires_at.checked_sub(buffer).unwrap_or(self.expires_at)) {
-            Ok(_) => true,  // Token will expire within buffer time
-            Err(_) => false, // Token won't expire within buffer time
-        }
+        // Token will expire within buffer time
+        SystemTime::now().duration_since(self.expires_at.checked_sub(buffer).unwrap_or(self.expires_at)).is_ok()
     }
     
     pub fn authorization_header(&self) -> String {
@@ -138,11 +212,17 @@ impl OAuth2Token {
 pub struct OAuth2Client {
     config: OAuth2Config,
     http_client: Client,
-    current_token: Option<OAuth2Token>,
+    // Tokens are looked up per account, so one store can serve every user of the gateway
+    token_store: SharedTokenStore,
+    username: String,
+    // Set for the resource owner password flow, the password a local client logged in with
+    password: Option<String>,
+    // Set for the on-behalf-of flow, the token a front-end service obtained for the user
+    assertion: Option<String>,
 }
 
 impl OAuth2Client {
-    pub fn new(config: OAuth2Config) -> Result<Self, OAuth2Error> {
+    pub fn new(config: OAuth2Config, http_client: Client) -> Result<Self, OAuth2Error> {
         // Validate configuration
         if config.tenant_id.is_empty() {
             return Err(OAuth2Error::ConfigError("Tenant ID cannot be empty".to_string()));
@@ -150,28 +230,77 @@ impl OAuth2Client {
         if config.client_id.is_empty() {
             return Err(OAuth2Error::ConfigError("Client ID cannot be empty".to_string()));
         }
-        if config.client_secret.is_empty() {
-            return Err(OAuth2Error::ConfigError("Client secret cannot be empty".to_string()));
-        }
         if config.scope.is_empty() {
             return Err(OAuth2Error::ConfigError("Scope cannot be empty".to_string()));
         }
-        
-        let http_client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()?;
+        // The client secret is only checked by the client credentials flow,
+        // delegated flows can use a public client registration without one
         
         Ok(Self {
             config,
             http_client,
-            current_token: None,
+            token_store: TokenStore::shared(),
+            username: String::new(),
+            password: None,
+            assertion: None,
         })
     }
     
+    // Keep this account's tokens in a store shared with the other sessions
+    pub fn with_token_store(mut self, token_store: SharedTokenStore, username: &str) -> Self {
+        self.token_store = token_store;
+        self.username = username.to_string();
+        self
+    }
+    
+    // Exchange the user's own credentials for tokens (ROPC), for clients that can only send a password
+    pub fn with_password_credentials(mut self, username: &str, password: &str) -> Self {
+        self.username = username.to_string();
+        self.password = Some(password.to_string());
+        self
+    }
+    
+    // Exchange a token issued to a front-end service for the user (on-behalf-of),
+    // the gateway must be a confidential client the front-end's app registration trusts
+    pub fn with_user_assertion(mut self, username: &str, assertion: &str) -> Self {
+        self.username = username.to_string();
+        self.assertion = Some(assertion.to_string());
+        self
+    }
+    
+    pub fn token_key(&self) -> TokenKey {
+        OAuth2Client::token_key_for(&self.config, &self.username)
+    }
+    
+    fn current_token(&self) -> Option<OAuth2Token> {
+        self.token_store.lock().unwrap().get(&self.token_key()).cloned()
+    }
+    
+    fn store_token(&self, token: &OAuth2Token) {
+        let mut token_store = self.token_store.lock().unwrap();
+        token_store.insert(self.token_key(), token.clone());
+        // Refresh tokens rotate, keep the persisted one current
+        if let Err(e) = token_store.save() {
+            warn!("Failed to save OAuth2 token store: {}", e);
+        }
+    }
+    
+    pub fn token_key_for(config: &OAuth2Config, username: &str) -> TokenKey {
+        TokenKey {
+            tenant_id: config.tenant_id.clone(),
+            client_id: config.client_id.clone(),
+            username: username.to_string(),
+        }
+    }
+    
     // Acquire a token using client credentials grant flow
     pub async fn acquire_token_client_credentials(&mut self) -> Result<OAuth2Token, OAuth2Error> {
         debug!("Acquiring OAuth2 token using client credentials flow");
         
+        if self.config.client_secret.is_empty() {
+            return Err(OAuth2Error::ConfigError("Client secret cannot be empty".to_string()));
+        }
+        
         let token_endpoint = format!("{}/oauth2/v2.0/token", self.config.authority);
         
         let mut headers = HeaderMap::new();
@@ -207,15 +336,70 @@ impl OAuth2Client {
         }
         
         let token = OAuth2Token::from_response(token_response);
-        self.current_token = Some(token.clone());
+        self.store_token(&token);
         
         debug!("Successfully acquired OAuth2 token, expires at {:?}", token.expires_at);
         Ok(token)
     }
     
-    // Acquire a token using authorization code grant flow
-    pub async fn acquire_token_by_authorization_code(&mut self, code: &str) -> Result<OAuth2Token, OAuth2Error> {
-        debug!("Acquiring OAuth2 token using authorization code flow");
+    // Acquire a token using resource owner password credentials grant flow,
+    // only works for tenants without MFA or federation on the account
+    pub async fn acquire_token_password(&mut self, username: &str, password: &str) -> Result<OAuth2Token, OAuth2Error> {
+        debug!("Acquiring OAuth2 token for {} using password credentials flow", username);
+        
+        let token_endpoint = format!("{}/oauth2/v2.0/token", self.config.authority);
+        
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        
+        let mut form_params = vec![
+            ("grant_type", "password"),
+            ("client_id", &self.config.client_id),
+            ("username", username),
+            ("password", password),
+            ("scope", &self.config.scope),
+        ];
+        if !self.config.client_secret.is_empty() {
+            form_params.push(("client_secret", &self.config.client_secret));
+        }
+        
+        let response = self.http_client
+            .post(&token_endpoint)
+            .headers(headers)
+            .form(&form_params)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_else(|_| "Could not read error response".to_string());
+            return Err(OAuth2Error::ResponseError(format!("Token request failed ({}): {}", status, error_text)));
+        }
+        
+        let token_response: TokenResponse = response.json().await?;
+        
+        // Check for errors in the response
+        if let Some(error) = token_response.error {
+            let description = token_response.error_description.unwrap_or_else(|| "No error description".to_string());
+            return Err(OAuth2Error::ResponseError(format!("OAuth error: {} - {}", error, description)));
+        }
+        
+        let token = OAuth2Token::from_response(token_response);
+        self.store_token(&token);
+        
+        debug!("Successfully acquired OAuth2 token, expires at {:?}", token.expires_at);
+        Ok(token)
+    }
+    
+    // Acquire a token using the on-behalf-of flow, the assertion is the access token
+    // the front-end service received for the user with the gateway API as audience
+    pub async fn acquire_token_on_behalf_of(&mut self, assertion: &str) -> Result<OAuth2Token, OAuth2Error> {
+        debug!("Acquiring OAuth2 token for {} using on-behalf-of flow", self.username);
+        
+        if self.config.client_secret.is_empty() {
+            return Err(OAuth2Error::ConfigError("Client secret cannot be empty".to_string()));
+        }
         
         let token_endpoint = format!("{}/oauth2/v2.0/token", self.config.authority);
         
@@ -224,13 +408,64 @@ impl OAuth2Client {
         headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
         
         let form_params = [
-            ("grant_type", "authorization_code"),
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
             ("client_id", &self.config.client_id),
             ("client_secret", &self.config.client_secret),
+            ("assertion", assertion),
+            ("scope", &self.config.scope),
+            ("requested_token_use", "on_behalf_of"),
+        ];
+        
+        let response = self.http_client
+            .post(&token_endpoint)
+            .headers(headers)
+            .form(&form_params)
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_else(|_| "Could not read error response".to_string());
+            return Err(OAuth2Error::ResponseError(format!("Token request failed ({}): {}", status, error_text)));
+        }
+        
+        let token_response: TokenResponse = response.json().await?;
+        
+        // Check for errors in the response
+        if let Some(error) = token_response.error {
+            let description = token_response.error_description.unwrap_or_else(|| "No error description".to_string());
+            return Err(OAuth2Error::ResponseError(format!("OAuth error: {} - {}", error, description)));
+        }
+        
+        let token = OAuth2Token::from_response(token_response);
+        self.store_token(&token);
+        
+        debug!("Successfully acquired OAuth2 token, expires at {:?}", token.expires_at);
+        Ok(token)
+    }
+    
+    // Acquire a token using authorization code grant flow, `code_verifier` is the PKCE verifier the
+    // authorization request was made with
+    pub async fn acquire_token_by_authorization_code(&mut self, code: &str, code_verifier: &str) -> Result<OAuth2Token, OAuth2Error> {
+        debug!("Acquiring OAuth2 token using authorization code flow");
+        
+        let token_endpoint = format!("{}/oauth2/v2.0/token", self.config.authority);
+        
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        
+        let mut form_params = vec![
+            ("grant_type", "authorization_code"),
+            ("client_id", &self.config.client_id),
             ("code", code),
             ("redirect_uri", &self.config.redirect_uri),
             ("scope", &self.config.scope),
+            ("code_verifier", code_verifier),
         ];
+        if !self.config.client_secret.is_empty() {
+            form_params.push(("client_secret", &self.config.client_secret));
+        }
         
         let response = self.http_client
             .post(&token_endpoint)
@@ -254,7 +489,7 @@ impl OAuth2Client {
         }
         
         let token = OAuth2Token::from_response(token_response);
-        self.current_token = Some(token.clone());
+        self.store_token(&token);
         
         debug!("Successfully acquired OAuth2 token, expires at {:?}", token.expires_at);
         Ok(token)
@@ -270,13 +505,15 @@ impl OAuth2Client {
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
         headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
         
-        let form_params = [
+        let mut form_params = vec![
             ("grant_type", "refresh_token"),
             ("client_id", &self.config.client_id),
-            ("client_secret", &self.config.client_secret),
             ("refresh_token", refresh_token),
             ("scope", &self.config.scope),
         ];
+        if !self.config.client_secret.is_empty() {
+            form_params.push(("client_secret", &self.config.client_secret));
+        }
         
         let response = self.http_client
             .post(&token_endpoint)
@@ -300,15 +537,23 @@ impl OAuth2Client {
         }
         
         let token = OAuth2Token::from_response(token_response);
-        self.current_token = Some(token.clone());
+        self.store_token(&token);
         
         debug!("Successfully refreshed OAuth2 token, expires at {:?}", token.expires_at);
         Ok(token)
     }
     
+    // Replace a token the server rejected before its expiry, e.g. after the session was revoked
+    pub async fn renew_token(&mut self) -> Result<OAuth2Token, OAuth2Error> {
+        match self.current_token().and_then(|token| token.refresh_token) {
+            Some(refresh_token) => self.refresh_token(&refresh_token).await,
+            None => self.acquire_token().await,
+        }
+    }
+    
     // Get a valid token, refreshing if necessary
     pub async fn get_token(&mut self) -> Result<OAuth2Token, OAuth2Error> {
-        if let Some(token) = &self.current_token.clone() {
+        if let Some(token) = &self.current_token() {
             // If token is expiring soon (within 5 minutes), refresh it
             if token.is_expiring_soon(300) {
                 debug!("Current token is expiring soon, refreshing");
@@ -316,7 +561,7 @@ impl OAuth2Client {
                     return self.refresh_token(refresh_token).await;
                 } else {
                     debug!("No refresh token available, acquiring new token");
-                    return self.acquire_token_client_credentials().await;
+                    return self.acquire_token().await;
                 }
             }
             
@@ -326,18 +571,231 @@ impl OAuth2Client {
         
         // No token yet, acquire a new one
         debug!("No current token, acquiring new token");
-        self.acquire_token_client_credentials().await
+        self.acquire_token().await
     }
     
-    // Generate authorization URL for user to visit
-    pub fn get_authorization_url(&self, state: &str) -> String {
+    // Acquire a token with the grant type selected by the configuration
+    async fn acquire_token(&mut self) -> Result<OAuth2Token, OAuth2Error> {
+        if let Some(password) = self.password.clone() {
+            let username = self.username.clone();
+            self.acquire_token_password(&username, &password).await
+        } else if let Some(assertion) = self.assertion.clone() {
+            self.acquire_token_on_behalf_of(&assertion).await
+        } else {
+            self.acquire_token_client_credentials().await
+        }
+    }
+    
+    // Authorization code flow: open the login page in the browser and catch the code on the localhost redirect URI
+    pub async fn acquire_token_interactive(&mut self) -> Result<OAuth2Token, OAuth2Error> {
+        debug!("Acquiring OAuth2 token using interactive authorization code flow");
+        
+        let (address, path) = local_redirect(&self.config.redirect_uri)?;
+        let listener = TcpListener::bind(&address)
+            .map_err(|e| OAuth2Error::ConfigError(format!("Cannot listen on redirect URI {}: {}", self.config.redirect_uri, e)))?;
+        
+        let state = random_state()?;
+        let (code_verifier, code_challenge) = pkce_pair()?;
+        let authorization_url = self.get_authorization_url(&state, &code_challenge);
+        open_browser(&authorization_url, "the Microsoft login page");
+        
+        let code = tokio::task::spawn_blocking(move || wait_for_authorization_code(listener, &path, &state))
+            .await
+            .map_err(|e| OAuth2Error::ResponseError(format!("Redirect listener failed: {}", e)))??;
+        
+        self.acquire_token_by_authorization_code(&code, &code_verifier).await
+    }
+    
+    // Device code flow for machines without a browser: `prompt` shows the code the user enters on
+    // another device, then the token endpoint is polled until the sign-in completes or the code expires
+    pub async fn acquire_token_device_code(&mut self, prompt: impl FnOnce(&DeviceCode)) -> Result<OAuth2Token, OAuth2Error> {
+        debug!("Acquiring OAuth2 token using device code flow");
+        
+        let response = self.http_client
+            .post(format!("{}/oauth2/v2.0/devicecode", self.config.authority))
+            .header(ACCEPT, "application/json")
+            .form(&[("client_id", self.config.client_id.as_str()), ("scope", self.config.scope.as_str())])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_else(|_| "Could not read error response".to_string());
+            return Err(OAuth2Error::ResponseError(format!("Device code request failed ({}): {}", status, error_text)));
+        }
+        let device_code: DeviceCode = response.json().await?;
+        prompt(&device_code);
+        
+        let token_endpoint = format!("{}/oauth2/v2.0/token", self.config.authority);
+        let deadline = Instant::now() + Duration::from_secs(device_code.expires_in);
+        let mut interval = Duration::from_secs(device_code.interval.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            if Instant::now() >= deadline {
+                return Err(OAuth2Error::ResponseError("The device code expired before the sign-in was completed".to_string()));
+            }
+            
+            let mut form_params = vec![
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("client_id", &self.config.client_id),
+                ("device_code", &device_code.device_code),
+            ];
+            if !self.config.client_secret.is_empty() {
+                form_params.push(("client_secret", &self.config.client_secret));
+            }
+            let response = self.http_client
+                .post(&token_endpoint)
+                .header(ACCEPT, "application/json")
+                .form(&form_params)
+                .send()
+                .await?;
+            
+            if response.status().is_success() {
+                let token = OAuth2Token::from_response(response.json().await?);
+                self.store_token(&token);
+                debug!("Successfully acquired OAuth2 token, expires at {:?}", token.expires_at);
+                return Ok(token);
+            }
+            let status = response.status();
+            match response.json::<TokenError>().await {
+                Ok(error) if error.error == "authorization_pending" => {},
+                Ok(error) if error.error == "slow_down" => interval += Duration::from_secs(5),
+                Ok(error) => {
+                    let description = error.error_description.unwrap_or_else(|| "No error description".to_string());
+                    return Err(OAuth2Error::ResponseError(format!("OAuth error: {} - {}", error.error, description)));
+                },
+                Err(_) => return Err(OAuth2Error::ResponseError(format!("Token request failed ({})", status))),
+            }
+        }
+    }
+    
+    // Generate authorization URL for user to visit, `code_challenge` is the S256 PKCE challenge
+    pub fn get_authorization_url(&self, state: &str, code_challenge: &str) -> String {
         format!(
-            "{}/oauth2/v2.0/authorize?client_id={}&response_type=code&redirect_uri={}&scope={}&state={}",
+            "{}/oauth2/v2.0/authorize?client_id={}&response_type=code&redirect_uri={}&scope={}&state={}&code_challenge={}&code_challenge_method=S256",
             self.config.authority,
             self.config.client_id,
             urlencoding::encode(&self.config.redirect_uri),
             urlencoding::encode(&self.config.scope),
-            urlencoding::encode(state)
+            urlencoding::encode(state),
+            code_challenge
         )
     }
 }
+
+// Listen address and path of a http://localhost redirect URI
+fn local_redirect(redirect_uri: &str) -> Result<(String, String), OAuth2Error> {
+    let invalid = || OAuth2Error::ConfigError(format!(
+        "Interactive login needs a http://localhost:<port>/ redirect URI, got '{}'", redirect_uri
+    ));
+    
+    let rest = redirect_uri.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = authority.rsplit_once(':').unwrap_or((authority, "80"));
+    if host != "localhost" && host != "127.0.0.1" && host != "[::1]" {
+        return Err(invalid());
+    }
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    
+    let host = if host == "localhost" { "127.0.0.1" } else { host };
+    Ok((format!("{}:{}", host, port), path.to_string()))
+}
+
+// Unguessable state parameter, ties the redirect to the login this process started
+fn random_state() -> Result<String, OAuth2Error> {
+    let mut random = [0u8; 16];
+    SystemRandom::new().fill(&mut random)
+        .map_err(|_| OAuth2Error::ConfigError("No random source for the state parameter".to_string()))?;
+    Ok(URL_SAFE_NO_PAD.encode(random))
+}
+
+// PKCE (RFC 7636) verifier and its S256 challenge. Only the holder of the verifier can redeem the
+// code, the public client registrations without a secret have nothing else to prove it.
+fn pkce_pair() -> Result<(String, String), OAuth2Error> {
+    let mut random = [0u8; 32];
+    SystemRandom::new().fill(&mut random)
+        .map_err(|_| OAuth2Error::ConfigError("No random source for the PKCE verifier".to_string()))?;
+    let verifier = URL_SAFE_NO_PAD.encode(random);
+    let challenge = URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, verifier.as_bytes()));
+    Ok((verifier, challenge))
+}
+
+// Open `url` in the desktop's default browser, `what` names the page in the log
+pub fn open_browser(url: &str, what: &str) {
+    // cmd /C start would split the URL at each &
+    let result = if cfg!(target_os = "windows") {
+        Command::new("rundll32").args(["url.dll,FileProtocolHandler", url]).spawn()
+    } else if cfg!(target_os = "macos") {
+        Command::new("open").arg(url).spawn()
+    } else {
+        Command::new("xdg-open").arg(url).spawn()
+    };
+    
+    match result {
+        Ok(_) => info!("Opened {} in the browser", what),
+        Err(e) => warn!("Could not open a browser ({}), {} is at: {}", e, what, url),
+    }
+}
+
+// Serve the redirect URI until the browser comes back with a code for our state
+fn wait_for_authorization_code(listener: TcpListener, path: &str, state: &str) -> Result<String, OAuth2Error> {
+    let io_error = |e: std::io::Error| OAuth2Error::ResponseError(format!("Redirect listener failed: {}", e));
+    
+    listener.set_nonblocking(true).map_err(io_error)?;
+    let deadline = Instant::now() + INTERACTIVE_LOGIN_TIMEOUT;
+    
+    while Instant::now() < deadline {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(e) => return Err(io_error(e)),
+        };
+        stream.set_nonblocking(false).map_err(io_error)?;
+        stream.set_read_timeout(Some(REDIRECT_READ_TIMEOUT)).map_err(io_error)?;
+        
+        // Only the request line matters: GET /path?code=...&state=... HTTP/1.1
+        let mut request_line = String::new();
+        if let Err(e) = BufReader::new(&stream).read_line(&mut request_line) {
+            // A connection that stays silent mustn't keep the browser's one from being served
+            debug!("Dropping redirect listener connection: {}", e);
+            continue;
+        }
+        let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let (request_path, query) = target.split_once('?').unwrap_or((target, ""));
+        if request_path != path {
+            let _ = write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            continue;
+        }
+        
+        let params: Vec<(String, String)> = query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (
+                key.to_string(),
+                urlencoding::decode(&value.replace('+', " ")).map(|v| v.into_owned()).unwrap_or_default(),
+            ))
+            .collect();
+        let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        
+        let (result, message) = if let Some(error) = param("error") {
+            let description = param("error_description").unwrap_or("No error description");
+            (Err(OAuth2Error::ResponseError(format!("OAuth error: {} - {}", error, description))), "Sign-in failed, check the DavMail log.")
+        } else if param("state") != Some(state) {
+            (Err(OAuth2Error::ResponseError("OAuth state mismatch in redirect".to_string())), "Sign-in failed, check the DavMail log.")
+        } else if let Some(code) = param("code") {
+            (Ok(code.to_string()), "Sign-in complete, you can close this window.")
+        } else {
+            (Err(OAuth2Error::ResponseError("Redirect without authorization code".to_string())), "Sign-in failed, check the DavMail log.")
+        };
+        
+        let body = format!("<html><body><p>{}</p></body></html>", message);
+        let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        return result;
+    }
+    
+    Err(OAuth2Error::ResponseError("Timed out waiting for the interactive login".to_string()))
+}
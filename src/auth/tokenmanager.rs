@@ -0,0 +1,183 @@
+// auth/tokenmanager.rs
+// Renews OAuth2 tokens ahead of expiry for all sessions of an account
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use futures_util::future::{select, Either};
+use log::{debug, info, warn};
+use tokio::runtime::Handle;
+use tokio::sync::{watch, Notify};
+
+use super::oauth2::{OAuth2Error, OAuth2Token};
+use super::tokenstore::TokenKey;
+use super::OAuth2Auth;
+
+// Same margin as OAuth2Client::get_token, so the renewal wakes up when the token counts as expiring
+const RENEWAL_MARGIN: Duration = Duration::from_secs(300);
+// Wait before trying again after a failed renewal, the current token stays valid until then
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// How long a session rejected by the server waits for the renewal task to replace the token
+const RENEW_NOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Sessions hold a receiver for the Authorization header of their account
+pub struct TokenUpdates {
+    receiver: watch::Receiver<String>,
+    renew_now: Arc<Notify>,
+}
+
+impl TokenUpdates {
+    // Latest Authorization header
+    pub fn header(&self) -> String {
+        self.receiver.borrow().clone()
+    }
+
+    // Ask for a new token after the server rejected the current one (revoked session, password change),
+    // sessions rejected at the same time share one renewal
+    pub async fn renew(&self) -> Result<String, OAuth2Error> {
+        let mut receiver = self.receiver.clone();
+        receiver.borrow_and_update();
+        self.renew_now.notify_one();
+
+        match tokio::time::timeout(RENEW_NOW_TIMEOUT, receiver.changed()).await {
+            Ok(Ok(())) => Ok(receiver.borrow().clone()),
+            Ok(Err(_)) => Err(OAuth2Error::ResponseError("Token renewal stopped".to_string())),
+            Err(_) => Err(OAuth2Error::ResponseError("Token renewal timed out".to_string())),
+        }
+    }
+}
+
+struct Renewal {
+    sender: Arc<watch::Sender<String>>,
+    renew_now: Arc<Notify>,
+    expires_at: SystemTime,
+    last_error: Option<String>,
+}
+
+// Token state of an account with live sessions, for the admin UI
+pub struct AccountStatus {
+    pub username: String,
+    pub client_id: String,
+    pub sessions: usize,
+    pub expires_at: SystemTime,
+    // Why the last renewal failed, None once one succeeds
+    pub last_error: Option<String>,
+}
+
+type Renewals = Arc<Mutex<HashMap<TokenKey, Renewal>>>;
+
+// One renewal task per account, running on the main runtime so it outlives the connection threads
+pub struct TokenManager {
+    handle: Handle,
+    renewals: Renewals,
+}
+
+impl TokenManager {
+    pub fn new(handle: Handle) -> Self {
+        TokenManager {
+            handle,
+            renewals: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Acquire a token with the session's own credentials, then join the account's renewal task.
+    // Acquiring first makes every login prove its credentials even when the account is already renewed.
+    pub async fn subscribe(&self, mut oauth2_auth: OAuth2Auth) -> Result<TokenUpdates, OAuth2Error> {
+        let key = oauth2_auth.token_key();
+        let token = oauth2_auth.async_get_token().await?;
+        let header = token.authorization_header();
+
+        let mut renewals = self.renewals.lock().unwrap();
+        if let Some(renewal) = renewals.get_mut(&key) {
+            renewal.expires_at = token.expires_at;
+            renewal.last_error = None;
+            renewal.sender.send_replace(header);
+            return Ok(TokenUpdates {
+                receiver: renewal.sender.subscribe(),
+                renew_now: renewal.renew_now.clone(),
+            });
+        }
+
+        let (sender, receiver) = watch::channel(header);
+        let sender = Arc::new(sender);
+        let renew_now = Arc::new(Notify::new());
+        renewals.insert(key.clone(), Renewal {
+            sender: sender.clone(),
+            renew_now: renew_now.clone(),
+            expires_at: token.expires_at,
+            last_error: None,
+        });
+        debug!("Starting token renewal for '{}' ({})", key.username, key.client_id);
+        self.handle.spawn(renew(self.renewals.clone(), key, oauth2_auth, renewal_delay(&token), sender, renew_now.clone()));
+
+        Ok(TokenUpdates { receiver, renew_now })
+    }
+
+    // Accounts being renewed, sorted by username
+    pub fn accounts(&self) -> Vec<AccountStatus> {
+        let mut accounts: Vec<AccountStatus> = self.renewals.lock().unwrap().iter()
+            .map(|(key, renewal)| AccountStatus {
+                username: key.username.clone(),
+                client_id: key.client_id.clone(),
+                sessions: renewal.sender.receiver_count(),
+                expires_at: renewal.expires_at,
+                last_error: renewal.last_error.clone(),
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.username.cmp(&b.username));
+        accounts
+    }
+}
+
+async fn renew(renewals: Renewals, key: TokenKey, mut oauth2_auth: OAuth2Auth, mut delay: Duration,
+               sender: Arc<watch::Sender<String>>, renew_now: Arc<Notify>) {
+    loop {
+        // closed() completes once every session of the account has ended, notified() when a session was rejected
+        let woken = tokio::time::timeout(delay, select(Box::pin(sender.closed()), Box::pin(renew_now.notified()))).await;
+        let forced = match woken {
+            Ok(Either::Left(_)) => {
+                let mut renewals = renewals.lock().unwrap();
+                // A session may have subscribed again in the meantime
+                if sender.receiver_count() == 0 {
+                    renewals.remove(&key);
+                    info!("Stopped token renewal for '{}', no session left", key.username);
+                    return;
+                }
+                continue;
+            },
+            Ok(Either::Right(_)) => true,
+            Err(_) => false,
+        };
+
+        let renewed = if forced {
+            debug!("Server rejected the token of '{}', renewing it now", key.username);
+            oauth2_auth.async_renew_token().await
+        } else {
+            oauth2_auth.async_get_token().await
+        };
+        match renewed {
+            Ok(token) => {
+                debug!("Renewed OAuth2 token for '{}', expires at {:?}", key.username, token.expires_at);
+                if let Some(renewal) = renewals.lock().unwrap().get_mut(&key) {
+                    renewal.expires_at = token.expires_at;
+                    renewal.last_error = None;
+                }
+                sender.send_replace(token.authorization_header());
+                delay = renewal_delay(&token);
+            },
+            Err(e) => {
+                warn!("Failed to renew OAuth2 token for '{}': {}", key.username, e);
+                if let Some(renewal) = renewals.lock().unwrap().get_mut(&key) {
+                    renewal.last_error = Some(e.to_string());
+                }
+                delay = RETRY_INTERVAL;
+            }
+        }
+    }
+}
+
+fn renewal_delay(token: &OAuth2Token) -> Duration {
+    token.expires_at.duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .saturating_sub(RENEWAL_MARGIN)
+}
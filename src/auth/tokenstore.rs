@@ -0,0 +1,233 @@
+// auth/tokenstore.rs
+// OAuth2 tokens of every account using the gateway
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use log::{debug, warn};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::oauth2::OAuth2Token;
+
+// Tokens are only valid for the tenant and application they were issued to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TokenKey {
+    pub tenant_id: String,
+    pub client_id: String,
+    // Empty for application (client credentials) tokens
+    pub username: String,
+}
+
+const SALT_LEN: usize = 16;
+const VERIFIER_LEN: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    // Refresh tokens are written back here when set (davmail.oauth.tokenFile)
+    path: Option<PathBuf>,
+    tokens: HashMap<TokenKey, OAuth2Token>,
+    // Salt and PBKDF2 verifier of the password a broker mode user logs in to the gateway with
+    passwords: HashMap<TokenKey, (Vec<u8>, Vec<u8>)>,
+    // Accounts stored, given a password or removed since the file was read, only they are written back
+    changed: HashSet<TokenKey>,
+}
+
+pub type SharedTokenStore = Arc<Mutex<TokenStore>>;
+
+impl TokenStore {
+    pub fn shared() -> SharedTokenStore {
+        Arc::new(Mutex::new(TokenStore::default()))
+    }
+
+    // Load refresh tokens from a tab separated file (tenant, client id, username, refresh token and, for
+    // broker mode, the base64 salt and verifier of the user's gateway password), a missing file is an empty store
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut store = TokenStore {
+            path: Some(path.as_ref().to_path_buf()),
+            ..TokenStore::default()
+        };
+
+        let file = match File::open(path.as_ref()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                [tenant_id, client_id, username, refresh_token, password @ ..] if password.len() <= 1 => {
+                    let key = TokenKey {
+                        tenant_id: tenant_id.to_string(),
+                        client_id: client_id.to_string(),
+                        username: username.to_string(),
+                    };
+                    if let Some(password) = password.first() {
+                        match parse_password(password) {
+                            Some(password) => {
+                                store.passwords.insert(key.clone(), password);
+                            },
+                            None => warn!("Ignoring invalid password verifier in the token store"),
+                        }
+                    }
+                    store.tokens.insert(key, refresh_only(refresh_token));
+                },
+                _ => warn!("Ignoring invalid token store line"),
+            }
+        }
+
+        debug!("Loaded {} refresh tokens from {:?}", store.tokens.len(), store.path);
+        Ok(store)
+    }
+
+    pub fn get(&self, key: &TokenKey) -> Option<&OAuth2Token> {
+        self.tokens.get(key)
+    }
+
+    pub fn insert(&mut self, key: TokenKey, token: OAuth2Token) {
+        debug!("Storing OAuth2 token for '{}' ({})", key.username, key.client_id);
+        self.changed.insert(key.clone());
+        self.tokens.insert(key, token);
+    }
+
+    // Refresh token obtained outside the gateway, for tenants whose policies block every flow it can run.
+    // The access token is fetched with it on first use.
+    pub fn import_refresh_token(&mut self, key: TokenKey, refresh_token: &str) {
+        self.insert(key, refresh_only(refresh_token));
+    }
+
+    // Password the user logs in to the gateway with in broker mode, set by the administrator with the refresh token
+    pub fn set_password(&mut self, key: &TokenKey, password: &str) -> io::Result<()> {
+        let mut salt = vec![0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt)
+            .map_err(|_| io::Error::other("No random source for the password salt"))?;
+        let mut verifier = vec![0u8; VERIFIER_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &salt, password.as_bytes(), &mut verifier);
+        self.passwords.insert(key.clone(), (salt, verifier));
+        self.changed.insert(key.clone());
+        Ok(())
+    }
+
+    // False when no password was set for the account, in constant time otherwise
+    pub fn verify_password(&self, key: &TokenKey, password: &str) -> bool {
+        self.passwords.get(key).is_some_and(|(salt, verifier)| {
+            pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), salt, password.as_bytes(), verifier).is_ok()
+        })
+    }
+
+    // Forget an account, e.g. after its refresh token was revoked
+    pub fn remove(&mut self, key: &TokenKey) -> Option<OAuth2Token> {
+        self.changed.insert(key.clone());
+        self.passwords.remove(key);
+        self.tokens.remove(key)
+    }
+
+    // Accounts with a token, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &TokenKey> {
+        self.tokens.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // Write the accounts changed here back to disk. Login sessions and the token command each hold a
+    // store of their own, so the file is read again under a lock and only those accounts replace its
+    // entries: tokens imported or rotated elsewhere since this store was read are kept.
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let lock = OpenOptions::new().write(true).create(true).truncate(false).open(path.with_extension("lock"))?;
+        lock.lock()?;
+        let mut current = TokenStore::open(&path)?;
+        for key in &self.changed {
+            match self.tokens.get(key) {
+                Some(token) => current.tokens.insert(key.clone(), token.clone()),
+                None => current.tokens.remove(key),
+            };
+            match self.passwords.get(key) {
+                Some(password) => current.passwords.insert(key.clone(), password.clone()),
+                None => current.passwords.remove(key),
+            };
+        }
+        current.write(&path)?;
+        drop(lock);
+
+        // Take in the other accounts' changes, keeping the access tokens of refresh tokens that didn't change
+        for (key, token) in current.tokens.iter_mut() {
+            if let Some(own) = self.tokens.get(key).filter(|own| own.refresh_token == token.refresh_token) {
+                *token = own.clone();
+            }
+        }
+        self.tokens = current.tokens;
+        self.passwords = current.passwords;
+        self.changed.clear();
+        Ok(())
+    }
+
+    // Refresh tokens readable by the gateway's account only, through a temporary file so a crash can't
+    // truncate the store
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        {
+            // The mode only applies to a new file, not to one left behind by an earlier crash
+            let _ = fs::remove_file(&tmp_path);
+            let mut options = OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&tmp_path)?;
+            writeln!(file, "# DavMail Rust OAuth2 refresh tokens")?;
+            for (key, token) in &self.tokens {
+                if let Some(refresh_token) = &token.refresh_token {
+                    write!(file, "{}\t{}\t{}\t{}", key.tenant_id, key.client_id, key.username, refresh_token)?;
+                    if let Some((salt, verifier)) = self.passwords.get(key) {
+                        write!(file, "\t{}:{}", STANDARD.encode(salt), STANDARD.encode(verifier))?;
+                    }
+                    writeln!(file)?;
+                }
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+}
+
+// "salt:verifier", both base64
+fn parse_password(field: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (salt, verifier) = field.split_once(':')?;
+    Some((STANDARD.decode(salt).ok()?, STANDARD.decode(verifier).ok()?))
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PBKDF2_ITERATIONS).unwrap()
+}
+
+// Token without usable access token, so the first get_token() call redeems the refresh token
+fn refresh_only(refresh_token: &str) -> OAuth2Token {
+    OAuth2Token {
+        access_token: String::new(),
+        token_type: "Bearer".to_string(),
+        expires_at: SystemTime::UNIX_EPOCH,
+        refresh_token: Some(refresh_token.to_string()),
+        scope: None,
+    }
+}
@@ -0,0 +1,128 @@
+// autoconfig.rs
+// Outlook autodiscover and Thunderbird autoconfig documents pointing mail clients at the gateway's own listeners
+
+use config::Config;
+
+use crate::exchange::request::escape_xml;
+use crate::protocols::tls;
+
+const AUTODISCOVER_RESPONSE_NS: &str = "http://schemas.microsoft.com/exchange/autodiscover/responseschema/2006";
+const OUTLOOK_RESPONSE_NS: &str = "http://schemas.microsoft.com/exchange/autodiscover/outlook/responseschema/2006a";
+
+// Placeholder Thunderbird replaces with the address the user typed
+const EMAIL_PLACEHOLDER: &str = "%EMAILADDRESS%";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Listener {
+    port: u16,
+    // Implicit TLS, the listeners have no STARTTLS
+    tls: bool,
+}
+
+impl Listener {
+    // davmail.<protocol>Enabled and davmail.<protocol>Port, None when the listener is off
+    fn from_config(config: &Config, protocol: &str, default_port: u16) -> Option<Self> {
+        if !config.get_bool(&format!("davmail.{}Enabled", protocol)).unwrap_or(false) {
+            return None;
+        }
+        let port = config.get_int(&format!("davmail.{}Port", protocol)).ok()
+            .and_then(|port| u16::try_from(port).ok())
+            .unwrap_or(default_port);
+        Some(Listener { port, tls: tls::enabled(config, protocol) })
+    }
+}
+
+// What the documents advertise, read once when the admin server starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Autoconfig {
+    // davmail.autoconfigHost, the Host header of the request otherwise
+    host: Option<String>,
+    imap: Option<Listener>,
+    smtp: Option<Listener>,
+    caldav: Option<Listener>,
+}
+
+impl Autoconfig {
+    // davmail.autoconfigEnabled, off by default since the documents are served without the admin password
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if !config.get_bool("davmail.autoconfigEnabled").unwrap_or(false) {
+            return None;
+        }
+        Some(Autoconfig {
+            host: config.get_string("davmail.autoconfigHost").ok()
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty()),
+            imap: Listener::from_config(config, "imap", 1143),
+            smtp: Listener::from_config(config, "smtp", 1025),
+            caldav: Listener::from_config(config, "caldav", 1080),
+        })
+    }
+
+    // Host name clients are told to connect to, `requested` is the Host header without its port
+    fn host<'a>(&'a self, requested: &'a str) -> &'a str {
+        self.host.as_deref().unwrap_or(requested)
+    }
+
+    // POX autodiscover answer for Outlook and the clients that copied it (eM Client, Apple Mail)
+    pub fn autodiscover(&self, requested_host: &str, email: Option<&str>) -> String {
+        let host = escape_xml(self.host(requested_host));
+        let mut xml = format!(concat!(
+            r#"<?xml version="1.0" encoding="utf-8"?>"#, "\n",
+            r#"<Autodiscover xmlns="{}"><Response xmlns="{}"><Account>"#,
+            "<AccountType>email</AccountType><Action>settings</Action>"),
+            AUTODISCOVER_RESPONSE_NS, OUTLOOK_RESPONSE_NS);
+        let login = email.map(|email| format!("<LoginName>{}</LoginName>", escape_xml(email))).unwrap_or_default();
+        for (kind, listener) in [("IMAP", self.imap), ("SMTP", self.smtp)] {
+            let Some(listener) = listener else { continue };
+            xml.push_str(&format!(concat!(
+                "<Protocol><Type>{}</Type><Server>{}</Server><Port>{}</Port>{}<DomainRequired>off</DomainRequired>",
+                "<SPA>off</SPA><SSL>{}</SSL><AuthRequired>on</AuthRequired></Protocol>"),
+                kind, host, listener.port, login, if listener.tls { "on" } else { "off" }));
+        }
+        xml.push_str("</Account></Response></Autodiscover>\n");
+        xml
+    }
+
+    // Thunderbird's config-v1.1.xml, with the CalDAV calendar Thunderbird 102 and later set up alongside
+    pub fn thunderbird(&self, requested_host: &str, email: Option<&str>) -> String {
+        let host = escape_xml(self.host(requested_host));
+        // The domain the client asked for, so it accepts the document for that address
+        let domain = email.and_then(|email| email.rsplit_once('@')).map_or(host.clone(), |(_, domain)| escape_xml(domain));
+        let mut xml = format!(concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#, "\n",
+            r#"<clientConfig version="1.1"><emailProvider id="{}"><domain>{}</domain>"#,
+            "<displayName>gatewayrs563 on {}</displayName><displayShortName>gatewayrs563</displayShortName>"),
+            host, domain, host);
+        for (element, kind, listener) in [("incomingServer", "imap", self.imap), ("outgoingServer", "smtp", self.smtp)] {
+            let Some(listener) = listener else { continue };
+            xml.push_str(&format!(concat!(
+                r#"<{} type="{}"><hostname>{}</hostname><port>{}</port><socketType>{}</socketType>"#,
+                "<authentication>password-cleartext</authentication><username>{}</username></{}>"),
+                element, kind, host, listener.port, if listener.tls { "SSL" } else { "plain" }, EMAIL_PLACEHOLDER, element));
+        }
+        xml.push_str("</emailProvider>");
+        if let Some(caldav) = self.caldav {
+            xml.push_str(&format!(concat!(
+                r#"<calendar type="caldav"><username>{}</username><authentication>http-basic</authentication>"#,
+                "<serverURL>{}://{}:{}/users/{}/calendar/</serverURL></calendar>"),
+                EMAIL_PLACEHOLDER, if caldav.tls { "https" } else { "http" }, host, caldav.port, EMAIL_PLACEHOLDER));
+        }
+        xml.push_str("</clientConfig>\n");
+        xml
+    }
+}
+
+// EMailAddress of an autodiscover request body
+pub fn requested_email(body: &str) -> Option<&str> {
+    let start = body.find("<EMailAddress>")? + "<EMailAddress>".len();
+    let end = body[start..].find("</EMailAddress>")? + start;
+    Some(body[start..end].trim()).filter(|email| !email.is_empty())
+}
+
+// Host header without the port, [::1]:8080 included
+pub fn host_name(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map_or(host, |(name, _)| name),
+    }
+}
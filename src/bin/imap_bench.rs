@@ -0,0 +1,237 @@
+// bin/imap_bench.rs
+// Load test: concurrent IMAP clients cycling through LOGIN, SELECT, FETCH and LOGOUT against a gateway in front of the mock EWS endpoint
+//
+// cargo run --release --features bench --bin imap_bench -- --clients 50 --cycles 20
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use clap::Parser;
+use config::Config;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, watch};
+
+use davmail_core::auth::TokenManager;
+use davmail_core::exchange::http::HttpClientConfig;
+use davmail_core::exchange::sessions::SessionCache;
+use davmail_core::mock_ews::{Fault, MockEws, MockMessage};
+use davmail_core::protocols::lockout::LoginGuard;
+use davmail_core::{ImapServer, LiveSettings, UserOverrides};
+
+const USERNAME: &str = "bench@example.com";
+const PASSWORD: &str = "secret";
+
+// A command slower than this counts as failed instead of stalling the run
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const COMMANDS: [&str; 5] = ["connect", "LOGIN", "SELECT", "FETCH", "LOGOUT"];
+
+#[derive(Parser, Debug)]
+#[command(about = "Measure IMAP throughput and latency of the gateway against the mock EWS endpoint")]
+struct Args {
+    /// Concurrent IMAP clients
+    #[arg(long, default_value_t = 10)]
+    clients: usize,
+
+    /// LOGIN, SELECT, FETCH, LOGOUT cycles each client runs
+    #[arg(long, default_value_t = 20)]
+    cycles: usize,
+
+    /// Messages in the mock INBOX
+    #[arg(long, default_value_t = 50)]
+    messages: usize,
+
+    /// FETCH arguments sent after SELECT
+    #[arg(long, default_value = "1:* (FLAGS ENVELOPE)")]
+    fetch: String,
+
+    /// Latency the mock EWS endpoint adds to every request, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    ews_latency: u64,
+
+    /// Additional davmail.* settings of the gateway, e.g. davmail.ews.maxConcurrentRequests=4
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    settings: Vec<String>,
+}
+
+// Latencies of every command of one kind, and how many of them failed
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    failed: usize,
+}
+
+type Results = BTreeMap<&'static str, Samples>;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let handle = runtime.handle().clone();
+    runtime.block_on(run(args, handle))
+}
+
+async fn run(args: Args, handle: tokio::runtime::Handle) -> Result<(), Box<dyn Error>> {
+    let mock = MockEws::start().await?;
+    mock.require_basic_auth(USERNAME, PASSWORD);
+    mock.set_messages((1..=args.messages)
+        .map(|n| MockMessage::new(&format!("message-{}", n), &format!("Message {}", n), "sender@example.com"))
+        .collect());
+    if args.ews_latency > 0 {
+        mock.fail("*", Fault::Delay(Duration::from_millis(args.ews_latency)), None);
+    }
+
+    let (port, _shutdown) = start_gateway(&mock, &args.settings, handle).await?;
+    println!("{} client(s) x {} cycle(s) against 127.0.0.1:{}, {} message(s), FETCH {}",
+        args.clients, args.cycles, port, args.messages, args.fetch);
+
+    let started = Instant::now();
+    let clients: Vec<_> = (0..args.clients)
+        .map(|_| tokio::spawn(client(port, args.cycles, args.fetch.clone())))
+        .collect();
+    let mut results = Results::new();
+    for client in clients {
+        for (command, samples) in client.await? {
+            let total = results.entry(command).or_default();
+            total.latencies.extend(samples.latencies);
+            total.failed += samples.failed;
+        }
+    }
+    let elapsed = started.elapsed();
+
+    report(&results, elapsed, args.clients * args.cycles, mock.requests().len());
+    let failed: usize = results.values().map(|samples| samples.failed).sum();
+    if failed > 0 {
+        return Err(format!("{} command(s) failed", failed).into());
+    }
+    Ok(())
+}
+
+// An IMAP listener the way main starts one, settings on top of the mock's URL, until the sender is dropped
+async fn start_gateway(mock: &MockEws, settings: &[String], handle: tokio::runtime::Handle) -> Result<(u16, watch::Sender<bool>), Box<dyn Error>> {
+    let mut builder = Config::builder().set_override("davmail.url", mock.url())?;
+    for setting in settings {
+        let (key, value) = setting.split_once('=').ok_or_else(|| format!("--set {} is not KEY=VALUE", setting))?;
+        builder = builder.set_override(key, value)?;
+    }
+    let config = Arc::new(builder.build()?);
+    let http_client = HttpClientConfig::from_config(&config).build()?;
+    let settings = Arc::new(RwLock::new(LiveSettings { config, http_client, user_overrides: Arc::new(UserOverrides::default()) }));
+
+    // A port that was free a moment ago, the server binds it again
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let (bound, bound_receiver) = oneshot::channel();
+    let (shutdown, shutdown_receiver) = watch::channel(false);
+    let server = ImapServer::new(settings, vec!["127.0.0.1".to_string()], port, Arc::new(TokenManager::new(handle)),
+        Arc::new(SessionCache::new(Duration::ZERO)), Arc::new(LoginGuard::new()))
+        .with_bound_signal(bound);
+    tokio::spawn(server.run(shutdown_receiver));
+    bound_receiver.await.map_err(|_| "IMAP server failed to start")?;
+    Ok((port, shutdown))
+}
+
+// One client's cycles, a failed command ends its cycle and the next one reconnects
+async fn client(port: u16, cycles: usize, fetch: String) -> Results {
+    let mut results = Results::new();
+    for _ in 0..cycles {
+        let _ = cycle(port, &fetch, &mut results).await;
+    }
+    results
+}
+
+async fn cycle(port: u16, fetch: &str, results: &mut Results) -> Result<(), ()> {
+    let started = Instant::now();
+    let connected = tokio::time::timeout(RESPONSE_TIMEOUT, async {
+        let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).await?);
+        let mut greeting = String::new();
+        stream.read_line(&mut greeting).await?;
+        Ok::<_, std::io::Error>((stream, greeting))
+    }).await;
+    let mut stream = match connected {
+        Ok(Ok((stream, greeting))) if greeting.starts_with("* OK") => {
+            record(results, "connect", Ok(started.elapsed()));
+            stream
+        },
+        _ => {
+            record(results, "connect", Err(()));
+            return Err(());
+        }
+    };
+
+    let commands = [
+        ("LOGIN", format!("LOGIN {} {}", USERNAME, PASSWORD)),
+        ("SELECT", "SELECT INBOX".to_string()),
+        ("FETCH", format!("FETCH {}", fetch)),
+        ("LOGOUT", "LOGOUT".to_string()),
+    ];
+    for (tag, (name, command)) in commands.iter().enumerate() {
+        let outcome = command_latency(&mut stream, &format!("b{}", tag), command).await;
+        record(results, name, outcome);
+        outcome?;
+    }
+    Ok(())
+}
+
+// Time from sending the command to its OK completion
+async fn command_latency(stream: &mut BufReader<TcpStream>, tag: &str, command: &str) -> Result<Duration, ()> {
+    let started = Instant::now();
+    let exchange = async {
+        stream.get_mut().write_all(format!("{} {}\r\n", tag, command).as_bytes()).await?;
+        let completion = format!("{} ", tag);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(false);
+            }
+            if let Some(status) = line.strip_prefix(&completion) {
+                return Ok(status.starts_with("OK"));
+            }
+        }
+    };
+    match tokio::time::timeout(RESPONSE_TIMEOUT, exchange).await {
+        Ok(Ok::<_, std::io::Error>(true)) => Ok(started.elapsed()),
+        _ => Err(()),
+    }
+}
+
+fn record(results: &mut Results, command: &'static str, outcome: Result<Duration, ()>) {
+    let samples = results.entry(command).or_default();
+    match outcome {
+        Ok(latency) => samples.latencies.push(latency),
+        Err(()) => samples.failed += 1,
+    }
+}
+
+fn report(results: &Results, elapsed: Duration, cycles: usize, ews_requests: usize) {
+    let completed = results.get("LOGOUT").map_or(0, |samples| samples.latencies.len());
+    let commands: usize = results.values().map(|samples| samples.latencies.len()).sum();
+    let seconds = elapsed.as_secs_f64();
+    println!("{}/{} cycle(s) completed in {:.2}s: {:.1} cycles/s, {:.1} commands/s, {} EWS request(s)",
+        completed, cycles, seconds, completed as f64 / seconds, commands as f64 / seconds, ews_requests);
+    println!();
+    println!("{:<8} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}", "command", "ok", "failed", "p50 ms", "p90 ms", "p99 ms", "max ms", "mean ms");
+    for command in COMMANDS {
+        let Some(samples) = results.get(command) else { continue };
+        let mut latencies = samples.latencies.clone();
+        latencies.sort();
+        let mean = latencies.iter().sum::<Duration>().checked_div(latencies.len() as u32).unwrap_or_default();
+        println!("{:<8} {:>7} {:>7} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>9.2}", command, latencies.len(), samples.failed,
+            millis(percentile(&latencies, 50.0)), millis(percentile(&latencies, 90.0)), millis(percentile(&latencies, 99.0)),
+            millis(latencies.last().copied().unwrap_or_default()), millis(mean));
+    }
+}
+
+// Nearest-rank percentile of sorted latencies
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
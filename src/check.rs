@@ -0,0 +1,245 @@
+// check.rs
+// davmail-rust check: walk from the configuration to a sample EWS request and report what works and what doesn't
+
+use std::error::Error;
+use std::time::Duration;
+use config::Config;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::runtime::Runtime;
+
+use davmail_core::configuration;
+use davmail_core::exchange::http::HttpClientConfig;
+
+use crate::signin::{self, Login};
+
+// Office 365 answers for every tenant, on-premises servers usually don't have this endpoint
+const AUTODISCOVER_URL: &str = "https://outlook.office365.com/autodiscover/autodiscover.json/v1.0";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+// Printed as each step finishes, the network steps can take a while
+#[derive(Default)]
+pub struct Report {
+    pub failed: usize,
+    pub warned: usize,
+}
+
+impl Report {
+    pub fn add(&mut self, step: &str, outcome: Outcome, detail: impl AsRef<str>) {
+        let label = match outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Warn => "WARN",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        };
+        match outcome {
+            Outcome::Fail => self.failed += 1,
+            Outcome::Warn => self.warned += 1,
+            _ => {},
+        }
+        println!("{:<5} {:<15} {}", label, step, detail.as_ref());
+    }
+}
+
+#[derive(Deserialize)]
+struct AutodiscoverResponse {
+    #[serde(rename = "Url")]
+    url: String,
+}
+
+// Entry point of the check subcommand, an error when a step failed
+pub fn run(config: &Config, username: Option<&str>) -> Result<(), Box<dyn Error>> {
+    // Asked before the report starts, so the prompt doesn't land in the middle of it
+    let login = match username {
+        Some(username) => Some(signin::login_for(config, username)?),
+        None => None,
+    };
+    let runtime = Runtime::new()?;
+    let report = runtime.block_on(check(config, username.zip(login)));
+
+    if report.failed > 0 {
+        return Err(format!("{} check(s) failed", report.failed).into());
+    }
+    if report.warned > 0 {
+        println!("Passed with {} warning(s)", report.warned);
+    } else {
+        println!("All checks passed");
+    }
+    Ok(())
+}
+
+async fn check(config: &Config, login: Option<(&str, Login)>) -> Report {
+    let mut report = Report::default();
+
+    // Configuration: everything else needs a usable davmail.url and HTTP client
+    let (url, http_config, client) = match check_config(config) {
+        Ok(checked) => {
+            report.add("Configuration", Outcome::Pass, format!("davmail.url is {}", checked.0));
+            checked
+        },
+        Err(e) => {
+            report.add("Configuration", Outcome::Fail, e);
+            return report;
+        }
+    };
+    let endpoint = format!("{}/EWS/Exchange.asmx", url.as_str().trim_end_matches('/'));
+
+    match &login {
+        Some((username, _)) if username.contains('@') => {
+            let (outcome, detail) = autodiscover(&client, username, &endpoint).await;
+            report.add("Autodiscover", outcome, detail);
+        },
+        Some(_) => report.add("Autodiscover", Outcome::Skip, "the username is not an email address"),
+        None => report.add("Autodiscover", Outcome::Skip, "needs --user"),
+    }
+
+    // With a proxy the host name may only resolve on the proxy
+    let proxy = proxy_address(&http_config);
+    let host = url.host_str().unwrap_or_default().to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let resolved = match lookup_host((host.as_str(), port)).await {
+        Ok(addresses) => {
+            let addresses: Vec<String> = addresses.map(|address| address.ip().to_string()).collect();
+            report.add("DNS", Outcome::Pass, format!("{} is {}", host, addresses.join(", ")));
+            true
+        },
+        Err(e) if proxy.is_some() => {
+            report.add("DNS", Outcome::Warn, format!("{} doesn't resolve here ({}), the proxy has to resolve it", host, e));
+            true
+        },
+        Err(e) => {
+            report.add("DNS", Outcome::Fail, format!("{}: {}", host, e));
+            false
+        }
+    };
+
+    match &proxy {
+        Some((source, address)) => match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address.as_str())).await {
+            Ok(Ok(_)) => report.add("Proxy", Outcome::Pass, format!("{} {} is reachable", source, address)),
+            Ok(Err(e)) => report.add("Proxy", Outcome::Fail, format!("{} {}: {}", source, address, e)),
+            Err(_) => report.add("Proxy", Outcome::Fail, format!("{} {}: no answer within {:?}", source, address, CONNECT_TIMEOUT)),
+        },
+        None => report.add("Proxy", Outcome::Skip, "none configured"),
+    }
+
+    // An unauthenticated request, Exchange answers 401 once TLS and HTTP work
+    let connected = if !resolved {
+        report.add("TLS", Outcome::Skip, "the host name doesn't resolve");
+        false
+    } else {
+        match client.get(&endpoint).send().await {
+            Ok(response) if url.scheme() == "http" => {
+                report.add("TLS", Outcome::Warn, format!("davmail.url uses plain HTTP, {} answered {}", endpoint, response.status()));
+                true
+            },
+            Ok(response) => {
+                report.add("TLS", Outcome::Pass, format!("{} answered {}", endpoint, response.status()));
+                true
+            },
+            Err(e) => {
+                report.add("TLS", Outcome::Fail, describe(&e));
+                false
+            }
+        }
+    };
+
+    let Some((username, login)) = login else {
+        report.add("Authentication", Outcome::Skip, "needs --user");
+        report.add("FindFolder", Outcome::Skip, "needs --user");
+        return report;
+    };
+    if !connected {
+        report.add("Authentication", Outcome::Skip, "Exchange is unreachable");
+        report.add("FindFolder", Outcome::Skip, "Exchange is unreachable");
+        return report;
+    }
+
+    let exchange_client = match signin::authenticate(config, url.as_str().trim_end_matches('/'), &http_config, &client, username, login).await {
+        Ok((exchange_client, method)) => {
+            report.add("Authentication", Outcome::Pass, format!("{} as {}", method, username));
+            exchange_client
+        },
+        Err(e) => {
+            report.add("Authentication", Outcome::Fail, e);
+            report.add("FindFolder", Outcome::Skip, "not authenticated");
+            return report;
+        }
+    };
+
+    match exchange_client.list_folders("", "*").await {
+        Ok(folders) => report.add("FindFolder", Outcome::Pass, format!("{} folder(s) listed", folders.len())),
+        Err(e) => report.add("FindFolder", Outcome::Fail, e.to_string()),
+    }
+    report
+}
+
+fn check_config(config: &Config) -> Result<(Url, HttpClientConfig, Client), String> {
+    let profiles = configuration::profiles(config).map_err(|e| e.to_string())?;
+    crate::check_listeners(&profiles).map_err(|e| e.to_string())?;
+
+    let url = config.get_string("davmail.url").unwrap_or_default();
+    if url.trim().is_empty() {
+        return Err("davmail.url is not set".to_string());
+    }
+    let parsed = Url::parse(url.trim()).map_err(|e| format!("davmail.url '{}' is not a URL: {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(format!("davmail.url '{}' is not an http or https URL", url));
+    }
+
+    let http_config = HttpClientConfig::from_config(config);
+    let client = http_config.build().map_err(|e| format!("HTTP client: {}", e))?;
+    Ok((parsed, http_config, client))
+}
+
+async fn autodiscover(client: &Client, username: &str, endpoint: &str) -> (Outcome, String) {
+    let request = format!("{}/{}?Protocol=EWS", AUTODISCOVER_URL, urlencoding::encode(username));
+    let response = match client.get(&request).header("Accept", "application/json").send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => return (Outcome::Warn, format!("Office 365 doesn't know {} ({})", username, response.status())),
+        Err(e) => return (Outcome::Warn, format!("no answer from Office 365: {}", describe(&e))),
+    };
+    match response.json::<AutodiscoverResponse>().await {
+        Ok(found) if found.url.eq_ignore_ascii_case(endpoint) => (Outcome::Pass, format!("{} matches davmail.url", found.url)),
+        Ok(found) => (Outcome::Warn, format!("the mailbox is served by {}, davmail.url points to {}", found.url, endpoint)),
+        Err(e) => (Outcome::Warn, format!("unexpected answer: {}", e)),
+    }
+}
+
+// Where the HTTP client connects instead of Exchange, and which setting says so
+fn proxy_address(http_config: &HttpClientConfig) -> Option<(&'static str, String)> {
+    if let Some(proxy) = &http_config.proxy {
+        return Some(("davmail.proxy.host", format!("{}:{}", proxy.host, proxy.port)));
+    }
+    if !http_config.use_env_proxy {
+        return None;
+    }
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"].iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+        .and_then(|value| Url::parse(&value).ok())
+        .and_then(|proxy| Some(("HTTPS_PROXY", format!("{}:{}", proxy.host_str()?, proxy.port_or_known_default()?))))
+}
+
+// The error with its causes, the useful part of a TLS failure is usually a few levels down
+fn describe(error: &dyn Error) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_text = cause.to_string();
+        if !text.contains(&cause_text) {
+            text.push_str(": ");
+            text.push_str(&cause_text);
+        }
+        source = cause.source();
+    }
+    text
+}
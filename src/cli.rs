@@ -0,0 +1,190 @@
+// cli.rs
+// Command line options, applied on top of the configuration file
+
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use config::{Config, ConfigBuilder, ConfigError, Environment};
+use config::builder::DefaultState;
+
+use davmail_core::configuration::{ConfigFile, UserOverrides};
+
+#[derive(Parser, Debug)]
+#[command(name = "davmail-rust", version, about = "POP/IMAP/SMTP/CalDav/CardDav/LDAP gateway for Microsoft Exchange/Office 365")]
+pub struct Cli {
+    /// Configuration file, .toml, .yaml or .properties [default: davmail.toml, davmail.yaml, davmail.yml or davmail.properties in the working directory]
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Enable the IMAP server on this port
+    #[arg(long, value_name = "PORT")]
+    pub imap_port: Option<u16>,
+
+    /// Enable the POP3 server on this port
+    #[arg(long, value_name = "PORT")]
+    pub pop_port: Option<u16>,
+
+    /// Enable the SMTP server on this port
+    #[arg(long, value_name = "PORT")]
+    pub smtp_port: Option<u16>,
+
+    /// Enable the CalDAV server on this port
+    #[arg(long, value_name = "PORT")]
+    pub caldav_port: Option<u16>,
+
+    /// Enable the LDAP server on this port
+    #[arg(long, value_name = "PORT")]
+    pub ldap_port: Option<u16>,
+
+    /// Log filter (error, warn, info, debug, trace or env_logger directives), overrides RUST_LOG
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Sign USERNAME in through the browser, save the tokens to davmail.oauth.tokenFile and exit
+    #[arg(long, value_name = "USERNAME")]
+    pub token: Option<String>,
+
+    /// Read a refresh token for USERNAME from standard input into davmail.oauth.tokenFile and exit. The second line
+    /// is the password USERNAME logs in to the gateway with in broker mode (davmail.oauth.broker).
+    #[arg(long, value_name = "USERNAME")]
+    pub import_refresh_token: Option<String>,
+
+    /// Load the configuration, initialize what a start would, bind every listener, sign USERNAME in and list its folders,
+    /// then exit with status 0 only if all of it worked. The password is read from standard input unless a token is saved.
+    #[arg(long, value_name = "USERNAME", conflicts_with_all = ["daemon", "takeover", "token", "import_refresh_token"])]
+    pub validate: Option<String>,
+
+    /// Profile whose settings --token, --import-refresh-token, --validate and the check, token, import and diag commands use (see davmail.profiles)
+    #[arg(long, value_name = "NAME", default_value = "default")]
+    pub profile: String,
+
+    /// Run in the foreground without a tray icon (the default unless built with the tray feature)
+    #[arg(long, alias = "foreground")]
+    pub notray: bool,
+
+    /// Detach from the terminal and run in the background (Unix only)
+    #[arg(long, conflicts_with = "notray")]
+    pub daemon: bool,
+
+    /// Write the process id to PATH, refusing to start while another instance holds it [default: davmail.pidFile,
+    /// or a lock file per configuration file in $XDG_RUNTIME_DIR or the temporary directory]
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Stop the instance running with the same PID file and take its place (Unix only)
+    #[arg(long)]
+    pub takeover: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Encrypt a secret read from standard input with DAVMAIL_MASTER_KEY (or DAVMAIL_MASTER_KEY_FILE)
+    /// and print the {ENC} value to paste into the configuration file
+    EncryptSecret,
+    /// Check the configuration and the way to Exchange (autodiscover, DNS, proxy, TLS, sign-in, FindFolder)
+    /// and print a report. The password of --user is read from standard input unless a token is saved for it.
+    Check {
+        /// Mailbox to sign in with, without it the check stops before authentication
+        #[arg(long, value_name = "USERNAME")]
+        user: Option<String>,
+    },
+    /// Manage the OAuth2 tokens of davmail.oauth.tokenFile without starting the servers
+    Token {
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+    /// Collect the redacted configuration, version, environment, recent logs and the last EWS errors of the
+    /// running instance into a .tar.gz to attach to bug reports
+    Diag {
+        /// Archive to write [default: gatewayrs563-diag-TIMESTAMP.tar.gz in the working directory]
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Upload a directory of .eml files, a single .eml file or an mbox into an Exchange folder, keeping dates
+    /// and read state. The password of --user is read from standard input unless a token is saved for it.
+    Import {
+        /// Directory of .eml files, an .eml file, or an mbox file
+        path: PathBuf,
+        /// Mailbox to import into
+        #[arg(long, value_name = "USERNAME")]
+        user: String,
+        /// Folder to import into, by its IMAP name
+        #[arg(long, value_name = "FOLDER", default_value = "INBOX")]
+        folder: String,
+        /// Mark every imported message as read, whatever its state was
+        #[arg(long)]
+        read: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokenAction {
+    /// Sign USER in and save the tokens, the same as --token
+    Login {
+        user: String,
+        /// Show a code to enter on another device instead of opening a browser, for headless machines
+        #[arg(long)]
+        device_code: bool,
+    },
+    /// Redeem the saved refresh token of USER and print the expiry and scopes of the new access token
+    Refresh {
+        user: String,
+    },
+    /// List the saved tokens, or those of USER
+    Show {
+        user: Option<String>,
+    },
+    /// Remove the saved tokens of USER, or all of them with --all
+    Clear {
+        #[arg(required_unless_present = "all")]
+        user: Option<String>,
+        #[arg(long, conflicts_with = "user")]
+        all: bool,
+    },
+}
+
+impl Cli {
+    // Precedence, lowest first: configuration file, DAVMAIL_* environment variables, command line options
+    pub fn load_config(&self) -> Result<Config, ConfigError> {
+        let mut builder = Config::builder();
+        if let Some(file) = self.config_file() {
+            builder = builder.add_source(file);
+        }
+        builder = builder.add_source(Environment::with_prefix("DAVMAIL"));
+
+        builder = enable_port(builder, "imap", self.imap_port)?;
+        builder = enable_port(builder, "pop", self.pop_port)?;
+        builder = enable_port(builder, "smtp", self.smtp_port)?;
+        builder = enable_port(builder, "caldav", self.caldav_port)?;
+        builder = enable_port(builder, "ldap", self.ldap_port)?;
+
+        builder.build()
+    }
+
+    pub fn load_user_overrides(&self) -> Result<UserOverrides, ConfigError> {
+        match self.config_file() {
+            Some(file) => file.user_overrides(),
+            None => Ok(UserOverrides::default()),
+        }
+    }
+
+    // A file named on the command line must exist, otherwise the first default file found is used
+    pub fn config_file(&self) -> Option<ConfigFile> {
+        match &self.config {
+            Some(path) => Some(ConfigFile::new(path, true)),
+            None => ConfigFile::find_default(),
+        }
+    }
+}
+
+// --imap-port and friends set davmail.<protocol>Port and turn the server on
+fn enable_port(builder: ConfigBuilder<DefaultState>, protocol: &str, port: Option<u16>) -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+    match port {
+        Some(port) => builder
+            .set_override(format!("davmail.{}Port", protocol), i64::from(port))?
+            .set_override(format!("davmail.{}Enabled", protocol), true),
+        None => Ok(builder),
+    }
+}
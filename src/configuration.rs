@@ -3,14 +3,27 @@
 
 use std::fs::File;
 use std::io::{self, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use log::{info, error, warn, debug};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use config::{Config, ConfigError, Map, Source, Value};
+use reqwest::Client;
+use serde::Deserialize;
+use log::debug;
+
+pub mod secrets;
 
 pub struct DavMailConfig {
     settings: HashMap<String, String>,
 }
 
+impl Default for DavMailConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DavMailConfig {
     pub fn new() -> Self {
         DavMailConfig {
@@ -40,6 +53,10 @@ impl DavMailConfig {
         Ok(())
     }
     
+    pub fn settings(&self) -> &HashMap<String, String> {
+        &self.settings
+    }
+    
     pub fn get_string(&self, key: &str) -> Option<String> {
         self.settings.get(key).cloned()
     }
@@ -84,3 +101,308 @@ impl DavMailConfig {
         Ok(())
     }
 }
+
+// Configuration and HTTP client handed to new connections, replaced when the configuration file changes.
+// Connections keep the snapshot they started with.
+#[derive(Clone)]
+pub struct LiveSettings {
+    pub config: Arc<Config>,
+    pub http_client: Client,
+    pub user_overrides: Arc<UserOverrides>,
+}
+
+// Settings of [users."alice@contoso.com"] sections, layered over the shared configuration once
+// a connection knows who logged in. Keys are the same as in [davmail], e.g. smtpSaveInSent.
+#[derive(Debug, Default)]
+pub struct UserOverrides {
+    // Keyed by lower case login name
+    users: HashMap<String, Map<String, Value>>,
+}
+
+impl UserOverrides {
+    // Shared configuration with the user's overrides on top, the shared one when there are none
+    pub fn config_for(&self, config: &Arc<Config>, username: &str) -> Result<Arc<Config>, ConfigError> {
+        let overrides = match self.users.get(&username.to_lowercase()) {
+            Some(overrides) => overrides,
+            None => return Ok(config.clone()),
+        };
+        
+        let mut builder = Config::builder().add_source(config.as_ref().clone());
+        for (key, value) in overrides {
+            builder = builder.set_override(key.as_str(), value.clone())?;
+        }
+        debug!("Applying {} configuration overrides for {}", overrides.len(), username);
+        Ok(Arc::new(builder.build()?))
+    }
+    
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+pub type SharedSettings = Arc<RwLock<LiveSettings>>;
+
+// The top level settings always run as this profile
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Several gateways in one process, e.g. one per Office 365 tenant: davmail.profiles lists extra
+// profile names, and each one runs with davmail.profile.<name>.* on top of the top level settings
+// (davmail.profile.fabrikam.url, davmail.profile.fabrikam.imapPort, ...). Listeners are inherited
+// too, so each profile has to move its own to free ports.
+pub fn profiles(config: &Config) -> Result<Vec<(String, Config)>, ConfigError> {
+    let mut profiles = vec![(DEFAULT_PROFILE.to_string(), config.clone())];
+    let names = config.get_string("davmail.profiles").unwrap_or_default();
+    for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        if profiles.iter().any(|(profile, _)| profile == name) {
+            return Err(ConfigError::Message(format!("Profile {} is listed twice in davmail.profiles", name)));
+        }
+        profiles.push((name.to_string(), profile_config(config, name)?));
+    }
+    Ok(profiles)
+}
+
+// Configuration of one profile, the top level settings for the default one
+pub fn profile_config(config: &Config, name: &str) -> Result<Config, ConfigError> {
+    if name == DEFAULT_PROFILE {
+        return Ok(config.clone());
+    }
+    
+    let prefix = format!("davmail.profile.{}", name);
+    let table = config.get_table(&prefix)
+        .map_err(|_| ConfigError::Message(format!("Profile {} has no {}.* settings", name, prefix)))?;
+    let mut overrides = Map::new();
+    for (key, value) in table {
+        flatten(&format!("davmail.{}", key), value, &mut overrides)?;
+    }
+    
+    let mut builder = Config::builder().add_source(config.clone());
+    for (key, value) in overrides {
+        builder = builder.set_override(key.as_str(), value)?;
+    }
+    builder.build()
+}
+
+// Looked up in the working directory when no --config is given, the first one found is used
+pub const DEFAULT_CONFIG_FILES: [&str; 4] = ["davmail.toml", "davmail.yaml", "davmail.yml", "davmail.properties"];
+
+// Servers that get a typed [imap] / imap: section in structured files
+const PROTOCOLS: [&str; 5] = ["imap", "pop", "smtp", "caldav", "ldap"];
+
+// Settings of one protocol server in davmail.toml / davmail.yaml
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProtocolSection {
+    enabled: Option<bool>,
+    port: Option<u16>,
+    #[serde(rename = "bindAddress")]
+    bind_address: Option<BindAddresses>,
+}
+
+// bindAddress = "127.0.0.1" or bindAddress = ["127.0.0.1", "::1"]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BindAddresses {
+    One(String),
+    Many(Vec<String>),
+}
+
+// All interfaces unless davmail.bindAddress says otherwise, e.g. 127.0.0.1 when mail clients run on the same host
+pub const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0";
+
+// davmail.<protocol>BindAddress, falling back to davmail.bindAddress. Several addresses are separated
+// by commas or spaces (or given as a list in structured files), IPv6 ones with or without brackets,
+// e.g. "127.0.0.1, [::1]". Linux binds :: to IPv4 as well, so :: can't be listed next to 0.0.0.0.
+pub fn bind_addresses(config: &Config, protocol: &str) -> Vec<String> {
+    let addresses: Vec<String> = [format!("davmail.{}BindAddress", protocol), "davmail.bindAddress".to_string()].iter()
+        .map(|key| match config.get_array(key) {
+            Ok(values) => values.into_iter().filter_map(|value| value.into_string().ok()).collect(),
+            Err(_) => config.get_string(key).map(|value| vec![value]).unwrap_or_default(),
+        })
+        .map(|values| values.iter()
+            .flat_map(|value| value.split(|c: char| c == ',' || c.is_whitespace()))
+            .map(|address| address.trim().trim_start_matches('[').trim_end_matches(']').to_string())
+            .filter(|address| !address.is_empty())
+            .collect::<Vec<_>>())
+        .find(|addresses| !addresses.is_empty())
+        .unwrap_or_default();
+    
+    if addresses.is_empty() {
+        vec![DEFAULT_BIND_ADDRESS.to_string()]
+    } else {
+        addresses
+    }
+}
+
+// Configuration file in any supported format, exposed with the flat davmail.* keys the rest
+// of the gateway reads:
+// - .toml, .yaml, .yml: a [davmail] table holding davmail.* keys (nested tables allowed,
+//   e.g. [davmail.oauth]) plus [imap], [pop], [smtp], [caldav], [ldap] sections with
+//   enabled, port and bindAddress, which become davmail.imapEnabled / davmail.imapPort /
+//   davmail.imapBindAddress and so on,
+//   and [users."login"] tables with per-user overrides (see UserOverrides)
+// - anything else: DavMail's key=value properties format, without per-user overrides
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    path: PathBuf,
+    required: bool,
+}
+
+impl ConfigFile {
+    pub fn new<P: AsRef<Path>>(path: P, required: bool) -> Self {
+        ConfigFile { path: path.as_ref().to_path_buf(), required }
+    }
+    
+    // First default file present in the working directory, None when there is none
+    pub fn find_default() -> Option<Self> {
+        DEFAULT_CONFIG_FILES.iter()
+            .find(|name| Path::new(name).is_file())
+            .map(|name| ConfigFile::new(name, true))
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    // Polled to reload the configuration when the file is saved
+    pub fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok()
+    }
+    
+    fn is_structured(&self) -> bool {
+        matches!(self.path.extension().and_then(|extension| extension.to_str()), Some("toml" | "yaml" | "yml"))
+    }
+    
+    fn collect_properties(&self) -> Result<Map<String, Value>, ConfigError> {
+        let mut properties = DavMailConfig::new();
+        properties.load_from_file(&self.path)
+            .map_err(|e| ConfigError::Message(format!("Cannot read {}: {}", self.path.display(), e)))?;
+        
+        let origin = self.path.display().to_string();
+        Ok(properties.settings().iter()
+            .map(|(key, value)| (key.clone(), Value::new(Some(&origin), value.clone())))
+            .collect())
+    }
+    
+    fn collect_structured(&self) -> Result<Map<String, Value>, ConfigError> {
+        let file = Config::builder()
+            .add_source(config::File::from(self.path.as_path()))
+            .build()?;
+        
+        let mut settings = Map::new();
+        for (section, value) in file.collect()? {
+            if section == "users" {
+                // Read by user_overrides(), login names can't be part of a config path
+                continue;
+            } else if section == "davmail" {
+                flatten("davmail", value, &mut settings)?;
+            } else if PROTOCOLS.contains(&section.as_str()) {
+                let protocol: ProtocolSection = value.try_deserialize()
+                    .map_err(|e| ConfigError::Message(format!("{}: invalid [{}] section: {}", self.path.display(), section, e)))?;
+                if let Some(enabled) = protocol.enabled {
+                    settings.insert(format!("davmail.{}Enabled", section), Value::from(enabled));
+                }
+                if let Some(port) = protocol.port {
+                    settings.insert(format!("davmail.{}Port", section), Value::from(i64::from(port)));
+                }
+                if let Some(bind_address) = protocol.bind_address {
+                    let bind_address = match bind_address {
+                        BindAddresses::One(address) => address,
+                        BindAddresses::Many(addresses) => addresses.join(","),
+                    };
+                    settings.insert(format!("davmail.{}BindAddress", section), Value::from(bind_address));
+                }
+            } else {
+                return Err(ConfigError::Message(format!("{}: unknown section [{}]", self.path.display(), section)));
+            }
+        }
+        
+        debug!("Loaded {} settings from {}", settings.len(), self.path.display());
+        Ok(settings)
+    }
+    
+    // {ENC} values are decrypted with the master key, which is only required when the file has some
+    fn decrypt(&self, settings: &mut Map<String, Value>) -> Result<(), ConfigError> {
+        let mut master_key = None;
+        for (key, value) in settings.iter_mut() {
+            let encrypted = match &value.kind {
+                config::ValueKind::String(text) if secrets::is_encrypted(text) => text.clone(),
+                _ => continue,
+            };
+            if master_key.is_none() {
+                master_key = Some(secrets::master_key().map_err(ConfigError::Message)?);
+            }
+            let plaintext = secrets::decrypt(&encrypted, master_key.as_ref().unwrap())
+                .map_err(|e| ConfigError::Message(format!("{}: {}: {}", self.path.display(), key, e)))?;
+            *value = Value::new(Some(&self.path.display().to_string()), plaintext);
+        }
+        Ok(())
+    }
+    
+    pub fn user_overrides(&self) -> Result<UserOverrides, ConfigError> {
+        let mut user_overrides = UserOverrides::default();
+        if !self.is_structured() || !self.path.is_file() {
+            return Ok(user_overrides);
+        }
+        
+        let file = Config::builder()
+            .add_source(config::File::from(self.path.as_path()))
+            .build()?;
+        let users = match file.collect()?.remove("users") {
+            Some(users) => users.into_table()
+                .map_err(|e| ConfigError::Message(format!("{}: invalid [users] section: {}", self.path.display(), e)))?,
+            None => return Ok(user_overrides),
+        };
+        
+        for (username, value) in users {
+            let mut overrides = Map::new();
+            flatten("davmail", value, &mut overrides)?;
+            self.decrypt(&mut overrides)?;
+            user_overrides.users.insert(username.to_lowercase(), overrides);
+        }
+        
+        debug!("Loaded configuration overrides for {} users from {}", user_overrides.len(), self.path.display());
+        Ok(user_overrides)
+    }
+}
+
+impl Source for ConfigFile {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+    
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        if !self.path.is_file() {
+            if self.required {
+                return Err(ConfigError::Message(format!("Configuration file {} not found", self.path.display())));
+            }
+            return Ok(Map::new());
+        }
+        
+        let mut settings = if self.is_structured() {
+            self.collect_structured()?
+        } else {
+            self.collect_properties()?
+        };
+        self.decrypt(&mut settings)?;
+        Ok(settings)
+    }
+}
+
+// Nested tables become dotted keys, so davmail.oauth.clientId reads the same from every format
+pub fn flatten(prefix: &str, value: Value, settings: &mut Map<String, Value>) -> Result<(), ConfigError> {
+    match value.kind {
+        config::ValueKind::Table(_) => {
+            for (key, value) in value.into_table()? {
+                flatten(&format!("{}.{}", prefix, key), value, settings)?;
+            }
+        },
+        _ => {
+            settings.insert(prefix.to_string(), value);
+        }
+    }
+    Ok(())
+}
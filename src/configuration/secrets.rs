@@ -0,0 +1,84 @@
+// configuration/secrets.rs
+// Encrypted values in configuration files ({ENC}...)
+
+use std::env;
+use std::fs;
+use std::num::NonZeroU32;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+pub const ENCRYPTED_PREFIX: &str = "{ENC}";
+
+// Master key, or a file holding it (systemd credentials, mounted secrets)
+pub const MASTER_KEY_VARIABLE: &str = "DAVMAIL_MASTER_KEY";
+pub const MASTER_KEY_FILE_VARIABLE: &str = "DAVMAIL_MASTER_KEY_FILE";
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+// Each value has its own salt, so the key is derived again for every encrypted value
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+// {ENC}base64(salt || nonce || AES-256-GCM ciphertext and tag)
+pub fn encrypt(plaintext: &str, master_key: &str) -> Result<String, String> {
+    let random = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    random.fill(&mut salt).map_err(|_| "No random source available".to_string())?;
+    random.fill(&mut nonce).map_err(|_| "No random source available".to_string())?;
+
+    let mut ciphertext = plaintext.as_bytes().to_vec();
+    value_key(master_key, &salt)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut ciphertext)
+        .map_err(|_| "Encryption failed".to_string())?;
+
+    let mut encoded = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    encoded.extend_from_slice(&salt);
+    encoded.extend_from_slice(&nonce);
+    encoded.extend_from_slice(&ciphertext);
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::Engine::encode(&base64::engine::general_purpose::STANDARD, encoded)))
+}
+
+pub fn decrypt(value: &str, master_key: &str) -> Result<String, String> {
+    let encoded = value.strip_prefix(ENCRYPTED_PREFIX).ok_or("Value is not encrypted")?;
+    let decoded = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, encoded.trim())
+        .map_err(|e| format!("Invalid encrypted value: {}", e))?;
+    if decoded.len() < SALT_LEN + NONCE_LEN {
+        return Err("Invalid encrypted value: too short".to_string());
+    }
+
+    let (salt, rest) = decoded.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "Invalid encrypted value: bad nonce".to_string())?;
+
+    let mut buffer = Zeroizing::new(ciphertext.to_vec());
+    let plaintext = value_key(master_key, salt)?
+        .open_in_place(nonce, Aad::empty(), &mut buffer)
+        .map_err(|_| "Cannot decrypt value, wrong master key?".to_string())?;
+    String::from_utf8(plaintext.to_vec()).map_err(|_| "Decrypted value is not UTF-8".to_string())
+}
+
+// From DAVMAIL_MASTER_KEY, else from the file named by DAVMAIL_MASTER_KEY_FILE
+pub fn master_key() -> Result<Zeroizing<String>, String> {
+    if let Ok(master_key) = env::var(MASTER_KEY_VARIABLE) {
+        return Ok(Zeroizing::new(master_key));
+    }
+    if let Ok(path) = env::var(MASTER_KEY_FILE_VARIABLE) {
+        let master_key = fs::read_to_string(&path)
+            .map_err(|e| format!("Cannot read master key file {}: {}", path, e))?;
+        return Ok(Zeroizing::new(master_key.trim().to_string()));
+    }
+    Err(format!("Encrypted configuration values need {} or {}", MASTER_KEY_VARIABLE, MASTER_KEY_FILE_VARIABLE))
+}
+
+fn value_key(master_key: &str, salt: &[u8]) -> Result<LessSafeKey, String> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(), salt, master_key.as_bytes(), key.as_mut());
+    let key = UnboundKey::new(&AES_256_GCM, key.as_ref()).map_err(|_| "Invalid key".to_string())?;
+    Ok(LessSafeKey::new(key))
+}
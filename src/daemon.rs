@@ -0,0 +1,167 @@
+// daemon.rs
+// --daemon and the PID file: detaching from the terminal on Unix, one running instance per PID file
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+// How long --takeover waits for the running instance to shut down, and how often it checks
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+const TAKEOVER_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Locked for the life of the process, so a file left by a crashed instance is recognized as stale
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    // Fails when another running instance holds the file, unless `takeover` stops that instance first.
+    // Taken before daemonize() so errors still reach the terminal, the lock is inherited by the detached process.
+    pub fn acquire(path: &Path, takeover: bool) -> io::Result<PidFile> {
+        let running = match PidFile::try_acquire(path)? {
+            Ok(pid_file) => return Ok(pid_file),
+            Err(running) => running,
+        };
+        let running = if running.is_empty() { "?".to_string() } else { running };
+        if !takeover {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("Already running with PID {} (locked {}), stop it or start with --takeover", running, path.display())));
+        }
+
+        stop_instance(&running)?;
+        let started = Instant::now();
+        // The stopping instance removes the file, so it is opened again rather than waiting on the old one
+        loop {
+            std::thread::sleep(TAKEOVER_POLL_INTERVAL);
+            if let Ok(pid_file) = PidFile::try_acquire(path)? {
+                info!("Took over from the instance with PID {}", running);
+                return Ok(pid_file);
+            }
+            if started.elapsed() >= TAKEOVER_TIMEOUT {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    format!("The instance with PID {} did not stop within {:?}", running, TAKEOVER_TIMEOUT)));
+            }
+        }
+    }
+
+    // Err with the PID written by the running instance that holds the lock
+    fn try_acquire(path: &Path) -> io::Result<Result<PidFile, String>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut previous = String::new();
+        let _ = file.read_to_string(&mut previous);
+        let previous = previous.trim().to_string();
+
+        if !lock(&file)? {
+            return Ok(Err(previous));
+        }
+        if !previous.is_empty() {
+            warn!("Replacing stale PID file {} of process {}", path.display(), previous);
+        }
+        Ok(Ok(PidFile { path: path.to_path_buf(), file }))
+    }
+
+    // Called by the process that keeps running, after daemonize()
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.sync_all()?;
+        info!("Wrote PID {} to {}", std::process::id(), self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+// No advisory locks, the file only records the PID
+#[cfg(not(unix))]
+fn lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+// SIGTERM, the same graceful shutdown as a service stop
+#[cfg(unix)]
+fn stop_instance(pid: &str) -> io::Result<()> {
+    let pid: libc::pid_t = pid.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The running instance wrote no PID, stop it by hand"))?;
+    info!("Asking the instance with PID {} to shut down", pid);
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Never called, lock() can't report a running instance here
+#[cfg(not(unix))]
+fn stop_instance(_pid: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--takeover is only supported on Unix"))
+}
+
+// Lock file used without --pid-file or davmail.pidFile, one per configuration file so instances
+// with different configurations can run side by side
+pub fn default_lock_path(config_file: Option<&Path>) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    config_file.map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())).hash(&mut hasher);
+    let directory = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    directory.join(format!("gatewayrs563-{:016x}.lock", hasher.finish()))
+}
+
+// Detach from the terminal: fork twice with a new session in between, standard streams on /dev/null.
+// Must run before any thread is started, the working directory is kept for relative configuration paths.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    fn fork() -> io::Result<()> {
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(()),
+            // The parent leaves without running destructors, they belong to the child now
+            _ => unsafe { libc::_exit(0) },
+        }
+    }
+
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // The session leader exits, so the daemon can never acquire a controlling terminal again
+    fork()?;
+    unsafe { libc::umask(0o027) };
+
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--daemon is only supported on Unix, install the gateway as a service instead"))
+}
@@ -0,0 +1,249 @@
+// diag.rs
+// davmail-rust diag: redacted configuration, versions, environment, recent logs and EWS errors in one archive for bug reports
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::Utc;
+use config::{Config, Map, Source};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use reqwest::Url;
+use tokio::runtime::Runtime;
+
+use davmail_core::admin::AdminServer;
+use davmail_core::configuration::{self, UserOverrides};
+use davmail_core::wirelog;
+
+use crate::cli::Cli;
+
+// Tail of each log file, enough for the minutes before a problem
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Settings whose last name part contains one of these are masked
+const SECRET_WORDS: [&str; 3] = ["password", "secret", "pass"];
+
+// Variables that change how the gateway connects, logs or finds its files
+const ENVIRONMENT: [&str; 17] = [
+    "RUST_LOG", "HTTP_PROXY", "http_proxy", "HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy", "NO_PROXY", "no_proxy",
+    "SSL_CERT_FILE", "SSL_CERT_DIR", "TZ", "LANG", "LC_ALL", "XDG_RUNTIME_DIR", "NOTIFY_SOCKET", "LISTEN_FDS",
+];
+
+pub fn run(cli: &Cli, config: &Config, user_overrides: &UserOverrides, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let stamp = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+    let name = format!("gatewayrs563-diag-{}", stamp);
+    let path = output.map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(format!("{}.tar.gz", name)));
+
+    // What couldn't be collected goes into README.txt, the rest of the bundle is still useful
+    let mut notes = Vec::new();
+    let mut files = vec![
+        ("version.txt", version(cli).into_bytes()),
+        ("config.txt", settings(config, user_overrides)?.into_bytes()),
+        ("environment.txt", environment().into_bytes()),
+    ];
+    for (file_name, key) in [("gateway.log", "davmail.logFilePath"), ("wire.log", "davmail.wireLogFile")] {
+        let Some(log_path) = config.get_string(key).ok().filter(|path| !path.trim().is_empty()) else {
+            notes.push(format!("{}: {} is not set", file_name, key));
+            continue;
+        };
+        match tail(Path::new(log_path.trim())) {
+            Ok(content) => files.push((file_name, content)),
+            Err(e) => notes.push(format!("{}: {}: {}", file_name, log_path.trim(), e)),
+        }
+    }
+    match status(config) {
+        Ok(status) => files.push(("status.json", status.into_bytes())),
+        Err(e) => notes.push(format!("status.json: {}", e)),
+    }
+
+    let mut readme = format!("Diagnostic bundle of gatewayrs563 {}, created {}\n\n", env!("CARGO_PKG_VERSION"), Utc::now().to_rfc3339());
+    readme.push_str("Passwords, secrets and proxy credentials are masked. Host names, user names and log messages are kept,\n");
+    readme.push_str("look through the files before attaching them somewhere public.\n\n");
+    readme.push_str("status.json comes from the admin API of the running instance, its ewsErrors are the last failed EWS responses.\n");
+    if !notes.is_empty() {
+        readme.push_str("\nNot collected:\n");
+        for note in &notes {
+            readme.push_str(&format!("  {}\n", note));
+        }
+    }
+    files.insert(0, ("README.txt", readme.into_bytes()));
+
+    let mut archive = TarGz::create(&path)?;
+    for (file_name, content) in &files {
+        archive.add(&format!("{}/{}", name, file_name), content)?;
+    }
+    archive.finish()?;
+
+    println!("Wrote {} ({} file(s))", path.display(), files.len());
+    for note in notes {
+        println!("Not collected: {}", note);
+    }
+    Ok(())
+}
+
+fn version(cli: &Cli) -> String {
+    let mut text = format!("gatewayrs563 {}\n", env!("CARGO_PKG_VERSION"));
+    text.push_str(&format!("Target: {} {} ({})\n", std::env::consts::OS, std::env::consts::ARCH, std::env::consts::FAMILY));
+    text.push_str(&format!("Features: {}\n", if cfg!(feature = "tray") { "tray" } else { "none" }));
+    if let Some(os) = os_release() {
+        text.push_str(&format!("System: {}\n", os));
+    }
+    if let Ok(executable) = std::env::current_exe() {
+        text.push_str(&format!("Executable: {}\n", executable.display()));
+    }
+    let config_file = cli.config_file().map_or_else(|| "none".to_string(), |file| file.path().display().to_string());
+    text.push_str(&format!("Configuration file: {}\n", config_file));
+    text.push_str(&format!("Profile: {}\n", cli.profile));
+    text
+}
+
+// PRETTY_NAME of /etc/os-release, on the systems that have one
+fn os_release() -> Option<String> {
+    let release = std::fs::read_to_string("/etc/os-release").ok()?;
+    release.lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+// Every davmail.* setting as key = value, sorted, with secrets masked. Other keys only come from
+// DAVMAIL_* variables such as DAVMAIL_MASTER_KEY, which aren't settings.
+fn settings(config: &Config, user_overrides: &UserOverrides) -> Result<String, Box<dyn Error>> {
+    let mut settings = Map::new();
+    for (key, value) in config.collect()? {
+        configuration::flatten(&key, value, &mut settings)?;
+    }
+    let mut keys: Vec<&String> = settings.keys().filter(|key| key.starts_with("davmail.")).collect();
+    keys.sort();
+
+    let mut text = String::new();
+    for key in keys {
+        let value = settings[key].to_string();
+        text.push_str(&format!("{} = {}\n", key, redact_setting(key, &value)));
+    }
+    if !user_overrides.is_empty() {
+        text.push_str(&format!("# {} user override(s) in [users], not included\n", user_overrides.len()));
+    }
+    Ok(text)
+}
+
+fn redact_setting(key: &str, value: &str) -> String {
+    let name = key.rsplit('.').next().unwrap_or(key).to_lowercase();
+    if !value.is_empty() && SECRET_WORDS.iter().any(|word| name.contains(word)) {
+        return wirelog::redacted().to_string();
+    }
+    redact_url(value)
+}
+
+// user:password@ of proxy URLs
+fn redact_url(value: &str) -> String {
+    match Url::parse(value) {
+        Ok(mut url) if url.has_host() && (!url.username().is_empty() || url.password().is_some()) => {
+            let _ = url.set_username(wirelog::redacted());
+            let _ = url.set_password(None);
+            url.to_string()
+        },
+        _ => value.to_string(),
+    }
+}
+
+fn environment() -> String {
+    let mut text = String::new();
+    for name in ENVIRONMENT {
+        if let Ok(value) = std::env::var(name) {
+            text.push_str(&format!("{}={}\n", name, redact_url(&value)));
+        }
+    }
+    // The master key decrypts the configuration file, only whether it is there matters
+    let mut davmail: Vec<(String, String)> = std::env::vars().filter(|(name, _)| name.starts_with("DAVMAIL")).collect();
+    davmail.sort();
+    for (name, value) in davmail {
+        let value = if name.contains("KEY") || SECRET_WORDS.iter().any(|word| name.to_lowercase().contains(word)) {
+            wirelog::redacted().to_string()
+        } else {
+            value
+        };
+        text.push_str(&format!("{}={}\n", name, value));
+    }
+    if text.is_empty() {
+        text.push_str("None of the variables the gateway reads are set\n");
+    }
+    text
+}
+
+// The last MAX_LOG_BYTES of a file, from the first complete line
+fn tail(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let start = length.saturating_sub(MAX_LOG_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    if start > 0 {
+        let line_start = content.iter().position(|&byte| byte == b'\n').map_or(0, |end| end + 1);
+        content.drain(..line_start);
+    }
+    Ok(content)
+}
+
+// Listeners, tokens, recent errors and EWS error responses of the running instance
+fn status(config: &Config) -> Result<String, Box<dyn Error>> {
+    let admin_server = AdminServer::from_config(config, |_| {}).ok_or("davmail.adminPort is not set, no running instance to ask")?;
+    let url = format!("{}api/status", admin_server.url());
+    let client = reqwest::Client::builder().timeout(STATUS_TIMEOUT).no_proxy().build()?;
+    let mut request = client.get(&url);
+    if let Ok(password) = config.get_string("davmail.adminPassword") {
+        request = request.basic_auth("admin", Some(password));
+    }
+    let response = Runtime::new()?.block_on(async {
+        let response = request.send().await?.error_for_status()?;
+        response.text().await
+    }).map_err(|e| format!("{}: {}", url, e))?;
+    Ok(response)
+}
+
+// Just enough of ustar for a few regular files, gzip compressed
+struct TarGz {
+    encoder: GzEncoder<File>,
+    modified: i64,
+}
+
+impl TarGz {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(TarGz { encoder: GzEncoder::new(File::create(path)?, Compression::default()), modified: Utc::now().timestamp() })
+    }
+
+    fn add(&mut self, name: &str, content: &[u8]) -> io::Result<()> {
+        if name.len() >= 100 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is too long for a tar header", name)));
+        }
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[108..115].copy_from_slice(b"0000000");
+        header[116..123].copy_from_slice(b"0000000");
+        header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+        header[136..147].copy_from_slice(format!("{:011o}", self.modified).as_bytes());
+        // The checksum is computed with its own field as spaces
+        header[148..156].fill(b' ');
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+        self.encoder.write_all(&header)?;
+        self.encoder.write_all(content)?;
+        let padding = (512 - content.len() % 512) % 512;
+        self.encoder.write_all(&vec![0; padding])
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        // Two empty blocks end the archive
+        self.encoder.write_all(&[0; 1024])?;
+        self.encoder.finish()?.sync_all()
+    }
+}
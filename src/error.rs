@@ -0,0 +1,182 @@
+// error.rs
+// The crate's error type, wrapping the errors of each part of the gateway with context, and how each
+// error is reported to clients: IMAP response code (RFC 5530), SMTP reply (RFC 3463) and HTTP status
+
+use std::io;
+
+use crate::auth::{NtlmError, OAuth2Error};
+use crate::exchange::ExchangeError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Exchange(#[from] ExchangeError),
+    #[error(transparent)]
+    OAuth2(#[from] OAuth2Error),
+    #[error(transparent)]
+    Ntlm(#[from] NtlmError),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Configuration error: {0}")]
+    Config(#[from] config::ConfigError),
+    // What was being done when the error happened, e.g. the file or the user
+    #[error("{context}: {source}")]
+    Context { context: String, source: Box<Error> },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    // The error without the context around it
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            error => error,
+        }
+    }
+}
+
+// `.context("reading the token file")` on any result whose error converts to Error
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|error| Error::Context { context: context.into(), source: Box::new(error.into()) })
+    }
+}
+
+// How an error reaches the client, implemented for each error type of the crate
+pub trait ProtocolStatus {
+    // Response code of the tagged NO, e.g. UNAVAILABLE, None for a plain NO
+    fn imap_code(&self) -> Option<&'static str>;
+
+    // Reply code and enhanced status code
+    fn smtp_code(&self) -> (u16, &'static str);
+
+    fn http_status(&self) -> u16;
+
+    // "NO [UNAVAILABLE] SELECT failed", after the tag
+    fn imap_response(&self, text: &str) -> String {
+        match self.imap_code() {
+            Some(code) => format!("NO [{}] {}", code, text),
+            None => format!("NO {}", text),
+        }
+    }
+
+    // "451 4.4.1 Exchange is unreachable"
+    fn smtp_reply(&self, text: &str) -> String {
+        let (code, enhanced) = self.smtp_code();
+        format!("{} {} {}", code, enhanced, text)
+    }
+}
+
+impl ProtocolStatus for ExchangeError {
+    fn imap_code(&self) -> Option<&'static str> {
+        Some(match self {
+            ExchangeError::HttpError(_) => "UNAVAILABLE",
+            ExchangeError::AuthError(_) => "AUTHENTICATIONFAILED",
+            ExchangeError::ParseError(_) | ExchangeError::RuntimeError(_) => "SERVERBUG",
+            ExchangeError::ConfigError(_) => "UNAVAILABLE",
+            ExchangeError::Unsupported(_) => "CANNOT",
+            ExchangeError::RateLimited(_) => "LIMIT",
+        })
+    }
+
+    fn smtp_code(&self) -> (u16, &'static str) {
+        match self {
+            ExchangeError::HttpError(_) => (451, "4.4.1"),
+            ExchangeError::AuthError(_) => (535, "5.7.8"),
+            ExchangeError::ParseError(_) | ExchangeError::RuntimeError(_) => (451, "4.3.0"),
+            ExchangeError::ConfigError(_) => (451, "4.3.5"),
+            ExchangeError::Unsupported(_) => (502, "5.5.1"),
+            ExchangeError::RateLimited(_) => (451, "4.7.1"),
+        }
+    }
+
+    fn http_status(&self) -> u16 {
+        match self {
+            ExchangeError::HttpError(e) if e.is_timeout() => 504,
+            ExchangeError::HttpError(_) => 502,
+            ExchangeError::AuthError(_) => 401,
+            ExchangeError::ParseError(_) => 502,
+            ExchangeError::ConfigError(_) | ExchangeError::RuntimeError(_) => 500,
+            ExchangeError::Unsupported(_) => 501,
+            ExchangeError::RateLimited(_) => 429,
+        }
+    }
+}
+
+impl ProtocolStatus for OAuth2Error {
+    fn imap_code(&self) -> Option<&'static str> {
+        Some(match self {
+            OAuth2Error::RequestError(_) | OAuth2Error::ConfigError(_) => "UNAVAILABLE",
+            OAuth2Error::ResponseError(_) | OAuth2Error::TokenExpired => "AUTHENTICATIONFAILED",
+            OAuth2Error::ParseError(_) => "SERVERBUG",
+        })
+    }
+
+    fn smtp_code(&self) -> (u16, &'static str) {
+        match self {
+            OAuth2Error::RequestError(_) => (451, "4.4.1"),
+            OAuth2Error::ConfigError(_) => (451, "4.3.5"),
+            OAuth2Error::ResponseError(_) | OAuth2Error::TokenExpired => (535, "5.7.8"),
+            OAuth2Error::ParseError(_) => (451, "4.3.0"),
+        }
+    }
+
+    fn http_status(&self) -> u16 {
+        match self {
+            OAuth2Error::RequestError(_) | OAuth2Error::ParseError(_) => 502,
+            OAuth2Error::ConfigError(_) => 500,
+            OAuth2Error::ResponseError(_) | OAuth2Error::TokenExpired => 401,
+        }
+    }
+}
+
+impl ProtocolStatus for NtlmError {
+    fn imap_code(&self) -> Option<&'static str> {
+        Some("AUTHENTICATIONFAILED")
+    }
+
+    fn smtp_code(&self) -> (u16, &'static str) {
+        (535, "5.7.8")
+    }
+
+    fn http_status(&self) -> u16 {
+        401
+    }
+}
+
+impl ProtocolStatus for Error {
+    fn imap_code(&self) -> Option<&'static str> {
+        match self.root() {
+            Error::Exchange(e) => e.imap_code(),
+            Error::OAuth2(e) => e.imap_code(),
+            Error::Ntlm(e) => e.imap_code(),
+            Error::Io(_) | Error::Config(_) => Some("UNAVAILABLE"),
+            Error::Context { .. } => None,
+        }
+    }
+
+    fn smtp_code(&self) -> (u16, &'static str) {
+        match self.root() {
+            Error::Exchange(e) => e.smtp_code(),
+            Error::OAuth2(e) => e.smtp_code(),
+            Error::Ntlm(e) => e.smtp_code(),
+            Error::Io(_) => (451, "4.3.0"),
+            Error::Config(_) => (451, "4.3.5"),
+            Error::Context { .. } => (451, "4.3.0"),
+        }
+    }
+
+    fn http_status(&self) -> u16 {
+        match self.root() {
+            Error::Exchange(e) => e.http_status(),
+            Error::OAuth2(e) => e.http_status(),
+            Error::Ntlm(e) => e.http_status(),
+            Error::Io(_) | Error::Config(_) | Error::Context { .. } => 500,
+        }
+    }
+}
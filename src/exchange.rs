@@ -1,129 +1,395 @@
 // exchange/client.rs
 // Exchange Web Services (EWS) client implementation
 
-use std::error::Error;
-use std::fmt;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use reqwest::Client;
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, AUTHORIZATION};
-use tokio::runtime::Runtime;
-use log::{debug, error, info};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, AUTHORIZATION, WWW_AUTHENTICATE};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use futures_util::future::join;
+use log::{debug, error, warn};
 use regex;
 
 use crate::auth::*;
+use crate::headers::HeaderRules;
+use crate::metadata::MetadataCache;
+use crate::telemetry::Span;
+use crate::wirelog::{self, Direction};
+use crate::uidmap::{self, UidMap};
 
-#[derive(Debug)]
+pub mod archive;
+pub mod calendar;
+pub mod charset;
+pub mod contacts;
+pub mod flags;
+pub mod foldercache;
+pub mod folders;
+pub mod html;
+pub mod http;
+pub mod ids;
+pub mod import;
+pub mod junk;
+pub mod limiter;
+pub mod metrics;
+pub mod mime;
+pub mod names;
+pub mod proxyauth;
+pub mod receipts;
+pub mod request;
+pub mod response;
+pub mod rules;
+pub mod sessions;
+pub mod spool;
+pub mod sync;
+pub mod tasks;
+pub mod timezones;
+
+use request::{BaseShape, EwsRequest, FieldPath, FindFolder, FindItem, FolderRef, GetFolder, ItemId, ItemView, Restriction, Traversal};
+use response::XmlElement;
+use timezones::TimeZoneMap;
+use mime::{MimeCache, ReadAhead, DEFAULT_READ_AHEAD};
+use receipts::ReadReceipts;
+use html::HtmlConversion;
+use names::FolderNames;
+use foldercache::FolderCache;
+use spool::BodySection;
+use http::{HttpClientConfig, RequestTimeouts};
+use limiter::{RequestLimiter, RequestPermit};
+use sync::MetadataSync;
+
+#[derive(Debug, thiserror::Error)]
 pub enum ExchangeError {
-    HttpError(reqwest::Error),
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Authentication error: {0}")]
     AuthError(String),
+    #[error("Parse error: {0}")]
     ParseError(String),
+    #[error("Configuration error: {0}")]
     ConfigError(String),
+    #[error("Runtime error: {0}")]
     RuntimeError(String),
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+    // Over the user's davmail.ews rate limits, with how long to wait before the next request
+    #[error("Rate limit reached, retry in {}s", .0.as_secs() + 1)]
+    RateLimited(Duration),
 }
 
-impl fmt::Display for ExchangeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ExchangeError::HttpError(e) => write!(f, "HTTP error: {}", e),
-            ExchangeError::AuthError(s) => write!(f, "Authentication error: {}", s),
-            ExchangeError::ParseError(s) => write!(f, "Parse error: {}", s),
-            ExchangeError::ConfigError(s) => write!(f, "Configuration error: {}", s),
-            ExchangeError::RuntimeError(s) => write!(f, "Runtime error: {}", s),
-        }
-    }
-}
-
-impl Error for ExchangeError {}
-
-impl From<reqwest::Error> for ExchangeError {
-    fn from(error: reqwest::Error) -> Self {
-        ExchangeError::HttpError(error)
+impl ExchangeError {
+    // Exchange could not be reached at all, as opposed to refusing or failing the request
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, ExchangeError::HttpError(e) if e.is_connect() || e.is_timeout())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FolderStats {
     pub exists: u32,
     pub recent: u32,
     pub unseen: u32,
     pub uid_validity: u32,
     pub uid_next: u32,
+    // Served from the metadata cache while Exchange is unreachable
+    pub read_only: bool,
 }
 
 #[derive(Debug)]
 pub struct Message {
     pub sequence: u32,
-    pub data: String,
+    // FETCH data items in the order the client asked for them
+    pub items: Vec<FetchItem>,
+}
+
+#[derive(Debug)]
+pub enum FetchItem {
+    // Sent as is, e.g. FLAGS (\Seen)
+    Value(String),
+    // Item name and the content sent after it as a literal
+    Literal(String, BodySection),
 }
 
 pub enum AuthMethod {
     Basic(BasicAuth),
-    OAuth2(OAuth2Auth),
+    // Async lock so requests can refresh the token without blocking the runtime
+    OAuth2(tokio::sync::Mutex<OAuth2Auth>),
+    // No token, the handshake runs when the server challenges a request
+    Ntlm(NtlmAuth),
+    // Access token obtained by the local client (XOAUTH2/OAUTHBEARER), used as is until it expires
+    Bearer,
+    // Authorization header kept current by the TokenManager renewal task
+    Renewed(TokenUpdates),
 }
 
+/// EWS session for one mailbox. The `new_with_*` constructors authenticate before returning.
 pub struct ExchangeClient {
     base_url: String,
     client: Client,
     auth_method: AuthMethod,
     token: Option<String>,
-    runtime: Runtime,
+    // Stable UID assignment, keyed by HexEntryId of the items in `mailbox`
+    uid_map: Option<Arc<Mutex<UidMap>>>,
+    mailbox: Option<String>,
+    // Windows time zone ids seen in calendar items, loaded once per profile and shared by its sessions
+    time_zones: Arc<TimeZoneMap>,
+    // Send gzip compressed SOAP bodies, only for servers configured to accept them
+    compress_requests: bool,
+    // Avoids downloading a message again for each BODY section a client fetches
+    mime_cache: Mutex<MimeCache>,
+    // Messages prefetched past a sequential body FETCH, 0 disables read-ahead
+    read_ahead_count: usize,
+    read_ahead: Mutex<ReadAhead>,
+    // Folder metadata kept in the SQLite cache, only bodies are fetched from Exchange
+    metadata: Option<MetadataSync>,
+    // Concurrency limit shared with the other sessions of the profile, and the user it counts against
+    request_limiter: Option<(Arc<RequestLimiter>, String)>,
+    // Whether Exchange or the IMAP client answers read receipt requests
+    read_receipts: ReadReceipts,
+    // Plain text alternative and tracker removal for HTML-only messages, applied as they are downloaded
+    html_conversion: HtmlConversion,
+    // Transcode legacy charsets of downloaded messages to UTF-8
+    utf8_messages: bool,
+    // English or localized names for the well-known folders, and the localized names once looked up
+    folder_names: FolderNames,
+    localized_names: Mutex<Option<Vec<(&'static str, String)>>>,
+    // davmail.headerRules applied to downloaded messages, after the other conversions
+    header_rules: HeaderRules,
+    // Folder lists and counts shared with the user's other sessions, for LIST and STATUS
+    folder_cache: Option<Arc<FolderCache>>,
+    // Deadlines of interactive and bulk requests, see EwsRequest::bulk
+    request_timeouts: RequestTimeouts,
 }
 
 impl ExchangeClient {
-        pub async fn new_with_basic_auth(base_url: &str, username: &'static str, password: &'static str) -> Result<Self, ExchangeError> {
-            if base_url.is_empty() {
-                return Err(ExchangeError::ConfigError("Exchange URL not configured".to_string()));
-            }
+    // Session before authentication, with the default options, built on by the new_with_* constructors
+    fn with_auth(base_url: &str, auth_method: AuthMethod, client: Client) -> Result<Self, ExchangeError> {
+        if base_url.is_empty() {
+            return Err(ExchangeError::ConfigError("Exchange URL not configured".to_string()));
+        }
+
+        Ok(ExchangeClient {
+            base_url: base_url.to_string(),
+            client,
+            auth_method,
+            token: None,
+            uid_map: None,
+            metadata: None,
+            request_limiter: None,
+            mailbox: None,
+            time_zones: Arc::default(),
+            compress_requests: false,
+            mime_cache: Mutex::new(MimeCache::default()),
+            read_ahead_count: DEFAULT_READ_AHEAD,
+            read_ahead: Mutex::new(ReadAhead::default()),
+            read_receipts: ReadReceipts::default(),
+            html_conversion: HtmlConversion::default(),
+            utf8_messages: false,
+            folder_names: FolderNames::default(),
+            localized_names: Mutex::new(None),
+            header_rules: HeaderRules::default(),
+            folder_cache: None,
+            request_timeouts: RequestTimeouts::default(),
+        })
+    }
 
-            let client = Client::builder()
-                .timeout(std::time::Duration::from_secs(30))
-                .build()?;
+    /// Basic authentication, for on-premise servers and proxies that still accept it
+    pub async fn new_with_basic_auth(base_url: &str, credentials: Credentials, client: Client) -> Result<Self, ExchangeError> {
+        let mut exchange_client = ExchangeClient::with_auth(base_url, AuthMethod::Basic(BasicAuth::new(credentials)), client)?;
 
-            let auth_method = AuthMethod::Basic(BasicAuth::new(username, password));
+        // Authenticate immediately
+        exchange_client.authenticate().await?;
 
-            let runtime = Runtime::new()
-                .map_err(|e| ExchangeError::RuntimeError(format!("Failed to create Tokio runtime: {}", e)))?;
+        Ok(exchange_client)
+    }
 
-            let mut exchange_client = ExchangeClient {
-                base_url: base_url.to_string(),
-                client,
-                auth_method,
-                token: None,
-                runtime,
-            };
+    /// OAuth2 with the flow chosen by `oauth2_config`
+    pub async fn new_with_oauth2(base_url: &str, oauth2_config: OAuth2Config, client: Client) -> Result<Self, ExchangeError> {
+        let oauth2_auth = OAuth2Auth::new(oauth2_config, client.clone())
+            .map_err(|e| ExchangeError::ConfigError(e.to_string()))?;
+        ExchangeClient::new_with_oauth2_auth(base_url, oauth2_auth, client).await
+    }
 
-            // Authenticate immediately
-            exchange_client.authenticate().await;
+    /// OAuth2 with a prepared authenticator, e.g. carrying the user's password for the ROPC flow
+    pub async fn new_with_oauth2_auth(base_url: &str, oauth2_auth: OAuth2Auth, client: Client) -> Result<Self, ExchangeError> {
+        let auth_method = AuthMethod::OAuth2(tokio::sync::Mutex::new(oauth2_auth));
+        let mut exchange_client = ExchangeClient::with_auth(base_url, auth_method, client)?;
 
-            Ok(exchange_client)
+        // Authenticate immediately
+        exchange_client.authenticate().await?;
+
+        Ok(exchange_client)
+    }
+
+    /// Use an access token the client already holds instead of running an OAuth flow
+    pub async fn new_with_access_token(base_url: &str, access_token: &str, client: Client) -> Result<Self, ExchangeError> {
+        let mut exchange_client = ExchangeClient::with_auth(base_url, AuthMethod::Bearer, client)?;
+        exchange_client.token = Some(format!("Bearer {}", access_token));
+
+        // Reject tokens Exchange doesn't accept before reporting a successful login
+        exchange_client.keepalive().await
+            .map_err(|e| ExchangeError::AuthError(format!("Access token rejected: {}", e)))?;
+
+        Ok(exchange_client)
+    }
+
+    /// OAuth2 session whose token is renewed in the background and shared with the account's other sessions
+    pub async fn new_with_token_updates(base_url: &str, token_updates: TokenUpdates, client: Client) -> Result<Self, ExchangeError> {
+        let token = Some(token_updates.header());
+        let mut exchange_client = ExchangeClient::with_auth(base_url, AuthMethod::Renewed(token_updates), client)?;
+        exchange_client.token = token;
+        Ok(exchange_client)
     }
-    pub async fn new_with_oauth2(base_url: &str, oauth2_config: OAuth2Config) -> Result<Self, ExchangeError> {
+
+    /// On-premise servers that only accept NTLM (davmail.enableNtlm), with a dedicated single-connection client
+    pub async fn new_with_ntlm(base_url: &str, login: &str, password: &str, http_config: &HttpClientConfig) -> Result<Self, ExchangeError> {
         if base_url.is_empty() {
             return Err(ExchangeError::ConfigError("Exchange URL not configured".to_string()));
         }
-        
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
-            .build()?;
-        
-        let auth_method = AuthMethod::OAuth2(OAuth2Auth::new(oauth2_config).unwrap());
-        
-        let runtime = Runtime::new()
-            .map_err(|e| ExchangeError::RuntimeError(format!("Failed to create Tokio runtime: {}", e)))?;
-        
-        let mut exchange_client = ExchangeClient {
-            base_url: base_url.to_string(),
-            client,
-            auth_method,
-            token: None,
-            runtime,
-        };
-        
-        // Authenticate immediately
-        exchange_client.authenticate().await;
-        
+
+        let client = http_config.for_ntlm().build()?;
+        let mut exchange_client = ExchangeClient::with_auth(base_url, AuthMethod::Ntlm(NtlmAuth::new(login, password)), client)?;
+        exchange_client.request_timeouts = http_config.timeouts;
+
+        // The keepalive request runs the handshake and proves the credentials
+        exchange_client.keepalive().await?;
+
         Ok(exchange_client)
     }
     
+    /// Assign UIDs from a persistent map instead of deriving them from sequence numbers
+    pub fn with_uid_map(mut self, mailbox: &str, uid_map: Arc<Mutex<UidMap>>) -> Self {
+        self.mailbox = Some(mailbox.to_string());
+        self.uid_map = Some(uid_map);
+        self
+    }
+
+    /// IIS rejects compressed request bodies unless dynamic request decompression is enabled
+    pub fn with_request_compression(mut self, compress_requests: bool) -> Self {
+        self.compress_requests = compress_requests;
+        self
+    }
+
+    pub fn with_mime_cache_size(mut self, max_bytes: usize) -> Self {
+        self.mime_cache = Mutex::new(MimeCache::new(max_bytes));
+        self
+    }
+
+    pub fn with_read_ahead(mut self, count: usize) -> Self {
+        self.read_ahead_count = count;
+        self
+    }
+
+    pub fn with_read_receipts(mut self, read_receipts: ReadReceipts) -> Self {
+        self.read_receipts = read_receipts;
+        self
+    }
+
+    pub fn with_html_conversion(mut self, html_conversion: HtmlConversion) -> Self {
+        self.html_conversion = html_conversion;
+        self
+    }
+
+    /// For clients and scripts that assume UTF-8 and show other charsets as mojibake
+    pub fn with_utf8_messages(mut self, utf8_messages: bool) -> Self {
+        self.utf8_messages = utf8_messages;
+        self
+    }
+
+    pub fn with_folder_names(mut self, folder_names: FolderNames) -> Self {
+        self.folder_names = folder_names;
+        self
+    }
+
+    pub fn with_header_rules(mut self, header_rules: HeaderRules) -> Self {
+        self.header_rules = header_rules;
+        self
+    }
+
+    // Server time zone definitions, see load_time_zones
+    pub fn with_time_zones(mut self, time_zones: Arc<TimeZoneMap>) -> Self {
+        self.time_zones = time_zones;
+        self
+    }
+
+    // Answer LIST and STATUS from the cache while its entries are fresh
+    pub fn with_folder_cache(mut self, folder_cache: Arc<FolderCache>) -> Self {
+        self.folder_cache = Some(folder_cache);
+        self
+    }
+
+    pub fn with_request_timeouts(mut self, request_timeouts: RequestTimeouts) -> Self {
+        self.request_timeouts = request_timeouts;
+        self
+    }
+
+    // Serve FETCH metadata, SEARCH and STATUS from the cache, syncing a folder at most every `refresh_interval`
+    pub fn with_metadata_cache(mut self, mailbox: &str, cache: Arc<MetadataCache>, refresh_interval: Duration) -> Self {
+        self.metadata = Some(MetadataSync::new(cache, mailbox, refresh_interval));
+        self
+    }
+
+    // Queue requests beyond the profile's concurrency limits instead of sending them all at once
+    pub fn with_request_limiter(mut self, user: &str, request_limiter: Arc<RequestLimiter>) -> Self {
+        self.request_limiter = Some((request_limiter, user.to_string()));
+        self
+    }
+
+    // Slot for one request, held until its response is read. Refused when the user is over their rate limits.
+    async fn request_permit(&self) -> Result<Option<RequestPermit>, ExchangeError> {
+        match &self.request_limiter {
+            Some((request_limiter, user)) => {
+                request_limiter.check_rate(user).map_err(|wait| {
+                    debug!("EWS request of {} refused by the rate limits, {:?} to wait", user, wait);
+                    ExchangeError::RateLimited(wait)
+                })?;
+                Ok(Some(request_limiter.acquire(user).await))
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Counted against the user's bandwidth limit
+    fn record_received(&self, bytes: usize) {
+        if let Some((request_limiter, user)) = &self.request_limiter {
+            request_limiter.record_received(user, bytes);
+        }
+    }
+
+    // UIDs of the items listed in a FindItem response, in response order. A response listing every item
+    // of the folder also drops the items that are gone from the map.
+    async fn message_uids(&self, folder: &str, find_item_response: &str) -> Result<Option<Vec<u32>>, ExchangeError> {
+        let (uid_map, mailbox) = match (&self.uid_map, &self.mailbox) {
+            (Some(uid_map), Some(mailbox)) => (uid_map, mailbox),
+            _ => return Ok(None),
+        };
+
+        let response = response::parse_response(find_item_response)?;
+        let ews_ids = response.descendants("Items")
+            .into_iter()
+            .flat_map(|items| items.children.iter())
+            .map(|item| response::item_id(item).map(|id| id.id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let complete = response.descendants("RootFolder").first()
+            .is_some_and(|root| root.attr("IncludesLastItemInRange") == Some("true"));
+        let stable_ids = self.stable_ids(mailbox, &ews_ids).await?;
+
+        let (uids, assigned) = {
+            let mut uid_map = uid_map.lock().unwrap();
+            if complete {
+                uid_map.retain_items(folder, &stable_ids);
+            }
+            let uids = stable_ids.iter().map(|id| uid_map.uid_for(folder, id)).collect();
+            (uids, uid_map.needs_save())
+        };
+        if assigned {
+            save_uid_map(uid_map);
+        }
+        Ok(Some(uids))
+    }
+
     async fn authenticate(&mut self) -> Result<(), ExchangeError> {
         debug!("Authenticating to Exchange server: {}", self.base_url);
 
@@ -134,11 +400,14 @@ impl ExchangeClient {
                 self.verify_basic_auth().await?;
             },
             AuthMethod::OAuth2(oauth2_auth) => {
-                // We need to block on the async call to get the OAuth2 token
-                let token = self.runtime.block_on(async {
-                    oauth2_auth.async_get_auth_header().await
-                }).unwrap();
-                self.token = Some(token);
+                self.token = Some(oauth2_auth.get_mut().async_get_auth_header().await
+                    .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+            },
+            AuthMethod::Ntlm(_) | AuthMethod::Bearer => {
+                // Authenticated per connection in send_request, or token supplied by the client
+            },
+            AuthMethod::Renewed(token_updates) => {
+                self.token = Some(token_updates.header());
             }
         }
 
@@ -158,22 +427,13 @@ impl ExchangeClient {
         let response = self.client
             .post(format!("{}/EWS/Exchange.asmx", self.base_url))
             .headers(headers)
-            .body(r#"<?xml version="1.0" encoding="utf-8"?>
-                <soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
-                               xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types">
-                  <soap:Body>
-                    <FindFolder xmlns="http://schemas.microsoft.com/exchange/services/2006/messages"
-                               xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types"
-                               Traversal="Shallow">
-                      <FolderShape>
-                        <t:BaseShape>IdOnly</t:BaseShape>
-                      </FolderShape>
-                      <ParentFolderIds>
-                        <t:DistinguishedFolderId Id="inbox"/>
-                      </ParentFolderIds>
-                    </FindFolder>
-                  </soap:Body>
-                </soap:Envelope>"#)
+            .body(FindFolder {
+                traversal: Traversal::Shallow,
+                shape: BaseShape::IdOnly,
+                restriction: None,
+                parent: FolderRef::distinguished("inbox"),
+            }.to_soap())
+            .timeout(self.request_timeouts.interactive)
             .send().await?;
 
         if !response.status().is_success() {
@@ -183,92 +443,268 @@ impl ExchangeClient {
         Ok(())
     }
     
-    // Refreshes the authentication token if necessary
-    fn ensure_authenticated(&mut self) -> Result<(), ExchangeError> {
-        match &mut self.auth_method {
-            AuthMethod::Basic(_) => {
-                // Basic auth doesn't expire, so nothing to do
-                Ok(())
-            },
-            AuthMethod::OAuth2(oauth2_auth) => {
-                // Refresh the OAuth2 token if needed
-                let token = self.runtime.block_on(async {
-                    oauth2_auth.async_get_auth_header().await
-                }).unwrap();
-                self.token = Some(token);
-                Ok(())
+    // Post an EWS request and return the parsed response envelope
+    async fn send_request(&self, request: &impl EwsRequest) -> Result<XmlElement, ExchangeError> {
+        let mut span = Span::call("EWS", request.operation());
+        if let Some(folder) = request.folder() {
+            span.set_attribute("ews.folder", folder.label());
+        }
+        let _permit = span.record(self.request_permit().await)?;
+        let (response, started) = span.record(self.post_request(request).await)?;
+        let status = response.status();
+        span.set_attribute("http.response.status_code", status.as_u16());
+
+        let text = response.text().await;
+        self.record_received(text.as_ref().map_or(0, String::len));
+        metrics::record(request.operation(), request.folder().map(FolderRef::label).as_deref(), Some(status.as_u16()), started.elapsed(),
+            text.as_ref().map_or(0, String::len));
+        span.record(text.map_err(ExchangeError::from).and_then(|text| response::parse_response(&text)
+            .inspect_err(|e| metrics::record_error_response(request.operation(), status.as_u16(), e, &text))))
+    }
+
+    // Post an EWS request, authenticating again once when it is rejected. Returns the response with
+    // its body still to be read and when the request was sent, errors and SOAP faults are already handled.
+    async fn post_request(&self, request: &impl EwsRequest) -> Result<(reqwest::Response, Instant), ExchangeError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/xml; charset=utf-8"));
+
+        let body = if self.compress_requests {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(request.to_soap().as_bytes())
+                .and_then(|_| encoder.finish())
+                .map_err(|e| ExchangeError::RuntimeError(format!("Failed to compress request: {}", e)))?
+        } else {
+            request.to_soap().into_bytes()
+        };
+
+        let operation = request.operation();
+        let folder = request.folder().map(FolderRef::label);
+        let url = format!("{}/EWS/Exchange.asmx", self.base_url);
+        let timeout = self.request_timeouts.get(request.bulk());
+
+        let mut authorization = self.auth_header().await?;
+        let mut reauthenticated = false;
+        let (response, started) = loop {
+            let mut request_headers = headers.clone();
+            if let Some(authorization) = &authorization {
+                request_headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization)
+                    .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+            }
+
+            if wirelog::enabled() {
+                wirelog::log("EWS", operation, Direction::Sent, &format!("POST {}", url));
+                for (name, value) in &request_headers {
+                    wirelog::log("EWS", operation, Direction::Sent, &wirelog::redact_header(name.as_str(), value.to_str().unwrap_or_default()));
+                }
+            }
+
+            let started = Instant::now();
+            let sent = match &self.auth_method {
+                AuthMethod::Ntlm(ntlm) => self.send_ntlm(ntlm, &url, request_headers, body.clone(), timeout).await,
+                _ => self.client.post(&url).headers(request_headers).body(body.clone()).timeout(timeout).send().await
+                    .map_err(ExchangeError::from),
+            };
+            let response = match sent {
+                Ok(response) => response,
+                Err(e) => {
+                    metrics::record(operation, folder.as_deref(), None, started.elapsed(), 0);
+                    return Err(e);
+                }
+            };
+
+            if wirelog::enabled() {
+                wirelog::log("EWS", operation, Direction::Received, &format!("{:?} {}", response.version(), response.status()));
+                for (name, value) in response.headers() {
+                    wirelog::log("EWS", operation, Direction::Received, &wirelog::redact_header(name.as_str(), value.to_str().unwrap_or_default()));
+                }
             }
+
+            // Expired token, changed password or revoked session: authenticate again once and retry
+            let status = response.status();
+            if !reauthenticated && (status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN) {
+                metrics::record(operation, folder.as_deref(), Some(status.as_u16()), started.elapsed(), 0);
+                if let Some(fresh) = self.reauthenticate().await? {
+                    warn!("{} was rejected with {}, retrying after authenticating again", operation, status);
+                    authorization = Some(fresh);
+                    reauthenticated = true;
+                    continue;
+                }
+            }
+            break (response, started);
+        };
+        let status = response.status();
+
+        // SOAP faults are returned with HTTP 500 and a fault envelope describing the error
+        if status == reqwest::StatusCode::INTERNAL_SERVER_ERROR {
+            let text = response.text().await;
+            metrics::record(operation, folder.as_deref(), Some(status.as_u16()), started.elapsed(),
+                text.as_ref().map_or(0, String::len));
+            let text = text?;
+            let error = response::parse_response(&text).err()
+                .unwrap_or_else(|| ExchangeError::ParseError(format!("Request failed with status: {}", status)));
+            metrics::record_error_response(operation, status.as_u16(), &error, &text);
+            return Err(error);
+        }
+        if let Some(e) = response.error_for_status_ref().err() {
+            metrics::record(operation, folder.as_deref(), Some(status.as_u16()), started.elapsed(), 0);
+            metrics::record_error_response(operation, status.as_u16(), &e, &response.text().await.unwrap_or_default());
+            return Err(e.into());
         }
+
+        Ok((response, started))
     }
 
-    
-    pub async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
-        // Ensure we have a valid authentication token
-        self.ensure_authenticated()?;
 
-        debug!("Listing folders with reference '{}' and pattern '{}'", reference, pattern);
+    // Authorization header for the next request, None when the connection itself is authenticated
+    async fn auth_header(&self) -> Result<Option<String>, ExchangeError> {
+        match &self.auth_method {
+            AuthMethod::Ntlm(_) => Ok(None),
+            // Latest header from the renewal task, never refreshed in the request path
+            AuthMethod::Renewed(token_updates) => Ok(Some(token_updates.header())),
+            // Cached until it is about to expire
+            AuthMethod::OAuth2(oauth2_auth) => oauth2_auth.lock().await.async_get_auth_header().await
+                .map(Some)
+                .map_err(|e| ExchangeError::AuthError(e.to_string())),
+            AuthMethod::Basic(_) | AuthMethod::Bearer => self.token.clone()
+                .map(Some)
+                .ok_or_else(|| ExchangeError::AuthError("Not authenticated".to_string())),
+        }
+    }
 
-        // Prepare headers
+    // Run the auth provider again after the server rejected a request, None when there is nothing to renew
+    async fn reauthenticate(&self) -> Result<Option<String>, ExchangeError> {
+        let renewed = match &self.auth_method {
+            AuthMethod::Basic(basic_auth) => basic_auth.get_auth_header()
+                .map_err(|e| ExchangeError::AuthError(e.to_string()))?,
+            AuthMethod::OAuth2(oauth2_auth) => oauth2_auth.lock().await.async_renew_token().await
+                .map(|token| token.authorization_header())
+                .map_err(|e| ExchangeError::AuthError(format!("Authenticating again failed: {}", e)))?,
+            AuthMethod::Renewed(token_updates) => token_updates.renew().await
+                .map_err(|e| ExchangeError::AuthError(format!("Authenticating again failed: {}", e)))?,
+            // send_ntlm already answers every challenge, client supplied tokens can only be renewed by the client
+            AuthMethod::Ntlm(_) | AuthMethod::Bearer => return Ok(None),
+        };
+        Ok(Some(renewed))
+    }
+
+    // Send on the current connection and run the NTLM handshake when the server challenges it
+    async fn send_ntlm(&self, ntlm: &NtlmAuth, url: &str, headers: HeaderMap, body: Vec<u8>, timeout: Duration)
+                       -> Result<reqwest::Response, ExchangeError> {
+        let response = self.client.post(url).headers(headers.clone()).body(body.clone()).timeout(timeout).send().await?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        // Drain the body so the next leg goes over the same connection
+        response.bytes().await?;
+
+        debug!("Starting NTLM handshake");
+        let mut negotiate_headers = headers.clone();
+        negotiate_headers.insert(AUTHORIZATION, HeaderValue::from_str(&ntlm.negotiate_header())
+            .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+        let challenge_response = self.client.post(url).headers(negotiate_headers).body(body.clone()).timeout(timeout).send().await?;
+
+        let challenge = challenge_response.headers().get_all(WWW_AUTHENTICATE).iter()
+            .filter_map(|value| value.to_str().ok())
+            .find(|value| value.starts_with("NTLM "))
+            .map(str::to_string);
+        let challenge = match challenge {
+            Some(challenge) => challenge,
+            None => return Err(ExchangeError::AuthError("Server did not send an NTLM challenge".to_string())),
+        };
+        challenge_response.bytes().await?;
+
+        let authenticate = ntlm.authenticate_header(&challenge)
+            .map_err(|e| ExchangeError::AuthError(e.to_string()))?;
+        let mut authenticate_headers = headers;
+        authenticate_headers.insert(AUTHORIZATION, HeaderValue::from_str(&authenticate)
+            .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+        let response = self.client.post(url).headers(authenticate_headers).body(body).timeout(timeout).send().await?;
+
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(ExchangeError::AuthError("NTLM authentication failed".to_string()));
+        }
+        Ok(response)
+    }
+
+    // Headers of the requests built here rather than through send_request
+    async fn request_headers(&self) -> Result<HeaderMap, ExchangeError> {
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/xml; charset=utf-8"));
-        headers.insert(AUTHORIZATION, HeaderValue::from_str(self.token.as_ref().unwrap())
-            .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+        if let Some(auth_header) = self.auth_header().await? {
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_header)
+                .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+        }
+        Ok(headers)
+    }
+
+    pub async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        if let Some(folders) = self.folder_cache.as_ref().and_then(|folder_cache| folder_cache.list(reference, pattern)) {
+            return Ok(folders);
+        }
+        match self.list_exchange_folders(reference, pattern).await {
+            Ok(folders) => {
+                if let Some(folder_cache) = &self.folder_cache {
+                    folder_cache.insert_list(reference, pattern, &folders);
+                }
+                Ok(folders)
+            },
+            Err(e) => self.offline(e, |metadata| metadata.list_folders(pattern)),
+        }
+    }
+
+    async fn list_exchange_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        debug!("Listing folders with reference '{}' and pattern '{}'", reference, pattern);
+
+        // Prepare headers, with a current token
+        let headers = self.request_headers().await?;
 
         // Build the EWS FindFolder request
-        let parent_folder: String = if reference.is_empty() {
+        let parent = if reference.is_empty() {
             // If reference is empty, use msgfolderroot
-            format!(r#"<t:DistinguishedFolderId Id="msgfolderroot"/>"#)
+            FolderRef::distinguished("msgfolderroot")
         } else {
             // Otherwise use the specified folder ID
-            format!(r#"<t:FolderId Id="{}"/>"#, reference)
+            FolderRef::Id(reference.to_string())
         };
 
-        let body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
-            <soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
-                           xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types">
-              <soap:Body>
-                <FindFolder xmlns="http://schemas.microsoft.com/exchange/services/2006/messages"
-                           xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types"
-                           Traversal="Deep">
-                  <FolderShape>
-                    <t:BaseShape>Default</t:BaseShape>
-                  </FolderShape>
-                  <ParentFolderIds>
-                    {}
-                  </ParentFolderIds>
-                </FindFolder>
-              </soap:Body>
-            </soap:Envelope>"#, parent_folder);
+        let body = FindFolder {
+            traversal: Traversal::Deep,
+            shape: BaseShape::Default,
+            restriction: None,
+            parent,
+        }.to_soap();
 
         // Send the request
+        let _span = Span::call("EWS", "FindFolder");
+        let _permit = self.request_permit().await?;
         let response = self.client
             .post(format!("{}/EWS/Exchange.asmx", self.base_url))
             .headers(headers)
             .body(body)
+            .timeout(self.request_timeouts.interactive)
             .send().await?;
 
-        if !response.status().is_success() {
-            return Err(ExchangeError::HttpError(
-                reqwest::Error::from(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Request failed with status: {}", response.status())
-                ))
-            ));
-        }
+        let response = checked_status(response)?;
 
         let response_text = response.text().await;
+        self.record_received(response_text.as_ref().map_or(0, String::len));
 
         // In a real implementation, you would parse the XML response
         // For this example, we'll return simulated folders
+        let mut all_folders = vec![
+            "INBOX".to_string(),
+            "Sent Items".to_string(),
+            "Drafts".to_string(),
+            "Deleted Items".to_string(),
+            "Junk Email".to_string(),
+            "Archive".to_string(),
+        ];
+        all_folders.extend(self.list_archive_folders().await?);
+        let all_folders = self.present_folder_names(all_folders).await;
+
         if pattern == "*" {
-            Ok(vec![
-                "INBOX".to_string(),
-                "Sent Items".to_string(),
-                "Drafts".to_string(),
-                "Deleted Items".to_string(),
-                "Junk Email".to_string(),
-                "Archive".to_string(),
-            ])
+            Ok(all_folders)
         } else {
             // Filter folders based on pattern (simple wildcard implementation)
             let pattern = pattern.replace("*", ".*");
@@ -276,15 +712,6 @@ impl ExchangeClient {
                 ExchangeError::ParseError(format!("Invalid pattern: {}", e))
             })?;
 
-            let all_folders = vec![
-                "INBOX".to_string(),
-                "Sent Items".to_string(),
-                "Drafts".to_string(),
-                "Deleted Items".to_string(),
-                "Junk Email".to_string(),
-                "Archive".to_string(),
-            ];
-
             Ok(all_folders.into_iter()
                 .filter(|folder| regex.is_match(folder))
                 .collect())
@@ -294,82 +721,105 @@ impl ExchangeClient {
     pub async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
         debug!("Selecting folder: {}", folder_name);
         
+        if let Some((state, messages)) = self.cached_messages(folder_name).await? {
+            return Ok(sync::folder_stats(&state, &messages, self.metadata.as_ref().is_some_and(MetadataSync::is_offline)));
+        }
+        
         // Prepare headers
-        let mut headers = HeaderMap::new();
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/xml; charset=utf-8"));
-        headers.insert(AUTHORIZATION, HeaderValue::from_str(self.token.as_ref().unwrap()).unwrap());
+        let headers = self.request_headers().await?;
         
         // Determine folder ID (distinguished or by name)
-        let folder_id = match folder_name.to_uppercase().as_str() {
-            "INBOX" => r#"<t:DistinguishedFolderId Id="inbox"/>"#.to_string(),
-            "SENT" | "SENT ITEMS" => r#"<t:DistinguishedFolderId Id="sentitems"/>"#.to_string(),
-            "DRAFTS" => r#"<t:DistinguishedFolderId Id="drafts"/>"#.to_string(),
-            "TRASH" | "DELETED ITEMS" => r#"<t:DistinguishedFolderId Id="deleteditems"/>"#.to_string(),
-            _ => {
-                // For other folders, we would need to find the folder ID first
-                // This is simplified for this example
-                format!(r#"<t:DistinguishedFolderId Id="msgfolderroot"/>
-                         <t:Folders>
-                           <t:Folder>
-                             <t:DisplayName>{}</t:DisplayName>
-                           </t:Folder>
-                         </t:Folders>"#, folder_name)
+        let body = match request::distinguished_folder(folder_name) {
+            // Build the EWS GetFolder request
+            Some(distinguished) => GetFolder {
+                shape: BaseShape::Default,
+                additional_properties: vec!["folder:TotalCount", "folder:UnreadCount"],
+                folders: vec![FolderRef::distinguished(distinguished)],
+            }.to_soap(),
+            // For other folders, look the folder up by display name, in the archive mailbox for Archive/ paths
+            None => {
+                let (root, display_name) = archive::search_root(folder_name);
+                FindFolder {
+                    traversal: Traversal::Deep,
+                    shape: BaseShape::Default,
+                    restriction: Some(Restriction::IsEqualTo {
+                        field: FieldPath::Field("folder:DisplayName"),
+                        value: display_name.to_string(),
+                    }),
+                    parent: root,
+                }.to_soap()
             },
         };
         
-        // Build the EWS GetFolder request
-        let body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
-            <soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
-                           xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types">
-              <soap:Body>
-                <GetFolder xmlns="http://schemas.microsoft.com/exchange/services/2006/messages"
-                          xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types">
-                  <FolderShape>
-                    <t:BaseShape>Default</t:BaseShape>
-                    <t:AdditionalProperties>
-                      <t:FieldURI FieldURI="folder:TotalCount"/>
-                      <t:FieldURI FieldURI="folder:UnreadCount"/>
-                    </t:AdditionalProperties>
-                  </FolderShape>
-                  <FolderIds>
-                    {}
-                  </FolderIds>
-                </GetFolder>
-              </soap:Body>
-            </soap:Envelope>"#, folder_id);
-        
         // Send the request
+        let _span = Span::call("EWS", if request::distinguished_folder(folder_name).is_some() { "GetFolder" } else { "FindFolder" });
+        let _permit = self.request_permit().await?;
         let response = self.client
             .post(format!("{}/EWS/Exchange.asmx", self.base_url))
             .headers(headers)
             .body(body)
+            .timeout(self.request_timeouts.interactive)
             .send().await?;
         
-        if !response.status().is_success() {
-            return Err(ExchangeError::HttpError(
-                reqwest::Error::from(std::io::Error::new(
-                    std::io::ErrorKind::Other, 
-                    format!("Request failed with status: {}", response.status())
-                ))
-            ));
-        }
+        let response = checked_status(response)?;
         
         let response_text = response.text().await;
+        self.record_received(response_text.as_ref().map_or(0, String::len));
         
         // In a real implementation, you would parse the XML response
         // For this example, we'll return simulated stats
         // In a production environment, parse the XML response to get the actual values
         
-        // Generate a deterministic UID validity based on folder name
-        let uid_validity = folder_name.bytes().fold(0u32, |acc, b| acc.wrapping_add(b as u32));
+        // UIDVALIDITY follows the FolderId, which stays the same when the folder is renamed
+        let folder_id = response::parse_response(&response_text?)?
+            .descendants("FolderId")
+            .first()
+            .and_then(|folder_id| folder_id.attr("Id"))
+            .map(str::to_string)
+            .ok_or_else(|| ExchangeError::ParseError(format!("Folder not found: {}", folder_name)))?;
+        let (uid_validity, uid_next) = match &self.uid_map {
+            Some(uid_map) => {
+                let (uid_validity, uid_next, changed) = {
+                    let mut locked = uid_map.lock().unwrap();
+                    let uid_validity = locked.uid_validity(folder_name, &folder_id);
+                    (uid_validity, locked.uid_next(folder_name), locked.needs_save())
+                };
+                if changed {
+                    save_uid_map(uid_map);
+                }
+                (uid_validity, uid_next)
+            },
+            None => (uidmap::uid_validity(0, &folder_id), 1000),
+        };
         
-        Ok(FolderStats {
+        let stats = FolderStats {
             exists: 125,          // Total messages in folder
             recent: 5,            // New messages since last check
             unseen: 10,           // Unread messages
             uid_validity,         // A unique identifier for the folder state
-            uid_next: 1000,       // Next UID to be assigned
-        })
+            uid_next,             // Next UID to be assigned
+            read_only: false,
+        };
+        // SELECT always asks Exchange, the counts it got serve the next STATUS
+        if let Some(folder_cache) = &self.folder_cache {
+            folder_cache.insert_stats(folder_name, &stats);
+        }
+        Ok(stats)
+    }
+
+    // Counts for STATUS, from the folder cache while they are fresh
+    pub async fn folder_status(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        match self.folder_cache.as_ref().and_then(|folder_cache| folder_cache.stats(folder_name)) {
+            Some(stats) => Ok(stats),
+            None => self.select_folder(folder_name).await,
+        }
+    }
+
+    // Counts of a folder changed by this session are looked up again
+    fn folder_changed(&self, folder_name: &str) {
+        if let Some(folder_cache) = &self.folder_cache {
+            folder_cache.expire(folder_name);
+        }
     }
     
     pub async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) 
@@ -377,66 +827,52 @@ impl ExchangeClient {
         debug!("Fetching messages from folder '{}', sequence '{}', items '{}'", 
                folder, sequence_set, items);
         
-        // Prepare headers
-        let mut headers = HeaderMap::new();
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/xml; charset=utf-8"));
-        headers.insert(AUTHORIZATION, HeaderValue::from_str(self.token.as_ref().unwrap()).unwrap());
-        
         // Parse sequence set (e.g., "1:10", "1,3,5", "*")
         let sequences = parse_sequence_set(sequence_set)?;
         
+        // With a metadata cache only the body sections go to Exchange
+        if let Some((_, messages)) = self.cached_messages(folder).await? {
+            let fetch_items: Vec<&str> = items.trim_matches(|c| c == '(' || c == ')').split_whitespace().collect();
+            return self.fetch_cached(&messages, &sequences, &fetch_items).await;
+        }
+        
+        // Prepare headers
+        let headers = self.request_headers().await?;
+        
         // Determine folder ID
-        let folder_id = match folder.to_uppercase().as_str() {
-            "INBOX" => "inbox".to_string(),
-            "SENT" | "SENT ITEMS" => "sentitems".to_string(),
-            "DRAFTS" => "drafts".to_string(),
-            "TRASH" | "DELETED ITEMS" => "deleteditems".to_string(),
-            _ => folder.to_string(),
-        };
+        let parent = self.resolve_folder(folder).await?;
         
         // Build the EWS FindItem request
         // In a real implementation, you would need to handle paging for large result sets
-        let body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
-            <soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
-                          xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types">
-              <soap:Body>
-                <FindItem xmlns="http://schemas.microsoft.com/exchange/services/2006/messages"
-                         xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types"
-                         Traversal="Shallow">
-                  <ItemShape>
-                    <t:BaseShape>IdOnly</t:BaseShape>
-                    <t:AdditionalProperties>
-                      <t:FieldURI FieldURI="item:Subject"/>
-                      <t:FieldURI FieldURI="item:DateTimeReceived"/>
-                      <t:FieldURI FieldURI="message:From"/>
-                      <t:FieldURI FieldURI="message:IsRead"/>
-                    </t:AdditionalProperties>
-                  </ItemShape>
-                  <IndexedPageItemView MaxEntriesReturned="100" Offset="0" BasePoint="Beginning"/>
-                  <ParentFolderIds>
-                    <t:DistinguishedFolderId Id="{}"/>
-                  </ParentFolderIds>
-                </FindItem>
-              </soap:Body>
-            </soap:Envelope>"#, folder_id);
+        let body = FindItem {
+            traversal: Traversal::Shallow,
+            shape: BaseShape::IdOnly,
+            additional_properties: vec![
+                "item:Subject",
+                "item:DateTimeReceived",
+                "message:From",
+                "message:IsRead",
+            ],
+            extended_properties: vec![flags::ANSWERED_PROPERTY],
+            view: ItemView::Indexed { max_entries: 100, offset: 0 },
+            restriction: None,
+            parent,
+        }.to_soap();
         
         // Send the request
+        let _span = Span::call("EWS", "FindItem");
+        let _permit = self.request_permit().await?;
         let response = self.client
             .post(format!("{}/EWS/Exchange.asmx", self.base_url))
             .headers(headers)
             .body(body)
+            .timeout(self.request_timeouts.interactive)
             .send().await?;
         
-        if !response.status().is_success() {
-            return Err(ExchangeError::HttpError(
-                reqwest::Error::from(std::io::Error::new(
-                    std::io::ErrorKind::Other, 
-                    format!("Request failed with status: {}", response.status())
-                ))
-            ));
-        }
+        let response = checked_status(response)?;
         
-        let response_text = response.text().await;
+        let response_text = response.text().await?;
+        self.record_received(response_text.len());
         
         // In a real implementation, you would parse the XML response and build IMAP responses
         // For this example, we'll simulate messages
@@ -444,30 +880,98 @@ impl ExchangeClient {
         // Parse the items requested (e.g., "BODY[HEADER] FLAGS UID")
         let fetch_items: Vec<&str> = items.trim_matches(|c| c == '(' || c == ')').split_whitespace().collect();
         
+        let uids = if fetch_items.contains(&"UID") {
+            self.message_uids(folder, &response_text).await?
+        } else {
+            None
+        };
+        
+        // Items in response order, IsRead and the last verb come with the FindItem response so FLAGS needs no extra request
+        let response = response::parse_response(&response_text)?;
+        let found_items: Vec<&XmlElement> = response.descendants("Items")
+            .into_iter()
+            .flat_map(|items| items.children.iter())
+            .collect();
+        
+        // Download every requested body in batched GetItem calls rather than one request per message
+        let mut contents = HashMap::new();
+        if fetch_items.iter().any(|item| item.starts_with("BODY[")) {
+            let wanted: Vec<(u32, ItemId)> = sequences.iter()
+                .filter_map(|&seq| seq.checked_sub(1)
+                    .and_then(|index| found_items.get(index as usize))
+                    .map(|item| response::item_id(item).map(|id| (seq, id))))
+                .collect::<Result<_, _>>()?;
+            let ids: Vec<ItemId> = wanted.iter().map(|(_, id)| id.clone()).collect();
+            
+            // A client walking through the folder gets the following messages prefetched alongside
+            let sequential = self.read_ahead_count > 0
+                && self.read_ahead.lock().unwrap().is_sequential(folder, &sequences);
+            let prefetch_ids: Vec<ItemId> = if sequential {
+                let last = sequences.iter().max().copied().unwrap_or(0) as usize;
+                found_items.iter()
+                    .skip(last)
+                    .take(self.read_ahead_count)
+                    .map(|item| response::item_id(item))
+                    .collect::<Result<_, _>>()?
+            } else {
+                Vec::new()
+            };
+            let (fetched, _) = join(self.get_mime_contents(&ids), self.prefetch_mime(&prefetch_ids)).await;
+            let fetched = fetched?;
+            contents = wanted.into_iter().map(|(seq, _)| seq).zip(fetched).collect();
+        }
+        
         let mut result = Vec::new();
         for &seq in &sequences {
             // Generate message data based on requested items
-            let mut data_parts = Vec::new();
+            let mut data_items = Vec::new();
+            
+            let content = contents.get(&seq);
+            let found_item = seq.checked_sub(1).and_then(|index| found_items.get(index as usize));
             
             for item in &fetch_items {
+                let section = content.and_then(|content| match *item {
+                    "BODY[HEADER]" => Some(BodySection::header(content.clone())),
+                    "BODY[TEXT]" => Some(BodySection::text(content.clone())),
+                    "BODY[]" => Some(BodySection::whole(content.clone())),
+                    _ => None,
+                });
+                if let Some(section) = section {
+                    data_items.push(FetchItem::Literal(item.to_string(), section));
+                    continue;
+                }
                 match *item {
                     "FLAGS" => {
-                        data_parts.push("FLAGS (\\Seen)".to_string());
+                        let mut flags = Vec::new();
+                        if found_item.and_then(|item| item.child_text("IsRead")).is_none_or(|value| value == "true") {
+                            flags.push("\\Seen");
+                        }
+                        if found_item.is_some_and(|item| flags::is_answered(item)) {
+                            flags.push("\\Answered");
+                        }
+                        flags.extend(self.read_receipts.flags());
+                        data_items.push(FetchItem::Value(format!("FLAGS ({})", flags.join(" "))));
                     },
                     "UID" => {
-                        let uid = 1000 + seq;
-                        data_parts.push(format!("UID {}", uid));
+                        let uid = uids.as_ref()
+                            .and_then(|uids| seq.checked_sub(1).and_then(|index| uids.get(index as usize)))
+                            .copied()
+                            .unwrap_or(seq.saturating_add(1000));
+                        data_items.push(FetchItem::Value(format!("UID {}", uid)));
                     },
                     item if item.starts_with("BODY[HEADER]") => {
-                        data_parts.push(format!("BODY[HEADER] {{320}}\r\nFrom: user{}@example.com\r\nTo: recipient@example.com\r\nSubject: Test message {}\r\nDate: Fri, 28 Mar 2025 10:{}:00 +0000\r\nMessage-ID: <{}.{}.{}@example.com>\r\n\r\n", 
-                                               seq % 10, seq, seq % 60, seq, seq, seq));
+                        data_items.push(FetchItem::Literal("BODY[HEADER]".to_string(), BodySection::from_text(format!(
+                            "From: user{}@example.com\r\nTo: recipient@example.com\r\nSubject: Test message {}\r\nDate: Fri, 28 Mar 2025 10:{}:00 +0000\r\nMessage-ID: <{}.{}.{}@example.com>\r\n\r\n",
+                            seq % 10, seq, seq % 60, seq, seq, seq))));
                     },
                     item if item.starts_with("BODY[TEXT]") => {
-                        data_parts.push(format!("BODY[TEXT] {{42}}\r\nThis is the body of test message {}.\r\n", seq));
+                        data_items.push(FetchItem::Literal("BODY[TEXT]".to_string(), BodySection::from_text(format!(
+                            "This is the body of test message {}.\r\n", seq))));
                     },
                     item if item == "BODY[]" || item.starts_with("BODY[") => {
-                        data_parts.push(format!("BODY[] {{362}}\r\nFrom: user{}@example.com\r\nTo: recipient@example.com\r\nSubject: Test message {}\r\nDate: Fri, 28 Mar 2025 10:{}:00 +0000\r\nMessage-ID: <{}.{}.{}@example.com>\r\n\r\nThis is the body of test message {}.\r\n", 
-                                               seq % 10, seq, seq % 60, seq, seq, seq, seq));
+                        data_items.push(FetchItem::Literal("BODY[]".to_string(), BodySection::from_text(format!(
+                            "From: user{}@example.com\r\nTo: recipient@example.com\r\nSubject: Test message {}\r\nDate: Fri, 28 Mar 2025 10:{}:00 +0000\r\nMessage-ID: <{}.{}.{}@example.com>\r\n\r\nThis is the body of test message {}.\r\n",
+                            seq % 10, seq, seq % 60, seq, seq, seq, seq))));
                     },
                     _ => {
                         // Ignore unsupported items
@@ -475,11 +979,10 @@ impl ExchangeClient {
                 }
             }
             
-            if !data_parts.is_empty() {
-                let data = format!("({})", data_parts.join(" "));
+            if !data_items.is_empty() {
                 result.push(Message {
                     sequence: seq,
-                    data,
+                    items: data_items,
                 });
             }
         }
@@ -488,9 +991,37 @@ impl ExchangeClient {
     }
 }
 
+// Write the UID map back off the runtime threads, the response doesn't wait for the disk
+fn save_uid_map(uid_map: &Arc<Mutex<UidMap>>) {
+    let uid_map = uid_map.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = uid_map.lock().unwrap().save() {
+            error!("Failed to save UID map: {}", e);
+        }
+    });
+}
+
+// Response of a request built without send_request, an error status as an error: rejected
+// credentials are an authentication error, any other a HTTP error carrying the status
+fn checked_status(response: reqwest::Response) -> Result<reqwest::Response, ExchangeError> {
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(ExchangeError::AuthError(format!("Request rejected with status {}", status)));
+    }
+    Ok(response.error_for_status()?)
+}
+
+// Numbers one sequence set may expand to, "1:4294967295" would otherwise allocate gigabytes
+const MAX_SEQUENCE_SET_SIZE: usize = 100_000;
+
 // Helper function to parse an IMAP sequence set
-fn parse_sequence_set(sequence_set: &str) -> Result<Vec<u32>, ExchangeError> {
+pub(crate) fn parse_sequence_set(sequence_set: &str) -> Result<Vec<u32>, ExchangeError> {
     let mut result = Vec::new();
+    let too_large = || ExchangeError::ParseError(format!("Sequence set {} is too large", sequence_set));
+    let number = |value: &str| match value.parse::<u32>() {
+        Ok(0) | Err(_) => Err(ExchangeError::ParseError(format!("Invalid sequence number: {}", value))),
+        Ok(number) => Ok(number),
+    };
     
     for part in sequence_set.split(',') {
         if part == "*" {
@@ -509,29 +1040,27 @@ fn parse_sequence_set(sequence_set: &str) -> Result<Vec<u32>, ExchangeError> {
                 // In a real implementation, this would be the highest message number
                 10
             } else {
-                range_parts[0].parse::<u32>().map_err(|_| {
-                    ExchangeError::ParseError(format!("Invalid sequence number: {}", range_parts[0]))
-                })?
+                number(range_parts[0])?
             };
             
             let end = if range_parts[1] == "*" {
                 // In a real implementation, this would be the highest message number
                 10
             } else {
-                range_parts[1].parse::<u32>().map_err(|_| {
-                    ExchangeError::ParseError(format!("Invalid sequence number: {}", range_parts[1]))
-                })?
+                number(range_parts[1])?
             };
             
-            for i in start.min(end)..=start.max(end) {
-                result.push(i);
+            let (low, high) = (start.min(end), start.max(end));
+            if (high - low) as usize >= MAX_SEQUENCE_SET_SIZE - result.len() {
+                return Err(too_large());
             }
+            result.extend(low..=high);
         } else {
             // Single message number
-            let num = part.parse::<u32>().map_err(|_| {
-                ExchangeError::ParseError(format!("Invalid sequence number: {}", part))
-            })?;
-            result.push(num);
+            result.push(number(part)?);
+        }
+        if result.len() > MAX_SEQUENCE_SET_SIZE {
+            return Err(too_large());
         }
     }
     
@@ -0,0 +1,79 @@
+// exchange/archive.rs
+// In-Place Archive mailbox, exposed to clients under the Archive/ folder namespace
+
+use std::collections::HashMap;
+use log::debug;
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::{BaseShape, FindFolder, FolderRef, Traversal};
+
+pub const ARCHIVE_NAMESPACE: &str = "Archive/";
+
+// Path of a folder inside the archive mailbox, None for primary mailbox folders
+pub fn archive_path(folder_name: &str) -> Option<&str> {
+    folder_name.strip_prefix(ARCHIVE_NAMESPACE).filter(|path| !path.is_empty())
+}
+
+// Root to search a folder under and the display name to look for
+pub fn search_root(folder_name: &str) -> (FolderRef, &str) {
+    match archive_path(folder_name) {
+        Some(path) => (
+            FolderRef::distinguished("archivemsgfolderroot"),
+            path.rsplit('/').next().unwrap_or(path),
+        ),
+        None => (FolderRef::distinguished("msgfolderroot"), folder_name),
+    }
+}
+
+impl ExchangeClient {
+    // Every archive folder as an Archive/ prefixed path, empty when the mailbox has no archive
+    pub async fn list_archive_folders(&self) -> Result<Vec<String>, ExchangeError> {
+        let response = match self.send_request(&FindFolder {
+            traversal: Traversal::Deep,
+            // ParentFolderId isn't part of the default folder shape
+            shape: BaseShape::AllProperties,
+            restriction: None,
+            parent: FolderRef::distinguished("archivemsgfolderroot"),
+        }).await {
+            Ok(response) => response,
+            Err(ExchangeError::ParseError(message)) if message.contains("ErrorFolderNotFound") => {
+                debug!("Mailbox has no online archive");
+                return Ok(Vec::new());
+            },
+            Err(e) => return Err(e),
+        };
+
+        // Deep traversal returns a flat list, rebuild paths from the parent ids
+        let folders: Vec<(String, String, String)> = response.descendants("Folder")
+            .into_iter()
+            .filter_map(|folder| {
+                let id = folder.child("FolderId")?.attr("Id")?.to_string();
+                let parent = folder.child("ParentFolderId").and_then(|parent| parent.attr("Id")).unwrap_or_default().to_string();
+                let name = folder.child_text("DisplayName")?.to_string();
+                Some((id, parent, name))
+            })
+            .collect();
+        let by_id: HashMap<&str, (&str, &str)> = folders.iter()
+            .map(|(id, parent, name)| (id.as_str(), (parent.as_str(), name.as_str())))
+            .collect();
+
+        let paths = folders.iter()
+            .map(|(id, _, _)| {
+                let mut segments = Vec::new();
+                let mut current = id.as_str();
+                // Stop at the archive root, which isn't part of the result, and guard against cycles
+                while let Some((parent, name)) = by_id.get(current) {
+                    if segments.len() > folders.len() {
+                        break;
+                    }
+                    segments.push(*name);
+                    current = parent;
+                }
+                segments.reverse();
+                format!("{}{}", ARCHIVE_NAMESPACE, segments.join("/"))
+            })
+            .collect();
+
+        Ok(paths)
+    }
+}
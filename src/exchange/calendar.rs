@@ -0,0 +1,90 @@
+// exchange/calendar.rs
+// Calendar queries for Exchange Web Services (EWS)
+
+use chrono::{DateTime, Utc};
+use log::debug;
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::{BaseShape, FindItem, FolderRef, ItemId, ItemView, Traversal};
+use super::response::{self, XmlElement};
+
+#[derive(Debug, Clone)]
+pub struct CalendarItem {
+    pub id: ItemId,
+    // iCalendar UID shared by every occurrence of a series
+    pub uid: Option<String>,
+    pub subject: String,
+    pub location: Option<String>,
+    pub organizer: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub is_all_day: bool,
+    // Single, Occurrence, Exception or RecurringMaster
+    pub item_type: String,
+    // Free, Tentative, Busy, OOF, WorkingElsewhere or NoData
+    pub free_busy_status: String,
+}
+
+const CALENDAR_PROPERTIES: [&str; 9] = [
+    "item:Subject",
+    "calendar:UID",
+    "calendar:Start",
+    "calendar:End",
+    "calendar:IsAllDayEvent",
+    "calendar:Location",
+    "calendar:Organizer",
+    "calendar:CalendarItemType",
+    "calendar:LegacyFreeBusyStatus",
+];
+
+fn parse_datetime(value: Option<&str>, field: &str) -> Result<DateTime<Utc>, ExchangeError> {
+    let value = value.ok_or_else(|| ExchangeError::ParseError(format!("Calendar item without {}", field)))?;
+    DateTime::parse_from_rfc3339(value)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| ExchangeError::ParseError(format!("Invalid {} '{}': {}", field, value, e)))
+}
+
+impl CalendarItem {
+    pub(crate) fn from_xml(element: &XmlElement) -> Result<Self, ExchangeError> {
+        Ok(CalendarItem {
+            id: response::item_id(element)?,
+            uid: element.child_text("UID").map(str::to_string),
+            subject: element.child_text("Subject").unwrap_or_default().to_string(),
+            location: element.child_text("Location").map(str::to_string),
+            organizer: element.child("Organizer")
+                .and_then(|organizer| organizer.descendants("EmailAddress").first().map(|e| e.text.clone())),
+            start: parse_datetime(element.child_text("Start"), "Start")?,
+            end: parse_datetime(element.child_text("End"), "End")?,
+            is_all_day: element.child_text("IsAllDayEvent") == Some("true"),
+            item_type: element.child_text("CalendarItemType").unwrap_or("Single").to_string(),
+            free_busy_status: element.child_text("LegacyFreeBusyStatus").unwrap_or("Busy").to_string(),
+        })
+    }
+}
+
+impl ExchangeClient {
+    // Calendar items overlapping [start, end), recurring series are expanded server-side
+    // into individual occurrences, which is what CalDAV time-range REPORTs and free/busy need
+    pub async fn find_calendar_items(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<CalendarItem>, ExchangeError> {
+        debug!("Finding calendar items between {} and {}", start, end);
+
+        if end <= start {
+            return Err(ExchangeError::ParseError("Calendar view end must be after start".to_string()));
+        }
+
+        let response = self.send_request(&FindItem {
+            traversal: Traversal::Shallow,
+            shape: BaseShape::IdOnly,
+            additional_properties: CALENDAR_PROPERTIES.to_vec(),
+            extended_properties: Vec::new(),
+            view: ItemView::Calendar { start, end, max_entries: None },
+            restriction: None,
+            parent: FolderRef::distinguished("calendar"),
+        }).await?;
+
+        response.descendants("CalendarItem")
+            .into_iter()
+            .map(CalendarItem::from_xml)
+            .collect()
+    }
+}
@@ -0,0 +1,185 @@
+// exchange/charset.rs
+// Transcoding of legacy Western charsets to UTF-8, for old clients and scripts that assume UTF-8.
+// Other charsets are left as they are, like signed and encrypted messages.
+
+use std::sync::LazyLock;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use regex::bytes::{Captures, Regex};
+
+use super::mime::{
+    decode_body, encode_base64, header, is_signed_or_encrypted, media_type, parameter, split_header, without_content_headers,
+};
+
+// RFC 2047 encoded-word: charset, encoding and text
+static ENCODED_WORDS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"=\?([^?\s*]+)(?:\*[^?\s]*)?\?([bBqQ])\?([^?\s]*)\?=").unwrap());
+
+// Windows-1252 characters at 0x80 to 0x9F, the C1 controls of ISO-8859-1
+const WINDOWS_1252: [u16; 32] = [
+    0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, 0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008D, 0x017D, 0x008F,
+    0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014, 0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178,
+];
+
+// Where ISO-8859-15 differs from ISO-8859-1
+const ISO_8859_15: [(u8, u16); 8] = [
+    (0xA4, 0x20AC), (0xA6, 0x0160), (0xA8, 0x0161), (0xB4, 0x017D), (0xB8, 0x017E), (0xBC, 0x0152), (0xBD, 0x0153), (0xBE, 0x0178),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Legacy {
+    Latin1,
+    Windows1252,
+    Latin9,
+}
+
+impl Legacy {
+    fn from_name(charset: &str) -> Option<Self> {
+        match charset.trim().to_lowercase().as_str() {
+            // Mail labelled Latin-1 is nearly always written with Windows-1252, which only adds printable characters
+            "iso-8859-1" | "iso_8859-1" | "iso8859-1" | "latin1" | "l1" => Some(Legacy::Latin1),
+            "windows-1252" | "cp1252" | "x-cp1252" => Some(Legacy::Windows1252),
+            "iso-8859-15" | "iso_8859-15" | "latin-9" | "latin9" => Some(Legacy::Latin9),
+            _ => None,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> String {
+        bytes.iter().map(|&byte| {
+            let code = match (self, byte) {
+                (Legacy::Latin1 | Legacy::Windows1252, 0x80..=0x9F) => WINDOWS_1252[(byte - 0x80) as usize],
+                (Legacy::Latin9, _) => ISO_8859_15.iter().find(|(from, _)| *from == byte).map_or(byte as u16, |(_, to)| *to),
+                _ => byte as u16,
+            };
+            char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+        }).collect()
+    }
+}
+
+// The message with headers and text parts in legacy charsets transcoded to UTF-8, None when there is
+// nothing to change
+pub fn to_utf8(content: &[u8]) -> Option<Vec<u8>> {
+    if is_signed_or_encrypted(content) {
+        return None;
+    }
+    normalize_entity(content, 0)
+}
+
+// Nested multiparts deeper than this are left alone
+const MAX_DEPTH: usize = 8;
+
+fn normalize_entity(entity: &[u8], depth: usize) -> Option<Vec<u8>> {
+    let (head, body) = split_header(entity)?;
+    let separator = &entity[head.len()..entity.len() - body.len()];
+    let new_head = normalize_header(head);
+    let content_type = header(entity, "Content-Type").unwrap_or_else(|| "text/plain".to_string());
+    let media_type = media_type(&content_type).to_lowercase();
+
+    if media_type.starts_with("text/") {
+        if let Some(legacy) = parameter(&content_type, "charset").as_deref().and_then(Legacy::from_name) {
+            let encoding = header(entity, "Content-Transfer-Encoding").unwrap_or_default().to_lowercase();
+            let text = legacy.decode(&decode_body(body, &encoding)?);
+            let mut converted = without_content_headers(new_head.as_deref().unwrap_or(head));
+            converted.extend_from_slice(format!("Content-Type: {}\r\n", with_utf8_charset(&content_type)).as_bytes());
+            if text.is_ascii() && encoding != "base64" && encoding != "quoted-printable" {
+                if !encoding.is_empty() {
+                    converted.extend_from_slice(format!("Content-Transfer-Encoding: {}\r\n", encoding).as_bytes());
+                }
+                converted.extend_from_slice(separator);
+                converted.extend_from_slice(text.as_bytes());
+            } else {
+                converted.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n");
+                converted.extend_from_slice(separator);
+                converted.extend_from_slice(&encode_base64(text.as_bytes()));
+            }
+            return Some(converted);
+        }
+    } else if media_type.starts_with("multipart/") && depth < MAX_DEPTH {
+        if let Some(boundary) = parameter(&content_type, "boundary") {
+            if let Some(new_body) = normalize_multipart(body, &boundary, depth) {
+                let mut converted = new_head.unwrap_or_else(|| head.to_vec());
+                converted.extend_from_slice(separator);
+                converted.extend_from_slice(&new_body);
+                return Some(converted);
+            }
+        }
+    }
+
+    let mut converted = new_head?;
+    converted.extend_from_slice(separator);
+    converted.extend_from_slice(body);
+    Some(converted)
+}
+
+// The body with its parts normalized, None when none of them changed. Preamble, delimiter lines and
+// epilogue are kept as they are.
+fn normalize_multipart(body: &[u8], boundary: &str, depth: usize) -> Option<Vec<u8>> {
+    let delimiter = format!("--{}", boundary);
+    let mut pieces: Vec<(usize, usize)> = Vec::new();
+    let mut offset = 0;
+    for line in body.split_inclusive(|&byte| byte == b'\n') {
+        if line.starts_with(delimiter.as_bytes()) {
+            pieces.push((offset, offset + line.len()));
+        }
+        offset += line.len();
+    }
+    if pieces.len() < 2 {
+        return None;
+    }
+
+    let mut converted = Vec::with_capacity(body.len());
+    let mut changed = false;
+    converted.extend_from_slice(&body[..pieces[0].1]);
+    for window in pieces.windows(2) {
+        let (part_start, part_end) = (window[0].1, window[1].0);
+        // The line break before a delimiter belongs to the delimiter
+        let part = &body[part_start..part_end];
+        let line_break = if part.ends_with(b"\r\n") { 2 } else if part.ends_with(b"\n") { 1 } else { 0 };
+        let (content, line_break) = part.split_at(part.len() - line_break);
+        match normalize_entity(content, depth + 1) {
+            Some(normalized) => {
+                changed = true;
+                converted.extend_from_slice(&normalized);
+                converted.extend_from_slice(if line_break.is_empty() { &b"\r\n"[..] } else { line_break });
+            },
+            None => converted.extend_from_slice(part),
+        }
+        converted.extend_from_slice(&body[window[1].0..window[1].1]);
+    }
+    converted.extend_from_slice(&body[pieces[pieces.len() - 1].1..]);
+    changed.then_some(converted)
+}
+
+// Header lines with encoded-words in legacy charsets encoded again as UTF-8, None when there are none
+fn normalize_header(head: &[u8]) -> Option<Vec<u8>> {
+    let mut changed = false;
+    let normalized = ENCODED_WORDS.replace_all(head, |word: &Captures| {
+        let legacy = std::str::from_utf8(&word[1]).ok().and_then(Legacy::from_name);
+        match legacy.and_then(|legacy| decode_word(&word[2], &word[3]).map(|bytes| legacy.decode(&bytes))) {
+            Some(text) => {
+                changed = true;
+                format!("=?UTF-8?B?{}?=", STANDARD.encode(text)).into_bytes()
+            },
+            None => word[0].to_vec(),
+        }
+    });
+    changed.then(|| normalized.into_owned())
+}
+
+fn decode_word(encoding: &[u8], text: &[u8]) -> Option<Vec<u8>> {
+    if encoding.eq_ignore_ascii_case(b"B") {
+        return STANDARD.decode(text).ok();
+    }
+    let text: Vec<u8> = text.iter().map(|&byte| if byte == b'_' { b' ' } else { byte }).collect();
+    decode_body(&text, "quoted-printable")
+}
+
+// Content-Type with its charset parameter replaced
+fn with_utf8_charset(content_type: &str) -> String {
+    content_type.split(';')
+        .map(|parameter| match parameter.split_once('=') {
+            Some((name, _)) if name.trim().eq_ignore_ascii_case("charset") => " charset=utf-8".to_string(),
+            _ => parameter.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
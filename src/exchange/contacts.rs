@@ -0,0 +1,267 @@
+// exchange/contacts.rs
+// Contact items for Exchange Web Services (EWS), backing CardDAV and LDAP contact search
+
+use log::debug;
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::{
+    BaseShape, CreateItem, DeleteItem, DeleteType, FieldPath, FieldUpdate, FindItem, FolderRef,
+    GetItem, ItemChange, ItemContent, ItemId, ItemView, Restriction, Traversal, UpdateItem, XmlWriter,
+};
+use super::response::{self, XmlElement};
+
+// Exchange stores at most three e-mail addresses per contact
+const EMAIL_KEYS: [&str; 3] = ["EmailAddress1", "EmailAddress2", "EmailAddress3"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Contact {
+    // None until the contact has been created on the server
+    pub id: Option<ItemId>,
+    pub display_name: String,
+    pub given_name: Option<String>,
+    pub surname: Option<String>,
+    pub company_name: Option<String>,
+    pub job_title: Option<String>,
+    pub email_addresses: Vec<String>,
+    pub business_phone: Option<String>,
+    pub mobile_phone: Option<String>,
+    pub home_phone: Option<String>,
+}
+
+impl Contact {
+    pub(crate) fn from_xml(element: &XmlElement) -> Result<Self, ExchangeError> {
+        let text = |name: &str| element.child_text(name).filter(|value| !value.is_empty()).map(str::to_string);
+
+        let entries = |container: &str| -> Vec<(String, String)> {
+            element.child(container)
+                .map(|container| container.children_named("Entry")
+                    .map(|entry| (entry.attr("Key").unwrap_or_default().to_string(), entry.text.clone()))
+                    .collect())
+                .unwrap_or_default()
+        };
+        let phones = entries("PhoneNumbers");
+        let phone = |key: &str| phones.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+
+        let mut emails = entries("EmailAddresses");
+        emails.sort();
+
+        Ok(Contact {
+            id: Some(response::item_id(element)?),
+            display_name: text("DisplayName").unwrap_or_default(),
+            given_name: text("GivenName"),
+            surname: text("Surname"),
+            company_name: text("CompanyName"),
+            job_title: text("JobTitle"),
+            email_addresses: emails.into_iter().map(|(_, value)| value).filter(|value| !value.is_empty()).collect(),
+            business_phone: phone("BusinessPhone"),
+            mobile_phone: phone("MobilePhone"),
+            home_phone: phone("HomePhone"),
+        })
+    }
+
+    fn phones(&self) -> [(&'static str, &Option<String>); 3] {
+        [
+            ("BusinessPhone", &self.business_phone),
+            ("MobilePhone", &self.mobile_phone),
+            ("HomePhone", &self.home_phone),
+        ]
+    }
+
+    // Field updates replacing every property of the server copy with this contact's values
+    fn updates(&self) -> Vec<FieldUpdate> {
+        let mut updates = Vec::new();
+
+        let simple_fields = [
+            ("contacts:DisplayName", "t:DisplayName", Some(&self.display_name)),
+            ("contacts:GivenName", "t:GivenName", self.given_name.as_ref()),
+            ("contacts:Surname", "t:Surname", self.surname.as_ref()),
+            ("contacts:CompanyName", "t:CompanyName", self.company_name.as_ref()),
+            ("contacts:JobTitle", "t:JobTitle", self.job_title.as_ref()),
+        ];
+        for (field_uri, element, value) in simple_fields {
+            let field = FieldPath::Field(field_uri);
+            updates.push(match value {
+                Some(value) => FieldUpdate::Set { field, item: contact_fragment(|w| { w.element(element, value); }) },
+                None => FieldUpdate::Delete { field },
+            });
+        }
+
+        for (index, key) in EMAIL_KEYS.iter().enumerate() {
+            let field = FieldPath::Indexed { field_uri: "contacts:EmailAddress", field_index: key };
+            updates.push(match self.email_addresses.get(index) {
+                Some(email) => FieldUpdate::Set {
+                    field,
+                    item: contact_fragment(|w| {
+                        w.open("t:EmailAddresses", &[]).open("t:Entry", &[("Key", key)]).text(email).close().close();
+                    }),
+                },
+                None => FieldUpdate::Delete { field },
+            });
+        }
+
+        for (key, value) in self.phones() {
+            let field = FieldPath::Indexed { field_uri: "contacts:PhoneNumber", field_index: key };
+            updates.push(match value {
+                Some(phone) => FieldUpdate::Set {
+                    field,
+                    item: contact_fragment(|w| {
+                        w.open("t:PhoneNumbers", &[]).open("t:Entry", &[("Key", key)]).text(phone).close().close();
+                    }),
+                },
+                None => FieldUpdate::Delete { field },
+            });
+        }
+
+        updates
+    }
+}
+
+// Serialize a partial t:Contact element for a SetItemField update
+fn contact_fragment(write: impl FnOnce(&mut XmlWriter)) -> String {
+    let mut w = XmlWriter::new();
+    w.open("t:Contact", &[]);
+    write(&mut w);
+    w.finish()
+}
+
+impl ItemContent for Contact {
+    fn write_item(&self, w: &mut XmlWriter) {
+        // Element order follows the ContactItemType schema sequence
+        w.open("t:Contact", &[]).element("t:DisplayName", &self.display_name);
+        if let Some(given_name) = &self.given_name {
+            w.element("t:GivenName", given_name);
+        }
+        if let Some(company_name) = &self.company_name {
+            w.element("t:CompanyName", company_name);
+        }
+        if !self.email_addresses.is_empty() {
+            w.open("t:EmailAddresses", &[]);
+            for (key, email) in EMAIL_KEYS.iter().zip(&self.email_addresses) {
+                w.open("t:Entry", &[("Key", key)]).text(email).close();
+            }
+            w.close();
+        }
+        if self.phones().iter().any(|(_, value)| value.is_some()) {
+            w.open("t:PhoneNumbers", &[]);
+            for (key, value) in self.phones() {
+                if let Some(phone) = value {
+                    w.open("t:Entry", &[("Key", key)]).text(phone).close();
+                }
+            }
+            w.close();
+        }
+        if let Some(job_title) = &self.job_title {
+            w.element("t:JobTitle", job_title);
+        }
+        if let Some(surname) = &self.surname {
+            w.element("t:Surname", surname);
+        }
+        w.close();
+    }
+}
+
+impl ExchangeClient {
+    // Contacts in the default Contacts folder, optionally filtered by a name/company substring
+    pub async fn find_contacts(&self, query: Option<&str>) -> Result<Vec<Contact>, ExchangeError> {
+        debug!("Finding contacts matching {:?}", query);
+
+        let restriction = query.filter(|query| !query.is_empty()).map(|query| {
+            Restriction::Or(
+                ["contacts:DisplayName", "contacts:GivenName", "contacts:Surname", "contacts:CompanyName"]
+                    .iter()
+                    .map(|field_uri| Restriction::Contains {
+                        field: FieldPath::Field(field_uri),
+                        value: query.to_string(),
+                    })
+                    .collect(),
+            )
+        });
+
+        let response = self.send_request(&FindItem {
+            traversal: Traversal::Shallow,
+            shape: BaseShape::IdOnly,
+            additional_properties: Vec::new(),
+            extended_properties: Vec::new(),
+            view: ItemView::Indexed { max_entries: 1000, offset: 0 },
+            restriction,
+            parent: FolderRef::distinguished("contacts"),
+        }).await?;
+
+        // E-mail addresses and phone numbers are only returned by GetItem
+        let ids = response.descendants("Contact")
+            .into_iter()
+            .map(response::item_id)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.get_contacts(ids).await
+    }
+
+    pub async fn get_contact(&self, id: &ItemId) -> Result<Contact, ExchangeError> {
+        self.get_contacts(vec![id.clone()]).await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ExchangeError::ParseError(format!("Contact not found: {}", id.id)))
+    }
+
+    async fn get_contacts(&self, ids: Vec<ItemId>) -> Result<Vec<Contact>, ExchangeError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let response = self.send_request(&GetItem {
+            shape: BaseShape::AllProperties,
+            additional_properties: Vec::new(),
+            item_ids: ids,
+        }).await?;
+
+        response.descendants("Contact")
+            .into_iter()
+            .map(Contact::from_xml)
+            .collect()
+    }
+
+    // Create the contact in the default Contacts folder and return its new id
+    pub async fn create_contact(&self, contact: &Contact) -> Result<ItemId, ExchangeError> {
+        debug!("Creating contact '{}'", contact.display_name);
+
+        let response = self.send_request(&CreateItem {
+            saved_folder: Some(FolderRef::distinguished("contacts")),
+            message_disposition: None,
+            items: vec![contact],
+        }).await?;
+
+        response.descendants("Contact")
+            .first()
+            .map(|element| response::item_id(element))
+            .unwrap_or_else(|| Err(ExchangeError::ParseError("CreateItem returned no contact".to_string())))
+    }
+
+    // Replace the server copy with this contact, returns the id with its new change key
+    pub async fn update_contact(&self, contact: &Contact) -> Result<ItemId, ExchangeError> {
+        let id = contact.id.clone()
+            .ok_or_else(|| ExchangeError::ParseError("Cannot update a contact without ItemId".to_string()))?;
+        debug!("Updating contact '{}'", contact.display_name);
+
+        let response = self.send_request(&UpdateItem {
+            conflict_resolution: "AutoResolve",
+            message_disposition: None,
+            changes: vec![ItemChange { item_id: id, updates: contact.updates() }],
+        }).await?;
+
+        response.descendants("Contact")
+            .first()
+            .map(|element| response::item_id(element))
+            .unwrap_or_else(|| Err(ExchangeError::ParseError("UpdateItem returned no contact".to_string())))
+    }
+
+    pub async fn delete_contact(&self, id: &ItemId) -> Result<(), ExchangeError> {
+        debug!("Deleting contact {}", id.id);
+
+        self.send_request(&DeleteItem {
+            delete_type: DeleteType::MoveToDeletedItems,
+            affected_task_occurrences: None,
+            item_ids: vec![id.clone()],
+        }).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+// exchange/flags.rs
+// IMAP \Answered kept in the MAPI properties behind Outlook's replied icon, both directions
+
+use chrono::Utc;
+use log::debug;
+
+use super::{parse_sequence_set, response, sync, ExchangeClient, ExchangeError};
+use super::request::{
+    format_datetime, BaseShape, FieldPath, FieldUpdate, FindItem, ItemChange, ItemId, ItemView, Traversal,
+    UpdateItem, XmlWriter,
+};
+use super::response::XmlElement;
+
+// PidTagLastVerbExecuted, the last reply or forward sent for the message
+const LAST_VERB_EXECUTED: (&str, &str) = ("0x1081", "Integer");
+// PidTagLastVerbExecutionTime, Outlook's "You replied on ..."
+const LAST_VERB_EXECUTION_TIME: (&str, &str) = ("0x1082", "SystemTime");
+// PidTagIconIndex, Outlook draws the list icon from it rather than from the verb
+const ICON_INDEX: (&str, &str) = ("0x1080", "Integer");
+
+// Requested along with the items whose FLAGS are served
+pub const ANSWERED_PROPERTY: FieldPath = extended(LAST_VERB_EXECUTED);
+
+// EXCHIVERB_REPLYTOSENDER and EXCHIVERB_REPLYTOALL, a forward (104) isn't an answer
+const VERB_REPLY_TO_SENDER: i64 = 102;
+const VERB_REPLY_TO_ALL: i64 = 103;
+const ICON_REPLIED: i64 = 261;
+
+// Messages FETCH numbers without a metadata cache, see fetch_messages
+const FIND_ITEM_LIMIT: u32 = 100;
+
+const fn extended((property_tag, property_type): (&'static str, &'static str)) -> FieldPath {
+    FieldPath::Extended { property_tag, property_type }
+}
+
+// Whether the item's last verb is a reply, from an item requested with ANSWERED_PROPERTY
+pub fn is_answered(item: &XmlElement) -> bool {
+    item.children_named("ExtendedProperty")
+        .filter(|property| property.child("ExtendedFieldURI")
+            .and_then(|uri| uri.attr("PropertyTag"))
+            .is_some_and(|tag| property_tag_eq(tag, LAST_VERB_EXECUTED.0)))
+        .filter_map(|property| property.child_text("Value").and_then(|value| value.trim().parse::<i64>().ok()))
+        .any(|verb| verb == VERB_REPLY_TO_SENDER || verb == VERB_REPLY_TO_ALL)
+}
+
+// Exchange may answer with another case or without the 0x prefix
+fn property_tag_eq(a: &str, b: &str) -> bool {
+    let number = |tag: &str| u32::from_str_radix(tag.trim().trim_start_matches("0x").trim_start_matches("0X"), 16).ok();
+    number(a).is_some_and(|a| Some(a) == number(b))
+}
+
+fn set_property(property: (&'static str, &'static str), value: &str) -> FieldUpdate {
+    let mut w = XmlWriter::new();
+    w.open("t:Message", &[])
+        .open("t:ExtendedProperty", &[])
+        .empty("t:ExtendedFieldURI", &[("PropertyTag", property.0), ("PropertyType", property.1)])
+        .element("t:Value", value);
+    FieldUpdate::Set { field: extended(property), item: w.finish() }
+}
+
+// What Outlook itself sets after a reply, or removes again
+fn answered_updates(answered: bool) -> Vec<FieldUpdate> {
+    if answered {
+        vec![
+            set_property(LAST_VERB_EXECUTED, &VERB_REPLY_TO_SENDER.to_string()),
+            set_property(LAST_VERB_EXECUTION_TIME, &format_datetime(&Utc::now())),
+            set_property(ICON_INDEX, &ICON_REPLIED.to_string()),
+        ]
+    } else {
+        [LAST_VERB_EXECUTED, LAST_VERB_EXECUTION_TIME, ICON_INDEX].into_iter()
+            .map(|property| FieldUpdate::Delete { field: extended(property) })
+            .collect()
+    }
+}
+
+impl ExchangeClient {
+    // STORE +FLAGS (\Answered) / -FLAGS (\Answered) on the messages of the sequence set
+    pub async fn set_answered(&self, folder: &str, sequence_set: &str, answered: bool) -> Result<(), ExchangeError> {
+        let sequences = parse_sequence_set(sequence_set)?;
+        let item_ids = self.item_ids(folder, &sequences).await?;
+        if item_ids.is_empty() {
+            return Ok(());
+        }
+        debug!("{} \\Answered on {} item(s) in '{}'", if answered { "Setting" } else { "Clearing" }, item_ids.len(), folder);
+
+        self.send_request(&UpdateItem {
+            conflict_resolution: "AutoResolve",
+            message_disposition: Some("SaveOnly"),
+            changes: item_ids.into_iter()
+                .map(|(_, item_id)| ItemChange { item_id, updates: answered_updates(answered) })
+                .collect(),
+        }).await?;
+
+        // The cached flags come back with the Update changes of the next sync
+        if let Some(metadata) = &self.metadata {
+            metadata.expire(folder);
+        }
+        Ok(())
+    }
+
+    // Items at the sequence numbers, numbers past the end are skipped. No change keys, a flag
+    // change or a move shouldn't fail because the item changed since the last sync.
+    pub(super) async fn item_ids(&self, folder: &str, sequences: &[u32]) -> Result<Vec<(u32, ItemId)>, ExchangeError> {
+        if let Some((_, messages)) = self.cached_messages(folder).await? {
+            return Ok(sync::select_messages(&messages, sequences).into_iter()
+                .map(|(seq, message)| (seq, ItemId::new(&message.metadata.item_id)))
+                .collect());
+        }
+
+        let parent = self.resolve_folder(folder).await?;
+        let response = self.send_request(&FindItem {
+            traversal: Traversal::Shallow,
+            shape: BaseShape::IdOnly,
+            additional_properties: Vec::new(),
+            extended_properties: Vec::new(),
+            view: ItemView::Indexed { max_entries: FIND_ITEM_LIMIT, offset: 0 },
+            restriction: None,
+            parent,
+        }).await?;
+        let found_items: Vec<&XmlElement> = response.descendants("Items")
+            .into_iter()
+            .flat_map(|items| items.children.iter())
+            .collect();
+        sequences.iter()
+            .filter_map(|&seq| seq.checked_sub(1).and_then(|index| found_items.get(index as usize)).map(|item| (seq, item)))
+            .map(|(seq, item)| response::item_id(item).map(|id| (seq, ItemId::new(&id.id))))
+            .collect()
+    }
+}
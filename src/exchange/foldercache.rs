@@ -0,0 +1,73 @@
+// exchange/foldercache.rs
+// Folder lists and folder counts kept for a few seconds and shared by a user's sessions: clients send
+// LIST and a STATUS for every folder on each refresh, each an EWS round trip without it
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use config::Config;
+
+use super::FolderStats;
+
+// Long enough to cover one refresh of a client, short enough for new mail to show on the next
+pub const DEFAULT_FOLDER_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// Folder names of a LIST and when they were listed
+type CachedList = (Instant, Vec<String>);
+
+pub struct FolderCache {
+    ttl: Duration,
+    // By LIST reference and pattern
+    lists: Mutex<HashMap<(String, String), CachedList>>,
+    stats: Mutex<HashMap<String, (Instant, FolderStats)>>,
+}
+
+impl FolderCache {
+    pub fn new(ttl: Duration) -> Self {
+        FolderCache { ttl, lists: Mutex::new(HashMap::new()), stats: Mutex::new(HashMap::new()) }
+    }
+
+    // davmail.folderCacheTtl in seconds, None when set to 0
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let ttl = config.get_int("davmail.folderCacheTtl")
+            .map_or(DEFAULT_FOLDER_CACHE_TTL, |seconds| Duration::from_secs(seconds.max(0) as u64));
+        (!ttl.is_zero()).then(|| FolderCache::new(ttl))
+    }
+
+    pub fn list(&self, reference: &str, pattern: &str) -> Option<Vec<String>> {
+        let lists = self.lists.lock().unwrap();
+        lists.get(&(reference.to_string(), pattern.to_string()))
+            .filter(|(cached, _)| cached.elapsed() < self.ttl)
+            .map(|(_, folders)| folders.clone())
+    }
+
+    pub fn insert_list(&self, reference: &str, pattern: &str, folders: &[String]) {
+        let mut lists = self.lists.lock().unwrap();
+        lists.retain(|_, (cached, _)| cached.elapsed() < self.ttl);
+        lists.insert((reference.to_string(), pattern.to_string()), (Instant::now(), folders.to_vec()));
+    }
+
+    pub fn stats(&self, folder: &str) -> Option<FolderStats> {
+        let stats = self.stats.lock().unwrap();
+        stats.get(folder)
+            .filter(|(cached, _)| cached.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    pub fn insert_stats(&self, folder: &str, stats: &FolderStats) {
+        let mut cached_stats = self.stats.lock().unwrap();
+        cached_stats.retain(|_, (cached, _)| cached.elapsed() < self.ttl);
+        cached_stats.insert(folder.to_string(), (Instant::now(), stats.clone()));
+    }
+
+    // The folder's messages changed, its counts are looked up again
+    pub fn expire(&self, folder: &str) {
+        self.stats.lock().unwrap().remove(folder);
+    }
+
+    // Folders were added, removed or renamed, or changed in ways that can't be narrowed down
+    pub fn clear(&self) {
+        self.lists.lock().unwrap().clear();
+        self.stats.lock().unwrap().clear();
+    }
+}
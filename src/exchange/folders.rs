@@ -0,0 +1,87 @@
+// exchange/folders.rs
+// Whole-folder operations for Exchange Web Services (EWS)
+
+use log::debug;
+
+use super::{archive, ExchangeClient, ExchangeError};
+use super::request::{
+    self, BaseShape, DeleteType, EmptyFolder, FieldPath, FindFolder, FolderRef, GetFolder,
+    MarkAllItemsAsRead, Restriction, Traversal,
+};
+
+impl ExchangeClient {
+    // Resolve an IMAP mailbox name to a folder reference usable in EWS requests
+    pub async fn resolve_folder(&self, folder_name: &str) -> Result<FolderRef, ExchangeError> {
+        if let Some(distinguished) = request::distinguished_folder(folder_name) {
+            return Ok(FolderRef::distinguished(distinguished));
+        }
+
+        let (root, display_name) = archive::search_root(folder_name);
+        let response = self.send_request(&FindFolder {
+            traversal: Traversal::Deep,
+            shape: BaseShape::IdOnly,
+            restriction: Some(Restriction::IsEqualTo {
+                field: FieldPath::Field("folder:DisplayName"),
+                value: display_name.to_string(),
+            }),
+            parent: root,
+        }).await?;
+
+        response.descendants("FolderId")
+            .first()
+            .and_then(|folder_id| folder_id.attr("Id"))
+            .map(|id| FolderRef::Id(id.to_string()))
+            .ok_or_else(|| ExchangeError::ParseError(format!("Folder not found: {}", folder_name)))
+    }
+
+    // Set or clear the read flag of every item in the folder with a single request
+    pub async fn mark_all_read(&self, folder_name: &str, read: bool) -> Result<(), ExchangeError> {
+        debug!("Marking all items in '{}' as {}", folder_name, if read { "read" } else { "unread" });
+
+        let result = async {
+            let folder = self.resolve_folder(folder_name).await?;
+            self.send_mark_all_read(folder, read).await
+        }.await;
+        self.folder_changed(folder_name);
+        // Kept in the metadata cache while offline and sent with the next sync of the folder
+        result.or_else(|e| self.offline(e, |metadata| metadata.mark_all_read(folder_name, read)))
+    }
+
+    pub(crate) async fn send_mark_all_read(&self, folder: FolderRef, read: bool) -> Result<(), ExchangeError> {
+        self.send_request(&MarkAllItemsAsRead {
+            read,
+            suppress_read_receipts: self.read_receipts.suppress_on_exchange(),
+            folders: vec![folder],
+        }).await?;
+
+        Ok(())
+    }
+
+    // Permanently delete every item in the folder, sub folders are kept
+    pub async fn empty_folder(&self, folder_name: &str) -> Result<(), ExchangeError> {
+        debug!("Emptying folder '{}'", folder_name);
+
+        let folder = self.resolve_folder(folder_name).await?;
+        self.send_request(&EmptyFolder {
+            delete_type: DeleteType::HardDelete,
+            delete_sub_folders: false,
+            folders: vec![folder],
+        }).await?;
+        self.folder_changed(folder_name);
+
+        Ok(())
+    }
+
+    // Cheapest authenticated round trip, keeps the session cookies and backend affinity alive while idle
+    pub async fn keepalive(&self) -> Result<(), ExchangeError> {
+        debug!("Sending EWS keepalive");
+
+        self.send_request(&GetFolder {
+            shape: BaseShape::IdOnly,
+            additional_properties: Vec::new(),
+            folders: vec![FolderRef::distinguished("inbox")],
+        }).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+// exchange/html.rs
+// Plain text alternative for messages that only have an HTML body, and removal of tracking images,
+// for console clients that show HTML poorly. Signed and encrypted messages are never changed.
+
+use std::sync::LazyLock;
+use config::Config;
+use regex::bytes::{Captures, Regex};
+
+use super::mime::{
+    decode_body, encode_base64, header, is_signed_or_encrypted, media_type, parameter, split_header, without_content_headers,
+};
+
+static HIDDEN_BLOCKS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<head\b.*?</head\s*>|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<title\b.*?</title\s*>").unwrap());
+static COMMENTS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<!--.*?-->").unwrap());
+static LINKS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?is)<a\b[^>]*?\bhref\s*=\s*["']([^"']*)["'][^>]*>(.*?)</a\s*>"#).unwrap());
+static LINE_BREAKS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<br\b[^>]*>").unwrap());
+static BLOCK_ENDS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)</?(p|div|tr|table|h[1-6]|ul|ol|blockquote|pre)\b[^>]*>").unwrap());
+static LIST_ITEMS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<li\b[^>]*>").unwrap());
+static CELLS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)</t[dh]\s*>").unwrap());
+static TAGS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<[^>]*>").unwrap());
+static ENTITIES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"&(#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[a-zA-Z]{2,6});").unwrap());
+static SPACES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[ \t\r\x0b\x0c]+").unwrap());
+static BLANK_LINES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n(?: ?\n){2,}").unwrap());
+static IMAGES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<img\b[^>]*>").unwrap());
+static TINY_SIZE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?i)\b(width|height)\s*[=:]\s*["']?\s*[01](px)?\b"#).unwrap());
+static HIDDEN_STYLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)display\s*:\s*none|visibility\s*:\s*hidden").unwrap());
+static REMOTE_SOURCE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?i)\bsrc\s*=\s*["']?\s*(https?:)?//"#).unwrap());
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HtmlConversion {
+    // Add a text/plain alternative to messages with only an HTML body
+    pub add_plain_text: bool,
+    // Drop remote images that are invisible or a pixel in size, the usual read trackers
+    pub strip_trackers: bool,
+}
+
+impl HtmlConversion {
+    // davmail.htmlToText and davmail.stripTrackers, per user like the other settings
+    pub fn from_config(config: &Config) -> Self {
+        HtmlConversion {
+            add_plain_text: config.get_bool("davmail.htmlToText").unwrap_or(false),
+            strip_trackers: config.get_bool("davmail.stripTrackers").unwrap_or(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.add_plain_text || self.strip_trackers
+    }
+
+    // The converted message, None when there is nothing to change. Only single part text/html
+    // messages are converted, messages with a plain text part already are left to the client.
+    pub fn convert(&self, content: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_enabled() || is_signed_or_encrypted(content) {
+            return None;
+        }
+        let content_type = header(content, "Content-Type")?;
+        if !media_type(&content_type).eq_ignore_ascii_case("text/html") {
+            return None;
+        }
+        let (head, body) = split_header(content)?;
+        let encoding = header(content, "Content-Transfer-Encoding").unwrap_or_default().to_lowercase();
+        let html = decode_body(body, &encoding)?;
+
+        let cleaned = if self.strip_trackers { strip_trackers(&html) } else { None };
+        if !self.add_plain_text {
+            let cleaned = cleaned?;
+            let mut converted = without_content_headers(head);
+            converted.extend_from_slice(format!("Content-Type: {}\r\nContent-Transfer-Encoding: base64\r\n\r\n", content_type).as_bytes());
+            converted.extend_from_slice(&encode_base64(&cleaned));
+            return Some(converted);
+        }
+
+        let charset = parameter(&content_type, "charset").unwrap_or_else(|| "us-ascii".to_string());
+        let text = html_to_text(cleaned.as_deref().unwrap_or(&html), charset.eq_ignore_ascii_case("utf-8"));
+        let boundary = format!("=_davmail_alt_{:016x}", fingerprint(content));
+
+        let mut converted = without_content_headers(head);
+        converted.extend_from_slice(format!(
+            "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n--{}\r\nContent-Type: text/plain; charset=\"{}\"\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+            boundary, boundary, charset).as_bytes());
+        converted.extend_from_slice(&encode_base64(&text));
+        converted.extend_from_slice(format!("--{}\r\nContent-Type: {}\r\n", boundary, content_type).as_bytes());
+        match cleaned {
+            Some(cleaned) => {
+                converted.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n\r\n");
+                converted.extend_from_slice(&encode_base64(&cleaned));
+            },
+            None => {
+                if !encoding.is_empty() {
+                    converted.extend_from_slice(format!("Content-Transfer-Encoding: {}\r\n", encoding).as_bytes());
+                }
+                converted.extend_from_slice(b"\r\n");
+                converted.extend_from_slice(body);
+                if !body.ends_with(b"\n") {
+                    converted.extend_from_slice(b"\r\n");
+                }
+            },
+        }
+        converted.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        Some(converted)
+    }
+}
+
+// Stable per message, so the boundary is the same each time the message is converted
+fn fingerprint(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// The HTML without tracking images, None when it has none
+fn strip_trackers(html: &[u8]) -> Option<Vec<u8>> {
+    let mut found = false;
+    let stripped = IMAGES.replace_all(html, |image: &Captures| {
+        let tag = &image[0];
+        if REMOTE_SOURCE.is_match(tag) && (TINY_SIZE.find_iter(tag).count() >= 2 || HIDDEN_STYLE.is_match(tag)) {
+            found = true;
+            Vec::new()
+        } else {
+            tag.to_vec()
+        }
+    });
+    found.then(|| stripped.into_owned())
+}
+
+// Stand in for the angle brackets around link targets until the tags are gone
+const TARGET_START: u8 = 0x01;
+const TARGET_END: u8 = 0x02;
+
+// Readable text of the HTML, in the charset of the HTML. Tags are ASCII, so this works on the bytes
+// of any ASCII compatible charset without decoding it.
+fn html_to_text(html: &[u8], utf8: bool) -> Vec<u8> {
+    let html = html.iter().copied().filter(|&byte| byte != TARGET_START && byte != TARGET_END).collect::<Vec<u8>>();
+    let text = HIDDEN_BLOCKS.replace_all(&html, &b""[..]);
+    let text = COMMENTS.replace_all(&text, &b""[..]);
+    let text = SPACES.replace_all(&text, &b" "[..]);
+    let text = text.iter().map(|&byte| if byte == b'\n' { b' ' } else { byte }).collect::<Vec<u8>>();
+    let text = LINKS.replace_all(&text, |link: &Captures| {
+        let (target, label) = (&link[1], TAGS.replace_all(&link[2], &b""[..]).into_owned());
+        if target.starts_with(b"mailto:") || label.trim_ascii().is_empty() || label.trim_ascii() == target {
+            label
+        } else {
+            [&label[..], &[b' ', TARGET_START][..], target, &[TARGET_END][..]].concat()
+        }
+    });
+    let text = LINE_BREAKS.replace_all(&text, &b"\n"[..]);
+    let text = BLOCK_ENDS.replace_all(&text, &b"\n\n"[..]);
+    let text = LIST_ITEMS.replace_all(&text, &b"\n* "[..]);
+    let text = CELLS.replace_all(&text, &b" "[..]);
+    let text = TAGS.replace_all(&text, &b""[..]);
+    let text = text.iter().map(|&byte| match byte {
+        TARGET_START => b'<',
+        TARGET_END => b'>',
+        _ => byte,
+    }).collect::<Vec<u8>>();
+    let text = ENTITIES.replace_all(&text, |entity: &Captures| decode_entity(&entity[1], utf8).unwrap_or_else(|| entity[0].to_vec()));
+
+    let lines: Vec<&[u8]> = text.split(|&byte| byte == b'\n').map(|line| line.trim_ascii()).collect();
+    let text = lines.join(&b"\n"[..]);
+    let text = BLANK_LINES.replace_all(&text, &b"\n\n"[..]);
+    let mut text = text.trim_ascii().split(|&byte| byte == b'\n').collect::<Vec<_>>().join(&b"\r\n"[..]);
+    text.extend_from_slice(b"\r\n");
+    text
+}
+
+// Named entities of the ASCII range, and numeric ones when the text is UTF-8. Others stay as they are.
+fn decode_entity(entity: &[u8], utf8: bool) -> Option<Vec<u8>> {
+    let entity = std::str::from_utf8(entity).ok()?;
+    let character = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let number = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))?
+                .ok()?;
+            char::from_u32(number).filter(|character| utf8 || character.is_ascii())?
+        },
+    };
+    Some(character.to_string().into_bytes())
+}
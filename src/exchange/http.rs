@@ -0,0 +1,381 @@
+// exchange/http.rs
+// HTTP client construction for Exchange Web Services (EWS)
+
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+use reqwest::{Client, Identity, Proxy};
+use config::Config;
+use log::{debug, warn};
+
+use super::ExchangeError;
+use super::proxyauth::{self, ProxyAuthScheme};
+
+// Which certificate roots the outbound TLS connections trust
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsTrust {
+    // Platform TLS library (SChannel, Secure Transport, OpenSSL) with its default roots
+    NativeTls,
+    // rustls verifying against the operating system certificate store, so enterprise
+    // CAs deployed through group policy are trusted without exporting PEM files
+    SystemStore,
+}
+
+impl TlsTrust {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "native" | "native-tls" => Some(TlsTrust::NativeTls),
+            "system" | "os" | "rustls-native-certs" => Some(TlsTrust::SystemStore),
+            _ => None,
+        }
+    }
+}
+
+// Kind of outbound proxy in front of Exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyType {
+    Http,
+    Socks5,
+}
+
+impl ProxyType {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "http" | "https" => Some(ProxyType::Http),
+            "socks" | "socks5" => Some(ProxyType::Socks5),
+            _ => None,
+        }
+    }
+}
+
+// Explicit outbound proxy configured through davmail.proxy.*
+#[derive(Clone)]
+pub struct ProxyConfig {
+    pub proxy_type: ProxyType,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub auth_scheme: ProxyAuthScheme,
+}
+
+impl ProxyConfig {
+    fn url(&self) -> String {
+        match self.proxy_type {
+            ProxyType::Http => format!("http://{}:{}", self.host, self.port),
+            // socks5h resolves host names on the proxy, corporate DNS often can't see Office 365
+            ProxyType::Socks5 => format!("socks5h://{}:{}", self.host, self.port),
+        }
+    }
+
+    fn to_proxy(&self) -> Result<Proxy, ExchangeError> {
+        // NTLM and Negotiate need a handshake, run by a local relay in front of the proxy
+        if self.proxy_type == ProxyType::Http && self.auth_scheme != ProxyAuthScheme::Basic {
+            let relay = proxyauth::relay_address(self)
+                .map_err(|e| ExchangeError::ConfigError(format!("Cannot start proxy authentication relay: {}", e)))?;
+            return Ok(Proxy::all(format!("http://{}", relay))?);
+        }
+
+        let proxy = Proxy::all(self.url())?;
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Ok(proxy.basic_auth(username, password)),
+            (Some(username), None) => Ok(proxy.basic_auth(username, "")),
+            _ => Ok(proxy),
+        }
+    }
+}
+
+// Don't print the proxy password in debug output
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("proxy_type", &self.proxy_type)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "[REDACTED]"))
+            .field("auth_scheme", &self.auth_scheme)
+            .finish()
+    }
+}
+
+// Certificate presented for mutual TLS in front of Exchange (davmail.ssl.clientCertificate*)
+#[derive(Clone)]
+pub enum ClientCertificate {
+    Pkcs12 { path: String, password: String },
+    // Certificate chain and PKCS#8 private key in separate PEM files
+    Pem { certificate_path: String, key_path: String },
+}
+
+impl ClientCertificate {
+    fn identity(&self, tls_trust: TlsTrust) -> Result<Identity, ExchangeError> {
+        let read = |path: &str| fs::read(path)
+            .map_err(|e| ExchangeError::ConfigError(format!("Cannot read client certificate file {}: {}", path, e)));
+
+        let identity = match (self, tls_trust) {
+            (ClientCertificate::Pkcs12 { path, password }, TlsTrust::NativeTls) => {
+                Identity::from_pkcs12_der(&read(path)?, password)
+            },
+            (ClientCertificate::Pkcs12 { .. }, TlsTrust::SystemStore) => {
+                return Err(ExchangeError::ConfigError(
+                    "PKCS#12 client certificates need the native trust store, use PEM files with davmail.ssl.trustStore=system".to_string()
+                ));
+            },
+            (ClientCertificate::Pem { certificate_path, key_path }, TlsTrust::NativeTls) => {
+                Identity::from_pkcs8_pem(&read(certificate_path)?, &read(key_path)?)
+            },
+            (ClientCertificate::Pem { certificate_path, key_path }, TlsTrust::SystemStore) => {
+                // rustls reads certificate and key from a single PEM bundle
+                let mut pem = read(certificate_path)?;
+                pem.push(b'\n');
+                pem.extend_from_slice(&read(key_path)?);
+                Identity::from_pem(&pem)
+            },
+        };
+
+        identity.map_err(|e| ExchangeError::ConfigError(format!("Invalid client certificate: {}", e)))
+    }
+}
+
+// Don't print the certificate password in debug output
+impl fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientCertificate::Pkcs12 { path, .. } => f.debug_struct("Pkcs12")
+                .field("path", path)
+                .field("password", &"[REDACTED]")
+                .finish(),
+            ClientCertificate::Pem { certificate_path, key_path } => f.debug_struct("Pem")
+                .field("certificate_path", certificate_path)
+                .field("key_path", key_path)
+                .finish(),
+        }
+    }
+}
+
+// Deadline of a whole EWS request, response body included. Folder lookups answer a client waiting on
+// LIST or SELECT and should fail fast, message downloads, uploads and sync pages can take minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTimeouts {
+    pub interactive: Duration,
+    pub bulk: Duration,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        RequestTimeouts {
+            interactive: Duration::from_secs(30),
+            bulk: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RequestTimeouts {
+    // davmail.http.timeout for interactive requests and davmail.http.bulkTimeout, in seconds
+    pub fn from_config(config: &Config) -> Self {
+        let mut timeouts = RequestTimeouts::default();
+        if let Ok(timeout) = config.get_int("davmail.http.timeout") {
+            timeouts.interactive = Duration::from_secs(timeout.max(1) as u64);
+        }
+        if let Ok(timeout) = config.get_int("davmail.http.bulkTimeout") {
+            timeouts.bulk = Duration::from_secs(timeout.max(1) as u64);
+        }
+        // A bulk request is never given less time than an interactive one
+        timeouts.bulk = timeouts.bulk.max(timeouts.interactive);
+        timeouts
+    }
+
+    pub fn get(&self, bulk: bool) -> Duration {
+        if bulk { self.bulk } else { self.interactive }
+    }
+}
+
+// Settings used to build the HTTP client talking to Exchange
+#[derive(Debug, Clone)]
+pub struct HttpClientConfig {
+    // Requests sent without a deadline of their own get the bulk one
+    pub timeouts: RequestTimeouts,
+    // TCP and TLS connection setup
+    pub connect_timeout: Duration,
+    // Longest wait for the next bytes of a response, None to rely on the request deadline alone
+    pub read_timeout: Option<Duration>,
+    // Keepalive probes on idle pooled connections, so firewalls don't drop them silently
+    pub tcp_keepalive: Option<Duration>,
+    pub tls_trust: TlsTrust,
+    pub proxy: Option<ProxyConfig>,
+    // Honour HTTP_PROXY / HTTPS_PROXY / NO_PROXY when no explicit proxy is configured
+    pub use_env_proxy: bool,
+    // Negotiate HTTP/2 through ALPN when the server offers it
+    pub http2: bool,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    // Advertise Accept-Encoding: gzip and decompress responses transparently
+    pub gzip: bool,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            timeouts: RequestTimeouts::default(),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Some(Duration::from_secs(60)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            tls_trust: TlsTrust::NativeTls,
+            proxy: None,
+            use_env_proxy: true,
+            http2: true,
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+            gzip: true,
+            client_certificate: None,
+        }
+    }
+}
+
+impl HttpClientConfig {
+    pub fn from_config(config: &Config) -> Self {
+        let mut http_config = HttpClientConfig::default();
+
+        // davmail.ssl.trustStore=system trusts the OS certificate store through rustls
+        if let Ok(value) = config.get_string("davmail.ssl.trustStore") {
+            match TlsTrust::parse(&value) {
+                Some(tls_trust) => http_config.tls_trust = tls_trust,
+                None => warn!("Unknown davmail.ssl.trustStore value '{}', using native-tls", value),
+            }
+        }
+
+        // davmail.proxy.host enables an explicit proxy, the other keys refine it
+        if let Ok(host) = config.get_string("davmail.proxy.host") {
+            if !host.is_empty() {
+                let proxy_type = config.get_string("davmail.proxy.type").ok()
+                    .and_then(|value| {
+                        let proxy_type = ProxyType::parse(&value);
+                        if proxy_type.is_none() {
+                            warn!("Unknown davmail.proxy.type value '{}', using http", value);
+                        }
+                        proxy_type
+                    })
+                    .unwrap_or(ProxyType::Http);
+                let default_port = match proxy_type {
+                    ProxyType::Http => 8080,
+                    ProxyType::Socks5 => 1080,
+                };
+
+                http_config.proxy = Some(ProxyConfig {
+                    proxy_type,
+                    host,
+                    port: config.get_int("davmail.proxy.port").unwrap_or(default_port) as u16,
+                    username: config.get_string("davmail.proxy.username").ok().filter(|u| !u.is_empty()),
+                    password: config.get_string("davmail.proxy.password").ok().filter(|p| !p.is_empty()),
+                    // davmail.proxy.authScheme=ntlm or negotiate for Windows proxies, username as DOMAIN\user
+                    auth_scheme: config.get_string("davmail.proxy.authScheme").ok()
+                        .and_then(|value| {
+                            let auth_scheme = ProxyAuthScheme::parse(&value);
+                            if auth_scheme.is_none() {
+                                warn!("Unknown davmail.proxy.authScheme value '{}', using basic", value);
+                            }
+                            auth_scheme
+                        })
+                        .unwrap_or(ProxyAuthScheme::Basic),
+                });
+            }
+        }
+
+        http_config.use_env_proxy = config.get_bool("davmail.proxy.useEnvironment").unwrap_or(true);
+
+        // Seconds, 0 disables the read timeout and the keepalive probes
+        http_config.timeouts = RequestTimeouts::from_config(config);
+        if let Ok(timeout) = config.get_int("davmail.http.connectTimeout") {
+            http_config.connect_timeout = Duration::from_secs(timeout.max(1) as u64);
+        }
+        if let Ok(timeout) = config.get_int("davmail.http.readTimeout") {
+            http_config.read_timeout = Some(Duration::from_secs(timeout.max(0) as u64)).filter(|timeout| !timeout.is_zero());
+        }
+        if let Ok(keepalive) = config.get_int("davmail.http.tcpKeepAlive") {
+            http_config.tcp_keepalive = Some(Duration::from_secs(keepalive.max(0) as u64)).filter(|keepalive| !keepalive.is_zero());
+        }
+
+        http_config.http2 = config.get_bool("davmail.http.enableHttp2").unwrap_or(true);
+        if let Ok(max_idle) = config.get_int("davmail.http.poolMaxIdlePerHost") {
+            http_config.pool_max_idle_per_host = max_idle.max(0) as usize;
+        }
+        if let Ok(idle_timeout) = config.get_int("davmail.http.poolIdleTimeout") {
+            http_config.pool_idle_timeout = Duration::from_secs(idle_timeout.max(0) as u64);
+        }
+        http_config.gzip = config.get_bool("davmail.http.gzip").unwrap_or(true);
+
+        // A .p12/.pfx file is used as is, anything else is a PEM certificate with davmail.ssl.clientKey
+        if let Ok(path) = config.get_string("davmail.ssl.clientCertificate") {
+            if !path.is_empty() {
+                let lower = path.to_lowercase();
+                http_config.client_certificate = if lower.ends_with(".p12") || lower.ends_with(".pfx") {
+                    Some(ClientCertificate::Pkcs12 {
+                        path,
+                        password: config.get_string("davmail.ssl.clientCertificatePassword").unwrap_or_default(),
+                    })
+                } else {
+                    match config.get_string("davmail.ssl.clientKey") {
+                        Ok(key_path) => Some(ClientCertificate::Pem { certificate_path: path, key_path }),
+                        Err(_) => {
+                            warn!("davmail.ssl.clientCertificate is a PEM file but davmail.ssl.clientKey is not set, ignoring it");
+                            None
+                        }
+                    }
+                };
+            }
+        }
+
+        http_config
+    }
+
+    // NTLM authenticates the connection, so requests must keep reusing one HTTP/1.1 connection
+    pub fn for_ntlm(&self) -> Self {
+        HttpClientConfig {
+            http2: false,
+            pool_max_idle_per_host: 1,
+            ..self.clone()
+        }
+    }
+
+    pub fn build(&self) -> Result<Client, ExchangeError> {
+        debug!("Building HTTP client with {:?} certificate trust", self.tls_trust);
+
+        let mut builder = Client::builder()
+            .timeout(self.timeouts.bulk)
+            .connect_timeout(self.connect_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .gzip(self.gzip);
+
+        if let Some(read_timeout) = self.read_timeout {
+            builder = builder.read_timeout(read_timeout);
+        }
+        if !self.http2 {
+            builder = builder.http1_only();
+        }
+
+        // reqwest reads the proxy environment variables by default; an explicit proxy replaces them
+        if let Some(proxy) = &self.proxy {
+            debug!("Using {:?} proxy {}:{}", proxy.proxy_type, proxy.host, proxy.port);
+            builder = builder.proxy(proxy.to_proxy()?);
+        } else if !self.use_env_proxy {
+            builder = builder.no_proxy();
+        }
+
+        let mut builder = match self.tls_trust {
+            TlsTrust::NativeTls => builder.use_native_tls(),
+            TlsTrust::SystemStore => builder
+                .use_rustls_tls()
+                .tls_built_in_native_certs(true),
+        };
+
+        if let Some(client_certificate) = &self.client_certificate {
+            debug!("Presenting client certificate {:?}", client_certificate);
+            builder = builder.identity(client_certificate.identity(self.tls_trust)?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
@@ -0,0 +1,51 @@
+// exchange/ids.rs
+// Id normalization through the EWS ConvertId operation
+
+use log::debug;
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::{ConvertId, IdFormat};
+
+// ConvertId accepts large batches, keep requests well below the server limits
+const CONVERT_BATCH_SIZE: usize = 500;
+
+impl ExchangeClient {
+    // Convert ids between formats, results are returned in the order of `ids`
+    pub async fn convert_ids(&self, mailbox: &str, ids: &[String], from: IdFormat, to: IdFormat) -> Result<Vec<String>, ExchangeError> {
+        if from == to {
+            return Ok(ids.to_vec());
+        }
+        debug!("Converting {} ids from {} to {}", ids.len(), from.as_str(), to.as_str());
+
+        let mut converted = Vec::with_capacity(ids.len());
+        for batch in ids.chunks(CONVERT_BATCH_SIZE) {
+            let response = self.send_request(&ConvertId {
+                destination_format: to,
+                source_format: from,
+                mailbox: mailbox.to_string(),
+                ids: batch.to_vec(),
+            }).await?;
+
+            // One ConvertIdResponseMessage per source id, each holding the converted AlternateId
+            let messages = response.descendants("ConvertIdResponseMessage");
+            if messages.len() != batch.len() {
+                return Err(ExchangeError::ParseError(format!(
+                    "ConvertId returned {} ids for {} requested", messages.len(), batch.len()
+                )));
+            }
+            for message in messages {
+                let id = message.child("AlternateId")
+                    .and_then(|alternate| alternate.attr("Id"))
+                    .ok_or_else(|| ExchangeError::ParseError("ConvertId response without AlternateId".to_string()))?;
+                converted.push(id.to_string());
+            }
+        }
+
+        Ok(converted)
+    }
+
+    // Stable representation used as key in the UID map
+    pub async fn stable_ids(&self, mailbox: &str, ews_ids: &[String]) -> Result<Vec<String>, ExchangeError> {
+        self.convert_ids(mailbox, ews_ids, IdFormat::EwsId, IdFormat::HexEntryId).await
+    }
+}
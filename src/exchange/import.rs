@@ -0,0 +1,171 @@
+// exchange/import.rs
+// Messages from other mail systems (.eml files, mbox) saved into a mailbox folder with CreateItem
+
+use std::io::{self, BufRead};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::debug;
+
+use super::{response, ExchangeClient, ExchangeError};
+use super::mime::header;
+use super::request::{format_datetime, CreateItem, FolderRef, ItemContent, ItemId, XmlWriter};
+
+// MAPI properties set on the new item, Exchange takes MIME dates as the time of the import otherwise
+const PR_CLIENT_SUBMIT_TIME: &str = "0x0039";
+const PR_MESSAGE_DELIVERY_TIME: &str = "0x0E06";
+// Without it the item is an unsent draft, 1 is MSGFLAG_READ
+const PR_MESSAGE_FLAGS: &str = "0x0E07";
+
+// A complete message and what can be kept of its state in the old mail system
+#[derive(Debug, Clone)]
+pub struct ImportMessage {
+    pub content: Vec<u8>,
+    // From the Date header
+    pub sent: Option<DateTime<Utc>>,
+    // From the mbox separator line, the Date header when there is none
+    pub received: Option<DateTime<Utc>>,
+    pub is_read: bool,
+}
+
+impl ImportMessage {
+    // Dates and read state from the headers, Status: R (mbox) or the read bit of X-Mozilla-Status (Thunderbird)
+    pub fn from_mime(content: Vec<u8>) -> Self {
+        let sent = header(&content, "Date").and_then(|date| parse_date(&date));
+        let is_read = header(&content, "Status").is_some_and(|status| status.contains('R'))
+            || header(&content, "X-Mozilla-Status").and_then(|flags| u32::from_str_radix(flags.trim(), 16).ok())
+                .is_some_and(|flags| flags & 0x0001 != 0);
+        ImportMessage { content, sent, received: sent, is_read }
+    }
+
+    pub fn subject(&self) -> String {
+        header(&self.content, "Subject").unwrap_or_default()
+    }
+}
+
+impl ItemContent for ImportMessage {
+    fn write_item(&self, w: &mut XmlWriter) {
+        // Element order follows the MessageType schema sequence, extended properties before IsRead
+        w.open("t:Message", &[]).element("t:MimeContent", &STANDARD.encode(&self.content));
+        let flags = if self.is_read { "1" } else { "0" };
+        write_property(w, PR_MESSAGE_FLAGS, "Integer", flags);
+        if let Some(sent) = &self.sent {
+            write_property(w, PR_CLIENT_SUBMIT_TIME, "SystemTime", &format_datetime(sent));
+        }
+        if let Some(received) = &self.received {
+            write_property(w, PR_MESSAGE_DELIVERY_TIME, "SystemTime", &format_datetime(received));
+        }
+        w.element("t:IsRead", if self.is_read { "true" } else { "false" });
+        w.close();
+    }
+}
+
+fn write_property(w: &mut XmlWriter, tag: &str, property_type: &str, value: &str) {
+    w.open("t:ExtendedProperty", &[])
+        .empty("t:ExtendedFieldURI", &[("PropertyTag", tag), ("PropertyType", property_type)])
+        .element("t:Value", value)
+        .close();
+}
+
+// RFC 5322 date, comments like "(UTC)" are dropped
+fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.split('(').next().unwrap_or_default().trim();
+    DateTime::parse_from_rfc2822(value).ok().map(|date| date.with_timezone(&Utc))
+}
+
+// Messages of an mbox file one at a time, for mboxo and mboxrd files alike
+pub struct MboxReader<R> {
+    reader: R,
+    // Separator line of the next message, already read
+    next_separator: Option<Vec<u8>>,
+    started: bool,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    pub fn new(reader: R) -> Self {
+        MboxReader { reader, next_separator: None, started: false }
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<ImportMessage>> {
+        // Anything before the first separator isn't a message
+        let separator = match self.next_separator.take() {
+            Some(separator) => separator,
+            None if self.started => return Ok(None),
+            None => loop {
+                let mut line = Vec::new();
+                if self.reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.starts_with(b"From ") {
+                    break line;
+                }
+            },
+        };
+        self.started = true;
+
+        let mut content = Vec::new();
+        let mut previous_blank = true;
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if previous_blank && line.starts_with(b"From ") {
+                self.next_separator = Some(line);
+                break;
+            }
+            previous_blank = line == b"\n" || line == b"\r\n";
+            // mboxrd quotes From lines in the body with one more '>' than they had
+            let quoted = line.iter().take_while(|byte| **byte == b'>').count();
+            if quoted > 0 && line[quoted..].starts_with(b"From ") {
+                line.remove(0);
+            }
+            content.extend_from_slice(&line);
+        }
+        // The blank line before the next separator belongs to the mbox format
+        if content.ends_with(b"\r\n\r\n") {
+            content.truncate(content.len() - 2);
+        } else if content.ends_with(b"\n\n") {
+            content.truncate(content.len() - 1);
+        }
+
+        let mut message = ImportMessage::from_mime(content);
+        if let Some(received) = separator_date(&separator) {
+            message.received = Some(received);
+        }
+        Ok(Some(message))
+    }
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+    type Item = io::Result<ImportMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+// "From sender Sat Jan  3 01:05:34 1996", the asctime date at the end is in UTC
+fn separator_date(line: &[u8]) -> Option<DateTime<Utc>> {
+    let line = String::from_utf8_lossy(line);
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let date = fields.get(fields.len().checked_sub(5)?..)?.join(" ");
+    NaiveDateTime::parse_from_str(&date, "%a %b %e %H:%M:%S %Y").ok().map(|date| date.and_utc())
+}
+
+impl ExchangeClient {
+    // Save the message into the folder as a received message, returns the id of the new item
+    pub async fn import_message(&self, folder: &FolderRef, message: &ImportMessage) -> Result<ItemId, ExchangeError> {
+        debug!("Importing a message of {} bytes into {}", message.content.len(), folder.label());
+        let response = self.send_request(&CreateItem {
+            saved_folder: Some(folder.clone()),
+            message_disposition: Some("SaveOnly"),
+            items: vec![message],
+        }).await?;
+
+        response.descendants("Message")
+            .first()
+            .map(|element| response::item_id(element))
+            .unwrap_or_else(|| Err(ExchangeError::ParseError("CreateItem returned no message".to_string())))
+    }
+}
@@ -0,0 +1,62 @@
+// exchange/junk.rs
+// MOVE between folders, reported as junk or not junk when it goes into or out of Junk Email so the
+// user's filter learns from IMAP clients too
+
+use log::debug;
+
+use super::{parse_sequence_set, ExchangeClient, ExchangeError};
+use super::request::{distinguished_folder, ItemId, MarkAsJunk, MoveItem};
+
+fn is_junk_folder(folder: &str) -> bool {
+    distinguished_folder(folder) == Some("junkemail")
+}
+
+impl ExchangeClient {
+    // Move the messages of the sequence set, returns the sequence numbers that were moved
+    pub async fn move_messages(&self, folder: &str, sequence_set: &str, target: &str) -> Result<Vec<u32>, ExchangeError> {
+        let sequences = parse_sequence_set(sequence_set)?;
+        let (moved, item_ids): (Vec<u32>, Vec<ItemId>) = self.item_ids(folder, &sequences).await?.into_iter().unzip();
+        if item_ids.is_empty() {
+            return Ok(moved);
+        }
+
+        match (is_junk_folder(folder), is_junk_folder(target)) {
+            // MarkAsJunk moves to Junk Email itself
+            (false, true) => {
+                debug!("Reporting {} item(s) from '{}' as junk", item_ids.len(), folder);
+                self.send_request(&MarkAsJunk { is_junk: true, move_item: true, item_ids }).await?;
+            },
+            // It would move to Inbox, the client may want another folder
+            (true, false) => {
+                debug!("Reporting {} item(s) moved to '{}' as not junk", item_ids.len(), target);
+                self.send_request(&MarkAsJunk { is_junk: false, move_item: false, item_ids: item_ids.clone() }).await?;
+                let to_folder = self.resolve_folder(target).await?;
+                self.send_request(&MoveItem { to_folder, item_ids }).await?;
+            },
+            _ => {
+                let to_folder = self.resolve_folder(target).await?;
+                self.send_request(&MoveItem { to_folder, item_ids }).await?;
+            },
+        }
+
+        if let Some(metadata) = &self.metadata {
+            metadata.expire(folder);
+            metadata.expire(target);
+        }
+        self.folder_changed(folder);
+        self.folder_changed(target);
+        Ok(moved)
+    }
+
+    // STORE of $Junk or $NotJunk, reported without moving, clients that move junk do so with MOVE
+    pub async fn set_junk(&self, folder: &str, sequence_set: &str, junk: bool) -> Result<(), ExchangeError> {
+        let sequences = parse_sequence_set(sequence_set)?;
+        let item_ids: Vec<ItemId> = self.item_ids(folder, &sequences).await?.into_iter().map(|(_, item_id)| item_id).collect();
+        if item_ids.is_empty() {
+            return Ok(());
+        }
+        debug!("Reporting {} item(s) in '{}' as {}", item_ids.len(), folder, if junk { "junk" } else { "not junk" });
+        self.send_request(&MarkAsJunk { is_junk: junk, move_item: false, item_ids }).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+// exchange/limiter.rs
+// Caps concurrent EWS requests, in total and per user, so one client syncing in parallel can't get the
+// whole gateway throttled by Exchange. Optional rate limits per user keep a runaway script from using
+// up the tenant's EWS budget: requests per minute and bandwidth.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use config::Config;
+use log::debug;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Exchange Online allows 27 concurrent connections per mailbox, a gateway shares that budget between its users
+pub const DEFAULT_MAX_REQUESTS: usize = 32;
+pub const DEFAULT_MAX_REQUESTS_PER_USER: usize = 4;
+
+// Waits shorter than this are normal contention and not logged
+const QUEUED_LOG_THRESHOLD: Duration = Duration::from_millis(100);
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+// Per user, 0 means no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    pub requests_per_minute: u64,
+    // Response bytes read from Exchange, bandwidth is configured in KB/s and averaged over a minute
+    pub bytes_per_minute: u64,
+}
+
+impl RateLimits {
+    // davmail.ews.maxRequestsPerMinute and davmail.ews.maxBandwidth (KB/s)
+    pub fn from_config(config: &Config) -> Self {
+        let limit = |key: &str| config.get_int(key).map_or(0, |limit| limit.max(0) as u64);
+        RateLimits {
+            requests_per_minute: limit("davmail.ews.maxRequestsPerMinute"),
+            bytes_per_minute: limit("davmail.ews.maxBandwidth") * 1024 * 60,
+        }
+    }
+}
+
+// Amounts counted over a sliding window, like Exchange Online's own throttling
+#[derive(Default)]
+struct Window {
+    recent: VecDeque<(Instant, u64)>,
+    total: u64,
+}
+
+impl Window {
+    fn expire(&mut self, now: Instant, period: Duration) {
+        while let Some((at, amount)) = self.recent.front().copied() {
+            if now.duration_since(at) < period {
+                break;
+            }
+            self.recent.pop_front();
+            self.total -= amount;
+        }
+    }
+
+    // How long until the window is below `limit` again, None when it already is
+    fn wait(&mut self, now: Instant, period: Duration, limit: u64) -> Option<Duration> {
+        self.expire(now, period);
+        if limit == 0 || self.total < limit {
+            return None;
+        }
+        // Enough of the oldest entries have to expire to get below the limit
+        let mut total = self.total;
+        self.recent.iter()
+            .find(|(_, amount)| {
+                total -= amount;
+                total < limit
+            })
+            .map(|(at, _)| period.saturating_sub(now.duration_since(*at)))
+    }
+
+    fn add(&mut self, now: Instant, amount: u64) {
+        self.recent.push_back((now, amount));
+        self.total += amount;
+    }
+}
+
+#[derive(Default)]
+struct UserRates {
+    requests: Window,
+    bytes: Window,
+}
+
+// One per profile, shared by the sessions of all its users
+pub struct RequestLimiter {
+    global: Option<Arc<Semaphore>>,
+    per_user: usize,
+    users: Mutex<HashMap<String, Arc<Semaphore>>>,
+    rate_limits: RateLimits,
+    rates: Mutex<HashMap<String, UserRates>>,
+}
+
+// Held for the whole request, response body included
+pub struct RequestPermit {
+    _user: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+impl RequestLimiter {
+    // 0 means no limit
+    pub fn new(max_requests: usize, max_requests_per_user: usize) -> Self {
+        RequestLimiter {
+            global: (max_requests > 0).then(|| Arc::new(Semaphore::new(max_requests))),
+            per_user: max_requests_per_user,
+            users: Mutex::new(HashMap::new()),
+            rate_limits: RateLimits::default(),
+            rates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    // davmail.ews.maxConcurrentRequests and davmail.ews.maxConcurrentRequestsPerUser
+    pub fn from_config(config: &Config) -> Self {
+        let limit = |key: &str, default: usize| config.get_int(key).map_or(default, |limit| limit.max(0) as usize);
+        RequestLimiter::new(
+            limit("davmail.ews.maxConcurrentRequests", DEFAULT_MAX_REQUESTS),
+            limit("davmail.ews.maxConcurrentRequestsPerUser", DEFAULT_MAX_REQUESTS_PER_USER),
+        ).with_rate_limits(RateLimits::from_config(config))
+    }
+
+    // Counts one EWS request of the user, or how long the user has to slow down when over the
+    // request or bandwidth limit. Refused requests are not counted.
+    pub fn check_rate(&self, user: &str) -> Result<(), Duration> {
+        let limits = self.rate_limits;
+        if limits.requests_per_minute == 0 && limits.bytes_per_minute == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut rates = self.rates.lock().unwrap();
+        let rates = rates.entry(user.to_lowercase()).or_default();
+        let wait = rates.requests.wait(now, MINUTE, limits.requests_per_minute)
+            .max(rates.bytes.wait(now, MINUTE, limits.bytes_per_minute));
+        match wait {
+            Some(wait) => Err(wait),
+            None => {
+                rates.requests.add(now, 1);
+                Ok(())
+            },
+        }
+    }
+
+    // Response bytes the user's request read, counted against the bandwidth limit
+    pub fn record_received(&self, user: &str, bytes: usize) {
+        if self.rate_limits.bytes_per_minute == 0 || bytes == 0 {
+            return;
+        }
+        let mut rates = self.rates.lock().unwrap();
+        rates.entry(user.to_lowercase()).or_default().bytes.add(Instant::now(), bytes as u64);
+    }
+
+    // Wait for a free slot. The user's own slot comes first, so a busy user queues behind their own
+    // requests instead of holding global slots other users are waiting for.
+    pub async fn acquire(&self, user: &str) -> RequestPermit {
+        let started = Instant::now();
+
+        let user_permit = match self.per_user {
+            0 => None,
+            per_user => {
+                let semaphore = self.users.lock().unwrap()
+                    .entry(user.to_lowercase())
+                    .or_insert_with(|| Arc::new(Semaphore::new(per_user)))
+                    .clone();
+                semaphore.acquire_owned().await.ok()
+            },
+        };
+        let global_permit = match &self.global {
+            Some(global) => global.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+
+        let queued = started.elapsed();
+        if queued >= QUEUED_LOG_THRESHOLD {
+            debug!("EWS request of {} queued for {:?}", user, queued);
+        }
+        RequestPermit { _user: user_permit, _global: global_permit }
+    }
+}
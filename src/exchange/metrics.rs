@@ -0,0 +1,101 @@
+// exchange/metrics.rs
+// Latency and size instrumentation of EWS operations
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+
+// Operations slower than this are logged at warn level
+const SLOW_OPERATION: Duration = Duration::from_secs(5);
+
+// Error responses kept for the admin status and diagnostic bundles
+const RECENT_ERROR_RESPONSES: usize = 20;
+// Error details come first, what follows is usually item data of the other responses in the batch
+const MAX_ERROR_RESPONSE_BYTES: usize = 4096;
+
+// Aggregated measurements of one EWS operation
+#[derive(Debug, Clone, Default)]
+pub struct OperationStats {
+    pub count: u64,
+    pub errors: u64,
+    pub total_duration: Duration,
+    pub max_duration: Duration,
+    pub response_bytes: u64,
+}
+
+fn registry() -> &'static Mutex<HashMap<&'static str, OperationStats>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<&'static str, OperationStats>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Record one round trip; status is None when no HTTP response was received
+pub fn record(operation: &'static str, folder: Option<&str>, status: Option<u16>, duration: Duration, response_bytes: usize) {
+    let failed = status.is_none_or(|status| status >= 400);
+    let status_text = status.map_or_else(|| "-".to_string(), |status| status.to_string());
+
+    if duration >= SLOW_OPERATION {
+        warn!("EWS {} folder={} status={} duration={}ms bytes={} (slow)",
+            operation, folder.unwrap_or("-"), status_text, duration.as_millis(), response_bytes);
+    } else {
+        debug!("EWS {} folder={} status={} duration={}ms bytes={}",
+            operation, folder.unwrap_or("-"), status_text, duration.as_millis(), response_bytes);
+    }
+
+    let mut registry = registry().lock().unwrap();
+    let stats = registry.entry(operation).or_default();
+    stats.count += 1;
+    if failed {
+        stats.errors += 1;
+    }
+    stats.total_duration += duration;
+    stats.max_duration = stats.max_duration.max(duration);
+    stats.response_bytes += response_bytes as u64;
+}
+
+// An EWS response that failed, as Exchange sent it
+#[derive(Debug, Clone)]
+pub struct ErrorResponse {
+    pub time: DateTime<Utc>,
+    pub operation: &'static str,
+    pub status: u16,
+    pub error: String,
+    // Cut at MAX_ERROR_RESPONSE_BYTES
+    pub body: String,
+}
+
+static ERROR_RESPONSES: Mutex<VecDeque<ErrorResponse>> = Mutex::new(VecDeque::new());
+
+pub fn record_error_response(operation: &'static str, status: u16, error: &impl ToString, body: &str) {
+    let mut end = body.len().min(MAX_ERROR_RESPONSE_BYTES);
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut responses = ERROR_RESPONSES.lock().unwrap();
+    if responses.len() == RECENT_ERROR_RESPONSES {
+        responses.pop_front();
+    }
+    responses.push_back(ErrorResponse { time: Utc::now(), operation, status, error: error.to_string(), body: body[..end].to_string() });
+}
+
+// Oldest first
+pub fn recent_error_responses() -> Vec<ErrorResponse> {
+    ERROR_RESPONSES.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn snapshot() -> HashMap<&'static str, OperationStats> {
+    registry().lock().unwrap().clone()
+}
+
+// One line per operation, sorted by name
+pub fn log_summary() {
+    let mut stats: Vec<_> = snapshot().into_iter().collect();
+    stats.sort_by_key(|(operation, _)| *operation);
+
+    for (operation, stats) in stats {
+        let average = stats.total_duration / stats.count.max(1) as u32;
+        info!("EWS {}: {} calls, {} errors, avg {}ms, max {}ms, {} bytes received",
+            operation, stats.count, stats.errors, average.as_millis(), stats.max_duration.as_millis(), stats.response_bytes);
+    }
+}
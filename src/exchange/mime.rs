@@ -0,0 +1,455 @@
+// exchange/mime.rs
+// MIME content download with a bounded cache
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures_util::future::try_join_all;
+use log::{debug, warn};
+
+use super::{charset, metrics, response, ExchangeClient, ExchangeError};
+use super::request::{BaseShape, EwsRequest, GetItem, ItemId};
+use super::response::XmlElement;
+use super::spool::{MimeBody, SpoolWriter};
+use crate::telemetry::Span;
+
+pub const DEFAULT_MIME_CACHE_BYTES: usize = 32 * 1024 * 1024;
+
+// Longest base64 line of parts the gateway encodes itself (RFC 2045)
+const BASE64_LINE_LENGTH: usize = 76;
+
+// Least recently used cache of message MIME content. Keys include the change key,
+// so a modified item is downloaded again instead of serving stale content. Spooled
+// messages count against the limit too, their files are removed on eviction.
+#[derive(Debug)]
+pub struct MimeCache {
+    max_bytes: usize,
+    used_bytes: usize,
+    entries: HashMap<String, Arc<MimeBody>>,
+    // Most recently used key at the back
+    order: VecDeque<String>,
+}
+
+impl MimeCache {
+    pub fn new(max_bytes: usize) -> Self {
+        MimeCache {
+            max_bytes,
+            used_bytes: 0,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn key(id: &ItemId) -> String {
+        format!("{}\t{}", id.id, id.change_key.as_deref().unwrap_or(""))
+    }
+
+    pub fn get(&mut self, id: &ItemId) -> Option<Arc<MimeBody>> {
+        let key = MimeCache::key(id);
+        let content = self.entries.get(&key)?.clone();
+        self.order.retain(|k| k != &key);
+        self.order.push_back(key);
+        Some(content)
+    }
+
+    pub fn insert(&mut self, id: &ItemId, content: Arc<MimeBody>) {
+        // Messages larger than the whole cache are never kept
+        let size = content.len() as usize;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let key = MimeCache::key(id);
+        if let Some(previous) = self.entries.insert(key.clone(), content) {
+            self.used_bytes -= previous.len() as usize;
+            self.order.retain(|k| k != &key);
+        }
+        self.used_bytes += size;
+        self.order.push_back(key);
+
+        while self.used_bytes > self.max_bytes {
+            let Some(oldest) = self.order.pop_front() else { break };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.used_bytes -= evicted.len() as usize;
+            }
+        }
+    }
+}
+
+impl Default for MimeCache {
+    fn default() -> Self {
+        MimeCache::new(DEFAULT_MIME_CACHE_BYTES)
+    }
+}
+
+// Messages per GetItem request, MIME content makes responses large so stay well below the EWS limit
+const MIME_BATCH_SIZE: usize = 50;
+
+// Read-ahead requests are smaller so several of them can be in flight at once
+const PREFETCH_BATCH_SIZE: usize = 10;
+
+pub const DEFAULT_READ_AHEAD: usize = 20;
+
+// Where the last body FETCH of a session ended, to recognize clients downloading a folder in order
+#[derive(Debug, Default)]
+pub struct ReadAhead {
+    folder: String,
+    next_sequence: u32,
+}
+
+impl ReadAhead {
+    // Record a body fetch and tell whether it continues the previous one
+    pub fn is_sequential(&mut self, folder: &str, sequences: &[u32]) -> bool {
+        let (Some(&first), Some(&last)) = (sequences.iter().min(), sequences.iter().max()) else {
+            return false;
+        };
+        let sequential = self.folder == folder && first == self.next_sequence;
+        self.folder = folder.to_string();
+        self.next_sequence = last.saturating_add(1);
+        sequential
+    }
+}
+
+impl ExchangeClient {
+    // Full RFC 822 content of a message, served from the cache when the same version was already fetched
+    pub async fn get_mime_content(&self, id: &ItemId) -> Result<Arc<MimeBody>, ExchangeError> {
+        self.get_mime_contents(std::slice::from_ref(id)).await?
+            .pop()
+            .ok_or_else(|| ExchangeError::ParseError(format!("No MimeContent for item {}", id.id)))
+    }
+
+    // MIME content of several messages in `ids` order, cache misses are downloaded in batched GetItem calls
+    pub async fn get_mime_contents(&self, ids: &[ItemId]) -> Result<Vec<Arc<MimeBody>>, ExchangeError> {
+        let mut contents: Vec<Option<Arc<MimeBody>>> = {
+            let mut cache = self.mime_cache.lock().unwrap();
+            ids.iter().map(|id| cache.get(id)).collect()
+        };
+
+        let missing: Vec<usize> = (0..ids.len()).filter(|&index| contents[index].is_none()).collect();
+        debug!("MIME content for {} items, {} cached", ids.len(), ids.len() - missing.len());
+
+        for batch in missing.chunks(MIME_BATCH_SIZE) {
+            let batch_ids: Vec<ItemId> = batch.iter().map(|&index| ids[index].clone()).collect();
+            for (&index, content) in batch.iter().zip(self.download_mime(batch_ids).await?) {
+                contents[index] = Some(content);
+            }
+        }
+
+        Ok(contents.into_iter().flatten().collect())
+    }
+
+    // Load messages into the cache ahead of the client asking for them, with several GetItem calls in flight
+    pub async fn prefetch_mime(&self, ids: &[ItemId]) {
+        let missing: Vec<ItemId> = {
+            let mut cache = self.mime_cache.lock().unwrap();
+            ids.iter().filter(|id| cache.get(id).is_none()).cloned().collect()
+        };
+        if missing.is_empty() {
+            return;
+        }
+        debug!("Prefetching MIME content for {} items", missing.len());
+
+        let batches = missing.chunks(PREFETCH_BATCH_SIZE).map(|batch| self.download_mime(batch.to_vec()));
+        if let Err(e) = try_join_all(batches).await {
+            // The client will download the messages itself when it gets there
+            warn!("MIME prefetch failed: {}", e);
+        }
+    }
+
+    // One GetItem call, results in `ids` order and added to the cache. The response is read as it
+    // arrives, the MIME content goes straight to spool writers instead of into the parsed envelope.
+    async fn download_mime(&self, ids: Vec<ItemId>) -> Result<Vec<Arc<MimeBody>>, ExchangeError> {
+        let request = GetItem {
+            shape: BaseShape::IdOnly,
+            additional_properties: vec!["item:MimeContent"],
+            item_ids: ids.clone(),
+        };
+        let mut span = Span::call("EWS", request.operation());
+        span.set_attribute("ews.items", ids.len());
+        let _permit = span.record(self.request_permit().await)?;
+        let (mut http_response, started) = span.record(self.post_request(&request).await)?;
+        let status = http_response.status();
+        span.set_attribute("http.response.status_code", status.as_u16());
+
+        let mut scanner = MimeContentScanner::default();
+        let mut received = 0;
+        let scanned = async {
+            while let Some(chunk) = http_response.chunk().await? {
+                received += chunk.len();
+                scanner.feed(&chunk).await.map_err(spool_error)?;
+            }
+            Ok::<_, ExchangeError>(())
+        }.await;
+        metrics::record(request.operation(), None, Some(status.as_u16()), started.elapsed(), received);
+        self.record_received(received);
+        span.record(scanned)?;
+        let (envelope, mut bodies) = scanner.finish().await?;
+
+        // One GetItemResponseMessage per requested id, in request order
+        let messages = envelope.descendants("GetItemResponseMessage");
+        if messages.len() != ids.len() {
+            return Err(ExchangeError::ParseError(format!(
+                "GetItem returned {} items for {} requested", messages.len(), ids.len()
+            )));
+        }
+
+        // Bodies come in document order, one for each message that has a MimeContent element
+        bodies.reverse();
+        let mut contents = Vec::with_capacity(ids.len());
+        let mut cache = self.mime_cache.lock().unwrap();
+        for (id, message) in ids.iter().zip(messages) {
+            let content = message.descendants("MimeContent").first()
+                .and_then(|_| bodies.pop())
+                .ok_or_else(|| ExchangeError::ParseError(format!("No MimeContent for item {}", id.id)))?;
+
+            // Conversions only apply to messages in memory, spooled ones are served as they are
+            let content = self.convert(content);
+            let content = Arc::new(content);
+            cache.insert(id, content.clone());
+            contents.push(content);
+        }
+
+        Ok(contents)
+    }
+}
+
+impl ExchangeClient {
+    // Downloaded content with the session's HTML, charset and header conversions applied
+    fn convert(&self, content: MimeBody) -> MimeBody {
+        let Some(bytes) = content.in_memory() else { return content };
+        let html = self.html_conversion.convert(bytes);
+        let utf8 = if self.utf8_messages { charset::to_utf8(html.as_deref().unwrap_or(bytes)) } else { None };
+        let converted = utf8.or(html);
+        let rewritten = self.header_rules.rewrite(converted.as_deref().unwrap_or(bytes));
+        match rewritten.or(converted) {
+            Some(converted) => MimeBody::from_bytes(converted),
+            None => content,
+        }
+    }
+}
+
+// Pulls the MimeContent elements out of a GetItem response as it arrives and decodes them into spool
+// writers. The rest of the response is small and kept to be parsed and checked for errors at the end.
+#[derive(Default)]
+pub(crate) struct MimeContentScanner {
+    envelope: Vec<u8>,
+    // Where to look for the next start tag in `envelope`
+    scan_from: usize,
+    // MimeContent element being received
+    content: Option<ContentDecoder>,
+    bodies: Vec<MimeBody>,
+}
+
+impl MimeContentScanner {
+    pub(crate) async fn feed(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let mut data = chunk.to_vec();
+        loop {
+            if let Some(decoder) = &mut self.content {
+                // The base64 text runs up to the closing tag
+                let end = data.iter().position(|&byte| byte == b'<');
+                decoder.write(&data[..end.unwrap_or(data.len())]).await?;
+                let Some(end) = end else { return Ok(()) };
+                if let Some(decoder) = self.content.take() {
+                    self.bodies.push(decoder.finish().await?);
+                }
+                data.drain(..end);
+            }
+
+            self.envelope.append(&mut data);
+            let Some((tag_end, empty)) = self.next_mime_content_tag() else { return Ok(()) };
+            // What follows the start tag is content, the element itself stays empty in the envelope
+            data = self.envelope.split_off(tag_end);
+            if empty {
+                self.bodies.push(SpoolWriter::new().finish().await?);
+            } else {
+                self.content = Some(ContentDecoder::default());
+            }
+        }
+    }
+
+    // End of the next complete MimeContent start tag and whether it is self-closing
+    fn next_mime_content_tag(&mut self) -> Option<(usize, bool)> {
+        while let Some(offset) = self.envelope[self.scan_from..].iter().position(|&byte| byte == b'<') {
+            let open = self.scan_from + offset;
+            let Some(length) = self.envelope[open..].iter().position(|&byte| byte == b'>') else {
+                // The rest of the tag is in the next chunk
+                self.scan_from = open;
+                return None;
+            };
+            let close = open + length;
+            self.scan_from = close + 1;
+
+            let tag = &self.envelope[open + 1..close];
+            let name_length = tag.iter().position(|&byte| byte.is_ascii_whitespace() || byte == b'/').unwrap_or(tag.len());
+            let local_name = tag[..name_length].rsplit(|&byte| byte == b':').next().unwrap_or_default();
+            if local_name == b"MimeContent" {
+                return Some((close + 1, tag.ends_with(b"/")));
+            }
+        }
+        self.scan_from = self.envelope.len();
+        None
+    }
+
+    pub(crate) async fn finish(self) -> Result<(XmlElement, Vec<MimeBody>), ExchangeError> {
+        if self.content.is_some() {
+            return Err(ExchangeError::ParseError("GetItem response ended inside MimeContent".to_string()));
+        }
+        let envelope = String::from_utf8(self.envelope)
+            .map_err(|e| ExchangeError::ParseError(format!("Invalid GetItem response: {}", e)))?;
+        Ok((response::parse_response(&envelope)?, self.bodies))
+    }
+}
+
+// Decodes base64 text split at arbitrary points, whole groups of four characters at a time
+#[derive(Default)]
+struct ContentDecoder {
+    writer: SpoolWriter,
+    pending: Vec<u8>,
+}
+
+impl ContentDecoder {
+    async fn write(&mut self, text: &[u8]) -> io::Result<()> {
+        self.pending.extend(text.iter().filter(|byte| !byte.is_ascii_whitespace()));
+        let complete = self.pending.len() / 4 * 4;
+        if complete > 0 {
+            let decoded = STANDARD.decode(&self.pending[..complete])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid MimeContent: {}", e)))?;
+            self.pending.drain(..complete);
+            self.writer.write(&decoded).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> io::Result<MimeBody> {
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid MimeContent: truncated base64"));
+        }
+        self.writer.finish().await
+    }
+}
+
+// Spooling and decoding failures of a message download
+pub fn spool_error(error: io::Error) -> ExchangeError {
+    ExchangeError::ParseError(format!("Failed to read MIME content: {}", error))
+}
+
+// Unfolded value of the first header with this name
+pub fn header(content: &[u8], name: &str) -> Option<String> {
+    let text = String::from_utf8_lossy(content);
+    let mut lines = text.lines().take_while(|line| !line.is_empty()).peekable();
+    while let Some(line) = lines.next() {
+        let Some((field, value)) = line.split_once(':') else { continue };
+        if field.eq_ignore_ascii_case(name) {
+            let mut value = value.trim().to_string();
+            while let Some(continuation) = lines.next_if(|line| line.starts_with([' ', '\t'])) {
+                value.push(' ');
+                value.push_str(continuation.trim());
+            }
+            return Some(value);
+        }
+    }
+    None
+}
+
+// S/MIME (multipart/signed, application/pkcs7-mime) and PGP/MIME (multipart/signed, multipart/encrypted)
+// messages. Their content is passed on byte for byte: the signature covers the exact headers and line
+// endings of the signed part, so anything that rewrites messages has to leave these alone.
+pub fn is_signed_or_encrypted(content: &[u8]) -> bool {
+    let Some(content_type) = header(content, "Content-Type") else { return false };
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    matches!(media_type.as_str(),
+        "multipart/signed" | "multipart/encrypted" | "application/pkcs7-mime" | "application/x-pkcs7-mime")
+}
+
+// text/html of "text/html; charset=utf-8"
+pub(crate) fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+// Value of a Content-Type parameter, unquoted
+pub(crate) fn parameter(content_type: &str, name: &str) -> Option<String> {
+    content_type.split(';').skip(1)
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+// Header lines up to the blank line, and the body after it
+pub(crate) fn split_header(content: &[u8]) -> Option<(&[u8], &[u8])> {
+    let crlf = content.windows(4).position(|window| window == b"\r\n\r\n").map(|index| (index + 2, index + 4));
+    let lf = content.windows(2).position(|window| window == b"\n\n").map(|index| (index + 1, index + 2));
+    let (head_end, body_start) = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => if crlf.0 < lf.0 { crlf } else { lf },
+        (crlf, lf) => crlf.or(lf)?,
+    };
+    Some((&content[..head_end], &content[body_start..]))
+}
+
+// Header lines without Content-Type and Content-Transfer-Encoding, for a conversion that replaces them
+pub(crate) fn without_content_headers(head: &[u8]) -> Vec<u8> {
+    let mut kept = Vec::with_capacity(head.len());
+    let mut skipping = false;
+    for line in head.split_inclusive(|&byte| byte == b'\n') {
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name = line.split(|&byte| byte == b':').next().unwrap_or_default();
+            skipping = name.eq_ignore_ascii_case(b"Content-Type") || name.eq_ignore_ascii_case(b"Content-Transfer-Encoding");
+        }
+        if !skipping {
+            kept.extend_from_slice(line);
+        }
+    }
+    kept
+}
+
+// Content of a body in its transfer encoding, None for unknown encodings
+pub(crate) fn decode_body(body: &[u8], encoding: &str) -> Option<Vec<u8>> {
+    match encoding.trim() {
+        "base64" => {
+            let text: Vec<u8> = body.iter().copied().filter(|byte| !byte.is_ascii_whitespace()).collect();
+            STANDARD.decode(text).ok()
+        },
+        "quoted-printable" => Some(decode_quoted_printable(body)),
+        "" | "7bit" | "8bit" | "binary" => Some(body.to_vec()),
+        _ => None,
+    }
+}
+
+fn decode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let hex = |byte: u8| (byte as char).to_digit(16).map(|digit| digit as u8);
+    let mut decoded = Vec::with_capacity(body.len());
+    let mut index = 0;
+    while index < body.len() {
+        if body[index] != b'=' {
+            decoded.push(body[index]);
+            index += 1;
+            continue;
+        }
+        match (body.get(index + 1).copied(), body.get(index + 2).copied()) {
+            // Soft line break
+            (Some(b'\r'), Some(b'\n')) => index += 3,
+            (Some(b'\n'), _) => index += 2,
+            (Some(high), Some(low)) if hex(high).is_some() && hex(low).is_some() => {
+                decoded.push((hex(high).unwrap() << 4) | hex(low).unwrap());
+                index += 3;
+            },
+            _ => {
+                decoded.push(b'=');
+                index += 1;
+            },
+        }
+    }
+    decoded
+}
+
+// Base64 in lines of BASE64_LINE_LENGTH
+pub(crate) fn encode_base64(content: &[u8]) -> Vec<u8> {
+    let encoded = STANDARD.encode(content);
+    let mut wrapped = Vec::with_capacity(encoded.len() + encoded.len() / BASE64_LINE_LENGTH * 2 + 2);
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LENGTH) {
+        wrapped.extend_from_slice(line);
+        wrapped.extend_from_slice(b"\r\n");
+    }
+    wrapped
+}
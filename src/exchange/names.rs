@@ -0,0 +1,110 @@
+// exchange/names.rs
+// Names of the well-known folders as IMAP clients see them: the English names used throughout the
+// gateway, or the display names of the mailbox language ("Gesendete Elemente", "Éléments envoyés")
+
+use config::Config;
+use log::{debug, warn};
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::{self, BaseShape, FolderRef, GetFolder};
+
+// Distinguished folders and their canonical names, in the order they are asked for
+const WELL_KNOWN: [(&str, &str); 5] = [
+    ("inbox", "INBOX"),
+    ("sentitems", "Sent Items"),
+    ("drafts", "Drafts"),
+    ("deleteditems", "Deleted Items"),
+    ("junkemail", "Junk Email"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FolderNames {
+    // The same English names whatever the mailbox language
+    #[default]
+    English,
+    // The display names of the mailbox, INBOX keeps its name as IMAP requires
+    Localized,
+}
+
+impl FolderNames {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "english" => Some(FolderNames::English),
+            "localized" => Some(FolderNames::Localized),
+            _ => None,
+        }
+    }
+
+    // davmail.folderNames
+    pub fn from_config(config: &Config) -> Self {
+        match config.get_string("davmail.folderNames") {
+            Ok(value) => FolderNames::parse(&value).unwrap_or_else(|| {
+                warn!("Unknown davmail.folderNames value '{}', using english", value);
+                FolderNames::English
+            }),
+            Err(_) => FolderNames::English,
+        }
+    }
+}
+
+impl ExchangeClient {
+    // Display names of the well-known folders as (canonical, localized), looked up once per session
+    async fn localized_names(&self) -> Result<Vec<(&'static str, String)>, ExchangeError> {
+        if let Some(names) = self.localized_names.lock().unwrap().clone() {
+            return Ok(names);
+        }
+
+        let response = self.send_request(&GetFolder {
+            shape: BaseShape::Default,
+            additional_properties: Vec::new(),
+            folders: WELL_KNOWN.iter().map(|(distinguished, _)| FolderRef::distinguished(distinguished)).collect(),
+        }).await?;
+
+        let names: Vec<(&'static str, String)> = WELL_KNOWN.iter()
+            .zip(response.descendants("Folders"))
+            .filter_map(|((_, canonical), folders)| {
+                let display_name = folders.descendants("DisplayName").first()?.text.trim().to_string();
+                (!display_name.is_empty()).then_some((*canonical, display_name))
+            })
+            .collect();
+        debug!("Localized folder names: {:?}", names);
+        *self.localized_names.lock().unwrap() = Some(names.clone());
+        Ok(names)
+    }
+
+    // Listed folder names with the well-known folders renamed for davmail.folderNames=localized
+    pub(crate) async fn present_folder_names(&self, folders: Vec<String>) -> Vec<String> {
+        if self.folder_names != FolderNames::Localized {
+            return folders;
+        }
+        match self.localized_names().await {
+            Ok(names) => folders.into_iter()
+                .map(|folder| match names.iter().find(|(canonical, _)| *canonical != "INBOX" && *canonical == folder) {
+                    Some((_, localized)) => localized.clone(),
+                    None => folder,
+                })
+                .collect(),
+            Err(e) => {
+                warn!("Could not look up localized folder names, listing English names: {}", e);
+                folders
+            },
+        }
+    }
+
+    // The English name of a well-known folder given by its localized name, so selecting "Posteingang"
+    // or "Sent Items" opens the same folder with the same UIDs. Other names are returned as they are.
+    pub async fn canonical_folder_name(&self, folder_name: &str) -> String {
+        if self.folder_names != FolderNames::Localized || request::distinguished_folder(folder_name).is_some() {
+            return folder_name.to_string();
+        }
+        match self.localized_names().await {
+            Ok(names) => names.into_iter()
+                .find(|(_, localized)| localized.to_lowercase() == folder_name.to_lowercase())
+                .map_or_else(|| folder_name.to_string(), |(canonical, _)| canonical.to_string()),
+            Err(e) => {
+                debug!("Could not look up localized folder names: {}", e);
+                folder_name.to_string()
+            },
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// exchange/proxyauth.rs
+// NTLM and Negotiate authentication to the outbound HTTP proxy
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use log::{debug, error, info, warn};
+
+use crate::auth::NtlmAuth;
+use super::http::ProxyConfig;
+
+// How the gateway authenticates to the proxy (davmail.proxy.authScheme)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyAuthScheme {
+    Basic,
+    Ntlm,
+    // SPNEGO accepts raw NTLM tokens, which is what Windows clients fall back to without Kerberos
+    Negotiate,
+}
+
+impl ProxyAuthScheme {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "basic" => Some(ProxyAuthScheme::Basic),
+            "ntlm" => Some(ProxyAuthScheme::Ntlm),
+            "negotiate" | "kerberos" => Some(ProxyAuthScheme::Negotiate),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ProxyAuthScheme::Basic => "Basic",
+            ProxyAuthScheme::Ntlm => "NTLM",
+            ProxyAuthScheme::Negotiate => "Negotiate",
+        }
+    }
+}
+
+// Relays already listening, keyed by upstream proxy and account
+static RELAYS: OnceLock<Mutex<HashMap<String, SocketAddr>>> = OnceLock::new();
+
+// reqwest can only send a fixed Proxy-Authorization header, while NTLM needs a challenge round trip
+// on the CONNECT connection. The HTTP client goes through a local relay instead, which runs the
+// handshake with the real proxy and then forwards the tunnel as is.
+pub fn relay_address(proxy: &ProxyConfig) -> io::Result<SocketAddr> {
+    let relays = RELAYS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut relays = relays.lock().unwrap();
+
+    let key = format!("{}:{}/{}", proxy.host, proxy.port, proxy.username.as_deref().unwrap_or(""));
+    if let Some(address) = relays.get(&key) {
+        return Ok(*address);
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    info!("Relaying proxy connections to {}:{} through {} with {} authentication",
+        proxy.host, proxy.port, address, proxy.auth_scheme.name());
+
+    let proxy = Arc::new(proxy.clone());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let proxy = proxy.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = relay(stream, &proxy) {
+                            debug!("Proxy relay connection ended: {}", e);
+                        }
+                    });
+                },
+                Err(e) => error!("Error accepting proxy relay connection: {}", e),
+            }
+        }
+    });
+
+    relays.insert(key, address);
+    Ok(address)
+}
+
+fn relay(mut client: TcpStream, proxy: &ProxyConfig) -> io::Result<()> {
+    let mut client_reader = BufReader::new(client.try_clone()?);
+    let (request_line, _) = read_head(&mut client_reader)?;
+
+    // Exchange is only reached over TLS, so the HTTP client always opens a tunnel
+    let target = match request_line.strip_prefix("CONNECT ").and_then(|rest| rest.split_whitespace().next()) {
+        Some(target) => target.to_string(),
+        None => {
+            warn!("Proxy relay only supports CONNECT, got '{}'", request_line);
+            client.write_all(b"HTTP/1.1 501 Not Implemented\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(());
+        }
+    };
+
+    let upstream = match connect_tunnel(proxy, &target) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            error!("Proxy authentication for {} failed: {}", target, e);
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(());
+        }
+    };
+    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+
+    // TLS records flow both ways until either side closes
+    let mut upstream_writer = upstream.try_clone()?;
+    let mut client_writer = client.try_clone()?;
+    let mut upstream_reader = upstream;
+    let to_upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.shutdown(std::net::Shutdown::Write);
+    });
+    let _ = io::copy(&mut upstream_reader, &mut client_writer);
+    let _ = client_writer.shutdown(std::net::Shutdown::Write);
+    let _ = to_upstream.join();
+    Ok(())
+}
+
+// CONNECT through the proxy, answering its NTLM challenge on the same connection
+fn connect_tunnel(proxy: &ProxyConfig, target: &str) -> io::Result<TcpStream> {
+    let ntlm = NtlmAuth::new(proxy.username.as_deref().unwrap_or(""), proxy.password.as_deref().unwrap_or(""));
+    let scheme = proxy.auth_scheme.name();
+
+    let mut upstream = TcpStream::connect((proxy.host.as_str(), proxy.port))?;
+    let mut reader = BufReader::new(upstream.try_clone()?);
+
+    send_connect(&mut upstream, target, &format!("{} {}", scheme, ntlm.negotiate_token()))?;
+    let (status_line, headers) = read_head(&mut reader)?;
+    if status_code(&status_line) == Some(200) {
+        // Some proxies let the connection through without completing the handshake
+        return Ok(upstream);
+    }
+    if status_code(&status_line) != Some(407) {
+        return Err(io::Error::other(format!("unexpected proxy response '{}'", status_line)));
+    }
+    skip_body(&mut reader, &headers)?;
+
+    let challenge = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Proxy-Authenticate"))
+        .find_map(|(_, value)| value.strip_prefix(scheme).map(str::trim).filter(|token| !token.is_empty()))
+        .ok_or_else(|| io::Error::other(format!("proxy did not send a {} challenge", scheme)))?;
+    let authenticate = ntlm.authenticate_token(challenge)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    send_connect(&mut upstream, target, &format!("{} {}", scheme, authenticate))?;
+    let (status_line, _) = read_head(&mut reader)?;
+    match status_code(&status_line) {
+        Some(200) => {
+            debug!("Proxy tunnel to {} established", target);
+            Ok(upstream)
+        },
+        Some(407) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "proxy rejected the credentials")),
+        _ => Err(io::Error::other(format!("unexpected proxy response '{}'", status_line))),
+    }
+}
+
+fn send_connect(upstream: &mut TcpStream, target: &str, authorization: &str) -> io::Result<()> {
+    write!(upstream, "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: {}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
+        target, target, authorization)?;
+    upstream.flush()
+}
+
+// Start line and headers of an HTTP message
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut start_line = String::new();
+    if reader.read_line(&mut start_line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok((start_line.trim_end().to_string(), headers))
+}
+
+// The 407 body must be consumed before the next request goes over the connection
+fn skip_body<R: BufRead>(reader: &mut R, headers: &[(String, String)]) -> io::Result<()> {
+    let length = headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+        .unwrap_or(0);
+    io::copy(&mut reader.take(length), &mut io::sink())?;
+    Ok(())
+}
+
+fn status_code(status_line: &str) -> Option<u16> {
+    status_line.split_whitespace().nth(1)?.parse().ok()
+}
@@ -0,0 +1,92 @@
+// exchange/receipts.rs
+// Who answers read receipt requests (Disposition-Notification-To) of incoming mail: the IMAP client
+// with its own MDN, Exchange when the message is marked read, or nobody
+
+use config::Config;
+use log::{debug, warn};
+
+use super::{parse_sequence_set, ExchangeClient, ExchangeError};
+use super::request::{FieldPath, FieldUpdate, ItemChange, UpdateItem, XmlWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadReceipts {
+    // Exchange's receipts are suppressed, clients send RFC 8098 MDNs and keep $MDNSent themselves
+    #[default]
+    Client,
+    // Marking read lets Exchange send its receipt, clients see $MDNSent so they don't send a second one
+    Exchange,
+    // No receipts at all, $MDNSent keeps clients from asking the user
+    Suppress,
+}
+
+impl ReadReceipts {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "client" => Some(ReadReceipts::Client),
+            "exchange" => Some(ReadReceipts::Exchange),
+            "suppress" => Some(ReadReceipts::Suppress),
+            _ => None,
+        }
+    }
+
+    // davmail.readReceipts
+    pub fn from_config(config: &Config) -> Self {
+        match config.get_string("davmail.readReceipts") {
+            Ok(value) => ReadReceipts::parse(&value).unwrap_or_else(|| {
+                warn!("Unknown davmail.readReceipts value '{}', using client", value);
+                ReadReceipts::Client
+            }),
+            Err(_) => ReadReceipts::Client,
+        }
+    }
+
+    // SuppressReadReceipts of read flag changes
+    pub fn suppress_on_exchange(self) -> bool {
+        self != ReadReceipts::Exchange
+    }
+
+    // Keywords added to FLAGS of every message
+    pub fn flags(self) -> &'static [&'static str] {
+        match self {
+            ReadReceipts::Client => &[],
+            ReadReceipts::Exchange | ReadReceipts::Suppress => &["$MDNSent"],
+        }
+    }
+}
+
+// PidTagReadReceiptRequested, cleared once the client has sent its own MDN
+const READ_RECEIPT_REQUESTED: FieldPath = FieldPath::Extended { property_tag: "0x0029", property_type: "Boolean" };
+
+fn receipt_handled() -> FieldUpdate {
+    let mut w = XmlWriter::new();
+    w.open("t:Message", &[])
+        .open("t:ExtendedProperty", &[])
+        .empty("t:ExtendedFieldURI", &[("PropertyTag", "0x0029"), ("PropertyType", "Boolean")])
+        .element("t:Value", "false");
+    FieldUpdate::Set { field: READ_RECEIPT_REQUESTED, item: w.finish() }
+}
+
+impl ExchangeClient {
+    // STORE +FLAGS ($MDNSent). With client receipts the request is cleared so Exchange doesn't send a
+    // second receipt when the message is marked read, otherwise $MDNSent is already on every message.
+    pub async fn set_mdn_sent(&self, folder: &str, sequence_set: &str) -> Result<(), ExchangeError> {
+        if self.read_receipts != ReadReceipts::Client {
+            return Ok(());
+        }
+        let sequences = parse_sequence_set(sequence_set)?;
+        let item_ids = self.item_ids(folder, &sequences).await?;
+        if item_ids.is_empty() {
+            return Ok(());
+        }
+        debug!("Clearing the read receipt request of {} item(s) in '{}'", item_ids.len(), folder);
+
+        self.send_request(&UpdateItem {
+            conflict_resolution: "AutoResolve",
+            message_disposition: Some("SaveOnly"),
+            changes: item_ids.into_iter()
+                .map(|(_, item_id)| ItemChange { item_id, updates: vec![receipt_handled()] })
+                .collect(),
+        }).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,840 @@
+// exchange/request.rs
+// Typed builders for Exchange Web Services (EWS) SOAP requests
+
+use chrono::{DateTime, Utc};
+
+const SOAP_NS: &str = "http://schemas.xmlsoap.org/soap/envelope/";
+const TYPES_NS: &str = "http://schemas.microsoft.com/exchange/services/2006/types";
+const MESSAGES_NS: &str = "http://schemas.microsoft.com/exchange/services/2006/messages";
+
+// Escape a value for use in XML text or a double-quoted attribute
+pub fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters are not allowed in XML 1.0, drop them rather than break the request
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Minimal XML writer, every attribute value and text node goes through escape_xml
+pub struct XmlWriter {
+    buf: String,
+    stack: Vec<&'static str>,
+}
+
+impl XmlWriter {
+    pub fn new() -> Self {
+        XmlWriter {
+            buf: String::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    fn write_tag(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.buf.push('<');
+        self.buf.push_str(name);
+        for (key, value) in attrs {
+            self.buf.push(' ');
+            self.buf.push_str(key);
+            self.buf.push_str("=\"");
+            self.buf.push_str(&escape_xml(value));
+            self.buf.push('"');
+        }
+    }
+
+    pub fn open(&mut self, name: &'static str, attrs: &[(&str, &str)]) -> &mut Self {
+        self.write_tag(name, attrs);
+        self.buf.push('>');
+        self.stack.push(name);
+        self
+    }
+
+    pub fn empty(&mut self, name: &'static str, attrs: &[(&str, &str)]) -> &mut Self {
+        self.write_tag(name, attrs);
+        self.buf.push_str("/>");
+        self
+    }
+
+    pub fn text(&mut self, value: &str) -> &mut Self {
+        self.buf.push_str(&escape_xml(value));
+        self
+    }
+
+    pub fn close(&mut self) -> &mut Self {
+        if let Some(name) = self.stack.pop() {
+            self.buf.push_str("</");
+            self.buf.push_str(name);
+            self.buf.push('>');
+        }
+        self
+    }
+
+    // Insert a fragment that was itself produced by an XmlWriter
+    pub fn raw(&mut self, xml: &str) -> &mut Self {
+        self.buf.push_str(xml);
+        self
+    }
+
+    // Element with a single text child
+    pub fn element(&mut self, name: &'static str, value: &str) -> &mut Self {
+        self.open(name, &[]).text(value).close()
+    }
+
+    pub fn finish(mut self) -> String {
+        while !self.stack.is_empty() {
+            self.close();
+        }
+        self.buf
+    }
+}
+
+impl Default for XmlWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traversal {
+    Shallow,
+    Deep,
+}
+
+impl Traversal {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Traversal::Shallow => "Shallow",
+            Traversal::Deep => "Deep",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseShape {
+    IdOnly,
+    Default,
+    AllProperties,
+}
+
+impl BaseShape {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BaseShape::IdOnly => "IdOnly",
+            BaseShape::Default => "Default",
+            BaseShape::AllProperties => "AllProperties",
+        }
+    }
+}
+
+// Reference to a folder, either well-known (inbox, sentitems...) or by EWS FolderId
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FolderRef {
+    Distinguished(String),
+    Id(String),
+}
+
+impl FolderRef {
+    // Short form for logs, folder ids are long opaque strings
+    pub fn label(&self) -> String {
+        match self {
+            FolderRef::Distinguished(id) => id.clone(),
+            FolderRef::Id(id) => format!("id:{}", &id[id.len().saturating_sub(8)..]),
+        }
+    }
+
+    pub fn distinguished(id: &str) -> Self {
+        FolderRef::Distinguished(id.to_string())
+    }
+
+    fn write(&self, w: &mut XmlWriter) {
+        match self {
+            FolderRef::Distinguished(id) => w.empty("t:DistinguishedFolderId", &[("Id", id)]),
+            FolderRef::Id(id) => w.empty("t:FolderId", &[("Id", id)]),
+        };
+    }
+}
+
+// Map an IMAP mailbox name to the matching EWS distinguished folder, if any
+pub fn distinguished_folder(name: &str) -> Option<&'static str> {
+    match name.to_uppercase().as_str() {
+        "INBOX" => Some("inbox"),
+        "SENT" | "SENT ITEMS" => Some("sentitems"),
+        "DRAFTS" => Some("drafts"),
+        "TRASH" | "DELETED ITEMS" => Some("deleteditems"),
+        "JUNK" | "JUNK EMAIL" => Some("junkemail"),
+        _ => None,
+    }
+}
+
+// EWS ItemId, the change key is needed for updates with conflict detection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemId {
+    pub id: String,
+    pub change_key: Option<String>,
+}
+
+impl ItemId {
+    pub fn new(id: &str) -> Self {
+        ItemId {
+            id: id.to_string(),
+            change_key: None,
+        }
+    }
+
+    fn write(&self, w: &mut XmlWriter) {
+        match &self.change_key {
+            Some(change_key) => w.empty("t:ItemId", &[("Id", &self.id), ("ChangeKey", change_key)]),
+            None => w.empty("t:ItemId", &[("Id", &self.id)]),
+        };
+    }
+}
+
+// Property path, either a plain FieldURI, an indexed one (contacts:EmailAddress / EmailAddress1)
+// or a MAPI property without an EWS field (0x1081 / Integer)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldPath {
+    Field(&'static str),
+    Indexed { field_uri: &'static str, field_index: &'static str },
+    Extended { property_tag: &'static str, property_type: &'static str },
+}
+
+impl FieldPath {
+    fn write(&self, w: &mut XmlWriter) {
+        match self {
+            FieldPath::Field(field_uri) => w.empty("t:FieldURI", &[("FieldURI", field_uri)]),
+            FieldPath::Indexed { field_uri, field_index } => {
+                w.empty("t:IndexedFieldURI", &[("FieldURI", field_uri), ("FieldIndex", field_index)])
+            },
+            FieldPath::Extended { property_tag, property_type } => {
+                w.empty("t:ExtendedFieldURI", &[("PropertyTag", property_tag), ("PropertyType", property_type)])
+            },
+        };
+    }
+}
+
+// Search filter applied to FindFolder / FindItem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Restriction {
+    IsEqualTo { field: FieldPath, value: String },
+    IsGreaterThan { field: FieldPath, value: String },
+    // Case-insensitive substring match
+    Contains { field: FieldPath, value: String },
+    Or(Vec<Restriction>),
+}
+
+impl Restriction {
+    fn write(&self, w: &mut XmlWriter) {
+        w.open("m:Restriction", &[]);
+        self.write_expression(w);
+        w.close();
+    }
+
+    fn write_expression(&self, w: &mut XmlWriter) {
+        match self {
+            Restriction::IsEqualTo { field, value } => {
+                w.open("t:IsEqualTo", &[]);
+                field.write(w);
+                w.open("t:FieldURIOrConstant", &[])
+                    .empty("t:Constant", &[("Value", value)])
+                    .close()
+                    .close();
+            },
+            Restriction::IsGreaterThan { field, value } => {
+                w.open("t:IsGreaterThan", &[]);
+                field.write(w);
+                w.open("t:FieldURIOrConstant", &[])
+                    .empty("t:Constant", &[("Value", value)])
+                    .close()
+                    .close();
+            },
+            Restriction::Contains { field, value } => {
+                w.open("t:Contains", &[("ContainmentMode", "Substring"), ("ContainmentComparison", "IgnoreCase")]);
+                field.write(w);
+                w.empty("t:Constant", &[("Value", value)]).close();
+            },
+            Restriction::Or(restrictions) => {
+                w.open("t:Or", &[]);
+                for restriction in restrictions {
+                    restriction.write_expression(w);
+                }
+                w.close();
+            },
+        }
+    }
+}
+
+fn write_shape(w: &mut XmlWriter, element: &'static str, shape: BaseShape, additional_properties: &[&'static str], extended_properties: &[FieldPath]) {
+    w.open(element, &[]).element("t:BaseShape", shape.as_str());
+    if !additional_properties.is_empty() || !extended_properties.is_empty() {
+        w.open("t:AdditionalProperties", &[]);
+        for field_uri in additional_properties {
+            w.empty("t:FieldURI", &[("FieldURI", field_uri)]);
+        }
+        for field in extended_properties {
+            field.write(w);
+        }
+        w.close();
+    }
+    w.close();
+}
+
+// An EWS operation that can be serialized into a SOAP envelope
+pub trait EwsRequest {
+    // Operation name, used to tag latency and size measurements
+    fn operation(&self) -> &'static str;
+
+    // Folder the operation targets, when there is a single one
+    fn folder(&self) -> Option<&FolderRef> {
+        None
+    }
+
+    // Moves message content or many items at once, given davmail.http.bulkTimeout instead of the interactive deadline
+    fn bulk(&self) -> bool {
+        false
+    }
+
+    // Write the operation element (m:FindFolder, m:GetItem...) inside soap:Body
+    fn write_body(&self, w: &mut XmlWriter);
+
+    fn to_soap(&self) -> String {
+        let mut w = XmlWriter::new();
+        w.raw(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        w.open("soap:Envelope", &[("xmlns:soap", SOAP_NS), ("xmlns:t", TYPES_NS), ("xmlns:m", MESSAGES_NS)])
+            .open("soap:Body", &[]);
+        self.write_body(&mut w);
+        w.finish()
+    }
+}
+
+pub struct FindFolder {
+    pub traversal: Traversal,
+    pub shape: BaseShape,
+    pub restriction: Option<Restriction>,
+    pub parent: FolderRef,
+}
+
+impl EwsRequest for FindFolder {
+    fn operation(&self) -> &'static str {
+        "FindFolder"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        Some(&self.parent)
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:FindFolder", &[("Traversal", self.traversal.as_str())]);
+        write_shape(w, "m:FolderShape", self.shape, &[], &[]);
+        if let Some(restriction) = &self.restriction {
+            restriction.write(w);
+        }
+        w.open("m:ParentFolderIds", &[]);
+        self.parent.write(w);
+        w.close().close();
+    }
+}
+
+pub struct GetFolder {
+    pub shape: BaseShape,
+    pub additional_properties: Vec<&'static str>,
+    // One response message per folder, in this order
+    pub folders: Vec<FolderRef>,
+}
+
+impl EwsRequest for GetFolder {
+    fn operation(&self) -> &'static str {
+        "GetFolder"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        self.folders.first()
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:GetFolder", &[]);
+        write_shape(w, "m:FolderShape", self.shape, &self.additional_properties, &[]);
+        w.open("m:FolderIds", &[]);
+        for folder in &self.folders {
+            folder.write(w);
+        }
+        w.close().close();
+    }
+}
+
+// Paging mode of a FindItem request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemView {
+    Indexed { max_entries: u32, offset: u32 },
+    // Calendar folders only, recurring series are expanded into occurrences by the server
+    Calendar { start: DateTime<Utc>, end: DateTime<Utc>, max_entries: Option<u32> },
+}
+
+impl ItemView {
+    fn write(&self, w: &mut XmlWriter) {
+        match self {
+            ItemView::Indexed { max_entries, offset } => {
+                let max_entries = max_entries.to_string();
+                let offset = offset.to_string();
+                w.empty("m:IndexedPageItemView", &[
+                    ("MaxEntriesReturned", &max_entries),
+                    ("Offset", &offset),
+                    ("BasePoint", "Beginning"),
+                ]);
+            },
+            ItemView::Calendar { start, end, max_entries } => {
+                let start = format_datetime(start);
+                let end = format_datetime(end);
+                let max_entries = max_entries.map(|max| max.to_string());
+                let mut attrs = vec![("StartDate", start.as_str()), ("EndDate", end.as_str())];
+                if let Some(max_entries) = &max_entries {
+                    attrs.insert(0, ("MaxEntriesReturned", max_entries.as_str()));
+                }
+                w.empty("m:CalendarView", &attrs);
+            },
+        }
+    }
+}
+
+// xs:dateTime in UTC as expected by EWS
+pub fn format_datetime(value: &DateTime<Utc>) -> String {
+    value.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+pub struct FindItem {
+    pub traversal: Traversal,
+    pub shape: BaseShape,
+    pub additional_properties: Vec<&'static str>,
+    pub extended_properties: Vec<FieldPath>,
+    pub view: ItemView,
+    pub restriction: Option<Restriction>,
+    pub parent: FolderRef,
+}
+
+impl EwsRequest for FindItem {
+    fn operation(&self) -> &'static str {
+        "FindItem"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        Some(&self.parent)
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:FindItem", &[("Traversal", self.traversal.as_str())]);
+        write_shape(w, "m:ItemShape", self.shape, &self.additional_properties, &self.extended_properties);
+        self.view.write(w);
+        if let Some(restriction) = &self.restriction {
+            restriction.write(w);
+        }
+        w.open("m:ParentFolderIds", &[]);
+        self.parent.write(w);
+        w.close().close();
+    }
+}
+
+pub struct GetItem {
+    pub shape: BaseShape,
+    pub additional_properties: Vec<&'static str>,
+    pub item_ids: Vec<ItemId>,
+}
+
+impl EwsRequest for GetItem {
+    fn operation(&self) -> &'static str {
+        "GetItem"
+    }
+
+    fn bulk(&self) -> bool {
+        self.additional_properties.contains(&"item:MimeContent")
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:GetItem", &[]);
+        write_shape(w, "m:ItemShape", self.shape, &self.additional_properties, &[]);
+        w.open("m:ItemIds", &[]);
+        for item_id in &self.item_ids {
+            item_id.write(w);
+        }
+        w.close().close();
+    }
+}
+
+// Changes to the items of a folder since `sync_state`, all items when it is None
+pub struct SyncFolderItems {
+    pub shape: BaseShape,
+    pub additional_properties: Vec<&'static str>,
+    pub extended_properties: Vec<FieldPath>,
+    pub folder: FolderRef,
+    pub sync_state: Option<String>,
+    // Up to 512, IncludesLastItemInRange in the response tells whether more are waiting
+    pub max_changes: u32,
+}
+
+impl EwsRequest for SyncFolderItems {
+    fn operation(&self) -> &'static str {
+        "SyncFolderItems"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        Some(&self.folder)
+    }
+
+    fn bulk(&self) -> bool {
+        true
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:SyncFolderItems", &[]);
+        write_shape(w, "m:ItemShape", self.shape, &self.additional_properties, &self.extended_properties);
+        w.open("m:SyncFolderId", &[]);
+        self.folder.write(w);
+        w.close();
+        if let Some(sync_state) = &self.sync_state {
+            w.element("m:SyncState", sync_state);
+        }
+        w.element("m:MaxChangesReturned", &self.max_changes.to_string());
+        w.close();
+    }
+}
+
+// Typed item (t:Contact, t:Task...) that can be sent in a CreateItem request
+pub trait ItemContent {
+    fn write_item(&self, w: &mut XmlWriter);
+}
+
+pub struct CreateItem<'a> {
+    pub saved_folder: Option<FolderRef>,
+    // SaveOnly, SendOnly or SendAndSaveCopy, only valid for messages
+    pub message_disposition: Option<&'static str>,
+    pub items: Vec<&'a dyn ItemContent>,
+}
+
+impl EwsRequest for CreateItem<'_> {
+    fn operation(&self) -> &'static str {
+        "CreateItem"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        self.saved_folder.as_ref()
+    }
+
+    // Imported and sent messages carry their whole MIME content
+    fn bulk(&self) -> bool {
+        true
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        match self.message_disposition {
+            Some(disposition) => w.open("m:CreateItem", &[("MessageDisposition", disposition)]),
+            None => w.open("m:CreateItem", &[]),
+        };
+        if let Some(folder) = &self.saved_folder {
+            w.open("m:SavedItemFolderId", &[]);
+            folder.write(w);
+            w.close();
+        }
+        w.open("m:Items", &[]);
+        for item in &self.items {
+            item.write_item(w);
+        }
+        w.close().close();
+    }
+}
+
+// Change applied by UpdateItem, `item` is the serialized item element holding the new value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldUpdate {
+    Set { field: FieldPath, item: String },
+    Delete { field: FieldPath },
+}
+
+impl FieldUpdate {
+    fn write(&self, w: &mut XmlWriter) {
+        match self {
+            FieldUpdate::Set { field, item } => {
+                w.open("t:SetItemField", &[]);
+                field.write(w);
+                w.raw(item).close();
+            },
+            FieldUpdate::Delete { field } => {
+                w.open("t:DeleteItemField", &[]);
+                field.write(w);
+                w.close();
+            },
+        }
+    }
+}
+
+pub struct ItemChange {
+    pub item_id: ItemId,
+    pub updates: Vec<FieldUpdate>,
+}
+
+pub struct UpdateItem {
+    // AutoResolve uses the change key to detect concurrent modifications
+    pub conflict_resolution: &'static str,
+    pub message_disposition: Option<&'static str>,
+    pub changes: Vec<ItemChange>,
+}
+
+impl EwsRequest for UpdateItem {
+    fn operation(&self) -> &'static str {
+        "UpdateItem"
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        match self.message_disposition {
+            Some(disposition) => w.open("m:UpdateItem", &[
+                ("ConflictResolution", self.conflict_resolution),
+                ("MessageDisposition", disposition),
+            ]),
+            None => w.open("m:UpdateItem", &[("ConflictResolution", self.conflict_resolution)]),
+        };
+        w.open("m:ItemChanges", &[]);
+        for change in &self.changes {
+            w.open("t:ItemChange", &[]);
+            change.item_id.write(w);
+            w.open("t:Updates", &[]);
+            for update in &change.updates {
+                update.write(w);
+            }
+            w.close().close();
+        }
+        w.close().close();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteType {
+    HardDelete,
+    SoftDelete,
+    MoveToDeletedItems,
+}
+
+impl DeleteType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeleteType::HardDelete => "HardDelete",
+            DeleteType::SoftDelete => "SoftDelete",
+            DeleteType::MoveToDeletedItems => "MoveToDeletedItems",
+        }
+    }
+}
+
+pub struct DeleteItem {
+    pub delete_type: DeleteType,
+    // Required by Exchange when the items are tasks
+    pub affected_task_occurrences: Option<&'static str>,
+    pub item_ids: Vec<ItemId>,
+}
+
+impl EwsRequest for DeleteItem {
+    fn operation(&self) -> &'static str {
+        "DeleteItem"
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        match self.affected_task_occurrences {
+            Some(occurrences) => w.open("m:DeleteItem", &[
+                ("DeleteType", self.delete_type.as_str()),
+                ("AffectedTaskOccurrences", occurrences),
+            ]),
+            None => w.open("m:DeleteItem", &[("DeleteType", self.delete_type.as_str())]),
+        };
+        w.open("m:ItemIds", &[]);
+        for item_id in &self.item_ids {
+            item_id.write(w);
+        }
+        w.close().close();
+    }
+}
+
+pub struct MoveItem {
+    pub to_folder: FolderRef,
+    pub item_ids: Vec<ItemId>,
+}
+
+impl EwsRequest for MoveItem {
+    fn operation(&self) -> &'static str {
+        "MoveItem"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        Some(&self.to_folder)
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:MoveItem", &[]).open("m:ToFolderId", &[]);
+        self.to_folder.write(w);
+        w.close().open("m:ItemIds", &[]);
+        for item_id in &self.item_ids {
+            item_id.write(w);
+        }
+        w.close().close();
+    }
+}
+
+// Junk report that trains the user's filter (Exchange 2013 and later): the sender is added to the
+// blocked or removed from the blocked senders, with MoveItem the item goes to Junk Email or Inbox
+pub struct MarkAsJunk {
+    pub is_junk: bool,
+    pub move_item: bool,
+    pub item_ids: Vec<ItemId>,
+}
+
+impl EwsRequest for MarkAsJunk {
+    fn operation(&self) -> &'static str {
+        "MarkAsJunk"
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:MarkAsJunk", &[
+            ("IsJunk", if self.is_junk { "true" } else { "false" }),
+            ("MoveItem", if self.move_item { "true" } else { "false" }),
+        ]).open("m:ItemIds", &[]);
+        for item_id in &self.item_ids {
+            item_id.write(w);
+        }
+        w.close().close();
+    }
+}
+
+// Item / folder id representations understood by ConvertId
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    EwsId,
+    EwsLegacyId,
+    EntryId,
+    HexEntryId,
+    StoreId,
+    OwaId,
+}
+
+impl IdFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdFormat::EwsId => "EwsId",
+            IdFormat::EwsLegacyId => "EwsLegacyId",
+            IdFormat::EntryId => "EntryId",
+            IdFormat::HexEntryId => "HexEntryId",
+            IdFormat::StoreId => "StoreId",
+            IdFormat::OwaId => "OwaId",
+        }
+    }
+}
+
+pub struct ConvertId {
+    pub destination_format: IdFormat,
+    pub source_format: IdFormat,
+    // Primary SMTP address of the mailbox owning the ids
+    pub mailbox: String,
+    pub ids: Vec<String>,
+}
+
+impl EwsRequest for ConvertId {
+    fn operation(&self) -> &'static str {
+        "ConvertId"
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:ConvertId", &[("DestinationFormat", self.destination_format.as_str())])
+            .open("m:SourceIds", &[]);
+        for id in &self.ids {
+            w.empty("t:AlternateId", &[
+                ("Format", self.source_format.as_str()),
+                ("Id", id),
+                ("Mailbox", &self.mailbox),
+            ]);
+        }
+        w.close().close();
+    }
+}
+
+pub struct MarkAllItemsAsRead {
+    pub read: bool,
+    pub suppress_read_receipts: bool,
+    pub folders: Vec<FolderRef>,
+}
+
+impl EwsRequest for MarkAllItemsAsRead {
+    fn operation(&self) -> &'static str {
+        "MarkAllItemsAsRead"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        self.folders.first()
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:MarkAllItemsAsRead", &[])
+            .element("m:ReadFlag", if self.read { "true" } else { "false" })
+            .element("m:SuppressReadReceipts", if self.suppress_read_receipts { "true" } else { "false" })
+            .open("m:FolderIds", &[]);
+        for folder in &self.folders {
+            folder.write(w);
+        }
+        w.close().close();
+    }
+}
+
+pub struct EmptyFolder {
+    pub delete_type: DeleteType,
+    pub delete_sub_folders: bool,
+    pub folders: Vec<FolderRef>,
+}
+
+impl EwsRequest for EmptyFolder {
+    fn operation(&self) -> &'static str {
+        "EmptyFolder"
+    }
+
+    fn folder(&self) -> Option<&FolderRef> {
+        self.folders.first()
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:EmptyFolder", &[
+            ("DeleteType", self.delete_type.as_str()),
+            ("DeleteSubFolders", if self.delete_sub_folders { "true" } else { "false" }),
+        ]).open("m:FolderIds", &[]);
+        for folder in &self.folders {
+            folder.write(w);
+        }
+        w.close().close();
+    }
+}
+
+pub struct GetServerTimeZones {
+    // Without full data only ids and names are returned, periods and transitions are needed for offsets
+    pub return_full_time_zone_data: bool,
+    // Empty requests every time zone the server knows
+    pub ids: Vec<String>,
+}
+
+impl EwsRequest for GetServerTimeZones {
+    fn operation(&self) -> &'static str {
+        "GetServerTimeZones"
+    }
+
+    fn write_body(&self, w: &mut XmlWriter) {
+        w.open("m:GetServerTimeZones", &[
+            ("ReturnFullTimeZoneData", if self.return_full_time_zone_data { "true" } else { "false" }),
+        ]);
+        if !self.ids.is_empty() {
+            w.open("m:Ids", &[]);
+            for id in &self.ids {
+                w.element("t:Id", id);
+            }
+            w.close();
+        }
+        w.close();
+    }
+}
@@ -0,0 +1,157 @@
+// exchange/response.rs
+// Parsing of Exchange Web Services (EWS) SOAP responses
+
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::Event;
+use quick_xml::{Reader, XmlVersion};
+
+use super::ExchangeError;
+use super::request::ItemId;
+
+// EWS responses nest about a dozen levels, walking and dropping the tree recurse once per level
+const MAX_DEPTH: usize = 64;
+
+// Parsed XML element, names are stored without their namespace prefix
+#[derive(Debug, Clone, Default)]
+pub struct XmlElement {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlElement>,
+    pub text: String,
+}
+
+impl XmlElement {
+    pub fn parse(xml: &str) -> Result<XmlElement, ExchangeError> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        let parse_error = |e: quick_xml::Error| ExchangeError::ParseError(format!("Invalid XML response: {}", e));
+
+        // Synthetic root so the document element is always its first child
+        let mut stack = vec![XmlElement::default()];
+        loop {
+            match reader.read_event().map_err(parse_error)? {
+                Event::Start(start) => {
+                    if stack.len() > MAX_DEPTH {
+                        return Err(ExchangeError::ParseError("XML response nested too deeply".to_string()));
+                    }
+                    stack.push(XmlElement::from_start(&start)?);
+                },
+                Event::Empty(start) => {
+                    let element = XmlElement::from_start(&start)?;
+                    stack.last_mut().unwrap().children.push(element);
+                },
+                Event::End(_) => {
+                    let element = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Err(ExchangeError::ParseError("Unbalanced XML response".to_string())),
+                    }
+                },
+                Event::Text(text) => {
+                    stack.last_mut().unwrap().text.push_str(&text.xml10_content());
+                },
+                Event::CData(data) => {
+                    stack.last_mut().unwrap().text.push_str(&data.xml10_content());
+                },
+                Event::GeneralRef(reference) => {
+                    let resolved = match reference.resolve_char_ref().map_err(parse_error)? {
+                        Some(c) => c.to_string(),
+                        None => resolve_predefined_entity(&reference).unwrap_or_default().to_string(),
+                    };
+                    stack.last_mut().unwrap().text.push_str(&resolved);
+                },
+                Event::Eof => break,
+                _ => {},
+            }
+        }
+
+        let mut root = stack.pop()
+            .filter(|_| stack.is_empty())
+            .ok_or_else(|| ExchangeError::ParseError("Unbalanced XML response".to_string()))?;
+        if root.children.is_empty() {
+            return Err(ExchangeError::ParseError("Empty XML response".to_string()));
+        }
+        Ok(root.children.remove(0))
+    }
+
+    fn from_start(start: &quick_xml::events::BytesStart) -> Result<XmlElement, ExchangeError> {
+        let mut attributes = Vec::new();
+        for attribute in start.attributes() {
+            let attribute = attribute.map_err(|e| ExchangeError::ParseError(format!("Invalid XML attribute: {}", e)))?;
+            let value = attribute.normalized_value(XmlVersion::Implicit1_0)
+                .map_err(|e| ExchangeError::ParseError(format!("Invalid XML attribute: {}", e)))?;
+            attributes.push((attribute.key.local_name().as_ref().to_string(), value.into_owned()));
+        }
+
+        Ok(XmlElement {
+            name: start.local_name().as_ref().to_string(),
+            attributes,
+            children: Vec::new(),
+            text: String::new(),
+        })
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    pub fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlElement> + 'a {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    pub fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.as_str())
+    }
+
+    // All elements with the given name anywhere below this one, in document order
+    pub fn descendants<'a>(&'a self, name: &str) -> Vec<&'a XmlElement> {
+        let mut found = Vec::new();
+        for child in &self.children {
+            if child.name == name {
+                found.push(child);
+            }
+            found.extend(child.descendants(name));
+        }
+        found
+    }
+}
+
+// Parse a SOAP envelope and turn faults or error response messages into errors
+pub fn parse_response(xml: &str) -> Result<XmlElement, ExchangeError> {
+    let envelope = XmlElement::parse(xml)?;
+
+    if let Some(fault) = envelope.descendants("Fault").first() {
+        let reason = fault.child_text("faultstring").unwrap_or("SOAP fault");
+        return Err(ExchangeError::ParseError(format!("EWS fault: {}", reason)));
+    }
+
+    // Every *ResponseMessage element carries ResponseClass="Success|Warning|Error"
+    let messages = envelope.descendants("ResponseMessages");
+    for message in messages.iter().flat_map(|messages| messages.children.iter()) {
+        if message.attr("ResponseClass") == Some("Error") {
+            let code = message.child_text("ResponseCode").unwrap_or("Unknown");
+            let text = message.child_text("MessageText").unwrap_or("");
+            return Err(ExchangeError::ParseError(format!("EWS error {}: {}", code, text)));
+        }
+    }
+
+    Ok(envelope)
+}
+
+// Read the ItemId child of an item element
+pub fn item_id(element: &XmlElement) -> Result<ItemId, ExchangeError> {
+    let item_id = element.child("ItemId")
+        .ok_or_else(|| ExchangeError::ParseError(format!("{} without ItemId", element.name)))?;
+
+    Ok(ItemId {
+        id: item_id.attr("Id").unwrap_or_default().to_string(),
+        change_key: item_id.attr("ChangeKey").map(str::to_string),
+    })
+}
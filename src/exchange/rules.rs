@@ -0,0 +1,212 @@
+// exchange/rules.rs
+// Incoming mail rules (see rules.rs) carried out on the items of a folder with EWS
+
+use chrono::Utc;
+use log::{debug, info};
+
+use super::{response, ExchangeClient, ExchangeError};
+use super::response::XmlElement;
+use super::request::{
+    format_datetime, BaseShape, DeleteItem, DeleteType, FieldPath, FieldUpdate, FindItem, FolderRef, ItemChange, ItemId,
+    ItemView, MoveItem, Restriction, Traversal, UpdateItem, XmlWriter,
+};
+use crate::rules::{Rule, RulesPass};
+
+// Items asked for at once
+const RULES_BATCH_SIZE: u32 = 200;
+
+const RULE_PROPERTIES: [&str; 5] = ["message:From", "item:Subject", "message:IsRead", "item:Categories", "item:DateTimeReceived"];
+
+struct RuleItem {
+    id: ItemId,
+    // "Name <address>", so a rule can match either
+    from: String,
+    subject: String,
+    is_read: bool,
+    categories: Vec<String>,
+    // xs:dateTime in UTC, compares as a string
+    received: String,
+}
+
+impl RuleItem {
+    fn from_xml(element: &XmlElement) -> Result<RuleItem, ExchangeError> {
+        let mailbox = element.child("From").and_then(|from| from.child("Mailbox"));
+        let from = mailbox.map(|mailbox| format!("{} <{}>",
+            mailbox.child_text("Name").unwrap_or_default(), mailbox.child_text("EmailAddress").unwrap_or_default()));
+        Ok(RuleItem {
+            id: response::item_id(element)?,
+            from: from.unwrap_or_default(),
+            subject: element.child_text("Subject").unwrap_or_default().to_string(),
+            is_read: element.child_text("IsRead") == Some("true"),
+            categories: element.child("Categories")
+                .map(|categories| categories.children_named("String").map(|category| category.text.clone()).collect())
+                .unwrap_or_default(),
+            received: element.child_text("DateTimeReceived").unwrap_or_default().to_string(),
+        })
+    }
+}
+
+fn message_fragment(write: impl FnOnce(&mut XmlWriter)) -> String {
+    let mut w = XmlWriter::new();
+    w.open("t:Message", &[]);
+    write(&mut w);
+    w.finish()
+}
+
+fn find_rule_items(folder: &FolderRef, restriction: Option<Restriction>, max_entries: u32, offset: u32) -> FindItem {
+    FindItem {
+        traversal: Traversal::Shallow,
+        shape: BaseShape::IdOnly,
+        additional_properties: RULE_PROPERTIES.to_vec(),
+        extended_properties: Vec::new(),
+        view: ItemView::Indexed { max_entries, offset },
+        restriction,
+        parent: folder.clone(),
+    }
+}
+
+impl ExchangeClient {
+    // DateTimeReceived of the newest item of the folder
+    async fn newest_received(&self, folder: &FolderRef) -> Result<Option<String>, ExchangeError> {
+        let response = self.send_request(&find_rule_items(folder, None, 1, 0)).await?;
+        let newest = response.descendants("Message").first().copied().map(RuleItem::from_xml).transpose()?;
+        Ok(newest.map(|item| item.received))
+    }
+
+    // Every item received after `received_after`, in batches until Exchange reports the last one
+    async fn items_received_after(&self, folder: &FolderRef, received_after: &str) -> Result<Vec<RuleItem>, ExchangeError> {
+        let restriction = Restriction::IsGreaterThan {
+            field: FieldPath::Field("item:DateTimeReceived"),
+            value: received_after.to_string(),
+        };
+        let mut items: Vec<RuleItem> = Vec::new();
+        loop {
+            let request = find_rule_items(folder, Some(restriction.clone()), RULES_BATCH_SIZE, items.len() as u32);
+            let response = self.send_request(&request).await?;
+            let batch = response.descendants("Message")
+                .into_iter()
+                .map(RuleItem::from_xml)
+                .collect::<Result<Vec<_>, _>>()?;
+            let last = batch.is_empty() || response.descendants("RootFolder").first()
+                .and_then(|root| root.attr("IncludesLastItemInRange"))
+                .is_none_or(|last| last == "true");
+            // Mail arriving between two batches pushes items already seen into the next one
+            for item in batch {
+                if !items.iter().any(|seen| seen.id.id == item.id.id) {
+                    items.push(item);
+                }
+            }
+            if last {
+                return Ok(items);
+            }
+        }
+    }
+
+    // Apply the first matching rule to each item received after `received_after`. Without it only the
+    // newest item is looked up, mail that was in the folder before the rules watched it is left alone,
+    // and the returned watermark is where the next pass starts.
+    pub async fn apply_rules(&self, folder_name: &str, rules: &[&Rule], received_after: Option<&str>) -> Result<RulesPass, ExchangeError> {
+        if rules.is_empty() {
+            return Ok(RulesPass { applied: 0, watermark: received_after.map(str::to_string) });
+        }
+
+        let folder = self.resolve_folder(folder_name).await?;
+        let Some(received_after) = received_after else {
+            // An empty folder starts from now
+            let watermark = self.newest_received(&folder).await?.unwrap_or_else(|| format_datetime(&Utc::now()));
+            debug!("Mail rules of '{}' start after {}", folder_name, watermark);
+            return Ok(RulesPass { applied: 0, watermark: Some(watermark) });
+        };
+        let items = self.items_received_after(&folder, received_after).await?;
+        let watermark = items.iter()
+            .map(|item| item.received.as_str())
+            .fold(received_after, |newest, received| newest.max(received))
+            .to_string();
+        debug!("Applying {} mail rule(s) to {} new item(s) of '{}'", rules.len(), items.len(), folder_name);
+
+        let mut changes = Vec::new();
+        // Grouped by target folder, one MoveItem each
+        let mut moves: Vec<(&str, Vec<ItemId>)> = Vec::new();
+        let mut drops = Vec::new();
+        let mut applied = 0;
+        for item in items {
+            let Some(rule) = rules.iter().find(|rule| rule.matches(&item.from, &item.subject)) else {
+                continue;
+            };
+            // Moves and deletes don't check the change key, which an update makes stale
+            let id = ItemId::new(&item.id.id);
+            if rule.actions.drop {
+                debug!("Rule {} drops '{}' from {}", rule.name, item.subject, item.from);
+                drops.push(id);
+                applied += 1;
+                continue;
+            }
+
+            let mut updates = Vec::new();
+            if rule.actions.mark_read && !item.is_read {
+                updates.push(FieldUpdate::Set {
+                    field: FieldPath::Field("message:IsRead"),
+                    item: message_fragment(|w| { w.element("t:IsRead", "true"); }),
+                });
+            }
+            if let Some(category) = &rule.actions.category {
+                if !item.categories.iter().any(|existing| existing.eq_ignore_ascii_case(category)) {
+                    let mut categories = item.categories.clone();
+                    categories.push(category.clone());
+                    updates.push(FieldUpdate::Set {
+                        field: FieldPath::Field("item:Categories"),
+                        item: message_fragment(|w| {
+                            w.open("t:Categories", &[]);
+                            for category in &categories {
+                                w.element("t:String", category);
+                            }
+                            w.close();
+                        }),
+                    });
+                }
+            }
+            let updated = !updates.is_empty();
+            if updated {
+                changes.push(ItemChange { item_id: item.id, updates });
+            }
+            if let Some(target) = &rule.actions.move_to {
+                match moves.iter_mut().find(|(folder, _)| folder == target) {
+                    Some((_, ids)) => ids.push(id),
+                    None => moves.push((target, vec![id])),
+                }
+            } else if !updated {
+                continue;
+            }
+            debug!("Rule {} applies to '{}' from {}", rule.name, item.subject, item.from);
+            applied += 1;
+        }
+
+        if !changes.is_empty() {
+            self.send_request(&UpdateItem {
+                conflict_resolution: "AutoResolve",
+                message_disposition: Some("SaveOnly"),
+                changes,
+            }).await?;
+        }
+        for (target, item_ids) in moves {
+            let to_folder = self.resolve_folder(target).await?;
+            self.send_request(&MoveItem { to_folder, item_ids }).await?;
+        }
+        if !drops.is_empty() {
+            self.send_request(&DeleteItem {
+                delete_type: DeleteType::MoveToDeletedItems,
+                affected_task_occurrences: None,
+                item_ids: drops,
+            }).await?;
+        }
+
+        if applied > 0 {
+            info!("Mail rules applied to {} message(s) in '{}'", applied, folder_name);
+            // Messages went to any number of folders
+            if let Some(folder_cache) = &self.folder_cache {
+                folder_cache.clear();
+            }
+        }
+        Ok(RulesPass { applied, watermark: Some(watermark) })
+    }
+}
@@ -0,0 +1,91 @@
+// exchange/sessions.rs
+// Authenticated Exchange sessions reused by later connections of the same user
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use config::Config;
+use log::debug;
+
+use crate::auth::SecretString;
+use crate::mailstore::MailStore;
+
+// Mail clients open several connections at once and reconnect often
+pub const DEFAULT_SESSION_TTL: u64 = 300;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub username: String,
+    // How the session authenticated (basic, ntlm, ropc, broker, bearer, obo), a session
+    // authenticated one way isn't handed out to a login using another
+    pub mode: &'static str,
+}
+
+struct CachedSession {
+    client: Arc<dyn MailStore>,
+    // Password or token the session was opened with, a login must present the same one to reuse it
+    secret: SecretString,
+    last_used: Instant,
+}
+
+// Sessions expire after `ttl` without a connection asking for them
+pub struct SessionCache {
+    ttl: Duration,
+    sessions: Mutex<HashMap<SessionKey, CachedSession>>,
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration) -> Self {
+        SessionCache {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // davmail.sessionCacheTtl in seconds, 0 disables the cache
+    pub fn from_config(config: &Config) -> Self {
+        let ttl = config.get_int("davmail.sessionCacheTtl").unwrap_or(DEFAULT_SESSION_TTL as i64).max(0) as u64;
+        SessionCache::new(Duration::from_secs(ttl))
+    }
+
+    pub fn get(&self, key: &SessionKey, secret: &str) -> Option<Arc<dyn MailStore>> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_expired(&mut sessions);
+
+        let session = sessions.get_mut(key)?;
+        if !session.secret.matches(secret) {
+            return None;
+        }
+        session.last_used = Instant::now();
+        debug!("Reusing Exchange session of {} ({})", key.username, key.mode);
+        Some(session.client.clone())
+    }
+
+    pub fn insert(&self, key: SessionKey, secret: &str, client: Arc<dyn MailStore>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_expired(&mut sessions);
+        sessions.insert(key, CachedSession {
+            client,
+            secret: SecretString::from(secret),
+            last_used: Instant::now(),
+        });
+    }
+
+    fn evict_expired(&self, sessions: &mut HashMap<SessionKey, CachedSession>) {
+        sessions.retain(|key, session| {
+            let live = session.last_used.elapsed() < self.ttl;
+            if !live {
+                debug!("Exchange session of {} ({}) expired", key.username, key.mode);
+            }
+            live
+        });
+    }
+}
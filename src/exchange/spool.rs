@@ -0,0 +1,258 @@
+// exchange/spool.rs
+// Message content kept in memory when small and in a temporary file otherwise
+
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use config::Config;
+use log::info;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// Larger messages go to disk, so a 50 MB attachment doesn't take 50 MB per connection
+pub const DEFAULT_SPOOL_THRESHOLD: usize = 1024 * 1024;
+
+// Bytes read from a spool file and handed to the client at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Names of the spool files of this process
+static NEXT_SPOOL_FILE: AtomicU64 = AtomicU64::new(0);
+
+// Process wide, memory is shared by all profiles
+static SPOOL_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_SPOOL_THRESHOLD);
+static SPOOL_DIRECTORY: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+// davmail.spoolThreshold in bytes and davmail.spoolDirectory (the system temporary directory by default),
+// applies to messages received from then on
+pub fn configure(config: &Config) {
+    let threshold = config.get_int("davmail.spoolThreshold")
+        .map_or(DEFAULT_SPOOL_THRESHOLD, |threshold| threshold.max(0) as usize);
+    let directory = config.get_string("davmail.spoolDirectory").ok()
+        .filter(|directory| !directory.trim().is_empty())
+        .map(|directory| PathBuf::from(directory.trim()));
+    if SPOOL_THRESHOLD.swap(threshold, Ordering::Relaxed) != threshold {
+        info!("Spooling messages larger than {} bytes to disk", threshold);
+    }
+    *SPOOL_DIRECTORY.write().unwrap() = directory;
+}
+
+// Raw RFC 822 content of a message, with the offset where the body starts
+#[derive(Debug)]
+pub struct MimeBody {
+    storage: Storage,
+    len: u64,
+    header_len: u64,
+}
+
+#[derive(Debug)]
+enum Storage {
+    Memory(Vec<u8>),
+    File(SpoolFile),
+}
+
+// Temporary file, removed once the last reference to the content is gone
+#[derive(Debug)]
+struct SpoolFile {
+    path: PathBuf,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl MimeBody {
+    pub fn from_bytes(content: Vec<u8>) -> Self {
+        let header_len = header_end(&content).unwrap_or(content.len()) as u64;
+        MimeBody { len: content.len() as u64, header_len, storage: Storage::Memory(content) }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Content of a message small enough to stay in memory, None for spooled ones
+    pub fn in_memory(&self) -> Option<&[u8]> {
+        match &self.storage {
+            Storage::Memory(content) => Some(content),
+            Storage::File(_) => None,
+        }
+    }
+}
+
+// Part of a message sent to a client as one IMAP literal
+#[derive(Debug, Clone)]
+pub struct BodySection {
+    body: Arc<MimeBody>,
+    range: Range<u64>,
+}
+
+impl BodySection {
+    pub fn whole(body: Arc<MimeBody>) -> Self {
+        let range = 0..body.len;
+        BodySection { body, range }
+    }
+
+    // Header block including the blank line
+    pub fn header(body: Arc<MimeBody>) -> Self {
+        let range = 0..body.header_len;
+        BodySection { body, range }
+    }
+
+    pub fn text(body: Arc<MimeBody>) -> Self {
+        let range = body.header_len..body.len;
+        BodySection { body, range }
+    }
+
+    // Generated content, small enough to stay in memory
+    pub fn from_text(text: String) -> Self {
+        BodySection::whole(Arc::new(MimeBody::from_bytes(text.into_bytes())))
+    }
+
+    // Exact number of bytes the reader returns, announced before the literal
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    pub async fn reader(&self) -> io::Result<SectionReader> {
+        match &self.body.storage {
+            Storage::Memory(_) => Ok(SectionReader::Memory { body: self.body.clone(), range: self.range.clone() }),
+            Storage::File(spool_file) => {
+                let mut file = File::open(&spool_file.path).await?;
+                file.seek(SeekFrom::Start(self.range.start)).await?;
+                Ok(SectionReader::File {
+                    file,
+                    remaining: self.len(),
+                    buffer: vec![0; CHUNK_SIZE],
+                    // Keeps the file from being removed while it is read
+                    _body: self.body.clone(),
+                })
+            },
+        }
+    }
+}
+
+// Hands out a section a chunk at a time
+pub enum SectionReader {
+    Memory { body: Arc<MimeBody>, range: Range<u64> },
+    File { file: File, remaining: u64, buffer: Vec<u8>, _body: Arc<MimeBody> },
+}
+
+impl SectionReader {
+    // Next chunk of the section, None once all of it was returned
+    pub async fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        match self {
+            SectionReader::Memory { body, range } => {
+                let Storage::Memory(content) = &body.storage else { unreachable!() };
+                if range.is_empty() {
+                    return Ok(None);
+                }
+                let start = range.start;
+                let end = range.end.min(start + CHUNK_SIZE as u64);
+                range.start = end;
+                Ok(Some(&content[start as usize..end as usize]))
+            },
+            SectionReader::File { file, remaining, buffer, .. } => {
+                if *remaining == 0 {
+                    return Ok(None);
+                }
+                let wanted = (*remaining).min(buffer.len() as u64) as usize;
+                let read = file.read(&mut buffer[..wanted]).await?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Spool file is shorter than the message"));
+                }
+                *remaining -= read as u64;
+                Ok(Some(&buffer[..read]))
+            },
+        }
+    }
+}
+
+// Collects content arriving in chunks, moving it to a temporary file past SPOOL_THRESHOLD
+#[derive(Default)]
+pub struct SpoolWriter {
+    buffer: Vec<u8>,
+    file: Option<(File, SpoolFile)>,
+    len: u64,
+    header_len: Option<u64>,
+    // Last bytes written, the blank line ending the header can span two chunks
+    tail: Vec<u8>,
+}
+
+impl SpoolWriter {
+    pub fn new() -> Self {
+        SpoolWriter::default()
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.header_len.is_none() {
+            let mut probe = std::mem::take(&mut self.tail);
+            let probe_start = self.len - probe.len() as u64;
+            probe.extend_from_slice(chunk);
+            self.header_len = header_end(&probe).map(|end| probe_start + end as u64);
+            self.tail = probe[probe.len().saturating_sub(3)..].to_vec();
+        }
+        self.len += chunk.len() as u64;
+
+        if self.file.is_none() && self.buffer.len() + chunk.len() > SPOOL_THRESHOLD.load(Ordering::Relaxed) {
+            let (mut file, spool_file) = create_spool_file().await?;
+            file.write_all(&self.buffer).await?;
+            self.buffer = Vec::new();
+            self.file = Some((file, spool_file));
+        }
+        match &mut self.file {
+            Some((file, _)) => file.write_all(chunk).await,
+            None => {
+                self.buffer.extend_from_slice(chunk);
+                Ok(())
+            },
+        }
+    }
+
+    pub async fn finish(self) -> io::Result<MimeBody> {
+        let header_len = self.header_len.unwrap_or(self.len);
+        let storage = match self.file {
+            Some((mut file, spool_file)) => {
+                file.flush().await?;
+                Storage::File(spool_file)
+            },
+            None => Storage::Memory(self.buffer),
+        };
+        Ok(MimeBody { storage, len: self.len, header_len })
+    }
+}
+
+// Readable by this user only, the content is someone's mail
+async fn create_spool_file() -> io::Result<(File, SpoolFile)> {
+    let name = format!("davmail-{}-{}.eml", std::process::id(), NEXT_SPOOL_FILE.fetch_add(1, Ordering::Relaxed));
+    let directory = SPOOL_DIRECTORY.read().unwrap().clone().unwrap_or_else(std::env::temp_dir);
+    let path = directory.join(name);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options.open(&path).await?;
+    Ok((file, SpoolFile { path }))
+}
+
+// Offset just past the blank line ending the header. Messages with bare LF line endings are split
+// where they end too, their content is served as stored since re-encoding would break signatures.
+fn header_end(content: &[u8]) -> Option<usize> {
+    let crlf = content.windows(4).position(|window| window == b"\r\n\r\n").map(|index| index + 4);
+    let lf = content.windows(2).position(|window| window == b"\n\n").map(|index| index + 2);
+    match (crlf, lf) {
+        (Some(crlf), Some(lf)) => Some(crlf.min(lf)),
+        (crlf, lf) => crlf.or(lf),
+    }
+}
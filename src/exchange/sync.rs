@@ -0,0 +1,522 @@
+// exchange/sync.rs
+// Folder metadata served from the local cache (see metadata.rs), brought up to date with SyncFolderItems
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::DateTime;
+use log::{debug, info, warn};
+
+use crate::metadata::{CachedMessage, FolderChange, FolderState, MessageMetadata, MetadataCache};
+use super::{flags, response, ExchangeClient, ExchangeError, FetchItem, FolderStats, Message};
+use super::request::{BaseShape, ItemId, SyncFolderItems};
+use super::response::XmlElement;
+use super::spool::{BodySection, MimeBody};
+
+// Seconds a synced folder is served from the cache before asking Exchange for changes again
+pub const DEFAULT_METADATA_REFRESH: u64 = 30;
+
+// Largest batch SyncFolderItems accepts
+const SYNC_BATCH_SIZE: u32 = 512;
+
+// Everything FLAGS, RFC822.SIZE, INTERNALDATE, ENVELOPE and SEARCH need, \Answered comes from flags::ANSWERED_PROPERTY
+const METADATA_PROPERTIES: &[&str] = &[
+    "item:Subject",
+    "item:DateTimeReceived",
+    "item:DateTimeSent",
+    "item:Size",
+    "item:IsDraft",
+    "item:InReplyTo",
+    "message:From",
+    "message:ToRecipients",
+    "message:CcRecipients",
+    "message:IsRead",
+    "message:InternetMessageId",
+];
+
+// Metadata cache of a session
+pub struct MetadataSync {
+    cache: Arc<MetadataCache>,
+    // Cache rows of this user
+    mailbox: String,
+    refresh_interval: Duration,
+    // When this session last synced each folder
+    synced: Mutex<HashMap<String, Instant>>,
+    // Set while Exchange is unreachable, the session is read-only until a request gets through again
+    offline: AtomicBool,
+}
+
+impl MetadataSync {
+    pub fn new(cache: Arc<MetadataCache>, mailbox: &str, refresh_interval: Duration) -> Self {
+        MetadataSync {
+            cache,
+            mailbox: mailbox.to_lowercase(),
+            refresh_interval,
+            synced: Mutex::new(HashMap::new()),
+            offline: AtomicBool::new(false),
+        }
+    }
+
+    // Session of a user who logged in while Exchange was unreachable, it stays offline until the next login
+    pub fn offline(cache: Arc<MetadataCache>, mailbox: &str) -> Self {
+        let metadata = MetadataSync::new(cache, mailbox, Duration::ZERO);
+        metadata.offline.store(true, Ordering::Relaxed);
+        metadata
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    fn set_offline(&self, offline: bool) {
+        if self.offline.swap(offline, Ordering::Relaxed) != offline {
+            if offline {
+                warn!("Exchange is unreachable, serving {} from the metadata cache in read-only mode", self.mailbox);
+            } else {
+                info!("Exchange is reachable again, {} is back online", self.mailbox);
+            }
+        }
+    }
+
+    pub fn list_folders(&self, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        let pattern = regex::Regex::new(&format!("^{}$", regex::escape(pattern).replace("\\*", ".*").replace('%', "[^/]*")))
+            .map_err(|e| ExchangeError::ParseError(format!("Invalid pattern: {}", e)))?;
+        Ok(self.cache.folders(&self.mailbox).map_err(cache_error)?
+            .into_iter()
+            .filter(|folder| pattern.is_match(folder))
+            .collect())
+    }
+
+    pub fn messages(&self, folder: &str) -> Result<(FolderState, Vec<CachedMessage>), ExchangeError> {
+        let state = self.cache.folder_state(&self.mailbox, folder).map_err(cache_error)?;
+        let messages = self.cache.messages(&self.mailbox, folder).map_err(cache_error)?;
+        Ok((state, messages))
+    }
+
+    // Sync the folder on its next use, after a change made here that the cache doesn't have yet
+    pub(crate) fn expire(&self, folder: &str) {
+        self.synced.lock().unwrap().remove(folder);
+    }
+
+    pub fn mark_all_read(&self, folder: &str, read: bool) -> Result<(), ExchangeError> {
+        debug!("Queueing read flag change of {} until Exchange is reachable", folder);
+        self.cache.queue_mark_all_read(&self.mailbox, folder, read).map_err(cache_error)
+    }
+
+    // Bodies kept from earlier downloads, messages never downloaded have none
+    pub fn stored_bodies(&self, selected: &[(u32, &CachedMessage)]) -> Result<HashMap<u32, Arc<MimeBody>>, ExchangeError> {
+        let mut bodies = HashMap::new();
+        for (seq, message) in selected {
+            if let Some(content) = self.cache.body(&self.mailbox, &message.metadata.item_id).map_err(cache_error)? {
+                bodies.insert(*seq, Arc::new(MimeBody::from_bytes(content)));
+            }
+        }
+        Ok(bodies)
+    }
+
+    // Keep downloaded bodies for offline reading, spooled ones are too large for the cache
+    fn store_bodies(&self, selected: &[(u32, &CachedMessage)], bodies: &HashMap<u32, Arc<MimeBody>>) {
+        for (seq, message) in selected {
+            if let Some(content) = bodies.get(seq).and_then(|body| body.in_memory()) {
+                if let Err(e) = self.cache.store_body(&self.mailbox, &message.metadata.item_id, content) {
+                    warn!("Failed to keep message body for offline use: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl ExchangeClient {
+    // Answer from the metadata cache when Exchange can't be reached. Other errors, and sessions
+    // without a cache, fail as before.
+    pub(crate) fn offline<T>(&self, error: ExchangeError, answer: impl FnOnce(&MetadataSync) -> Result<T, ExchangeError>) -> Result<T, ExchangeError> {
+        match &self.metadata {
+            Some(metadata) if error.is_unreachable() => {
+                metadata.set_offline(true);
+                answer(metadata)
+            },
+            _ => Err(error),
+        }
+    }
+
+    // Cached messages of the folder in sequence order, synced first unless that happened within the
+    // refresh interval. None when no cache is configured.
+    pub(crate) async fn cached_messages(&self, folder: &str) -> Result<Option<(FolderState, Vec<CachedMessage>)>, ExchangeError> {
+        let Some(metadata) = &self.metadata else {
+            return Ok(None);
+        };
+
+        let fresh = metadata.synced.lock().unwrap().get(folder)
+            .is_some_and(|synced| synced.elapsed() < metadata.refresh_interval);
+        if !fresh {
+            match self.sync_folder(metadata, folder).await {
+                Ok(()) => {
+                    metadata.set_offline(false);
+                    metadata.synced.lock().unwrap().insert(folder.to_string(), Instant::now());
+                },
+                Err(e) => return self.offline(e, |metadata| metadata.messages(folder)).map(Some),
+            }
+        }
+
+        metadata.messages(folder).map(Some)
+    }
+
+    // Store the changes since the last sync, in batches until Exchange reports the last one
+    async fn sync_folder(&self, metadata: &MetadataSync, folder: &str) -> Result<(), ExchangeError> {
+        let parent = self.resolve_folder(folder).await?;
+
+        // Replay the read flag change made while offline before asking for changes
+        if let Some(read) = metadata.cache.pending_mark_all_read(&metadata.mailbox, folder).map_err(cache_error)? {
+            info!("Sending read flag change of {} made while offline", folder);
+            self.send_mark_all_read(parent.clone(), read).await?;
+            metadata.cache.clear_pending_mark_all_read(&metadata.mailbox, folder).map_err(cache_error)?;
+        }
+
+        let mut reset = false;
+        loop {
+            let state = metadata.cache.folder_state(&metadata.mailbox, folder).map_err(cache_error)?;
+            let response = match self.send_request(&SyncFolderItems {
+                shape: BaseShape::IdOnly,
+                additional_properties: METADATA_PROPERTIES.to_vec(),
+                extended_properties: vec![flags::ANSWERED_PROPERTY],
+                folder: parent.clone(),
+                sync_state: state.sync_state,
+                max_changes: SYNC_BATCH_SIZE,
+            }).await {
+                Ok(response) => response,
+                // Sync states can be invalidated on the server, start the folder over once
+                Err(ExchangeError::ParseError(message)) if !reset && message.contains("ErrorInvalidSyncStateData") => {
+                    warn!("Sync state of {} was rejected, rebuilding its metadata cache", folder);
+                    metadata.cache.reset(&metadata.mailbox, folder).map_err(cache_error)?;
+                    reset = true;
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+
+            let changes = parse_changes(&response)?;
+            let sync_state = response.descendants("SyncState").first()
+                .map(|element| element.text.clone())
+                .ok_or_else(|| ExchangeError::ParseError("SyncFolderItems response without SyncState".to_string()))?;
+            debug!("Synced {} changes of {}", changes.len(), folder);
+            metadata.cache.apply(&metadata.mailbox, folder, &changes, &sync_state).map_err(cache_error)?;
+
+            let last = response.descendants("IncludesLastItemInRange").first()
+                .is_none_or(|element| element.text.trim() == "true");
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    // FETCH answered from the cache, only body sections are downloaded
+    pub(crate) async fn fetch_cached(&self, messages: &[CachedMessage], sequences: &[u32], fetch_items: &[&str]) -> Result<Vec<Message>, ExchangeError> {
+        let selected = select_messages(messages, sequences);
+
+        let mut contents = HashMap::new();
+        if let Some(metadata) = self.metadata.as_ref().filter(|_| fetch_items.iter().any(|item| item.starts_with("BODY["))) {
+            let ids: Vec<ItemId> = selected.iter()
+                .map(|(_, message)| ItemId { id: message.metadata.item_id.clone(), change_key: message.metadata.change_key.clone() })
+                .collect();
+            contents = match self.get_mime_contents(&ids).await {
+                Ok(bodies) => {
+                    let contents = selected.iter().map(|(seq, _)| *seq).zip(bodies).collect();
+                    metadata.store_bodies(&selected, &contents);
+                    contents
+                },
+                Err(e) => self.offline(e, |metadata| metadata.stored_bodies(&selected))?,
+            };
+        }
+
+        Ok(fetch_items_of(&selected, fetch_items, &contents, self.read_receipts.flags()))
+    }
+
+    // Sequence numbers of the messages matching every search key, answered from the cache
+    pub async fn search(&self, folder: &str, criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+        let keys = SearchKey::parse_all(criteria)?;
+        let Some((_, messages)) = self.cached_messages(folder).await? else {
+            return Err(ExchangeError::Unsupported("SEARCH without davmail.ews.metadataCacheFile".to_string()));
+        };
+        Ok(search_messages(&messages, &keys))
+    }
+}
+
+// SELECT and STATUS numbers of a cached folder
+pub fn folder_stats(state: &FolderState, messages: &[CachedMessage], read_only: bool) -> FolderStats {
+    FolderStats {
+        exists: messages.len() as u32,
+        // Exchange has no \Recent, every session sees the same messages
+        recent: 0,
+        unseen: messages.iter().filter(|message| !message.metadata.is_read).count() as u32,
+        uid_validity: state.uid_validity,
+        uid_next: state.uid_next,
+        read_only,
+    }
+}
+
+// Messages at the requested sequence numbers, numbers past the end are skipped
+pub fn select_messages<'a>(messages: &'a [CachedMessage], sequences: &[u32]) -> Vec<(u32, &'a CachedMessage)> {
+    sequences.iter()
+        .filter_map(|&seq| seq.checked_sub(1).and_then(|index| messages.get(index as usize)).map(|message| (seq, message)))
+        .collect()
+}
+
+// FETCH data of the selected messages, body sections only for the messages in `contents`. `keywords`
+// are added to the FLAGS of every message.
+pub fn fetch_items_of(selected: &[(u32, &CachedMessage)], fetch_items: &[&str], contents: &HashMap<u32, Arc<MimeBody>>,
+                      keywords: &[&str]) -> Vec<Message> {
+    let mut result = Vec::new();
+    for (seq, message) in selected {
+        let metadata = &message.metadata;
+        let content = contents.get(seq);
+        let mut items = Vec::new();
+        for item in fetch_items {
+            match *item {
+                "FLAGS" => {
+                    let mut flags = Vec::new();
+                    if metadata.is_read {
+                        flags.push("\\Seen");
+                    }
+                    if metadata.is_answered {
+                        flags.push("\\Answered");
+                    }
+                    if metadata.is_draft {
+                        flags.push("\\Draft");
+                    }
+                    flags.extend(keywords);
+                    items.push(FetchItem::Value(format!("FLAGS ({})", flags.join(" "))));
+                },
+                "UID" => items.push(FetchItem::Value(format!("UID {}", message.uid))),
+                "RFC822.SIZE" => items.push(FetchItem::Value(format!("RFC822.SIZE {}", metadata.size))),
+                "INTERNALDATE" => items.push(FetchItem::Value(format!("INTERNALDATE \"{}\"", internal_date(&metadata.internal_date)))),
+                "ENVELOPE" => items.push(FetchItem::Value(format!("ENVELOPE {}", metadata.envelope))),
+                "BODY[HEADER]" | "BODY[TEXT]" | "BODY[]" => {
+                    if let Some(content) = content {
+                        let section = match *item {
+                            "BODY[HEADER]" => BodySection::header(content.clone()),
+                            "BODY[TEXT]" => BodySection::text(content.clone()),
+                            _ => BodySection::whole(content.clone()),
+                        };
+                        items.push(FetchItem::Literal(item.to_string(), section));
+                    }
+                },
+                _ => {
+                    // Ignore unsupported items
+                }
+            }
+        }
+        if !items.is_empty() {
+            result.push(Message { sequence: *seq, items });
+        }
+    }
+    result
+}
+
+pub fn search(messages: &[CachedMessage], criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+    Ok(search_messages(messages, &SearchKey::parse_all(criteria)?))
+}
+
+fn search_messages(messages: &[CachedMessage], keys: &[SearchKey]) -> Vec<u32> {
+    messages.iter()
+        .enumerate()
+        .filter(|(_, message)| keys.iter().all(|key| key.matches(&message.metadata)))
+        .map(|(index, _)| index as u32 + 1)
+        .collect()
+}
+
+// Nested NOTs allowed in SEARCH criteria, parsing and matching recurse once per level
+const MAX_SEARCH_DEPTH: usize = 16;
+
+// SEARCH keys that can be checked against cached metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SearchKey {
+    All,
+    Seen(bool),
+    Answered(bool),
+    Draft(bool),
+    // Case-insensitive substring of the subject or sender
+    Subject(String),
+    From(String),
+    Not(Box<SearchKey>),
+}
+
+impl SearchKey {
+    fn parse_all(criteria: &str) -> Result<Vec<SearchKey>, ExchangeError> {
+        let mut tokens = search_tokens(criteria).into_iter();
+        let mut keys = Vec::new();
+        while let Some(token) = tokens.next() {
+            keys.push(SearchKey::parse(token, &mut tokens, 0)?);
+        }
+        Ok(keys)
+    }
+
+    fn parse(token: String, tokens: &mut impl Iterator<Item = String>, depth: usize) -> Result<SearchKey, ExchangeError> {
+        let mut argument = |key: &str| tokens.next()
+            .ok_or_else(|| ExchangeError::ParseError(format!("SEARCH {} without argument", key)));
+        Ok(match token.to_uppercase().as_str() {
+            "ALL" => SearchKey::All,
+            "SEEN" => SearchKey::Seen(true),
+            "UNSEEN" => SearchKey::Seen(false),
+            "ANSWERED" => SearchKey::Answered(true),
+            "UNANSWERED" => SearchKey::Answered(false),
+            "DRAFT" => SearchKey::Draft(true),
+            "UNDRAFT" => SearchKey::Draft(false),
+            "SUBJECT" => SearchKey::Subject(argument("SUBJECT")?.to_lowercase()),
+            "FROM" => SearchKey::From(argument("FROM")?.to_lowercase()),
+            "NOT" if depth >= MAX_SEARCH_DEPTH => {
+                return Err(ExchangeError::ParseError("SEARCH criteria nested too deeply".to_string()));
+            },
+            "NOT" => {
+                let negated = argument("NOT")?;
+                SearchKey::Not(Box::new(SearchKey::parse(negated, tokens, depth + 1)?))
+            },
+            other => return Err(ExchangeError::Unsupported(format!("SEARCH key {}", other))),
+        })
+    }
+
+    fn matches(&self, message: &MessageMetadata) -> bool {
+        match self {
+            SearchKey::All => true,
+            SearchKey::Seen(seen) => message.is_read == *seen,
+            SearchKey::Answered(answered) => message.is_answered == *answered,
+            SearchKey::Draft(draft) => message.is_draft == *draft,
+            SearchKey::Subject(text) => message.subject.to_lowercase().contains(text),
+            SearchKey::From(text) => message.sender.to_lowercase().contains(text),
+            SearchKey::Not(key) => !key.matches(message),
+        }
+    }
+}
+
+// Split search criteria into atoms and quoted strings
+fn search_tokens(criteria: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = criteria.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => token.extend(chars.next()),
+                    c => token.push(c),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+fn parse_changes(response: &XmlElement) -> Result<Vec<FolderChange>, ExchangeError> {
+    let Some(changes) = response.descendants("Changes").into_iter().next() else {
+        return Ok(Vec::new());
+    };
+    changes.children.iter()
+        .filter_map(|change| match change.name.as_str() {
+            "Create" | "Update" => change.children.first().map(|item| message_metadata(item).map(FolderChange::Upsert)),
+            "Delete" => Some(response::item_id(change).map(|id| FolderChange::Delete(id.id))),
+            "ReadFlagChange" => Some(response::item_id(change)
+                .map(|id| FolderChange::ReadFlag(id.id, change.child_text("IsRead") == Some("true")))),
+            _ => None,
+        })
+        .collect()
+}
+
+fn message_metadata(item: &XmlElement) -> Result<MessageMetadata, ExchangeError> {
+    let id = response::item_id(item)?;
+    let from = mailboxes(item, "From");
+    let sender = from.first()
+        .map(|(name, address)| format!("{} <{}>", name.unwrap_or_default(), address.unwrap_or_default()))
+        .unwrap_or_default();
+    Ok(MessageMetadata {
+        item_id: id.id,
+        change_key: id.change_key,
+        // Items other than messages (meeting requests, reports) have no read flag
+        is_read: item.child_text("IsRead").is_none_or(|value| value == "true"),
+        is_answered: flags::is_answered(item),
+        is_draft: item.child_text("IsDraft") == Some("true"),
+        size: item.child_text("Size").and_then(|size| size.trim().parse().ok()).unwrap_or(0),
+        internal_date: item.child_text("DateTimeReceived").unwrap_or_default().to_string(),
+        subject: item.child_text("Subject").unwrap_or_default().to_string(),
+        sender,
+        envelope: envelope(item),
+    })
+}
+
+// (name, address) of the Mailbox elements under `field`
+fn mailboxes<'a>(item: &'a XmlElement, field: &str) -> Vec<(Option<&'a str>, Option<&'a str>)> {
+    item.child(field)
+        .map(|field| field.children.iter()
+            .filter(|mailbox| mailbox.name == "Mailbox")
+            .map(|mailbox| (mailbox.child_text("Name"), mailbox.child_text("EmailAddress")))
+            .collect())
+        .unwrap_or_default()
+}
+
+// RFC 3501 ENVELOPE: date, subject, from, sender, reply-to, to, cc, bcc, in-reply-to, message-id.
+// EWS has no separate sender and reply-to here, both repeat from as servers do when the headers are missing.
+fn envelope(item: &XmlElement) -> String {
+    let date = item.child_text("DateTimeSent")
+        .and_then(|date| DateTime::parse_from_rfc3339(date.trim()).ok())
+        .map(|date| date.to_rfc2822());
+    let from = address_list(&mailboxes(item, "From"));
+    format!("({} {} {} {} {} {} {} NIL {} {})",
+        nstring(date.as_deref()),
+        nstring(item.child_text("Subject")),
+        from, from, from,
+        address_list(&mailboxes(item, "ToRecipients")),
+        address_list(&mailboxes(item, "CcRecipients")),
+        nstring(item.child_text("InReplyTo")),
+        nstring(item.child_text("InternetMessageId")))
+}
+
+fn address_list(mailboxes: &[(Option<&str>, Option<&str>)]) -> String {
+    if mailboxes.is_empty() {
+        return "NIL".to_string();
+    }
+    let addresses: Vec<String> = mailboxes.iter()
+        .map(|(name, address)| {
+            let address = address.unwrap_or_default();
+            let (local, domain) = address.split_once('@').unwrap_or((address, ""));
+            format!("({} NIL {} {})", nstring(*name), nstring(Some(local)), nstring(Some(domain)))
+        })
+        .collect();
+    format!("({})", addresses.join(""))
+}
+
+// Quoted string or NIL. Quoted strings are 7-bit, other text goes in an RFC 2047 encoded word.
+fn nstring(value: Option<&str>) -> String {
+    let value: String = match value {
+        Some(value) if !value.is_empty() => value.chars().filter(|&c| c != '\r' && c != '\n').collect(),
+        _ => return "NIL".to_string(),
+    };
+    let value = if value.is_ascii() { value } else { format!("=?UTF-8?B?{}?=", STANDARD.encode(value)) };
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// INTERNALDATE format, e.g. 28-Mar-2025 10:00:00 +0000
+fn internal_date(received: &str) -> String {
+    DateTime::parse_from_rfc3339(received.trim())
+        .map(|date| date.format("%d-%b-%Y %H:%M:%S %z").to_string())
+        .unwrap_or_else(|_| "01-Jan-1970 00:00:00 +0000".to_string())
+}
+
+fn cache_error(error: rusqlite::Error) -> ExchangeError {
+    ExchangeError::RuntimeError(format!("Metadata cache: {}", error))
+}
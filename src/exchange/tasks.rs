@@ -0,0 +1,214 @@
+// exchange/tasks.rs
+// Task items for Exchange Web Services (EWS), backing the CalDAV VTODO collection
+
+use chrono::{DateTime, Utc};
+use log::debug;
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::{
+    format_datetime, BaseShape, CreateItem, DeleteItem, DeleteType, FieldPath, FieldUpdate, FindItem,
+    FolderRef, ItemChange, ItemContent, ItemId, ItemView, Traversal, UpdateItem, XmlWriter,
+};
+use super::response::{self, XmlElement};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    NotStarted,
+    InProgress,
+    Completed,
+    WaitingOnOthers,
+    Deferred,
+}
+
+impl TaskStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::NotStarted => "NotStarted",
+            TaskStatus::InProgress => "InProgress",
+            TaskStatus::Completed => "Completed",
+            TaskStatus::WaitingOnOthers => "WaitingOnOthers",
+            TaskStatus::Deferred => "Deferred",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "NotStarted" => Some(TaskStatus::NotStarted),
+            "InProgress" => Some(TaskStatus::InProgress),
+            "Completed" => Some(TaskStatus::Completed),
+            "WaitingOnOthers" => Some(TaskStatus::WaitingOnOthers),
+            "Deferred" => Some(TaskStatus::Deferred),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Task {
+    // None until the task has been created on the server
+    pub id: Option<ItemId>,
+    pub subject: String,
+    pub start_date: Option<DateTime<Utc>>,
+    pub due_date: Option<DateTime<Utc>>,
+    pub status: TaskStatus,
+    pub percent_complete: u8,
+    // Set by Exchange when the task is marked completed
+    pub complete_date: Option<DateTime<Utc>>,
+}
+
+const TASK_PROPERTIES: [&str; 6] = [
+    "item:Subject",
+    "task:StartDate",
+    "task:DueDate",
+    "task:Status",
+    "task:PercentComplete",
+    "task:CompleteDate",
+];
+
+fn parse_optional_datetime(value: Option<&str>) -> Result<Option<DateTime<Utc>>, ExchangeError> {
+    value.filter(|value| !value.is_empty())
+        .map(|value| DateTime::parse_from_rfc3339(value)
+            .map(|date| date.with_timezone(&Utc))
+            .map_err(|e| ExchangeError::ParseError(format!("Invalid task date '{}': {}", value, e))))
+        .transpose()
+}
+
+impl Task {
+    pub fn new(subject: &str) -> Self {
+        Task {
+            id: None,
+            subject: subject.to_string(),
+            start_date: None,
+            due_date: None,
+            status: TaskStatus::NotStarted,
+            percent_complete: 0,
+            complete_date: None,
+        }
+    }
+
+    pub(crate) fn from_xml(element: &XmlElement) -> Result<Self, ExchangeError> {
+        Ok(Task {
+            id: Some(response::item_id(element)?),
+            subject: element.child_text("Subject").unwrap_or_default().to_string(),
+            start_date: parse_optional_datetime(element.child_text("StartDate"))?,
+            due_date: parse_optional_datetime(element.child_text("DueDate"))?,
+            status: element.child_text("Status").and_then(TaskStatus::parse).unwrap_or(TaskStatus::NotStarted),
+            percent_complete: element.child_text("PercentComplete")
+                .and_then(|value| value.parse::<f64>().ok())
+                .map(|percent| percent.clamp(0.0, 100.0) as u8)
+                .unwrap_or(0),
+            complete_date: parse_optional_datetime(element.child_text("CompleteDate"))?,
+        })
+    }
+}
+
+// Serialize a partial t:Task element for a SetItemField update
+fn task_fragment(element: &'static str, value: &str) -> String {
+    let mut w = XmlWriter::new();
+    w.open("t:Task", &[]).element(element, value);
+    w.finish()
+}
+
+impl ItemContent for Task {
+    fn write_item(&self, w: &mut XmlWriter) {
+        // Element order follows the TaskType schema sequence, item fields first
+        w.open("t:Task", &[]).element("t:Subject", &self.subject);
+        if let Some(due_date) = &self.due_date {
+            w.element("t:DueDate", &format_datetime(due_date));
+        }
+        if self.status != TaskStatus::Completed && self.percent_complete > 0 {
+            w.element("t:PercentComplete", &self.percent_complete.to_string());
+        }
+        if let Some(start_date) = &self.start_date {
+            w.element("t:StartDate", &format_datetime(start_date));
+        }
+        w.element("t:Status", self.status.as_str());
+        w.close();
+    }
+}
+
+impl ExchangeClient {
+    pub async fn list_tasks(&self) -> Result<Vec<Task>, ExchangeError> {
+        debug!("Listing tasks");
+
+        let response = self.send_request(&FindItem {
+            traversal: Traversal::Shallow,
+            shape: BaseShape::IdOnly,
+            additional_properties: TASK_PROPERTIES.to_vec(),
+            extended_properties: Vec::new(),
+            view: ItemView::Indexed { max_entries: 1000, offset: 0 },
+            restriction: None,
+            parent: FolderRef::distinguished("tasks"),
+        }).await?;
+
+        response.descendants("Task")
+            .into_iter()
+            .map(Task::from_xml)
+            .collect()
+    }
+
+    // Create the task in the default Tasks folder and return its new id
+    pub async fn create_task(&self, task: &Task) -> Result<ItemId, ExchangeError> {
+        debug!("Creating task '{}'", task.subject);
+
+        let response = self.send_request(&CreateItem {
+            saved_folder: Some(FolderRef::distinguished("tasks")),
+            message_disposition: None,
+            items: vec![task],
+        }).await?;
+
+        response.descendants("Task")
+            .first()
+            .map(|element| response::item_id(element))
+            .unwrap_or_else(|| Err(ExchangeError::ParseError("CreateItem returned no task".to_string())))
+    }
+
+    // Push subject, status/completion and due date changes, returns the id with its new change key
+    pub async fn update_task(&self, task: &Task) -> Result<ItemId, ExchangeError> {
+        let id = task.id.clone()
+            .ok_or_else(|| ExchangeError::ParseError("Cannot update a task without ItemId".to_string()))?;
+        debug!("Updating task '{}'", task.subject);
+
+        let mut updates = vec![
+            FieldUpdate::Set { field: FieldPath::Field("item:Subject"), item: task_fragment("t:Subject", &task.subject) },
+            FieldUpdate::Set { field: FieldPath::Field("task:Status"), item: task_fragment("t:Status", task.status.as_str()) },
+        ];
+        // Exchange forces PercentComplete to 100 for completed tasks and rejects other values
+        if task.status != TaskStatus::Completed {
+            updates.push(FieldUpdate::Set {
+                field: FieldPath::Field("task:PercentComplete"),
+                item: task_fragment("t:PercentComplete", &task.percent_complete.min(99).to_string()),
+            });
+        }
+        updates.push(match &task.due_date {
+            Some(due_date) => FieldUpdate::Set {
+                field: FieldPath::Field("task:DueDate"),
+                item: task_fragment("t:DueDate", &format_datetime(due_date)),
+            },
+            None => FieldUpdate::Delete { field: FieldPath::Field("task:DueDate") },
+        });
+
+        let response = self.send_request(&UpdateItem {
+            conflict_resolution: "AutoResolve",
+            message_disposition: None,
+            changes: vec![ItemChange { item_id: id, updates }],
+        }).await?;
+
+        response.descendants("Task")
+            .first()
+            .map(|element| response::item_id(element))
+            .unwrap_or_else(|| Err(ExchangeError::ParseError("UpdateItem returned no task".to_string())))
+    }
+
+    pub async fn delete_task(&self, id: &ItemId) -> Result<(), ExchangeError> {
+        debug!("Deleting task {}", id.id);
+
+        self.send_request(&DeleteItem {
+            delete_type: DeleteType::MoveToDeletedItems,
+            affected_task_occurrences: Some("AllOccurrences"),
+            item_ids: vec![id.clone()],
+        }).await?;
+
+        Ok(())
+    }
+}
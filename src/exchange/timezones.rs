@@ -0,0 +1,204 @@
+// exchange/timezones.rs
+// Windows time zone ids used by Exchange and their IANA equivalents
+
+use std::collections::HashMap;
+use log::{debug, warn};
+
+use super::{ExchangeClient, ExchangeError};
+use super::request::GetServerTimeZones;
+use super::response::XmlElement;
+
+// Default territory mapping from the CLDR windowsZones table
+const WINDOWS_ZONES: [(&str, &str); 48] = [
+    ("Dateline Standard Time", "Etc/GMT+12"),
+    ("Hawaiian Standard Time", "Pacific/Honolulu"),
+    ("Alaskan Standard Time", "America/Anchorage"),
+    ("Pacific Standard Time", "America/Los_Angeles"),
+    ("US Mountain Standard Time", "America/Phoenix"),
+    ("Mountain Standard Time", "America/Denver"),
+    ("Central America Standard Time", "America/Guatemala"),
+    ("Central Standard Time", "America/Chicago"),
+    ("Central Standard Time (Mexico)", "America/Mexico_City"),
+    ("Canada Central Standard Time", "America/Regina"),
+    ("SA Pacific Standard Time", "America/Bogota"),
+    ("Eastern Standard Time", "America/New_York"),
+    ("US Eastern Standard Time", "America/Indianapolis"),
+    ("Atlantic Standard Time", "America/Halifax"),
+    ("Newfoundland Standard Time", "America/St_Johns"),
+    ("E. South America Standard Time", "America/Sao_Paulo"),
+    ("Argentina Standard Time", "America/Buenos_Aires"),
+    ("UTC", "Etc/UTC"),
+    ("GMT Standard Time", "Europe/London"),
+    ("Greenwich Standard Time", "Atlantic/Reykjavik"),
+    ("W. Europe Standard Time", "Europe/Berlin"),
+    ("Central Europe Standard Time", "Europe/Budapest"),
+    ("Romance Standard Time", "Europe/Paris"),
+    ("Central European Standard Time", "Europe/Warsaw"),
+    ("W. Central Africa Standard Time", "Africa/Lagos"),
+    ("GTB Standard Time", "Europe/Bucharest"),
+    ("FLE Standard Time", "Europe/Kiev"),
+    ("Israel Standard Time", "Asia/Jerusalem"),
+    ("Egypt Standard Time", "Africa/Cairo"),
+    ("South Africa Standard Time", "Africa/Johannesburg"),
+    ("Turkey Standard Time", "Europe/Istanbul"),
+    ("Russian Standard Time", "Europe/Moscow"),
+    ("Arab Standard Time", "Asia/Riyadh"),
+    ("Arabian Standard Time", "Asia/Dubai"),
+    ("Iran Standard Time", "Asia/Tehran"),
+    ("Pakistan Standard Time", "Asia/Karachi"),
+    ("India Standard Time", "Asia/Calcutta"),
+    ("Nepal Standard Time", "Asia/Katmandu"),
+    ("Bangladesh Standard Time", "Asia/Dhaka"),
+    ("SE Asia Standard Time", "Asia/Bangkok"),
+    ("China Standard Time", "Asia/Shanghai"),
+    ("Singapore Standard Time", "Asia/Singapore"),
+    ("Taipei Standard Time", "Asia/Taipei"),
+    ("Tokyo Standard Time", "Asia/Tokyo"),
+    ("Korea Standard Time", "Asia/Seoul"),
+    ("AUS Eastern Standard Time", "Australia/Sydney"),
+    ("E. Australia Standard Time", "Australia/Brisbane"),
+    ("New Zealand Standard Time", "Pacific/Auckland"),
+];
+
+// Time zone definition as published by the server
+#[derive(Debug, Clone)]
+pub struct ServerTimeZone {
+    pub id: String,
+    pub name: String,
+    // Standard time offset from UTC in minutes, east positive
+    pub utc_offset_minutes: i32,
+}
+
+impl ServerTimeZone {
+    pub(crate) fn from_xml(element: &XmlElement) -> Result<Self, ExchangeError> {
+        let id = element.attr("Id")
+            .ok_or_else(|| ExchangeError::ParseError("TimeZoneDefinition without Id".to_string()))?;
+
+        // Bias is what gets added to local time to obtain UTC, so the offset is its negation
+        let bias = element.child("Periods")
+            .and_then(|periods| periods.children_named("Period")
+                .find(|period| period.attr("Name") == Some("Standard"))
+                .or_else(|| periods.children_named("Period").next()))
+            .and_then(|period| period.attr("Bias"))
+            .map(parse_duration_minutes)
+            .transpose()?
+            .unwrap_or(0);
+
+        Ok(ServerTimeZone {
+            id: id.to_string(),
+            name: element.attr("Name").unwrap_or(id).to_string(),
+            utc_offset_minutes: -bias,
+        })
+    }
+}
+
+// Parse an xs:duration such as -PT8H or P0DT5H30M0.0S into minutes
+fn parse_duration_minutes(value: &str) -> Result<i32, ExchangeError> {
+    let invalid = || ExchangeError::ParseError(format!("Invalid time zone bias '{}'", value));
+
+    let (sign, rest) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut minutes = 0.0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' => number.push(c),
+            'D' | 'H' | 'M' | 'S' => {
+                let amount: f64 = number.parse().map_err(|_| invalid())?;
+                number.clear();
+                minutes += match (c, in_time) {
+                    ('D', false) => amount * 1440.0,
+                    ('H', true) => amount * 60.0,
+                    ('M', true) => amount,
+                    ('S', true) => amount / 60.0,
+                    _ => return Err(invalid()),
+                };
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(sign * minutes.round() as i32)
+}
+
+// Windows to IANA lookup, built in table completed with the server's own definitions
+#[derive(Debug, Clone, Default)]
+pub struct TimeZoneMap {
+    server_zones: HashMap<String, ServerTimeZone>,
+}
+
+impl TimeZoneMap {
+    pub fn extend(&mut self, zones: Vec<ServerTimeZone>) {
+        for zone in zones {
+            self.server_zones.insert(zone.id.clone(), zone);
+        }
+    }
+
+    pub fn server_zone(&self, windows_id: &str) -> Option<&ServerTimeZone> {
+        self.server_zones.get(windows_id)
+    }
+
+    // IANA name for a Windows id, custom or legacy server zones fall back to a fixed offset zone
+    pub fn to_iana(&self, windows_id: &str) -> Option<String> {
+        if let Some((_, iana)) = WINDOWS_ZONES.iter().find(|(windows, _)| *windows == windows_id) {
+            return Some(iana.to_string());
+        }
+
+        let zone = self.server_zones.get(windows_id)?;
+        if zone.utc_offset_minutes == 0 {
+            return Some("Etc/UTC".to_string());
+        }
+        if zone.utc_offset_minutes % 60 != 0 {
+            warn!("No IANA equivalent for time zone '{}' with offset {} minutes", windows_id, zone.utc_offset_minutes);
+            return None;
+        }
+        // Etc/GMT names have inverted signs: Etc/GMT-2 is two hours east of UTC
+        Some(format!("Etc/GMT{:+}", -zone.utc_offset_minutes / 60))
+    }
+
+    pub fn to_windows(&self, iana: &str) -> Option<&'static str> {
+        WINDOWS_ZONES.iter()
+            .find(|(_, name)| *name == iana)
+            .map(|(windows, _)| *windows)
+    }
+}
+
+impl ExchangeClient {
+    pub async fn get_server_time_zones(&self) -> Result<Vec<ServerTimeZone>, ExchangeError> {
+        let response = self.send_request(&GetServerTimeZones {
+            return_full_time_zone_data: true,
+            ids: Vec::new(),
+        }).await?;
+
+        let zones = response.descendants("TimeZoneDefinition")
+            .into_iter()
+            .map(ServerTimeZone::from_xml)
+            .collect::<Result<Vec<_>, _>>()?;
+        debug!("Server published {} time zone definitions", zones.len());
+        Ok(zones)
+    }
+
+    // The built in table completed with the server definitions, meant to be loaded once per profile
+    // and handed to its sessions with with_time_zones. Failures leave the built in table alone.
+    pub async fn load_time_zones(&self) -> TimeZoneMap {
+        let mut time_zones = TimeZoneMap::default();
+        match self.get_server_time_zones().await {
+            Ok(zones) => time_zones.extend(zones),
+            Err(e) => warn!("Failed to load server time zones: {}", e),
+        }
+        time_zones
+    }
+
+    pub fn time_zones(&self) -> &TimeZoneMap {
+        &self.time_zones
+    }
+}
@@ -0,0 +1,119 @@
+// fuzzing.rs
+// Entry points of the cargo-fuzz targets in fuzz/ (cargo feature "fuzzing"), one per parser of client or server input
+
+//! Each function feeds arbitrary input to a parser and must return without panicking,
+//! whatever it is given. Errors are expected, they are how the parsers refuse bad input.
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use crate::exchange::calendar::CalendarItem;
+use crate::exchange::contacts::Contact;
+use crate::exchange::import::{ImportMessage, MboxReader};
+use crate::exchange::mime::{header, MimeContentScanner, ReadAhead};
+use crate::exchange::response::{self, XmlElement};
+use crate::exchange::spool::BodySection;
+use crate::exchange::tasks::Task;
+use crate::exchange::timezones::ServerTimeZone;
+use crate::exchange::{parse_sequence_set, sync};
+use crate::protocols::imap::split_command;
+use crate::protocols::sasl;
+
+/// A line read from an IMAP client: the command tokenizer, then the argument parsing of
+/// AUTHENTICATE, SEARCH and FETCH
+pub fn imap_command(data: &[u8]) {
+    let line = String::from_utf8_lossy(data);
+    let Some(parts) = split_command(&line) else { return };
+    let Some(arguments) = parts.get(2) else { return };
+    match parts[1].to_uppercase().as_str() {
+        "AUTHENTICATE" => {
+            let mut arguments = arguments.splitn(2, ' ');
+            let mechanism = arguments.next().unwrap_or_default();
+            let _ = sasl::parse_bearer_response(mechanism, arguments.next().unwrap_or_default());
+        },
+        "SEARCH" => {
+            let _ = sync::search(&[], arguments);
+        },
+        "FETCH" => {
+            if let Some((sequence_set, _)) = arguments.split_once(' ') {
+                sequence_set_of(sequence_set);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// A FETCH or STORE sequence set, with what is done with the numbers it expands to
+pub fn sequence_set(data: &[u8]) {
+    sequence_set_of(&String::from_utf8_lossy(data));
+}
+
+fn sequence_set_of(sequence_set: &str) {
+    if let Ok(sequences) = parse_sequence_set(sequence_set) {
+        let _ = sync::select_messages(&[], &sequences);
+        ReadAhead::default().is_sequential("INBOX", &sequences);
+    }
+}
+
+/// SEARCH criteria
+pub fn search(data: &[u8]) {
+    let _ = sync::search(&[], &String::from_utf8_lossy(data));
+}
+
+/// An EWS response: the SOAP envelope and error checks, then the item converters on every
+/// element they could be given
+pub fn ews_response(data: &[u8]) {
+    let Ok(xml) = std::str::from_utf8(data) else { return };
+    let Ok(envelope) = response::parse_response(xml) else {
+        let _ = XmlElement::parse(xml);
+        return;
+    };
+    for element in envelope.descendants("CalendarItem") {
+        let _ = CalendarItem::from_xml(element);
+    }
+    for element in envelope.descendants("Contact") {
+        let _ = Contact::from_xml(element);
+    }
+    for element in envelope.descendants("Task") {
+        let _ = Task::from_xml(element);
+    }
+    for element in envelope.descendants("TimeZoneDefinition") {
+        let _ = ServerTimeZone::from_xml(element);
+    }
+    for element in envelope.descendants("Message") {
+        let _ = response::item_id(element);
+    }
+}
+
+/// A streamed GetItem response, split into chunks where the first byte says, with the body
+/// sections served from the decoded MIME content
+pub fn mime_content(data: &[u8]) {
+    let Some((&chunk_size, data)) = data.split_first() else { return };
+    let chunk_size = usize::from(chunk_size).max(1);
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let mut scanner = MimeContentScanner::default();
+        for chunk in data.chunks(chunk_size) {
+            if scanner.feed(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok((_, bodies)) = scanner.finish().await {
+            for body in bodies {
+                let body = Arc::new(body);
+                let _ = (BodySection::header(body.clone()).len(), BodySection::text(body).len());
+            }
+        }
+    });
+}
+
+/// A message or mbox file given to the import command
+pub fn mailbox_file(data: &[u8]) {
+    let _ = header(data, "Subject");
+    let _ = ImportMessage::from_mime(data.to_vec());
+    for message in MboxReader::new(Cursor::new(data)) {
+        if message.is_err() {
+            break;
+        }
+    }
+}
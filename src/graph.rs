@@ -0,0 +1,310 @@
+// graph.rs
+// Microsoft Graph mail client, used when EWS is disabled on the tenant
+
+use std::sync::Arc;
+use reqwest::Client;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use log::debug;
+
+use crate::auth::*;
+use crate::exchange::{ExchangeError, FetchItem, FolderStats, Message};
+use crate::exchange::mime::spool_error;
+use crate::exchange::request::distinguished_folder;
+use crate::exchange::spool::{BodySection, MimeBody, SpoolWriter};
+use crate::uidmap;
+
+pub const DEFAULT_GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+pub const DEFAULT_GRAPH_SCOPE: &str = "https://graph.microsoft.com/.default";
+
+#[derive(Debug, Deserialize)]
+struct GraphList<T> {
+    value: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphFolder {
+    id: String,
+    display_name: String,
+    #[serde(default)]
+    child_folder_count: u32,
+    #[serde(default)]
+    total_item_count: u32,
+    #[serde(default)]
+    unread_item_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphFlag {
+    #[serde(rename = "flagStatus")]
+    flag_status: String,
+}
+
+// MAPI property without a Graph field, id is "Integer 0x1081" and the like
+#[derive(Debug, Deserialize)]
+struct GraphExtendedProperty {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphMessage {
+    id: String,
+    #[serde(default)]
+    is_read: bool,
+    #[serde(default)]
+    is_draft: bool,
+    flag: Option<GraphFlag>,
+    // PidTagLastVerbExecuted when it is set, expanded for \Answered
+    #[serde(default)]
+    single_value_extended_properties: Vec<GraphExtendedProperty>,
+}
+
+impl GraphMessage {
+    // EXCHIVERB_REPLYTOSENDER or EXCHIVERB_REPLYTOALL, as for EWS in exchange/flags.rs
+    fn is_answered(&self) -> bool {
+        self.single_value_extended_properties.iter()
+            .any(|property| matches!(property.value.trim(), "102" | "103"))
+    }
+}
+
+pub struct GraphClient {
+    base_url: String,
+    client: Client,
+    auth: Mutex<OAuth2Auth>,
+}
+
+impl GraphClient {
+    pub async fn new_with_oauth2(base_url: &str, oauth2_config: OAuth2Config, client: Client) -> Result<Self, ExchangeError> {
+        let auth = OAuth2Auth::new(oauth2_config, client.clone())
+            .map_err(|e| ExchangeError::AuthError(e.to_string()))?;
+        GraphClient::new_with_oauth2_auth(base_url, auth, client).await
+    }
+
+    // The authentication must be for the Graph scope, see mailstore::graph_auth
+    pub async fn new_with_oauth2_auth(base_url: &str, auth: OAuth2Auth, client: Client) -> Result<Self, ExchangeError> {
+        let base_url = if base_url.is_empty() { DEFAULT_GRAPH_URL } else { base_url };
+
+        let graph_client = GraphClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+            auth: Mutex::new(auth),
+        };
+
+        // Acquire a token immediately so configuration errors surface at login
+        graph_client.headers().await?;
+
+        Ok(graph_client)
+    }
+
+    async fn headers(&self) -> Result<HeaderMap, ExchangeError> {
+        let token = self.auth.lock().await.async_get_auth_header().await
+            .map_err(|e| ExchangeError::AuthError(e.to_string()))?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&token)
+            .map_err(|e| ExchangeError::AuthError(e.to_string()))?);
+        Ok(headers)
+    }
+
+    async fn get(&self, path: &str) -> Result<reqwest::Response, ExchangeError> {
+        let url = format!("{}{}", self.base_url, path);
+        debug!("Graph GET {}", url);
+
+        let response = self.client
+            .get(url)
+            .headers(self.headers().await?)
+            .send().await?;
+
+        Ok(response.error_for_status()?)
+    }
+
+    // Graph returns the raw RFC 822 content through $value, spooled as it arrives
+    async fn get_mime(&self, id: &str) -> Result<MimeBody, ExchangeError> {
+        let mut response = self.get(&format!("/me/messages/{}/$value", urlencoding::encode(id))).await?;
+        let mut writer = SpoolWriter::new();
+        while let Some(chunk) = response.chunk().await? {
+            writer.write(&chunk).await.map_err(spool_error)?;
+        }
+        writer.finish().await.map_err(spool_error)
+    }
+
+    async fn child_folders(&self, parent: Option<&str>) -> Result<Vec<GraphFolder>, ExchangeError> {
+        let path = match parent {
+            Some(id) => format!("/me/mailFolders/{}/childFolders?$top=250", urlencoding::encode(id)),
+            None => "/me/mailFolders?$top=250".to_string(),
+        };
+        let folders: GraphList<GraphFolder> = self.get(&path).await?.json().await?;
+        Ok(folders.value)
+    }
+
+    // Resolve an IMAP mailbox name to a Graph folder id or well-known name
+    async fn resolve_folder(&self, folder_name: &str) -> Result<String, ExchangeError> {
+        if let Some(distinguished) = distinguished_folder(folder_name) {
+            return Ok(distinguished.to_string());
+        }
+
+        // OData string literals escape single quotes by doubling them
+        let filter = format!("displayName eq '{}'", folder_name.replace('\'', "''"));
+        let path = format!("/me/mailFolders?$filter={}", urlencoding::encode(&filter));
+        let folders: GraphList<GraphFolder> = self.get(&path).await?.json().await?;
+
+        folders.value.into_iter().next()
+            .map(|folder| folder.id)
+            .ok_or_else(|| ExchangeError::ParseError(format!("Folder not found: {}", folder_name)))
+    }
+
+    pub async fn keepalive(&self) -> Result<(), ExchangeError> {
+        self.get("/me/mailFolders/inbox?$select=id").await?;
+        Ok(())
+    }
+
+    pub async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        debug!("Listing Graph folders with reference '{}' and pattern '{}'", reference, pattern);
+
+        let matcher = mailbox_pattern(pattern)?;
+        let root = if reference.is_empty() { None } else { Some(reference.to_string()) };
+
+        // Walk the folder tree, building "/" separated mailbox paths
+        let mut result = Vec::new();
+        let mut pending = vec![(root, String::new())];
+        while let Some((parent, prefix)) = pending.pop() {
+            for folder in self.child_folders(parent.as_deref()).await? {
+                let path = if prefix.is_empty() {
+                    folder.display_name.clone()
+                } else {
+                    format!("{}/{}", prefix, folder.display_name)
+                };
+                if folder.child_folder_count > 0 {
+                    pending.push((Some(folder.id.clone()), path.clone()));
+                }
+                if matcher.is_match(&path) {
+                    result.push(path);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        debug!("Selecting Graph folder: {}", folder_name);
+
+        let folder_id = self.resolve_folder(folder_name).await?;
+        let folder: GraphFolder = self.get(&format!("/me/mailFolders/{}", urlencoding::encode(&folder_id)))
+            .await?.json().await?;
+
+        // The folder id survives renames, unlike the name
+        let uid_validity = uidmap::uid_validity(0, &folder_id);
+
+        Ok(FolderStats {
+            exists: folder.total_item_count,
+            recent: 0,
+            unseen: folder.unread_item_count,
+            uid_validity,
+            uid_next: 1001 + folder.total_item_count,
+            read_only: false,
+        })
+    }
+
+    pub async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str)
+        -> Result<Vec<Message>, ExchangeError> {
+        debug!("Fetching Graph messages from folder '{}', sequence '{}', items '{}'",
+               folder, sequence_set, items);
+
+        let sequences = crate::exchange::parse_sequence_set(sequence_set)?;
+        let top = sequences.iter().copied().max().unwrap_or(0);
+        if top == 0 {
+            return Ok(Vec::new());
+        }
+
+        let folder_id = self.resolve_folder(folder).await?;
+        let path = format!(
+            "/me/mailFolders/{}/messages?$top={}&$orderby=receivedDateTime&$select=id,isRead,isDraft,flag\
+             &$expand=singleValueExtendedProperties($filter=id%20eq%20'Integer%200x1081')",
+            urlencoding::encode(&folder_id), top
+        );
+        let messages: GraphList<GraphMessage> = self.get(&path).await?.json().await?;
+
+        let fetch_items: Vec<&str> = items.trim_matches(|c| c == '(' || c == ')').split_whitespace().collect();
+        let needs_body = fetch_items.iter().any(|item| item.starts_with("BODY"));
+
+        let mut result = Vec::new();
+        for &seq in &sequences {
+            let message = match seq.checked_sub(1).and_then(|index| messages.value.get(index as usize)) {
+                Some(message) => message,
+                None => continue,
+            };
+
+            let mime = if needs_body {
+                Some(Arc::new(self.get_mime(&message.id).await?))
+            } else {
+                None
+            };
+
+            let mut data_items = Vec::new();
+            for item in &fetch_items {
+                match *item {
+                    "FLAGS" => {
+                        let mut flags = Vec::new();
+                        if message.is_read {
+                            flags.push("\\Seen");
+                        }
+                        if message.is_draft {
+                            flags.push("\\Draft");
+                        }
+                        if message.is_answered() {
+                            flags.push("\\Answered");
+                        }
+                        if message.flag.as_ref().is_some_and(|flag| flag.flag_status == "flagged") {
+                            flags.push("\\Flagged");
+                        }
+                        data_items.push(FetchItem::Value(format!("FLAGS ({})", flags.join(" "))));
+                    },
+                    "UID" => {
+                        data_items.push(FetchItem::Value(format!("UID {}", 1000 + seq)));
+                    },
+                    item if item.starts_with("BODY[HEADER]") => {
+                        if let Some(mime) = &mime {
+                            data_items.push(FetchItem::Literal("BODY[HEADER]".to_string(), BodySection::header(mime.clone())));
+                        }
+                    },
+                    item if item.starts_with("BODY[TEXT]") => {
+                        if let Some(mime) = &mime {
+                            data_items.push(FetchItem::Literal("BODY[TEXT]".to_string(), BodySection::text(mime.clone())));
+                        }
+                    },
+                    item if item == "BODY[]" || item.starts_with("BODY[") => {
+                        if let Some(mime) = &mime {
+                            data_items.push(FetchItem::Literal("BODY[]".to_string(), BodySection::whole(mime.clone())));
+                        }
+                    },
+                    _ => {
+                        // Ignore unsupported items
+                    }
+                }
+            }
+
+            if !data_items.is_empty() {
+                result.push(Message {
+                    sequence: seq,
+                    items: data_items,
+                });
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+// Translate an IMAP LIST pattern ("*" any, "%" any but hierarchy delimiter) to a regex
+fn mailbox_pattern(pattern: &str) -> Result<regex::Regex, ExchangeError> {
+    let escaped = regex::escape(pattern)
+        .replace("\\*", ".*")
+        .replace('%', "[^/]*");
+    regex::Regex::new(&format!("^{}$", escaped))
+        .map_err(|e| ExchangeError::ParseError(format!("Invalid pattern: {}", e)))
+}
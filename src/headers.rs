@@ -0,0 +1,167 @@
+// headers.rs
+// Header rewriting: davmail.headerRules lists the rules, each configured under davmail.headerRule.<name>.*.
+// They apply to fetched messages, e.g. to strip internal routing headers.
+
+use config::Config;
+use log::warn;
+use regex::Regex;
+use regex::bytes::Regex as BytesRegex;
+
+use crate::exchange::mime::{is_signed_or_encrypted, split_header};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderAction {
+    // A new field at the end of the header, whether or not the message already has one
+    Add(String),
+    Remove,
+    // The whole value, or with match set each matching part of it ($1 refers to its groups)
+    Replace(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub name: String,
+    // Field name as configured, * matches any characters, e.g. X-MS-Exchange-*
+    header: String,
+    header_pattern: Regex,
+    // Only fields whose unfolded value matches
+    value_pattern: Option<BytesRegex>,
+    pub action: HeaderAction,
+}
+
+impl HeaderRule {
+    // davmail.headerRule.<name>.header / action / value / match
+    fn from_config(config: &Config, name: &str) -> Result<HeaderRule, String> {
+        let setting = |key: &str| config.get_string(&format!("davmail.headerRule.{}.{}", name, key)).ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+
+        let header = setting("header").ok_or("header is required")?;
+        if header.contains(|c: char| c == ':' || c.is_whitespace() || c.is_control()) {
+            return Err(format!("invalid header name '{}'", header));
+        }
+        let header_pattern = Regex::new(&format!("(?i)^{}$", regex::escape(&header).replace(r"\*", ".*")))
+            .map_err(|e| e.to_string())?;
+        let value_pattern = setting("match")
+            .map(|pattern| BytesRegex::new(&pattern).map_err(|e| format!("invalid match: {}", e)))
+            .transpose()?;
+
+        let value = setting("value");
+        let action = match (setting("action").as_deref().map(str::to_lowercase).as_deref(), value) {
+            (Some("add"), Some(_)) if header.contains('*') => return Err("add needs a header name without *".to_string()),
+            (Some("add"), Some(_)) if value_pattern.is_some() => return Err("add can't be combined with match".to_string()),
+            (Some("add"), Some(value)) => HeaderAction::Add(value),
+            (Some("remove"), None) => HeaderAction::Remove,
+            (Some("replace"), Some(value)) => HeaderAction::Replace(value),
+            (Some("add" | "replace"), None) => return Err("value is required".to_string()),
+            (Some("remove"), Some(_)) => return Err("remove takes no value".to_string()),
+            (Some(other), _) => return Err(format!("unknown action '{}'", other)),
+            (None, _) => return Err("action is required, add, remove or replace".to_string()),
+        };
+        Ok(HeaderRule { name: name.to_string(), header, header_pattern, value_pattern, action })
+    }
+
+    fn matches(&self, field: &Field) -> bool {
+        self.header_pattern.is_match(&String::from_utf8_lossy(field.name()))
+            && self.value_pattern.as_ref().is_none_or(|pattern| pattern.is_match(&field.value()))
+    }
+}
+
+// A header field with its continuation lines and line endings, as it appears in the message
+struct Field(Vec<u8>);
+
+impl Field {
+    fn new(name: &[u8], value: &[u8], line_end: &[u8]) -> Self {
+        Field([name, b": ", value, line_end].concat())
+    }
+
+    fn name(&self) -> &[u8] {
+        let end = self.0.iter().position(|&byte| byte == b':').unwrap_or(self.0.len());
+        self.0[..end].trim_ascii()
+    }
+
+    // Unfolded value, RFC 5322 2.2.3
+    fn value(&self) -> Vec<u8> {
+        let start = self.0.iter().position(|&byte| byte == b':').map_or(self.0.len(), |colon| colon + 1);
+        let value: Vec<u8> = self.0[start..].iter().copied().filter(|&byte| byte != b'\r' && byte != b'\n').collect();
+        value.trim_ascii().to_vec()
+    }
+}
+
+// Rules in the order of davmail.headerRules, each one applies to every field it matches
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    // Invalid rules are logged and left out, the others still apply
+    pub fn from_config(config: &Config) -> HeaderRules {
+        let names = config.get_string("davmail.headerRules").unwrap_or_default();
+        let rules = names.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| HeaderRule::from_config(config, name)
+                .map_err(|e| warn!("Ignoring header rule {}: {}", name, e))
+                .ok())
+            .collect();
+        HeaderRules { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // The message with its top-level header rewritten, None when no rule changed it. Signed and
+    // encrypted messages are left as they are, like the other conversions.
+    pub fn rewrite(&self, content: &[u8]) -> Option<Vec<u8>> {
+        if self.rules.is_empty() || is_signed_or_encrypted(content) {
+            return None;
+        }
+        let (head, body) = split_header(content)?;
+        let separator = &content[head.len()..content.len() - body.len()];
+        let line_end: &[u8] = if separator.starts_with(b"\r\n") { b"\r\n" } else { b"\n" };
+
+        let mut fields: Vec<Field> = Vec::new();
+        for line in head.split_inclusive(|&byte| byte == b'\n') {
+            match fields.last_mut() {
+                Some(field) if line.starts_with(b" ") || line.starts_with(b"\t") => field.0.extend_from_slice(line),
+                _ => fields.push(Field(line.to_vec())),
+            }
+        }
+
+        let mut changed = false;
+        for rule in &self.rules {
+            match &rule.action {
+                HeaderAction::Add(value) => {
+                    fields.push(Field::new(rule.header.as_bytes(), value.as_bytes(), line_end));
+                    changed = true;
+                },
+                HeaderAction::Remove => {
+                    let count = fields.len();
+                    fields.retain(|field| !rule.matches(field));
+                    changed |= fields.len() != count;
+                },
+                HeaderAction::Replace(replacement) => {
+                    for field in fields.iter_mut().filter(|field| rule.matches(field)) {
+                        let value = match &rule.value_pattern {
+                            Some(pattern) => pattern.replace_all(&field.value(), replacement.as_bytes()).into_owned(),
+                            None => replacement.as_bytes().to_vec(),
+                        };
+                        let name = field.name().to_vec();
+                        *field = Field::new(&name, &value, line_end);
+                        changed = true;
+                    }
+                },
+            }
+        }
+        if !changed {
+            return None;
+        }
+
+        let mut rewritten: Vec<u8> = fields.into_iter().flat_map(|field| field.0).collect();
+        rewritten.extend_from_slice(separator);
+        rewritten.extend_from_slice(body);
+        Some(rewritten)
+    }
+}
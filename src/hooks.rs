@@ -0,0 +1,105 @@
+// hooks.rs
+// Message processing hooks: embedders inspect or change logins and messages without forking the gateway
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use async_trait::async_trait;
+use log::warn;
+
+use crate::exchange::Message;
+
+/// Refuses the login or the message, the reason is logged and not shown to the client
+pub type HookResult = Result<(), String>;
+
+/// A user that authenticated with Exchange, before the client is told so
+pub struct LoginInfo<'a> {
+    pub protocol: &'static str,
+    pub username: &'a str,
+    pub client_address: IpAddr,
+    /// Logged in to the metadata cache while Exchange is unreachable
+    pub offline: bool,
+}
+
+/// Where a message is read from or submitted by
+pub struct MessageContext<'a> {
+    pub protocol: &'static str,
+    pub username: &'a str,
+    /// Mailbox folder the message is read from, None for submissions
+    pub folder: Option<&'a str>,
+}
+
+/// A message on its way to Exchange, before it is sent
+pub struct OutgoingMessage {
+    pub from: String,
+    pub recipients: Vec<String>,
+    /// Complete MIME content
+    pub content: Vec<u8>,
+}
+
+/// Implement only the methods needed, the others let everything through.
+/// Hooks run on the connection's task, slow work should be handed off to a task of its own.
+#[async_trait]
+pub trait MessageHook: Send + Sync {
+    /// An error refuses the login
+    async fn on_login(&self, _login: &LoginInfo<'_>) -> HookResult {
+        Ok(())
+    }
+
+    /// A fetched message before the client gets it. The FETCH items can be changed, e.g. a body
+    /// section replaced with `BodySection::whole(Arc::new(MimeBody::from_bytes(..)))`, or an
+    /// error logged and the message sent unchanged. Signed and encrypted messages
+    /// (`exchange::mime::is_signed_or_encrypted`) should be left as they are, any change breaks the signature.
+    async fn on_incoming_message(&self, _context: &MessageContext<'_>, _message: &mut Message) -> HookResult {
+        Ok(())
+    }
+
+    /// A message the client submits, an error refuses it
+    async fn on_outgoing_message(&self, _context: &MessageContext<'_>, _message: &mut OutgoingMessage) -> HookResult {
+        Ok(())
+    }
+}
+
+/// Hooks registered at startup, run in registration order
+#[derive(Default, Clone)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn MessageHook>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Hooks::default()
+    }
+
+    pub fn register(&mut self, hook: Arc<dyn MessageHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    // The first refusal wins, later hooks don't see the login
+    pub async fn login(&self, login: &LoginInfo<'_>) -> HookResult {
+        for hook in &self.hooks {
+            hook.on_login(login).await?;
+        }
+        Ok(())
+    }
+
+    // A failing hook doesn't keep the message from the client, the next hooks still run
+    pub async fn incoming_message(&self, context: &MessageContext<'_>, message: &mut Message) {
+        for hook in &self.hooks {
+            if let Err(e) = hook.on_incoming_message(context, message).await {
+                warn!("Message hook failed on message {} of {}: {}", message.sequence, context.folder.unwrap_or_default(), e);
+            }
+        }
+    }
+
+    // The first refusal wins, the message is not sent
+    pub async fn outgoing_message(&self, context: &MessageContext<'_>, message: &mut OutgoingMessage) -> HookResult {
+        for hook in &self.hooks {
+            hook.on_outgoing_message(context, message).await?;
+        }
+        Ok(())
+    }
+}
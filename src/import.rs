@@ -0,0 +1,105 @@
+// import.rs
+// davmail-rust import: upload .eml files or an mbox into an Exchange folder, for migrations from other mail systems
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use config::Config;
+use log::{error, info};
+use tokio::runtime::Runtime;
+
+use davmail_core::exchange::http::HttpClientConfig;
+use davmail_core::exchange::import::{ImportMessage, MboxReader};
+
+use crate::signin;
+
+// Progress is logged every this many messages
+const PROGRESS_INTERVAL: usize = 100;
+
+pub fn run(config: &Config, path: &Path, username: &str, folder: &str, mark_read: bool) -> Result<(), Box<dyn Error>> {
+    let sources = sources(path)?;
+    let login = signin::login_for(config, username)?;
+    Runtime::new()?.block_on(async {
+        let url = config.get_string("davmail.url").map_err(|_| "davmail.url is not configured")?;
+        let http_config = HttpClientConfig::from_config(config);
+        let client = http_config.build()?;
+        let (exchange_client, _) = signin::authenticate(config, url.trim_end_matches('/'), &http_config, &client, username, login).await?;
+        let exchange_client = exchange_client.with_request_timeouts(http_config.timeouts);
+        let target = exchange_client.resolve_folder(folder).await?;
+
+        let import = async |label: String, message: ImportMessage| {
+            let message = ImportMessage { is_read: message.is_read || mark_read, ..message };
+            exchange_client.import_message(&target, &message).await
+                .map_err(|e| error!("Failed to import {} ('{}'): {}", label, message.subject(), e))
+                .is_ok()
+        };
+
+        let (mut imported, mut failed) = (0, 0);
+        for source in &sources {
+            let results = match source {
+                Source::Eml(path) => match fs::read(path) {
+                    Ok(content) => vec![import(path.display().to_string(), ImportMessage::from_mime(content)).await],
+                    Err(e) => {
+                        error!("Failed to read {}: {}", path.display(), e);
+                        vec![false]
+                    }
+                },
+                Source::Mbox(path) => {
+                    let mut results = Vec::new();
+                    let reader = MboxReader::new(BufReader::new(File::open(path)?));
+                    for (index, message) in reader.enumerate() {
+                        // A read error ends the file, its later messages can't be found
+                        let message = message.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                        let ok = import(format!("message {} of {}", index + 1, path.display()), message).await;
+                        if (index + 1) % PROGRESS_INTERVAL == 0 {
+                            info!("{} message(s) of {} processed", index + 1, path.display());
+                        }
+                        results.push(ok);
+                    }
+                    results
+                }
+            };
+            for ok in results {
+                if ok { imported += 1 } else { failed += 1 }
+            }
+            if matches!(source, Source::Eml(_)) && (imported + failed) % PROGRESS_INTERVAL == 0 {
+                info!("{} message(s) imported into {}, {} failed", imported, folder, failed);
+            }
+        }
+
+        println!("Imported {} message(s) into {}{}", imported, folder,
+            if failed > 0 { format!(", {} failed", failed) } else { String::new() });
+        if failed > 0 {
+            return Err(format!("{} message(s) could not be imported", failed).into());
+        }
+        Ok(())
+    })
+}
+
+enum Source {
+    Eml(PathBuf),
+    Mbox(PathBuf),
+}
+
+// The .eml files of a directory by name, a single .eml file, or any other file read as an mbox
+fn sources(path: &Path) -> Result<Vec<Source>, Box<dyn Error>> {
+    let is_eml = |path: &Path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("eml"));
+    if path.is_dir() {
+        let mut files: Vec<PathBuf> = fs::read_dir(path)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.is_file() && is_eml(file))
+            .collect();
+        if files.is_empty() {
+            return Err(format!("No .eml files in {}", path.display()).into());
+        }
+        files.sort();
+        Ok(files.into_iter().map(Source::Eml).collect())
+    } else if is_eml(path) {
+        Ok(vec![Source::Eml(path.to_path_buf())])
+    } else if path.is_file() {
+        Ok(vec![Source::Mbox(path.to_path_buf())])
+    } else {
+        Err(format!("{} is neither a directory nor a file", path.display()).into())
+    }
+}
@@ -0,0 +1,54 @@
+// lib.rs
+// davmail-core: the gateway as a library, main.rs is the command line front end on top of it
+
+//! An Exchange / Office 365 gateway in the spirit of DavMail.
+//!
+//! The crate can be embedded whole or in parts:
+//!
+//! * [`ImapServer`] serves IMAP clients from Exchange mailboxes. It runs on the caller's tokio
+//!   runtime until its shutdown signal is set, see `DavMailRust::start_imap_server` in the
+//!   binary for how the shared state it needs is built from a configuration.
+//! * [`ExchangeClient`] is the EWS client the servers use, usable on its own with
+//!   [`auth::BasicAuth`], [`auth::OAuth2Auth`] or [`auth::NtlmAuth`].
+//! * [`hooks::MessageHook`] implementations registered with [`ImapServer::with_hooks`] inspect or
+//!   change logins and messages, for filtering, tagging or archiving.
+//! * [`auth::TokenManager`] keeps OAuth2 tokens renewed in the background and
+//!   [`auth::TokenStore`] persists them.
+//! * [`Error`] wraps the errors of all of these, and [`error::ProtocolStatus`] maps any of them to
+//!   the IMAP response code, SMTP reply or HTTP status a client should get.
+//!
+//! Settings use the `davmail.*` keys of the DavMail properties file, read through the `config` crate.
+//! Process-wide facilities (`audit`, `logfile`, `logformat`, `syslog`, `telemetry`, `wirelog`,
+//! `exchange::spool`) are set up with their `configure` function and can be left alone by embedders.
+
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod autoconfig;
+pub mod configuration;
+pub mod error;
+pub mod exchange;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod graph;
+pub mod headers;
+pub mod hooks;
+pub mod logfile;
+pub mod logformat;
+pub mod mailstore;
+pub mod metadata;
+#[cfg(feature = "test-support")]
+pub mod mock_ews;
+pub mod protocols;
+pub mod rules;
+pub mod syslog;
+pub mod systemd;
+pub mod telemetry;
+pub mod uidmap;
+pub mod users;
+pub mod wirelog;
+
+pub use error::Error;
+pub use exchange::{ExchangeClient, ExchangeError};
+pub use protocols::imap::ImapServer;
+pub use configuration::{LiveSettings, SharedSettings, UserOverrides};
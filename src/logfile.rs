@@ -0,0 +1,179 @@
+// logfile.rs
+// Log output of the daemon, stderr until davmail.logFilePath names a file, rotated by size or by day
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::{DateTime, Local, NaiveDate};
+use config::Config;
+use log::{error, info};
+
+pub const DEFAULT_LOG_FILE_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_LOG_FILE_COUNT: usize = 5;
+
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+// Given to env_logger once at startup, so the destination can change with the configuration
+pub struct LogOutput;
+
+pub fn output() -> Box<dyn Write + Send> {
+    Box::new(LogOutput)
+}
+
+impl Write for LogOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => file.write(buf),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => io::stderr().flush(),
+        }
+    }
+}
+
+// davmail.logFilePath, rotated past davmail.logFileSize bytes (0 for no limit) and, with
+// davmail.logFileRotation = daily, at the first record of a new day. davmail.logFileCount rotated
+// files are kept as <path>.1 (newest) to <path>.<count>.
+pub fn configure(config: &Config) {
+    let path = config.get_string("davmail.logFilePath").ok().filter(|path| !path.trim().is_empty());
+    let Some(path) = path else {
+        if LOG_FILE.lock().unwrap().take().is_some() {
+            info!("Logging to stderr");
+        }
+        return;
+    };
+
+    let max_size = match config.get_string("davmail.logFileSize") {
+        Ok(size) => parse_size(&size).unwrap_or_else(|| {
+            error!("Invalid davmail.logFileSize value '{}'", size);
+            DEFAULT_LOG_FILE_SIZE
+        }),
+        Err(_) => DEFAULT_LOG_FILE_SIZE,
+    };
+    let count = config.get_int("davmail.logFileCount").map_or(DEFAULT_LOG_FILE_COUNT, |count| count.max(0) as usize);
+    let daily = match config.get_string("davmail.logFileRotation") {
+        Ok(rotation) => match rotation.trim().to_lowercase().as_str() {
+            "daily" => true,
+            "size" | "" => false,
+            _ => {
+                error!("Unknown davmail.logFileRotation value '{}'", rotation);
+                false
+            },
+        },
+        Err(_) => false,
+    };
+
+    match RotatingFile::open(PathBuf::from(path.trim()), max_size, count, daily) {
+        Ok(file) => {
+            *LOG_FILE.lock().unwrap() = Some(file);
+            info!("Logging to {}", path.trim());
+        },
+        Err(e) => error!("Failed to open log file {}: {}", path.trim(), e),
+    }
+}
+
+// 1048576, 512K, 10MB or 1G
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (digits, unit) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits.trim().parse::<u64>().ok().map(|size| size * unit)
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    // None between closing the full file and opening its replacement
+    file: Option<File>,
+    size: u64,
+    opened_on: NaiveDate,
+    max_size: u64,
+    count: usize,
+    daily: bool,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, count: usize, daily: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left by the previous run counts from the day it was last written to
+        let opened_on = metadata.modified().map_or_else(|_| Local::now().date_naive(), |modified| {
+            DateTime::<Local>::from(modified).date_naive()
+        });
+        Ok(RotatingFile { path, file: Some(file), size: metadata.len(), opened_on, max_size, count, daily })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        (self.max_size > 0 && self.size + incoming as u64 > self.max_size)
+            || (self.daily && Local::now().date_naive() != self.opened_on)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Windows doesn't rename open files, nor onto existing ones
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        let _ = fs::remove_file(self.rotated_path(self.count.max(1)));
+        if self.count == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.count).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            if let Err(e) = self.rotate() {
+                // Keep writing to the current file rather than losing records, and retry at the next limit
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+                self.size = 0;
+                self.opened_on = Local::now().date_naive();
+            }
+        }
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => OpenOptions::new().create(true).append(true).open(&self.path)?,
+        };
+        let file = self.file.insert(file);
+        file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+// logformat.rs
+// Text or JSON log records, JSON ones carrying the connection, protocol and user they were logged for
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use chrono::Utc;
+use config::Config;
+use env_logger::fmt::Formatter;
+use log::{warn, Record};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+// What a client connection task knows about itself, the user only once logged in
+struct ConnectionContext {
+    id: u64,
+    protocol: &'static str,
+    peer: String,
+    user: Option<String>,
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<ConnectionContext>;
+}
+
+// davmail.logFormat, text (the default) or json
+pub fn configure(config: &Config) {
+    let json = match config.get_string("davmail.logFormat") {
+        Ok(format) => match format.trim().to_lowercase().as_str() {
+            "json" => true,
+            "text" | "" => false,
+            _ => {
+                warn!("Unknown davmail.logFormat value '{}'", format);
+                false
+            },
+        },
+        Err(_) => false,
+    };
+    JSON.store(json, Ordering::Relaxed);
+}
+
+// Run a client connection with its own connection id, records it logs are tagged with it
+pub fn scope<F: Future>(protocol: &'static str, peer: String, connection: F) -> impl Future<Output = F::Output> {
+    let id = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    CONTEXT.scope(RefCell::new(ConnectionContext { id, protocol, peer, user: None }), connection)
+}
+
+// Called once the connection has authenticated
+pub fn set_user(user: &str) {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().user = Some(user.to_string()));
+}
+
+// Installed with env_logger::Builder::format, the format can change with a configuration reload
+pub fn format(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    if !JSON.load(Ordering::Relaxed) {
+        // Same layout as env_logger's default format
+        return writeln!(buf, "[{} {:<5} {}] {}", buf.timestamp(), record.level(), record.target(), record.args());
+    }
+
+    let mut line = String::with_capacity(256);
+    line.push('{');
+    push_field(&mut line, "timestamp", &Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string());
+    line.push(',');
+    push_field(&mut line, "level", record.level().as_str());
+    line.push(',');
+    push_field(&mut line, "target", record.target());
+    let _ = CONTEXT.try_with(|context| {
+        let context = context.borrow();
+        let _ = write!(line, ",\"connection\":{},", context.id);
+        push_field(&mut line, "protocol", context.protocol);
+        line.push(',');
+        push_field(&mut line, "peer", &context.peer);
+        if let Some(user) = &context.user {
+            line.push(',');
+            push_field(&mut line, "user", user);
+        }
+    });
+    line.push_str(",\"fields\":{");
+    let mut fields = Vec::new();
+    if let Some(module) = record.module_path() {
+        fields.push(("module", module.to_string()));
+    }
+    if let (Some(file), Some(number)) = (record.file(), record.line()) {
+        fields.push(("location", format!("{}:{}", file, number)));
+    }
+    for (index, (name, value)) in fields.iter().enumerate() {
+        if index > 0 {
+            line.push(',');
+        }
+        push_field(&mut line, name, value);
+    }
+    line.push_str("},");
+    push_field(&mut line, "message", &record.args().to_string());
+    line.push('}');
+    writeln!(buf, "{}", line)
+}
+
+fn push_field(line: &mut String, name: &str, value: &str) {
+    push_json_string(line, name);
+    line.push(':');
+    push_json_string(line, value);
+}
+
+pub fn push_json_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            },
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
@@ -0,0 +1,474 @@
+// mailstore.rs
+// Backend abstraction so protocol servers don't depend on a specific Exchange transport
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use async_trait::async_trait;
+use config::Config;
+use futures_util::future::join;
+use reqwest::Client;
+use log::{info, warn};
+
+use crate::auth::{OAuth2Auth, OAuth2Config};
+use crate::exchange::{parse_sequence_set, sync, ExchangeClient, ExchangeError, FolderStats, Message};
+use crate::exchange::sync::MetadataSync;
+use crate::graph::{GraphClient, DEFAULT_GRAPH_SCOPE};
+use crate::rules::{Rule, RulesPass};
+
+// Operations the protocol servers need from a mailbox backend
+#[async_trait]
+pub trait MailStore: Send + Sync {
+    async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError>;
+
+    async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError>;
+
+    // Counts for STATUS, which backends may serve from a short-lived cache where SELECT can't
+    async fn folder_status(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        self.select_folder(folder_name).await
+    }
+
+    async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) -> Result<Vec<Message>, ExchangeError>;
+
+    // The name the backend knows a mailbox by, e.g. a localized well-known folder name in English
+    async fn canonical_folder_name(&self, folder: &str) -> String {
+        folder.to_string()
+    }
+
+    // Bulk read flag change for a whole folder, backends without a bulk operation don't support it
+    async fn mark_all_read(&self, folder: &str, _read: bool) -> Result<(), ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("mark all read in {}", folder)))
+    }
+
+    // Set or clear \Answered on the messages of a sequence set
+    async fn set_answered(&self, folder: &str, _sequence_set: &str, _answered: bool) -> Result<(), ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("\\Answered in {}", folder)))
+    }
+
+    // Move the messages of a sequence set to another folder, returns the sequence numbers moved
+    async fn move_messages(&self, folder: &str, _sequence_set: &str, _target: &str) -> Result<Vec<u32>, ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("move from {}", folder)))
+    }
+
+    // Report the messages of a sequence set as junk or as not junk
+    async fn set_junk(&self, folder: &str, _sequence_set: &str, _junk: bool) -> Result<(), ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("junk reports in {}", folder)))
+    }
+
+    // Record that the client sent the read receipts of a sequence set itself ($MDNSent)
+    async fn set_mdn_sent(&self, folder: &str, _sequence_set: &str) -> Result<(), ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("$MDNSent in {}", folder)))
+    }
+
+    // Permanently delete every message in the folder
+    async fn empty_folder(&self, folder: &str) -> Result<(), ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("empty folder {}", folder)))
+    }
+
+    // Sequence numbers of the messages matching IMAP search criteria
+    async fn search(&self, folder: &str, _criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("search in {}", folder)))
+    }
+
+    // Carry out the gateway's mail rules on the messages of the folder received after `received_after`
+    async fn apply_rules(&self, folder: &str, _rules: &[&Rule], _received_after: Option<&str>) -> Result<RulesPass, ExchangeError> {
+        Err(ExchangeError::Unsupported(format!("mail rules in {}", folder)))
+    }
+
+    // Lightweight request sent while the client is idle so the server side session doesn't expire
+    async fn keepalive(&self) -> Result<(), ExchangeError>;
+}
+
+#[async_trait]
+impl MailStore for ExchangeClient {
+    async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        ExchangeClient::list_folders(self, reference, pattern).await
+    }
+
+    async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        ExchangeClient::select_folder(self, folder_name).await
+    }
+
+    async fn folder_status(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        ExchangeClient::folder_status(self, folder_name).await
+    }
+
+    async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) -> Result<Vec<Message>, ExchangeError> {
+        ExchangeClient::fetch_messages(self, folder, sequence_set, items).await
+    }
+
+    async fn canonical_folder_name(&self, folder: &str) -> String {
+        ExchangeClient::canonical_folder_name(self, folder).await
+    }
+
+    async fn mark_all_read(&self, folder: &str, read: bool) -> Result<(), ExchangeError> {
+        ExchangeClient::mark_all_read(self, folder, read).await
+    }
+
+    async fn set_answered(&self, folder: &str, sequence_set: &str, answered: bool) -> Result<(), ExchangeError> {
+        ExchangeClient::set_answered(self, folder, sequence_set, answered).await
+    }
+
+    async fn move_messages(&self, folder: &str, sequence_set: &str, target: &str) -> Result<Vec<u32>, ExchangeError> {
+        ExchangeClient::move_messages(self, folder, sequence_set, target).await
+    }
+
+    async fn set_junk(&self, folder: &str, sequence_set: &str, junk: bool) -> Result<(), ExchangeError> {
+        ExchangeClient::set_junk(self, folder, sequence_set, junk).await
+    }
+
+    async fn set_mdn_sent(&self, folder: &str, sequence_set: &str) -> Result<(), ExchangeError> {
+        ExchangeClient::set_mdn_sent(self, folder, sequence_set).await
+    }
+
+    async fn empty_folder(&self, folder: &str) -> Result<(), ExchangeError> {
+        ExchangeClient::empty_folder(self, folder).await
+    }
+
+    async fn search(&self, folder: &str, criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+        ExchangeClient::search(self, folder, criteria).await
+    }
+
+    async fn apply_rules(&self, folder: &str, rules: &[&Rule], received_after: Option<&str>) -> Result<RulesPass, ExchangeError> {
+        ExchangeClient::apply_rules(self, folder, rules, received_after).await
+    }
+
+    async fn keepalive(&self) -> Result<(), ExchangeError> {
+        ExchangeClient::keepalive(self).await
+    }
+}
+
+// Sessions shared between connections through the session cache
+#[async_trait]
+impl<T: MailStore + ?Sized> MailStore for Arc<T> {
+    async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        (**self).list_folders(reference, pattern).await
+    }
+
+    async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        (**self).select_folder(folder_name).await
+    }
+
+    async fn folder_status(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        (**self).folder_status(folder_name).await
+    }
+
+    async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) -> Result<Vec<Message>, ExchangeError> {
+        (**self).fetch_messages(folder, sequence_set, items).await
+    }
+
+    async fn canonical_folder_name(&self, folder: &str) -> String {
+        (**self).canonical_folder_name(folder).await
+    }
+
+    async fn mark_all_read(&self, folder: &str, read: bool) -> Result<(), ExchangeError> {
+        (**self).mark_all_read(folder, read).await
+    }
+
+    async fn set_answered(&self, folder: &str, sequence_set: &str, answered: bool) -> Result<(), ExchangeError> {
+        (**self).set_answered(folder, sequence_set, answered).await
+    }
+
+    async fn move_messages(&self, folder: &str, sequence_set: &str, target: &str) -> Result<Vec<u32>, ExchangeError> {
+        (**self).move_messages(folder, sequence_set, target).await
+    }
+
+    async fn set_junk(&self, folder: &str, sequence_set: &str, junk: bool) -> Result<(), ExchangeError> {
+        (**self).set_junk(folder, sequence_set, junk).await
+    }
+
+    async fn set_mdn_sent(&self, folder: &str, sequence_set: &str) -> Result<(), ExchangeError> {
+        (**self).set_mdn_sent(folder, sequence_set).await
+    }
+
+    async fn empty_folder(&self, folder: &str) -> Result<(), ExchangeError> {
+        (**self).empty_folder(folder).await
+    }
+
+    async fn search(&self, folder: &str, criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+        (**self).search(folder, criteria).await
+    }
+
+    async fn apply_rules(&self, folder: &str, rules: &[&Rule], received_after: Option<&str>) -> Result<RulesPass, ExchangeError> {
+        (**self).apply_rules(folder, rules, received_after).await
+    }
+
+    async fn keepalive(&self) -> Result<(), ExchangeError> {
+        (**self).keepalive().await
+    }
+}
+
+#[async_trait]
+impl MailStore for GraphClient {
+    async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        GraphClient::list_folders(self, reference, pattern).await
+    }
+
+    async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        GraphClient::select_folder(self, folder_name).await
+    }
+
+    async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) -> Result<Vec<Message>, ExchangeError> {
+        GraphClient::fetch_messages(self, folder, sequence_set, items).await
+    }
+
+    async fn keepalive(&self) -> Result<(), ExchangeError> {
+        GraphClient::keepalive(self).await
+    }
+}
+
+// Session opened while Exchange is unreachable: the metadata cache alone, read-only apart from
+// read flag changes queued for the next sync
+#[async_trait]
+impl MailStore for MetadataSync {
+    async fn list_folders(&self, _reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        MetadataSync::list_folders(self, pattern)
+    }
+
+    async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        let (state, messages) = self.messages(folder_name)?;
+        Ok(sync::folder_stats(&state, &messages, true))
+    }
+
+    async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) -> Result<Vec<Message>, ExchangeError> {
+        let (_, messages) = self.messages(folder)?;
+        let selected = sync::select_messages(&messages, &parse_sequence_set(sequence_set)?);
+        let fetch_items: Vec<&str> = items.trim_matches(|c| c == '(' || c == ')').split_whitespace().collect();
+        let bodies = if fetch_items.iter().any(|item| item.starts_with("BODY[")) {
+            self.stored_bodies(&selected)?
+        } else {
+            HashMap::new()
+        };
+        // No read receipt keywords, receipts aren't answered while offline
+        Ok(sync::fetch_items_of(&selected, &fetch_items, &bodies, &[]))
+    }
+
+    async fn mark_all_read(&self, folder: &str, read: bool) -> Result<(), ExchangeError> {
+        MetadataSync::mark_all_read(self, folder, read)
+    }
+
+    async fn search(&self, folder: &str, criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+        let (_, messages) = self.messages(folder)?;
+        sync::search(&messages, criteria)
+    }
+
+    // Nothing to keep alive
+    async fn keepalive(&self) -> Result<(), ExchangeError> {
+        Ok(())
+    }
+}
+
+// Backend selected through davmail.mode, EWS by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendMode {
+    Ews,
+    Graph,
+    // EWS first, switching to Graph for the rest of the session when EWS fails. Each login authenticates
+    // with both, so a wrong password counts twice against Entra ID's smart lockout.
+    Auto,
+}
+
+impl BackendMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "ews" => Some(BackendMode::Ews),
+            "graph" => Some(BackendMode::Graph),
+            "auto" => Some(BackendMode::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        match config.get_string("davmail.mode") {
+            Ok(value) => BackendMode::parse(&value).unwrap_or_else(|| {
+                warn!("Unknown davmail.mode value '{}', using ews", value);
+                BackendMode::Ews
+            }),
+            Err(_) => BackendMode::Ews,
+        }
+    }
+}
+
+// Tries the primary backend and permanently falls back once it fails
+pub struct FallbackStore {
+    primary: Box<dyn MailStore>,
+    fallback: Box<dyn MailStore>,
+    use_fallback: AtomicBool,
+}
+
+impl FallbackStore {
+    pub fn new(primary: Box<dyn MailStore>, fallback: Box<dyn MailStore>) -> Self {
+        FallbackStore {
+            primary,
+            fallback,
+            use_fallback: AtomicBool::new(false),
+        }
+    }
+
+    fn active(&self) -> &dyn MailStore {
+        if self.use_fallback.load(Ordering::Relaxed) {
+            self.fallback.as_ref()
+        } else {
+            self.primary.as_ref()
+        }
+    }
+
+    fn switch_on(&self, error: &ExchangeError) -> bool {
+        match error {
+            // Transport and auth failures mean the primary backend is unusable, parse errors don't
+            ExchangeError::HttpError(_) | ExchangeError::AuthError(_) => {
+                if !self.use_fallback.swap(true, Ordering::Relaxed) {
+                    warn!("Primary backend failed ({}), switching to fallback backend", error);
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[async_trait]
+impl MailStore for FallbackStore {
+    async fn list_folders(&self, reference: &str, pattern: &str) -> Result<Vec<String>, ExchangeError> {
+        match self.active().list_folders(reference, pattern).await {
+            Err(e) if self.switch_on(&e) => self.fallback.list_folders(reference, pattern).await,
+            result => result,
+        }
+    }
+
+    async fn select_folder(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        match self.active().select_folder(folder_name).await {
+            Err(e) if self.switch_on(&e) => self.fallback.select_folder(folder_name).await,
+            result => result,
+        }
+    }
+
+    async fn folder_status(&self, folder_name: &str) -> Result<FolderStats, ExchangeError> {
+        match self.active().folder_status(folder_name).await {
+            Err(e) if self.switch_on(&e) => self.fallback.folder_status(folder_name).await,
+            result => result,
+        }
+    }
+
+    async fn fetch_messages(&self, folder: &str, sequence_set: &str, items: &str) -> Result<Vec<Message>, ExchangeError> {
+        match self.active().fetch_messages(folder, sequence_set, items).await {
+            Err(e) if self.switch_on(&e) => self.fallback.fetch_messages(folder, sequence_set, items).await,
+            result => result,
+        }
+    }
+
+    async fn canonical_folder_name(&self, folder: &str) -> String {
+        self.active().canonical_folder_name(folder).await
+    }
+
+    async fn mark_all_read(&self, folder: &str, read: bool) -> Result<(), ExchangeError> {
+        match self.active().mark_all_read(folder, read).await {
+            Err(e) if self.switch_on(&e) => self.fallback.mark_all_read(folder, read).await,
+            result => result,
+        }
+    }
+
+    async fn set_answered(&self, folder: &str, sequence_set: &str, answered: bool) -> Result<(), ExchangeError> {
+        match self.active().set_answered(folder, sequence_set, answered).await {
+            Err(e) if self.switch_on(&e) => self.fallback.set_answered(folder, sequence_set, answered).await,
+            result => result,
+        }
+    }
+
+    async fn move_messages(&self, folder: &str, sequence_set: &str, target: &str) -> Result<Vec<u32>, ExchangeError> {
+        match self.active().move_messages(folder, sequence_set, target).await {
+            Err(e) if self.switch_on(&e) => self.fallback.move_messages(folder, sequence_set, target).await,
+            result => result,
+        }
+    }
+
+    async fn set_junk(&self, folder: &str, sequence_set: &str, junk: bool) -> Result<(), ExchangeError> {
+        match self.active().set_junk(folder, sequence_set, junk).await {
+            Err(e) if self.switch_on(&e) => self.fallback.set_junk(folder, sequence_set, junk).await,
+            result => result,
+        }
+    }
+
+    async fn set_mdn_sent(&self, folder: &str, sequence_set: &str) -> Result<(), ExchangeError> {
+        match self.active().set_mdn_sent(folder, sequence_set).await {
+            Err(e) if self.switch_on(&e) => self.fallback.set_mdn_sent(folder, sequence_set).await,
+            result => result,
+        }
+    }
+
+    async fn empty_folder(&self, folder: &str) -> Result<(), ExchangeError> {
+        match self.active().empty_folder(folder).await {
+            Err(e) if self.switch_on(&e) => self.fallback.empty_folder(folder).await,
+            result => result,
+        }
+    }
+
+    async fn search(&self, folder: &str, criteria: &str) -> Result<Vec<u32>, ExchangeError> {
+        match self.active().search(folder, criteria).await {
+            Err(e) if self.switch_on(&e) => self.fallback.search(folder, criteria).await,
+            result => result,
+        }
+    }
+
+    async fn apply_rules(&self, folder: &str, rules: &[&Rule], received_after: Option<&str>) -> Result<RulesPass, ExchangeError> {
+        match self.active().apply_rules(folder, rules, received_after).await {
+            Err(e) if self.switch_on(&e) => self.fallback.apply_rules(folder, rules, received_after).await,
+            result => result,
+        }
+    }
+
+    async fn keepalive(&self) -> Result<(), ExchangeError> {
+        match self.active().keepalive().await {
+            Err(e) if self.switch_on(&e) => self.fallback.keepalive().await,
+            result => result,
+        }
+    }
+}
+
+// The user's OAuth2 authentication for the Graph scope. Graph tokens are kept apart from the EWS
+// ones, the shared token store holds a single token per account.
+pub fn graph_auth(config: &Config, username: &str, client: &Client) -> Option<OAuth2Auth> {
+    let mut oauth2_config = OAuth2Config::for_user(config, username)?;
+    oauth2_config.scope = config.get_string("davmail.graphScope")
+        .unwrap_or_else(|_| DEFAULT_GRAPH_SCOPE.to_string());
+    OAuth2Auth::new(oauth2_config, client.clone())
+        .map_err(|e| warn!("No Graph backend for {}: {}", username, e))
+        .ok()
+}
+
+// Session of an OAuth2 login on the backend selected by davmail.mode. `ews` opens the EWS session,
+// which shares the token renewal and caches of the user's other sessions. `graph_auth` is None for
+// logins whose credentials only work for EWS, those stay on EWS in auto mode.
+pub async fn connect_oauth2(config: &Config, ews: impl Future<Output = Result<Arc<dyn MailStore>, ExchangeError>>,
+                            graph_auth: Option<OAuth2Auth>, client: Client) -> Result<Arc<dyn MailStore>, ExchangeError> {
+    let mode = BackendMode::from_config(config);
+    let graph_url = config.get_string("davmail.graphUrl").unwrap_or_default();
+
+    info!("Connecting mailbox backend in {:?} mode", mode);
+
+    match (mode, graph_auth) {
+        (BackendMode::Ews, _) | (BackendMode::Auto, None) => ews.await,
+        (BackendMode::Graph, None) => Err(ExchangeError::ConfigError(
+            "davmail.mode graph needs davmail.oauth.ropc or davmail.oauth.onBehalfOf logins".to_string())),
+        (BackendMode::Graph, Some(graph_auth)) => {
+            let graph = GraphClient::new_with_oauth2_auth(&graph_url, graph_auth, client).await?;
+            Ok(Arc::new(graph))
+        },
+        (BackendMode::Auto, Some(graph_auth)) => {
+            // Both logins at once, so auto mode doesn't double the login time
+            match join(ews, GraphClient::new_with_oauth2_auth(&graph_url, graph_auth, client)).await {
+                (Ok(ews), Ok(graph)) => Ok(Arc::new(FallbackStore::new(Box::new(ews), Box::new(graph)))),
+                (Ok(ews), Err(e)) => {
+                    warn!("Graph backend unavailable ({}), using EWS only", e);
+                    Ok(ews)
+                },
+                (Err(e), Ok(graph)) => {
+                    warn!("EWS backend unavailable ({}), using Graph", e);
+                    Ok(Arc::new(graph))
+                },
+                (Err(e), Err(_)) => Err(e),
+            }
+        },
+    }
+}
@@ -1,52 +1,149 @@
 // DavMail Rust Implementation
 // A POP/IMAP/SMTP/CalDav/CardDav/LDAP gateway for Microsoft Exchange/Office 365
+// The gateway itself is the davmail_core library (lib.rs), this binary adds the command line, service and tray handling
 
 //use crate::imap::ImapServer;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use log::{info, error };
-use config::{Config, File, Environment};
-use ctrlc;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use log::{info, error, warn, LevelFilter};
+use config::Config;
+use clap::Parser;
 
-mod configuration;
-mod exchange;
-mod protocols;
+use davmail_core::{admin, configuration, exchange, logfile, logformat, protocols, syslog, systemd, telemetry, wirelog};
+use davmail_core::admin::{AdminCommand, AdminRequest, AdminServer, ListenerStatus};
+use davmail_core::auth::TokenManager;
+use davmail_core::configuration::{secrets, ConfigFile, LiveSettings, SharedSettings, UserOverrides};
+use davmail_core::exchange::http::HttpClientConfig;
+use davmail_core::exchange::limiter::RequestLimiter;
+use davmail_core::exchange::sessions::SessionCache;
+use davmail_core::audit;
+use davmail_core::metadata::MetadataCache;
+use davmail_core::protocols::gate::ConnectionGate;
+use davmail_core::protocols::lockout::LoginGuard;
+use davmail_core::users::UserRegistry;
+
+use crate::cli::{Cli, Command};
+use crate::daemon::PidFile;
+use crate::privileges::RunAs;
+use crate::signals::Signal;
+
+mod check;
+mod cli;
+mod daemon;
+mod diag;
+mod import;
+mod privileges;
+mod signals;
+mod signin;
+mod token;
+mod validate;
+#[cfg(feature = "tray")]
+mod tray;
 //mod imap;
 //mod utils;
-mod auth;
+
+// How often the configuration file is checked for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 // Main application structure
 pub struct DavMailRust {
-    config: Arc<Config>,
     runtime: Runtime,
+    // Background OAuth2 token renewal, runs on the main runtime and is shared by all profiles
+    token_manager: Arc<TokenManager>,
+    // Failed login tracking, shared by all listeners so a lockout covers every protocol
+    login_guard: Arc<LoginGuard>,
+    profiles: Vec<Profile>,
     server_handles: Vec<ServerHandle>,
+    // Dashboard and admin API, see admin::AdminServer
+    admin_server: Option<(JoinHandle<()>, watch::Sender<bool>)>,
+    // One per listener still binding its addresses, see wait_until_bound
+    binding: Vec<oneshot::Receiver<()>>,
+}
+
+// What the main thread waits for
+enum Event {
+    Terminate,
+    // SIGHUP
+    Reload,
+    Admin(AdminRequest),
+    #[cfg(feature = "tray")]
+    Tray(tray::TrayAction),
+}
+
+// One gateway (see configuration::profiles) and the state its listeners share
+struct Profile {
+    name: String,
+    config: Arc<Config>,
+    // Current configuration and pooled HTTP client shared by new Exchange sessions
+    settings: SharedSettings,
+    // Authenticated Exchange sessions shared by the connections of each user, kept per profile
+    // since the same login can exist in two tenants
+    session_cache: Arc<SessionCache>,
+    // Folder metadata of all users of the profile, None unless davmail.ews.metadataCacheFile is set
+    metadata_cache: Option<Arc<MetadataCache>>,
+    // EWS concurrency limits of the profile, sized when the profile starts
+    request_limiter: Arc<RequestLimiter>,
+    // Logged in users of the profile, with their connection counts and UID maps
+    users: UserRegistry,
 }
 
 // Handle for each protocol server
 struct ServerHandle {
+    profile: String,
     protocol: String,
-    handle: Option<thread::JoinHandle<()>>,
-    shutdown_signal: Arc<Mutex<bool>>,
+    bind_addresses: Vec<String>,
+    port: u16,
+    // Implicit TLS, see protocols::tls
+    tls: bool,
+    // Accept loop running on the main runtime
+    handle: Option<JoinHandle<()>>,
+    shutdown_signal: watch::Sender<bool>,
+    // Open connections, None for listeners that don't count them
+    connections: Option<ConnectionGate>,
+}
+
+// davmail-rust encrypt-secret: print the {ENC} form of a password or client secret read from stdin
+fn encrypt_secret() -> Result<(), Box<dyn std::error::Error>> {
+    let master_key = secrets::master_key()?;
+    
+    let mut secret = zeroize::Zeroizing::new(String::new());
+    std::io::stdin().read_line(&mut secret)?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err("No secret on standard input".into());
+    }
+    
+    println!("{}", secrets::encrypt(secret, &master_key)?);
+    Ok(())
 }
 
 impl DavMailRust {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        // Set up configuration
-        let config = Config::builder()
-            .add_source(File::with_name("davmail.properties").required(false))
-            .add_source(Environment::with_prefix("DAVMAIL"))
-            .build()?;
-        
-        let config = Arc::new(config);
+    pub fn new(config: Config, user_overrides: UserOverrides) -> Result<Self, Box<dyn std::error::Error>> {
+        let profiles = configuration::profiles(&config)?;
+        check_listeners(&profiles)?;
         
         // Initialize runtime
         let runtime = Runtime::new()?;
         
+        let token_manager = Arc::new(TokenManager::new(runtime.handle().clone()));
+        let user_overrides = Arc::new(user_overrides);
+        let profiles = profiles.into_iter()
+            .map(|(name, config)| Profile::new(name, config, user_overrides.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        
         Ok(DavMailRust {
-            config,
             runtime,
+            token_manager,
+            login_guard: Arc::new(LoginGuard::new()),
+            profiles,
             server_handles: Vec::new(),
+            admin_server: None,
+            binding: Vec::new(),
         })
     }
     
@@ -54,51 +151,56 @@ impl DavMailRust {
         info!("Starting DavMail Rust implementation...");
         
         // Check for required configuration
-        let exchange_url = self.config.get_string("davmail.url")?;
-        info!("Exchange URL: {}", exchange_url);
+        for profile in &self.profiles {
+            let exchange_url = profile.config.get_string("davmail.url")
+                .map_err(|e| format!("Profile {}: {}", profile.name, e))?;
+            info!("Exchange URL of profile {}: {}", profile.name, exchange_url);
+        }
         
         // Start protocol servers based on configuration
-        self.start_protocol_servers()?;
+        for index in 0..self.profiles.len() {
+            self.start_protocol_servers(index)?;
+        }
         
         info!("DavMail Rust started successfully");
         Ok(())
     }
     
-    fn start_protocol_servers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    fn start_protocol_servers(&mut self, profile: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let config = self.profiles[profile].config.clone();
    /* 
         // Start POP3 server if enabled
-        if self.config.get_bool("davmail.popEnabled").unwrap_or(false) {
-            let port = self.config.get_int("davmail.popPort").unwrap_or(1110);
+        if config.get_bool("davmail.popEnabled").unwrap_or(false) {
+            let port = config.get_int("davmail.popPort").unwrap_or(1110);
             self.start_pop_server(port as u16)?;
         }
   */
         
         // Start IMAP server if enabled
-        if self.config.get_bool("davmail.imapEnabled").unwrap_or(false) {
-            let port = self.config.get_int("davmail.imapPort").unwrap_or(1143);
-            self.start_imap_server(port as u16)?;
+        if let Some((bind_addresses, port, tls)) = imap_listener(&config) {
+            self.start_imap_server(profile, bind_addresses, port, tls)?;
         }
         
    /* 
         // Start SMTP server if enabled
-        if self.config.get_bool("davmail.smtpEnabled").unwrap_or(false) {
-            let port = self.config.get_int("davmail.smtpPort").unwrap_or(1025);
+        if config.get_bool("davmail.smtpEnabled").unwrap_or(false) {
+            let port = config.get_int("davmail.smtpPort").unwrap_or(1025);
             self.start_smtp_server(port as u16)?;
         }
   */
         
    /* 
         // Start CalDAV server if enabled
-        if self.config.get_bool("davmail.caldavEnabled").unwrap_or(false) {
-            let port = self.config.get_int("davmail.caldavPort").unwrap_or(1080);
+        if config.get_bool("davmail.caldavEnabled").unwrap_or(false) {
+            let port = config.get_int("davmail.caldavPort").unwrap_or(1080);
             self.start_caldav_server(port as u16)?;
         }
   */
         
    /* 
         // Start LDAP server if enabled
-        if self.config.get_bool("davmail.ldapEnabled").unwrap_or(false) {
-            let port = self.config.get_int("davmail.ldapPort").unwrap_or(1389);
+        if config.get_bool("davmail.ldapEnabled").unwrap_or(false) {
+            let port = config.get_int("davmail.ldapPort").unwrap_or(1389);
             self.start_ldap_server(port as u16)?;
         }
   */
@@ -109,40 +211,57 @@ impl DavMailRust {
    /* 
     fn start_pop_server(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting POP3 server on port {}", port);
-        let config = self.config.clone();
-        let shutdown_signal = Arc::new(Mutex::new(false));
-        let shutdown_signal_clone = shutdown_signal.clone();
+        let settings = self.profiles[0].settings.clone();
+        let (shutdown_signal, shutdown_receiver) = watch::channel(false);
         
-        let handle = thread::spawn(move || {
-            let pop_server = protocols::pop::PopServer::new(config, port);
-            pop_server.run(shutdown_signal_clone);
-        });
+        let pop_server = protocols::pop::PopServer::new(settings, port);
+        let handle = self.runtime.spawn(pop_server.run(shutdown_receiver));
         
         self.server_handles.push(ServerHandle {
+            profile: self.profiles[0].name.clone(),
             protocol: "POP3".to_string(),
+            bind_addresses: Vec::new(),
+            port,
+            tls: false,
             handle: Some(handle),
             shutdown_signal,
+            connections: None,
         });
         
         Ok(())
     }
    */ 
 
-    fn start_imap_server(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Starting IMAP server on port {}", port);
-        let config = self.config.clone();
-        let shutdown_signal = Arc::new(Mutex::new(false));
-        let shutdown_signal_clone = shutdown_signal.clone();
-        
-        let handle = thread::spawn(move || {
-            let imap_server = protocols::imap::ImapServer::new(config, port);
-            imap_server.run(shutdown_signal_clone);
-        });
+    fn start_imap_server(&mut self, profile: usize, bind_addresses: Vec<String>, port: u16, tls: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let profile = &self.profiles[profile];
+        info!("Starting IMAP server of profile {} on {} port {}", profile.name, bind_addresses.join(", "), port);
+        let profile_name = profile.name.clone();
+        let settings = profile.settings.clone();
+        let token_manager = self.token_manager.clone();
+        let session_cache = profile.session_cache.clone();
+        let metadata_cache = profile.metadata_cache.clone();
+        let request_limiter = profile.request_limiter.clone();
+        let users = profile.users.clone();
+        let login_guard = self.login_guard.clone();
+        let (shutdown_signal, shutdown_receiver) = watch::channel(false);
+        
+        let imap_server = protocols::imap::ImapServer::new(settings, bind_addresses.clone(), port, token_manager, session_cache, login_guard)
+            .with_metadata_cache(metadata_cache)
+            .with_request_limiter(request_limiter)
+            .with_user_registry(users)
+            .with_bound_signal(self.bound_signal());
+        let connections = imap_server.connection_gate();
+        let handle = self.runtime.spawn(imap_server.run(shutdown_receiver));
         
         self.server_handles.push(ServerHandle {
+            profile: profile_name,
             protocol: "IMAP".to_string(),
+            bind_addresses,
+            port,
+            tls,
             handle: Some(handle),
             shutdown_signal,
+            connections: Some(connections),
         });
         
         Ok(())
@@ -152,19 +271,21 @@ impl DavMailRust {
    /*
     fn start_smtp_server(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting SMTP server on port {}", port);
-        let config = self.config.clone();
-        let shutdown_signal = Arc::new(Mutex::new(false));
-        let shutdown_signal_clone = shutdown_signal.clone();
+        let settings = self.profiles[0].settings.clone();
+        let (shutdown_signal, shutdown_receiver) = watch::channel(false);
         
-        let handle = thread::spawn(move || {
-            let smtp_server = protocols::smtp::SmtpServer::new(config, port);
-            smtp_server.run(shutdown_signal_clone);
-        });
+        let smtp_server = protocols::smtp::SmtpServer::new(settings, port);
+        let handle = self.runtime.spawn(smtp_server.run(shutdown_receiver));
         
         self.server_handles.push(ServerHandle {
+            profile: self.profiles[0].name.clone(),
             protocol: "SMTP".to_string(),
+            bind_addresses: Vec::new(),
+            port,
+            tls: false,
             handle: Some(handle),
             shutdown_signal,
+            connections: None,
         });
         
         Ok(())
@@ -175,19 +296,21 @@ impl DavMailRust {
    /*
     fn start_caldav_server(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting CalDAV server on port {}", port);
-        let config = self.config.clone();
-        let shutdown_signal = Arc::new(Mutex::new(false));
-        let shutdown_signal_clone = shutdown_signal.clone();
+        let settings = self.profiles[0].settings.clone();
+        let (shutdown_signal, shutdown_receiver) = watch::channel(false);
         
-        let handle = thread::spawn(move || {
-            let caldav_server = protocols::caldav::CalDavServer::new(config, port);
-            caldav_server.run(shutdown_signal_clone);
-        });
+        let caldav_server = protocols::caldav::CalDavServer::new(settings, port);
+        let handle = self.runtime.spawn(caldav_server.run(shutdown_receiver));
         
         self.server_handles.push(ServerHandle {
+            profile: self.profiles[0].name.clone(),
             protocol: "CalDAV".to_string(),
+            bind_addresses: Vec::new(),
+            port,
+            tls: false,
             handle: Some(handle),
             shutdown_signal,
+            connections: None,
         });
         
         Ok(())
@@ -198,70 +321,585 @@ impl DavMailRust {
    /*
     fn start_ldap_server(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting LDAP server on port {}", port);
-        let config = self.config.clone();
-        let shutdown_signal = Arc::new(Mutex::new(false));
-        let shutdown_signal_clone = shutdown_signal.clone();
+        let settings = self.profiles[0].settings.clone();
+        let (shutdown_signal, shutdown_receiver) = watch::channel(false);
         
-        let handle = thread::spawn(move || {
-            let ldap_server = protocols::ldap::LdapServer::new(config, port);
-            ldap_server.run(shutdown_signal_clone);
-        });
+        let ldap_server = protocols::ldap::LdapServer::new(settings, port);
+        let handle = self.runtime.spawn(ldap_server.run(shutdown_receiver));
         
         self.server_handles.push(ServerHandle {
+            profile: self.profiles[0].name.clone(),
             protocol: "LDAP".to_string(),
+            bind_addresses: Vec::new(),
+            port,
+            tls: false,
             handle: Some(handle),
             shutdown_signal,
+            connections: None,
         });
         
         Ok(())
     }
     */
     
+    // Apply a changed configuration: new connections get the new settings, listeners whose
+    // enabled flag, bind address, port or TLS setting changed are restarted, established connections are left alone.
+    // Profiles added to davmail.profiles start, removed ones stop.
+    pub fn reload(&mut self, config: Config, user_overrides: UserOverrides) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Reloading configuration");
+        apply_log_level(&config);
+        logfile::configure(&config);
+        logformat::configure(&config);
+        syslog::configure(&config);
+        telemetry::configure(&config);
+        exchange::spool::configure(&config);
+        wirelog::configure(&config);
+        audit::configure(&config);
+        
+        // Everything that can fail happens before the running profiles are touched
+        let profiles = configuration::profiles(&config)?;
+        check_listeners(&profiles)?;
+        let user_overrides = Arc::new(user_overrides);
+        let mut reloaded = Vec::new();
+        for (name, config) in profiles {
+            let config = Arc::new(config);
+            let http_client = HttpClientConfig::from_config(&config).build()?;
+            reloaded.push((name, LiveSettings { config, http_client, user_overrides: user_overrides.clone() }));
+        }
+        
+        let removed: Vec<String> = self.profiles.iter()
+            .filter(|profile| !reloaded.iter().any(|(name, _)| *name == profile.name))
+            .map(|profile| profile.name.clone())
+            .collect();
+        for name in removed {
+            info!("Profile {} was removed", name);
+            self.stop_server(&name, "IMAP");
+        }
+        
+        let mut profiles = Vec::new();
+        for (name, live_settings) in reloaded {
+            let config = live_settings.config.clone();
+            match self.profiles.iter().position(|profile| profile.name == name) {
+                Some(index) => {
+                    let mut profile = self.profiles.swap_remove(index);
+                    *profile.settings.write().unwrap() = live_settings;
+                    profile.config = config;
+                    profiles.push(profile);
+                },
+                None => {
+                    info!("Profile {} was added", name);
+                    let metadata_cache = MetadataCache::from_config(&config).unwrap_or_else(|e| {
+                        error!("Failed to open metadata cache of profile {}: {}", name, e);
+                        None
+                    });
+                    profiles.push(Profile {
+                        name,
+                        session_cache: Arc::new(SessionCache::from_config(&config)),
+                        metadata_cache: metadata_cache.map(Arc::new),
+                        request_limiter: Arc::new(RequestLimiter::from_config(&config)),
+                        users: UserRegistry::new(),
+                        config,
+                        settings: Arc::new(RwLock::new(live_settings)),
+                    });
+                }
+            }
+        }
+        self.profiles = profiles;
+        
+        for index in 0..self.profiles.len() {
+            let profile = &self.profiles[index];
+            let wanted_listener = imap_listener(&profile.config);
+            let running_listener = self.server_handles.iter()
+                .find(|server| server.profile == profile.name && server.protocol == "IMAP")
+                .map(|server| (server.bind_addresses.clone(), server.port, server.tls));
+            if wanted_listener != running_listener {
+                if running_listener.is_some() {
+                    let name = profile.name.clone();
+                    self.stop_server(&name, "IMAP");
+                }
+                if let Some((bind_addresses, port, tls)) = wanted_listener {
+                    self.start_imap_server(index, bind_addresses, port, tls)?;
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Stop a listener, its connections get a BYE and davmail.shutdownGracePeriod to finish. False when it wasn't running.
+    fn stop_server(&mut self, profile: &str, protocol: &str) -> bool {
+        let Some(index) = self.server_handles.iter().position(|server| server.profile == profile && server.protocol == protocol) else {
+            return false;
+        };
+        let mut server = self.server_handles.remove(index);
+        let _ = server.shutdown_signal.send(true);
+        if let Some(handle) = server.handle.take() {
+            if let Err(e) = self.runtime.block_on(handle) {
+                error!("Error joining {} server task: {}", server.protocol, e);
+            }
+        }
+        info!("{} server of profile {} on {} port {} stopped", server.protocol, server.profile, server.bind_addresses.join(", "), server.port);
+        true
+    }
+    
+    // Start a protocol listener of a profile without touching the others, on `port` or the configured one.
+    // Works whether or not the protocol is enabled in the configuration, until the next configuration reload.
+    pub fn enable_server(&mut self, profile: &str, protocol: &str, port: Option<u16>) -> Result<(), Box<dyn std::error::Error>> {
+        let index = self.profile_index(profile)?;
+        let protocol = protocol.to_uppercase();
+        if self.server_handles.iter().any(|server| server.profile == profile && server.protocol == protocol) {
+            return Err(format!("{} server of profile {} is already running", protocol, profile).into());
+        }
+        match protocol.as_str() {
+            "IMAP" => {
+                let config = self.profiles[index].config.clone();
+                let bind_addresses = configuration::bind_addresses(&config, "imap");
+                let port = port.unwrap_or(config.get_int("davmail.imapPort").unwrap_or(1143) as u16);
+                if let Some(server) = self.server_handles.iter().find(|server| server.port == port) {
+                    return Err(format!("Port {} is already used by the {} server of profile {}", port, server.protocol, server.profile).into());
+                }
+                self.start_imap_server(index, bind_addresses, port, protocols::tls::enabled(&config, "imap"))
+            },
+            _ => Err(format!("No {} server in this build", protocol).into()),
+        }
+    }
+    
+    // Stop a single protocol listener, other protocols and their connections keep running
+    pub fn disable_server(&mut self, profile: &str, protocol: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.profile_index(profile)?;
+        let protocol = protocol.to_uppercase();
+        if !self.stop_server(profile, &protocol) {
+            return Err(format!("{} server of profile {} is not running", protocol, profile).into());
+        }
+        Ok(())
+    }
+    
+    // Stop and start a listener again, on a new port when one is given
+    pub fn restart_server(&mut self, profile: &str, protocol: &str, port: Option<u16>) -> Result<(), Box<dyn std::error::Error>> {
+        let protocol = protocol.to_uppercase();
+        let running_port = self.server_handles.iter()
+            .find(|server| server.profile == profile && server.protocol == protocol)
+            .map(|server| server.port);
+        self.disable_server(profile, &protocol)?;
+        self.enable_server(profile, &protocol, port.or(running_port))
+    }
+    
+    // Serve the dashboard on the main runtime until shutdown
+    pub fn start_admin(&mut self, admin_server: AdminServer) {
+        let (shutdown_signal, shutdown_receiver) = watch::channel(false);
+        let admin_server = admin_server.with_bound_signal(self.bound_signal());
+        let handle = self.runtime.spawn(admin_server.run(shutdown_receiver));
+        self.admin_server = Some((handle, shutdown_signal));
+    }
+    
+    // Listeners started later are only waited for when privileges are dropped, so forget the ones done binding
+    fn bound_signal(&mut self) -> oneshot::Sender<()> {
+        self.binding.retain_mut(|bound| matches!(bound.try_recv(), Err(oneshot::error::TryRecvError::Empty)));
+        let (bound, binding) = oneshot::channel();
+        self.binding.push(binding);
+        bound
+    }
+    
+    // Block until every listener started so far has bound its addresses or given up
+    pub fn wait_until_bound(&mut self) {
+        let binding = std::mem::take(&mut self.binding);
+        self.runtime.block_on(futures_util::future::join_all(binding));
+    }
+    
+    // Every profile's IMAP listener, whether running or not, and the accounts with live tokens as JSON
+    pub fn admin_status(&self) -> String {
+        admin::status_json(&self.listener_status(), &self.token_manager.accounts())
+    }
+    
+    // One line for the tray icon: listeners running and their connections
+    #[cfg(feature = "tray")]
+    fn status_summary(&self) -> String {
+        let listeners: Vec<ListenerStatus> = self.listener_status().into_iter().filter(|listener| listener.running).collect();
+        if listeners.is_empty() {
+            return "No server running".to_string();
+        }
+        let connections: usize = listeners.iter().map(|listener| listener.connections).sum();
+        let ports: Vec<String> = listeners.iter().map(|listener| format!("{} {}", listener.protocol, listener.port)).collect();
+        format!("{}, {} connections", ports.join(", "), connections)
+    }
+    
+    // Sign the accounts in use in again through the browser, for a refresh token that was revoked
+    #[cfg(feature = "tray")]
+    fn sign_in_again(&self) {
+        let accounts = self.token_manager.accounts();
+        if accounts.is_empty() {
+            warn!("No OAuth2 account signed in yet, log in from the mail client first");
+            return;
+        }
+        let config = self.profiles[0].config.clone();
+        for account in accounts.into_iter().filter(|account| !account.username.is_empty()) {
+            let config = config.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = acquire_token(&config, &account.username) {
+                    error!("Signing {} in again failed: {}", account.username, e);
+                }
+            });
+        }
+    }
+    
+    fn listener_status(&self) -> Vec<ListenerStatus> {
+        self.profiles.iter().map(|profile| {
+            let running = self.server_handles.iter().find(|server| server.profile == profile.name && server.protocol == "IMAP");
+            match running {
+                Some(server) => ListenerStatus {
+                    profile: profile.name.clone(),
+                    protocol: server.protocol.clone(),
+                    bind_addresses: server.bind_addresses.clone(),
+                    port: server.port,
+                    tls: server.tls,
+                    running: true,
+                    connections: server.connections.as_ref().map_or(0, ConnectionGate::connections),
+                },
+                None => ListenerStatus {
+                    profile: profile.name.clone(),
+                    protocol: "IMAP".to_string(),
+                    bind_addresses: configuration::bind_addresses(&profile.config, "imap"),
+                    port: profile.config.get_int("davmail.imapPort").unwrap_or(1143) as u16,
+                    tls: protocols::tls::enabled(&profile.config, "imap"),
+                    running: false,
+                    connections: 0,
+                },
+            }
+        }).collect()
+    }
+    
+    fn profile_index(&self, profile: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        self.profiles.iter().position(|candidate| candidate.name == profile)
+            .ok_or_else(|| format!("Unknown profile {}", profile).into())
+    }
+    
     pub fn shutdown(&mut self) {
         info!("Shutting down DavMail Rust...");
         
         // Signal all servers to shut down
         for server in &self.server_handles {
-            let mut shutdown = server.shutdown_signal.lock().unwrap();
-            *shutdown = true;
+            let _ = server.shutdown_signal.send(true);
             info!("Sent shutdown signal to {} server", server.protocol);
         }
         
         // Wait for all servers to finish
         for server in &mut self.server_handles {
             if let Some(handle) = server.handle.take() {
-                if let Err(e) = handle.join() {
-                    error!("Error joining {} server thread: {:?}", server.protocol, e);
+                if let Err(e) = self.runtime.block_on(handle) {
+                    error!("Error joining {} server task: {}", server.protocol, e);
                 } else {
                     info!("{} server shut down successfully", server.protocol);
                 }
             }
         }
         
+        if let Some((handle, shutdown_signal)) = self.admin_server.take() {
+            let _ = shutdown_signal.send(true);
+            if let Err(e) = self.runtime.block_on(handle) {
+                error!("Error joining admin server task: {}", e);
+            }
+        }
+        
+        exchange::metrics::log_summary();
         info!("DavMail Rust shutdown complete");
     }
 }
 
+impl Profile {
+    fn new(name: String, config: Config, user_overrides: Arc<UserOverrides>) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Arc::new(config);
+        
+        // Build the HTTP client once so all sessions of the profile share its connection pool
+        let http_client = HttpClientConfig::from_config(&config).build()?;
+        
+        let session_cache = Arc::new(SessionCache::from_config(&config));
+        let metadata_cache = MetadataCache::from_config(&config)?.map(Arc::new);
+        let request_limiter = Arc::new(RequestLimiter::from_config(&config));
+        let settings = Arc::new(RwLock::new(LiveSettings {
+            config: config.clone(),
+            http_client,
+            user_overrides,
+        }));
+        
+        Ok(Profile { name, config, settings, session_cache, metadata_cache, request_limiter, users: UserRegistry::new() })
+    }
+}
+
+// Bind addresses, port and TLS setting of the IMAP server, None when it is disabled
+fn imap_listener(config: &Config) -> Option<(Vec<String>, u16, bool)> {
+    if !config.get_bool("davmail.imapEnabled").unwrap_or(false) {
+        return None;
+    }
+    Some((
+        configuration::bind_addresses(config, "imap"),
+        config.get_int("davmail.imapPort").unwrap_or(1143) as u16,
+        protocols::tls::enabled(config, "imap"),
+    ))
+}
+
+// Profiles inherit the top level listeners, so two of them ending up on the same port is an easy mistake
+fn check_listeners(profiles: &[(String, Config)]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut listeners: Vec<(String, u16, &str)> = Vec::new();
+    for (name, config) in profiles {
+        if let Some((addresses, port, _)) = imap_listener(config) {
+            for address in addresses {
+                // Different interfaces can share a port, but not with a wildcard address
+                let clash = listeners.iter().find(|(used_address, used_port, _)| *used_port == port
+                    && (*used_address == address || is_wildcard(used_address) || is_wildcard(&address)));
+                if let Some((_, _, other)) = clash {
+                    return Err(format!("IMAP port {} of profile {} is already used by profile {}, set davmail.profile.{}.imapPort",
+                        port, name, other, name).into());
+                }
+                listeners.push((address, port, name));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_wildcard(address: &str) -> bool {
+    address == "0.0.0.0" || address == "::"
+}
+
+// davmail.logLevel, only when neither --log-level nor RUST_LOG chose the filter
+fn apply_log_level(config: &Config) {
+    if let Ok(level) = config.get_string("davmail.logLevel") {
+        match level.parse::<LevelFilter>() {
+            Ok(level) => log::set_max_level(level),
+            Err(_) => warn!("Unknown davmail.logLevel value '{}'", level),
+        }
+    }
+}
+
+fn reload_config(davmail: &mut DavMailRust, cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    systemd::reloading();
+    let reloaded = cli.load_config()
+        .and_then(|config| Ok((config, cli.load_user_overrides()?)))
+        .map_err(|e| e.into())
+        .and_then(|(config, user_overrides)| davmail.reload(config, user_overrides));
+    // The running configuration stays in use when the reload fails
+    systemd::ready();
+    reloaded
+}
+
+// Run an admin API request, the answer is JSON for the status and a message for the actions
+fn admin_command(davmail: &mut DavMailRust, cli: &Cli, command: AdminCommand) -> Result<String, String> {
+    let done = match command {
+        AdminCommand::Status => return Ok(davmail.admin_status()),
+        AdminCommand::Enable { profile, protocol } => davmail.enable_server(&profile, &protocol, None)
+            .map(|_| format!("{} server of profile {} started", protocol.to_uppercase(), profile)),
+        AdminCommand::Disable { profile, protocol } => davmail.disable_server(&profile, &protocol)
+            .map(|_| format!("{} server of profile {} stopped", protocol.to_uppercase(), profile)),
+        AdminCommand::Restart { profile, protocol } => davmail.restart_server(&profile, &protocol, None)
+            .map(|_| format!("{} server of profile {} restarted", protocol.to_uppercase(), profile)),
+        AdminCommand::Reload => reload_config(davmail, cli).map(|_| "Configuration reloaded".to_string()),
+    };
+    done.map_err(|e| {
+        warn!("Admin request failed: {}", e);
+        e.to_string()
+    })
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    env_logger::init();
+    let cli = Cli::parse();
+    
+    // Initialize logging, --log-level replaces RUST_LOG and both replace davmail.logLevel
+    let mut logger = env_logger::Builder::from_default_env();
+    let config_log_level = cli.log_level.is_none() && std::env::var_os("RUST_LOG").is_none();
+    if let Some(log_level) = &cli.log_level {
+        logger.parse_filters(log_level);
+    } else if config_log_level {
+        // Let everything through env_logger and filter with the global level, which can change at runtime
+        logger.filter_level(LevelFilter::Trace);
+    }
+    // stderr until the configuration names a log file
+    logger.target(env_logger::Target::Pipe(logfile::output()));
+    logger.format(logformat::format);
+    syslog::init(logger.build())?;
+    systemd::init();
+    
+    // Needs no configuration file, only the master key
+    if let Some(Command::EncryptSecret) = &cli.command {
+        return encrypt_secret();
+    }
+    
+    let config = cli.load_config()?;
+    let user_overrides = cli.load_user_overrides()?;
+    
+    // These run next to a running instance, so before the lock is taken
+    if let Some(Command::Token { action }) = &cli.command {
+        if config_log_level {
+            log::set_max_level(LevelFilter::Info);
+        }
+        return token::run(&configuration::profile_config(&config, &cli.profile)?, action);
+    }
+    if let Some(Command::Check { user }) = &cli.command {
+        // Only problems get logged, the report says the rest
+        if config_log_level {
+            log::set_max_level(LevelFilter::Warn);
+        }
+        return check::run(&configuration::profile_config(&config, &cli.profile)?, user.as_deref());
+    }
+    if let Some(Command::Import { path, user, folder, read }) = &cli.command {
+        if config_log_level {
+            log::set_max_level(LevelFilter::Info);
+        }
+        return import::run(&configuration::profile_config(&config, &cli.profile)?, path, user, folder, *read);
+    }
+    if let Some(Command::Diag { output }) = &cli.command {
+        if config_log_level {
+            log::set_max_level(LevelFilter::Warn);
+        }
+        return diag::run(&cli, &configuration::profile_config(&config, &cli.profile)?, &user_overrides, output.as_deref());
+    }
+    // No lock either, next to a live gateway the ports that one holds are reported as taken
+    if let Some(username) = &cli.validate {
+        if config_log_level {
+            log::set_max_level(LevelFilter::Warn);
+        }
+        return validate::run(config, user_overrides, &cli.profile, username);
+    }
+    
+    // Detach before anything starts a thread, --token and --import-refresh-token stay in the foreground
+    let service = cli.token.is_none() && cli.import_refresh_token.is_none();
+    if service {
+        signals::block()?;
+    }
+    // One instance per configuration, a second one would fight the first over its ports
+    let mut pid_file = if service {
+        let path = cli.pid_file.clone()
+            .or_else(|| config.get_string("davmail.pidFile").ok().filter(|path| !path.trim().is_empty()).map(PathBuf::from))
+            .unwrap_or_else(|| daemon::default_lock_path(cli.config_file().as_ref().map(ConfigFile::path)));
+        let pid_file = PidFile::acquire(&path, cli.takeover)?;
+        // Catches instances with another lock file, and any instance where files can't be locked
+        if admin::port_in_use(&config) {
+            return Err("The admin port (davmail.adminPort) is in use, is another instance running?".into());
+        }
+        Some(pid_file)
+    } else {
+        None
+    };
+    // Looked up while errors still reach the terminal, switched to once the listeners are bound
+    let run_as = if service { RunAs::from_config(&config)? } else { None };
+    if cli.daemon && service {
+        if config.get_string("davmail.logFilePath").is_err() && config.get_string("davmail.syslog").is_err() {
+            warn!("Running as a daemon without davmail.logFilePath or davmail.syslog, the log is discarded");
+        }
+        daemon::daemonize()?;
+    }
+    if let Some(pid_file) = &mut pid_file {
+        pid_file.write_pid()?;
+    }
+    logfile::configure(&config);
+    logformat::configure(&config);
+    syslog::configure(&config);
+    telemetry::configure(&config);
+    if config_log_level {
+        log::set_max_level(LevelFilter::Info);
+        apply_log_level(&config);
+    }
+    
+    if let Some(username) = &cli.token {
+        return token::login(&configuration::profile_config(&config, &cli.profile)?, username, false);
+    }
+    if let Some(username) = &cli.import_refresh_token {
+        return token::import_refresh_token(&configuration::profile_config(&config, &cli.profile)?, username);
+    }
     
     info!("Initializing DavMail Rust");
+    exchange::spool::configure(&config);
+    wirelog::configure(&config);
+    audit::configure(&config);
+    
+    // Termination signal and admin requests are handled on this thread
+    let (tx, rx) = std::sync::mpsc::channel();
+    let admin_events = tx.clone();
+    let admin_server = AdminServer::from_config(&config, move |request| {
+        let _ = admin_events.send(Event::Admin(request));
+    });
+    
+    #[cfg(feature = "tray")]
+    let admin_url = admin_server.as_ref().map(AdminServer::url);
     
     // Create and start DavMail
-    let mut davmail = DavMailRust::new()?;
+    let mut davmail = DavMailRust::new(config, user_overrides)?;
     davmail.start()?;
+    if let Some(admin_server) = admin_server {
+        davmail.start_admin(admin_server);
+    }
+    if let Some(run_as) = &run_as {
+        davmail.wait_until_bound();
+        run_as.apply().map_err(|e| format!("Failed to drop root privileges: {}", e))?;
+    }
+    
+    // The desktop icon, unless told to run without one
+    #[cfg(feature = "tray")]
+    let tray = if cli.notray || cli.daemon {
+        None
+    } else {
+        let tray_events = tx.clone();
+        tray::start(admin_url, move |action| {
+            let _ = tray_events.send(Event::Tray(action));
+        })
+    };
+    systemd::ready();
     
     // Wait for termination signal
-    let (tx, rx) = std::sync::mpsc::channel();
-    ctrlc::set_handler(move || {
-        info!("Received termination signal");
-        tx.send(()).expect("Failed to send termination signal");
+    signals::listen(move |signal| {
+        let _ = tx.send(match signal {
+            Signal::Terminate => Event::Terminate,
+            Signal::Reload => Event::Reload,
+        });
     })?;
     
-    rx.recv()?;
+    // Reload the configuration when its file changes while waiting for the termination signal
+    let config_file = cli.config_file();
+    let mut last_modified = config_file.as_ref().and_then(ConfigFile::modified);
+    let watchdog_interval = systemd::watchdog_interval();
+    let mut last_watchdog = Instant::now();
+    loop {
+        if watchdog_interval.is_some_and(|interval| last_watchdog.elapsed() >= interval) {
+            systemd::watchdog();
+            last_watchdog = Instant::now();
+        }
+        #[cfg(feature = "tray")]
+        if let Some(tray) = &tray {
+            tray.set_status(davmail.status_summary());
+        }
+        let timeout = watchdog_interval.map_or(CONFIG_CHECK_INTERVAL, |interval| interval.min(CONFIG_CHECK_INTERVAL));
+        match rx.recv_timeout(timeout) {
+            Ok(Event::Terminate) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(Event::Reload) => {
+                // Also reopens the log files, for logrotate
+                if let Err(e) = reload_config(&mut davmail, &cli) {
+                    error!("Keeping the current configuration, reload failed: {}", e);
+                }
+            },
+            Ok(Event::Admin(request)) => {
+                let _ = request.reply.send(admin_command(&mut davmail, &cli, request.command));
+            },
+            #[cfg(feature = "tray")]
+            Ok(Event::Tray(tray::TrayAction::Quit)) => {
+                info!("Quit from the tray icon");
+                break;
+            },
+            #[cfg(feature = "tray")]
+            Ok(Event::Tray(tray::TrayAction::SignIn)) => davmail.sign_in_again(),
+            Err(RecvTimeoutError::Timeout) => {
+                let modified = config_file.as_ref().and_then(ConfigFile::modified);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                if let Err(e) = reload_config(&mut davmail, &cli) {
+                    error!("Keeping the current configuration, reload failed: {}", e);
+                }
+            }
+        }
+    }
     
     // Shutdown
+    systemd::stopping();
     davmail.shutdown();
     
     Ok(())
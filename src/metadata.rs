@@ -0,0 +1,371 @@
+// metadata.rs
+// Local SQLite cache of per-folder message metadata, kept current with SyncFolderItems
+
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use config::Config;
+use log::debug;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS folders (
+        mailbox TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        sync_state TEXT,
+        uid_validity INTEGER NOT NULL,
+        uid_next INTEGER NOT NULL,
+        PRIMARY KEY (mailbox, folder)
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        mailbox TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        item_id TEXT NOT NULL,
+        change_key TEXT,
+        uid INTEGER NOT NULL,
+        is_read INTEGER NOT NULL,
+        is_answered INTEGER NOT NULL DEFAULT 0,
+        is_draft INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        internal_date TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        envelope TEXT NOT NULL,
+        PRIMARY KEY (mailbox, folder, item_id)
+    );
+    CREATE INDEX IF NOT EXISTS messages_uid ON messages (mailbox, folder, uid);
+    CREATE TABLE IF NOT EXISTS bodies (
+        mailbox TEXT NOT NULL,
+        item_id TEXT NOT NULL,
+        content BLOB NOT NULL,
+        PRIMARY KEY (mailbox, item_id)
+    );
+    CREATE TABLE IF NOT EXISTS pending_read_flags (
+        mailbox TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        is_read INTEGER NOT NULL,
+        PRIMARY KEY (mailbox, folder)
+    );
+    CREATE TABLE IF NOT EXISTS rule_watermarks (
+        mailbox TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        received TEXT NOT NULL,
+        PRIMARY KEY (mailbox, folder)
+    );
+    CREATE TABLE IF NOT EXISTS logins (
+        mailbox TEXT PRIMARY KEY,
+        salt BLOB NOT NULL,
+        verifier BLOB NOT NULL
+    );
+";
+
+// Password verifiers for offline logins, same derivation as encrypted configuration values
+const SALT_LEN: usize = 16;
+const VERIFIER_LEN: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+// Sync position and UID counters of a cached folder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderState {
+    // Opaque SyncFolderItems state, None before the first sync
+    pub sync_state: Option<String>,
+    pub uid_validity: u32,
+    pub uid_next: u32,
+}
+
+// What SyncFolderItems reports about a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageMetadata {
+    pub item_id: String,
+    pub change_key: Option<String>,
+    pub is_read: bool,
+    // A reply was sent, from PidTagLastVerbExecuted
+    pub is_answered: bool,
+    pub is_draft: bool,
+    pub size: u64,
+    // DateTimeReceived as sent by EWS (xs:dateTime)
+    pub internal_date: String,
+    pub subject: String,
+    // Display name and address of the sender, for SEARCH FROM
+    pub sender: String,
+    // IMAP ENVELOPE structure, built once at sync time
+    pub envelope: String,
+}
+
+// Cached message with the UID it was given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedMessage {
+    pub uid: u32,
+    pub metadata: MessageMetadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FolderChange {
+    // Create and Update, an unknown item gets the next UID
+    Upsert(MessageMetadata),
+    Delete(String),
+    ReadFlag(String, bool),
+}
+
+// One database for all users of a profile, rows are keyed by mailbox (login) and IMAP folder name
+pub struct MetadataCache {
+    connection: Mutex<Connection>,
+}
+
+impl MetadataCache {
+    // Cache file from davmail.ews.metadataCacheFile, None when the cache is disabled
+    pub fn from_config(config: &Config) -> rusqlite::Result<Option<Self>> {
+        match config.get_string("davmail.ews.metadataCacheFile") {
+            Ok(path) if !path.trim().is_empty() => MetadataCache::open(path.trim()).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path.as_ref())?;
+        // WAL lets the file be read while a sync writes, the cache can be rebuilt so durability matters less
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+        // Caches from before \Answered was kept get the column, and their folders sync from the start to fill it
+        if connection.prepare("SELECT is_answered FROM messages LIMIT 0").is_err() {
+            connection.execute_batch("ALTER TABLE messages ADD COLUMN is_answered INTEGER NOT NULL DEFAULT 0;
+                UPDATE folders SET sync_state = NULL;")?;
+        }
+        debug!("Opened metadata cache {:?}", path.as_ref());
+        Ok(MetadataCache { connection: Mutex::new(connection) })
+    }
+
+    // State of the folder, a folder seen for the first time starts empty with a new UIDVALIDITY
+    pub fn folder_state(&self, mailbox: &str, folder: &str) -> rusqlite::Result<FolderState> {
+        let connection = self.connection.lock().unwrap();
+        let state = connection.query_row(
+            "SELECT sync_state, uid_validity, uid_next FROM folders WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder],
+            |row| Ok(FolderState { sync_state: row.get(0)?, uid_validity: row.get(1)?, uid_next: row.get(2)? }),
+        ).optional()?;
+        match state {
+            Some(state) => Ok(state),
+            None => {
+                let state = FolderState { sync_state: None, uid_validity: new_uid_validity(), uid_next: 1 };
+                connection.execute(
+                    "INSERT INTO folders (mailbox, folder, sync_state, uid_validity, uid_next) VALUES (?1, ?2, NULL, ?3, ?4)",
+                    params![mailbox, folder, state.uid_validity, state.uid_next],
+                )?;
+                Ok(state)
+            },
+        }
+    }
+
+    // Apply one batch of changes and the sync state that follows it in a single transaction,
+    // so an interrupted sync resumes from the last batch that was stored
+    pub fn apply(&self, mailbox: &str, folder: &str, changes: &[FolderChange], sync_state: &str) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut uid_next: u32 = transaction.query_row(
+            "SELECT uid_next FROM folders WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder],
+            |row| row.get(0),
+        )?;
+
+        for change in changes {
+            match change {
+                FolderChange::Upsert(message) => {
+                    let updated = transaction.execute(
+                        "UPDATE messages SET change_key = ?4, is_read = ?5, is_draft = ?6, size = ?7, internal_date = ?8,
+                             subject = ?9, sender = ?10, envelope = ?11, is_answered = ?12
+                         WHERE mailbox = ?1 AND folder = ?2 AND item_id = ?3",
+                        params![mailbox, folder, message.item_id, message.change_key, message.is_read, message.is_draft,
+                                message.size, message.internal_date, message.subject, message.sender, message.envelope,
+                                message.is_answered],
+                    )?;
+                    if updated == 0 {
+                        transaction.execute(
+                            "INSERT INTO messages (mailbox, folder, item_id, change_key, uid, is_read, is_draft, size, internal_date,
+                                 subject, sender, envelope, is_answered)
+                             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                            params![mailbox, folder, message.item_id, message.change_key, uid_next, message.is_read, message.is_draft,
+                                    message.size, message.internal_date, message.subject, message.sender, message.envelope,
+                                    message.is_answered],
+                        )?;
+                        uid_next += 1;
+                    }
+                },
+                FolderChange::Delete(item_id) => {
+                    transaction.execute(
+                        "DELETE FROM messages WHERE mailbox = ?1 AND folder = ?2 AND item_id = ?3",
+                        params![mailbox, folder, item_id],
+                    )?;
+                    transaction.execute("DELETE FROM bodies WHERE mailbox = ?1 AND item_id = ?2", params![mailbox, item_id])?;
+                },
+                FolderChange::ReadFlag(item_id, is_read) => {
+                    transaction.execute(
+                        "UPDATE messages SET is_read = ?4 WHERE mailbox = ?1 AND folder = ?2 AND item_id = ?3",
+                        params![mailbox, folder, item_id, is_read],
+                    )?;
+                },
+            }
+        }
+
+        transaction.execute(
+            "UPDATE folders SET sync_state = ?3, uid_next = ?4 WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder, sync_state, uid_next],
+        )?;
+        transaction.commit()
+    }
+
+    // Messages of the folder in UID order, the position in the list is the IMAP sequence number
+    pub fn messages(&self, mailbox: &str, folder: &str) -> rusqlite::Result<Vec<CachedMessage>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT uid, item_id, change_key, is_read, is_draft, size, internal_date, subject, sender, envelope, is_answered
+             FROM messages WHERE mailbox = ?1 AND folder = ?2 ORDER BY uid",
+        )?;
+        let rows = statement.query_map(params![mailbox, folder], |row| {
+            Ok(CachedMessage {
+                uid: row.get(0)?,
+                metadata: MessageMetadata {
+                    item_id: row.get(1)?,
+                    change_key: row.get(2)?,
+                    is_read: row.get(3)?,
+                    is_answered: row.get(10)?,
+                    is_draft: row.get(4)?,
+                    size: row.get(5)?,
+                    internal_date: row.get(6)?,
+                    subject: row.get(7)?,
+                    sender: row.get(8)?,
+                    envelope: row.get(9)?,
+                },
+            })
+        })?;
+        rows.collect()
+    }
+
+    // Forget the folder after Exchange rejected its sync state, the next sync starts over with new UIDs
+    pub fn reset(&self, mailbox: &str, folder: &str) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM messages WHERE mailbox = ?1 AND folder = ?2", params![mailbox, folder])?;
+        transaction.execute("DELETE FROM folders WHERE mailbox = ?1 AND folder = ?2", params![mailbox, folder])?;
+        transaction.commit()
+    }
+
+    // Folders synced at least once, what an offline LIST can show
+    pub fn folders(&self, mailbox: &str) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT folder FROM folders WHERE mailbox = ?1 AND sync_state IS NOT NULL ORDER BY folder",
+        )?;
+        let rows = statement.query_map(params![mailbox], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    // Message content kept for offline reading, dropped with the message
+    pub fn store_body(&self, mailbox: &str, item_id: &str, content: &[u8]) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO bodies (mailbox, item_id, content) VALUES (?1, ?2, ?3)",
+            params![mailbox, item_id, content],
+        )?;
+        Ok(())
+    }
+
+    pub fn body(&self, mailbox: &str, item_id: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT content FROM bodies WHERE mailbox = ?1 AND item_id = ?2",
+            params![mailbox, item_id],
+            |row| row.get(0),
+        ).optional()
+    }
+
+    // Read flag change made offline: applied to the cached messages now and sent to Exchange with the
+    // next sync. A later change of the same folder replaces an earlier one.
+    pub fn queue_mark_all_read(&self, mailbox: &str, folder: &str, is_read: bool) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO pending_read_flags (mailbox, folder, is_read) VALUES (?1, ?2, ?3)",
+            params![mailbox, folder, is_read],
+        )?;
+        transaction.execute(
+            "UPDATE messages SET is_read = ?3 WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder, is_read],
+        )?;
+        transaction.commit()
+    }
+
+    pub fn pending_mark_all_read(&self, mailbox: &str, folder: &str) -> rusqlite::Result<Option<bool>> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT is_read FROM pending_read_flags WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder],
+            |row| row.get(0),
+        ).optional()
+    }
+
+    pub fn clear_pending_mark_all_read(&self, mailbox: &str, folder: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM pending_read_flags WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder],
+        )?;
+        Ok(())
+    }
+
+    // DateTimeReceived of the newest item the mail rules of the folder looked at
+    pub fn rule_watermark(&self, mailbox: &str, folder: &str) -> rusqlite::Result<Option<String>> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT received FROM rule_watermarks WHERE mailbox = ?1 AND folder = ?2",
+            params![mailbox, folder],
+            |row| row.get(0),
+        ).optional()
+    }
+
+    pub fn set_rule_watermark(&self, mailbox: &str, folder: &str, received: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO rule_watermarks (mailbox, folder, received) VALUES (?1, ?2, ?3)",
+            params![mailbox, folder, received],
+        )?;
+        Ok(())
+    }
+
+    // Keep a verifier of a password Exchange accepted, so the same login works while Exchange is unreachable
+    pub fn remember_login(&self, mailbox: &str, password: &str) -> rusqlite::Result<()> {
+        let mut salt = [0u8; SALT_LEN];
+        if SystemRandom::new().fill(&mut salt).is_err() {
+            return Ok(());
+        }
+        let verifier = password_verifier(password, &salt);
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO logins (mailbox, salt, verifier) VALUES (?1, ?2, ?3)",
+            params![mailbox, &salt[..], &verifier[..]],
+        )?;
+        Ok(())
+    }
+
+    pub fn verify_login(&self, mailbox: &str, password: &str) -> rusqlite::Result<bool> {
+        let login: Option<(Vec<u8>, Vec<u8>)> = self.connection.lock().unwrap().query_row(
+            "SELECT salt, verifier FROM logins WHERE mailbox = ?1",
+            params![mailbox],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+        Ok(login.is_some_and(|(salt, verifier)| {
+            pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &salt, password.as_bytes(), &verifier).is_ok()
+        }))
+    }
+}
+
+fn password_verifier(password: &str, salt: &[u8]) -> [u8; VERIFIER_LEN] {
+    let mut verifier = [0u8; VERIFIER_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), salt, password.as_bytes(), &mut verifier);
+    verifier
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PBKDF2_ITERATIONS).unwrap()
+}
+
+// Seconds since the epoch, so a rebuilt folder always gets a higher UIDVALIDITY than before
+fn new_uid_validity() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_secs() as u32)
+}
@@ -0,0 +1,414 @@
+// mock_ews.rs
+// Fake EWS endpoint for integration tests (cargo feature "test-support"): canned responses, injected faults and throttling
+
+//! Start a [`MockEws`] on the test's tokio runtime, point `davmail.url` at [`MockEws::url`] and
+//! drive the gateway or an [`ExchangeClient`](crate::ExchangeClient) against it.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use flate2::read::GzDecoder;
+use futures_util::future::{self, Either};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::admin::read_head;
+use crate::exchange::request::escape_xml;
+use crate::exchange::response::XmlElement;
+
+const ENVELOPE_START: &str = concat!(
+    r#"<?xml version="1.0" encoding="utf-8"?>"#,
+    r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/""#,
+    r#" xmlns:m="http://schemas.microsoft.com/exchange/services/2006/messages""#,
+    r#" xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types"><s:Body>"#,
+);
+const ENVELOPE_END: &str = "</s:Body></s:Envelope>";
+
+// Folders the built-in FindFolder response lists, as (id, display name)
+const FOLDERS: [(&str, &str); 5] = [
+    ("mock-inbox", "Inbox"),
+    ("mock-sentitems", "Sent Items"),
+    ("mock-drafts", "Drafts"),
+    ("mock-deleteditems", "Deleted Items"),
+    ("mock-junk", "Junk Email"),
+];
+
+/// A message listed by the built-in FindItem response and returned by GetItem
+#[derive(Debug, Clone)]
+pub struct MockMessage {
+    pub id: String,
+    pub subject: String,
+    pub from: String,
+    pub is_read: bool,
+    /// Replied to, sent as PidTagLastVerbExecuted and changed by UpdateItem
+    pub is_answered: bool,
+    /// Complete MIME content, sent base64 encoded as MimeContent
+    pub mime: String,
+}
+
+impl MockMessage {
+    /// Unread plain text message with a generated body
+    pub fn new(id: &str, subject: &str, from: &str) -> Self {
+        MockMessage {
+            id: id.to_string(),
+            subject: subject.to_string(),
+            from: from.to_string(),
+            is_read: false,
+            is_answered: false,
+            mime: format!("From: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nBody of {}\r\n", from, subject, subject),
+        }
+    }
+}
+
+/// What a request gets instead of its normal answer
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// HTTP 500 with a SOAP fault carrying this message
+    Soap(String),
+    /// HTTP 200 with ResponseClass="Error" and this response code, e.g. ErrorItemNotFound
+    Error(String),
+    /// Bare HTTP status without a body, e.g. 401 or 503
+    Status(u16),
+    /// Connection closed without an answer
+    Disconnect,
+    /// The normal answer, after this delay
+    Delay(Duration),
+}
+
+/// A request the mock received
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    /// Name of the first element in soap:Body, e.g. FindItem
+    pub operation: String,
+    pub authorization: Option<String>,
+    /// SOAP envelope, decompressed when sent with gzip
+    pub body: String,
+}
+
+struct InjectedFault {
+    // "*" matches every operation
+    operation: String,
+    fault: Fault,
+    // None fails every matching request
+    remaining: Option<usize>,
+}
+
+struct Throttle {
+    max_requests: usize,
+    window: Duration,
+    back_off: Duration,
+    recent: VecDeque<Instant>,
+}
+
+enum Answer {
+    Reply { status: u16, body: String, delay: Option<Duration> },
+    Disconnect,
+}
+
+impl Answer {
+    fn ok(body: String) -> Self {
+        Answer::Reply { status: 200, body, delay: None }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    responses: HashMap<String, String>,
+    faults: Vec<InjectedFault>,
+    throttle: Option<Throttle>,
+    // Expected Authorization header, anything is accepted when None
+    authorization: Option<String>,
+    messages: Vec<MockMessage>,
+    requests: Vec<RecordedRequest>,
+    created: usize,
+}
+
+/// Fake EWS server on a loopback port, stopped when dropped
+pub struct MockEws {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl MockEws {
+    /// Listen on a free loopback port and serve on the current tokio runtime
+    pub async fn start() -> io::Result<MockEws> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            messages: vec![
+                MockMessage::new("mock-message-1", "Welcome", "alice@example.com"),
+                MockMessage { is_read: true, ..MockMessage::new("mock-message-2", "Meeting notes", "bob@example.com") },
+            ],
+            ..State::default()
+        }));
+        let (shutdown, shutdown_signal) = watch::channel(false);
+        tokio::spawn(serve(listener, state.clone(), shutdown_signal));
+        Ok(MockEws { address, state, shutdown })
+    }
+
+    /// Value for davmail.url, requests are expected at <url>/EWS/Exchange.asmx
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Answer `operation` with this soap:Body content instead of the built-in response
+    pub fn respond(&self, operation: &str, body: impl Into<String>) {
+        self.state.lock().unwrap().responses.insert(operation.to_string(), body.into());
+    }
+
+    /// Messages of every folder in the built-in FindItem and GetItem responses
+    pub fn set_messages(&self, messages: Vec<MockMessage>) {
+        self.state.lock().unwrap().messages = messages;
+    }
+
+    /// Answer the next `times` requests for `operation` ("*" for any) with the fault, all of them when None.
+    /// Faults are checked in the order they were added.
+    pub fn fail(&self, operation: &str, fault: Fault, times: Option<usize>) {
+        self.state.lock().unwrap().faults.push(InjectedFault { operation: operation.to_string(), fault, remaining: times });
+    }
+
+    /// Answer ErrorServerBusy with a BackOffMilliseconds hint once more than `max_requests`
+    /// requests arrived within `window`, like Exchange Online throttling
+    pub fn throttle(&self, max_requests: usize, window: Duration, back_off: Duration) {
+        self.state.lock().unwrap().throttle = Some(Throttle { max_requests, window, back_off, recent: VecDeque::new() });
+    }
+
+    /// Reject requests without these basic authentication credentials with 401
+    pub fn require_basic_auth(&self, username: &str, password: &str) {
+        let expected = format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password)));
+        self.state.lock().unwrap().authorization = Some(expected);
+    }
+
+    /// Every request received so far, oldest first
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// How many requests for `operation` were received
+    pub fn count(&self, operation: &str) -> usize {
+        self.state.lock().unwrap().requests.iter().filter(|request| request.operation == operation).count()
+    }
+}
+
+impl Drop for MockEws {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(true);
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<State>>, mut shutdown_signal: watch::Receiver<bool>) {
+    loop {
+        let accepted = match future::select(pin!(listener.accept()), pin!(shutdown_signal.wait_for(|shutdown| *shutdown))).await {
+            Either::Left((accepted, _)) => accepted,
+            Either::Right(_) => break,
+        };
+        let Ok((stream, _)) = accepted else {
+            break;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let _ = handle(stream, &state).await;
+        });
+    }
+}
+
+// One request per connection, like the admin server
+async fn handle(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let Some((_, headers)) = read_head(&mut stream).await? else {
+        return Ok(());
+    };
+    let header = |name: &str| headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.clone());
+
+    let length = header("Content-Length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    if header("Content-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("gzip")) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+        body = decoded;
+    }
+    let body = String::from_utf8_lossy(&body).into_owned();
+
+    let answer = state.lock().unwrap().answer(body, header("Authorization"));
+    let (status, body) = match answer {
+        Answer::Disconnect => return Ok(()),
+        Answer::Reply { status, body, delay } => {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            (status, body)
+        },
+    };
+
+    let mut message = format!("HTTP/1.1 {} Mock\r\nContent-Type: text/xml; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status, body.len());
+    if status == 401 {
+        message.push_str("WWW-Authenticate: Basic realm=\"mock\"\r\n");
+    }
+    message.push_str("\r\n");
+    message.push_str(&body);
+    let stream = stream.get_mut();
+    stream.write_all(message.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+impl State {
+    fn answer(&mut self, body: String, authorization: Option<String>) -> Answer {
+        let envelope = XmlElement::parse(&body).ok();
+        let operation = envelope.as_ref()
+            .and_then(|envelope| envelope.descendants("Body").first().and_then(|body| body.children.first()).map(|request| request.name.clone()))
+            .unwrap_or_default();
+        self.requests.push(RecordedRequest { operation: operation.clone(), authorization: authorization.clone(), body });
+
+        if self.authorization.as_ref().is_some_and(|expected| authorization.as_ref() != Some(expected)) {
+            return Answer::Reply { status: 401, body: String::new(), delay: None };
+        }
+
+        if let Some(throttle) = &mut self.throttle {
+            let now = Instant::now();
+            while throttle.recent.front().is_some_and(|sent| now.duration_since(*sent) > throttle.window) {
+                throttle.recent.pop_front();
+            }
+            if throttle.recent.len() >= throttle.max_requests {
+                return Answer::Reply { status: 500, body: server_busy(throttle.back_off), delay: None };
+            }
+            throttle.recent.push_back(now);
+        }
+
+        let mut delay = None;
+        if let Some(injected) = self.faults.iter_mut()
+            .find(|injected| (injected.operation == "*" || injected.operation == operation) && injected.remaining != Some(0)) {
+            if let Some(remaining) = &mut injected.remaining {
+                *remaining -= 1;
+            }
+            match injected.fault.clone() {
+                Fault::Soap(message) => return Answer::Reply { status: 500, body: soap_fault("a:ErrorInvalidRequest", &message, ""), delay: None },
+                Fault::Error(code) => return Answer::ok(envelope_of(&response_message(&operation, "Error", &code, ""))),
+                Fault::Status(status) => return Answer::Reply { status, body: String::new(), delay: None },
+                Fault::Disconnect => return Answer::Disconnect,
+                Fault::Delay(duration) => delay = Some(duration),
+            }
+        }
+
+        let body = match self.responses.get(&operation) {
+            Some(body) => body.clone(),
+            None => self.builtin(&operation, envelope.as_ref()),
+        };
+        Answer::Reply { status: 200, body: envelope_of(&body), delay }
+    }
+
+    // Enough of each response for the gateway to work, any other operation simply succeeds
+    fn builtin(&mut self, operation: &str, envelope: Option<&XmlElement>) -> String {
+        match operation {
+            "FindFolder" => {
+                let folders: String = FOLDERS.iter().map(|(id, name)| self.folder_xml(id, name)).collect();
+                response_message(operation, "Success", "NoError", &format!(
+                    r#"<m:RootFolder TotalItemsInView="{}" IncludesLastItemInRange="true"><t:Folders>{}</t:Folders></m:RootFolder>"#,
+                    FOLDERS.len(), folders))
+            },
+            "GetFolder" => {
+                let (id, name) = FOLDERS[0];
+                response_message(operation, "Success", "NoError", &format!("<m:Folders>{}</m:Folders>", self.folder_xml(id, name)))
+            },
+            "FindItem" => {
+                let items: String = self.messages.iter().map(|message| message_xml(message, false)).collect();
+                response_message(operation, "Success", "NoError", &format!(
+                    r#"<m:RootFolder TotalItemsInView="{}" IncludesLastItemInRange="true"><t:Items>{}</t:Items></m:RootFolder>"#,
+                    self.messages.len(), items))
+            },
+            "GetItem" => {
+                let ids: Vec<&str> = envelope.map(|envelope| envelope.descendants("ItemId").into_iter().filter_map(|id| id.attr("Id")).collect())
+                    .unwrap_or_default();
+                let messages: String = ids.iter().map(|id| match self.messages.iter().find(|message| message.id == *id) {
+                    Some(message) => response_message_named("GetItemResponseMessage", "Success", "NoError",
+                        &format!("<m:Items>{}</m:Items>", message_xml(message, true))),
+                    None => response_message_named("GetItemResponseMessage", "Error", "ErrorItemNotFound", ""),
+                }).collect();
+                format!("<m:GetItemResponse><m:ResponseMessages>{}</m:ResponseMessages></m:GetItemResponse>", messages)
+            },
+            "CreateItem" => {
+                self.created += 1;
+                response_message(operation, "Success", "NoError", &format!(
+                    r#"<m:Items><t:Message><t:ItemId Id="mock-created-{}" ChangeKey="1"/></t:Message></m:Items>"#, self.created))
+            },
+            "UpdateItem" => {
+                for change in envelope.map(|envelope| envelope.descendants("ItemChange")).unwrap_or_default() {
+                    let id = change.child("ItemId").and_then(|id| id.attr("Id")).unwrap_or_default();
+                    let Some(message) = self.messages.iter_mut().find(|message| message.id == id) else { continue };
+                    for update in change.descendants("SetItemField").into_iter().chain(change.descendants("DeleteItemField")) {
+                        if update.child("ExtendedFieldURI").and_then(|uri| uri.attr("PropertyTag")) == Some("0x1081") {
+                            message.is_answered = update.name == "SetItemField";
+                        }
+                    }
+                }
+                response_message(operation, "Success", "NoError", "")
+            },
+            "GetServerTimeZones" => response_message(operation, "Success", "NoError", "<m:TimeZoneDefinitions/>"),
+            _ => response_message(operation, "Success", "NoError", ""),
+        }
+    }
+
+    fn folder_xml(&self, id: &str, name: &str) -> String {
+        let unread = self.messages.iter().filter(|message| !message.is_read).count();
+        format!(r#"<t:Folder><t:FolderId Id="{}" ChangeKey="1"/><t:DisplayName>{}</t:DisplayName><t:TotalCount>{}</t:TotalCount><t:UnreadCount>{}</t:UnreadCount></t:Folder>"#,
+            id, escape_xml(name), self.messages.len(), unread)
+    }
+}
+
+fn message_xml(message: &MockMessage, with_mime: bool) -> String {
+    let mime = if with_mime {
+        format!(r#"<t:MimeContent CharacterSet="UTF-8">{}</t:MimeContent>"#, STANDARD.encode(&message.mime))
+    } else {
+        String::new()
+    };
+    format!(concat!(
+        r#"<t:Message>{}<t:ItemId Id="{}" ChangeKey="1"/><t:Subject>{}</t:Subject><t:DateTimeReceived>2024-01-01T00:00:00Z</t:DateTimeReceived>"#,
+        r#"<t:Size>{}</t:Size><t:From><t:Mailbox><t:Name>{}</t:Name><t:EmailAddress>{}</t:EmailAddress></t:Mailbox></t:From>"#,
+        r#"{}<t:IsRead>{}</t:IsRead></t:Message>"#),
+        mime, escape_xml(&message.id), escape_xml(&message.subject), message.mime.len(), escape_xml(&message.from), escape_xml(&message.from),
+        answered_xml(message), message.is_read)
+}
+
+// PidTagLastVerbExecuted of a replied message, EXCHIVERB_REPLYTOSENDER
+fn answered_xml(message: &MockMessage) -> &'static str {
+    if message.is_answered {
+        r#"<t:ExtendedProperty><t:ExtendedFieldURI PropertyTag="0x1081" PropertyType="Integer"/><t:Value>102</t:Value></t:ExtendedProperty>"#
+    } else {
+        ""
+    }
+}
+
+fn envelope_of(body: &str) -> String {
+    format!("{}{}{}", ENVELOPE_START, body, ENVELOPE_END)
+}
+
+// <m:FindItemResponse> with a single response message
+fn response_message(operation: &str, class: &str, code: &str, content: &str) -> String {
+    format!("<m:{}Response><m:ResponseMessages>{}</m:ResponseMessages></m:{}Response>",
+        operation, response_message_named(&format!("{}ResponseMessage", operation), class, code, content), operation)
+}
+
+fn response_message_named(name: &str, class: &str, code: &str, content: &str) -> String {
+    let text = if class == "Error" { format!("<m:MessageText>Mock {}</m:MessageText>", code) } else { String::new() };
+    format!(r#"<m:{} ResponseClass="{}">{}<m:ResponseCode>{}</m:ResponseCode>{}</m:{}>"#, name, class, text, code, content, name)
+}
+
+fn soap_fault(code: &str, message: &str, detail: &str) -> String {
+    envelope_of(&format!("<s:Fault><faultcode>{}</faultcode><faultstring>{}</faultstring><detail>{}</detail></s:Fault>",
+        code, escape_xml(message), detail))
+}
+
+// What Exchange Online answers when a user exceeds its EWS budget
+fn server_busy(back_off: Duration) -> String {
+    soap_fault("a:ErrorServerBusy", "The server cannot service this request right now. Try again later.", &format!(
+        r#"<e:ResponseCode xmlns:e="http://schemas.microsoft.com/exchange/services/2006/errors">ErrorServerBusy</e:ResponseCode><e:MessageXml xmlns:e="http://schemas.microsoft.com/exchange/services/2006/errors"><t:Value xmlns:t="http://schemas.microsoft.com/exchange/services/2006/types" Name="BackOffMilliseconds">{}</t:Value></e:MessageXml>"#,
+        back_off.as_millis()))
+}
@@ -0,0 +1,118 @@
+// privileges.rs
+// davmail.runAsUser and davmail.runAsGroup: started as root to bind low ports, the gateway continues as an unprivileged user
+
+use std::io;
+use config::Config;
+use log::{info, warn};
+
+// The user and group the process switches to once its listeners are bound
+#[cfg(unix)]
+pub struct RunAs {
+    // None for a numeric user id without a passwd entry, which gets no supplementary groups
+    name: Option<String>,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+#[cfg(unix)]
+impl RunAs {
+    // None unless davmail.runAsUser is set. The group defaults to the user's primary group.
+    // Names are looked up at startup, so a typo fails before anything is bound.
+    pub fn from_config(config: &Config) -> io::Result<Option<RunAs>> {
+        let user = match config.get_string("davmail.runAsUser") {
+            Ok(user) if !user.trim().is_empty() => user.trim().to_string(),
+            _ => return Ok(None),
+        };
+        let (name, uid, primary_gid) = lookup_user(&user)?;
+        let gid = match config.get_string("davmail.runAsGroup") {
+            Ok(group) if !group.trim().is_empty() => lookup_group(group.trim())?,
+            _ => primary_gid.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                format!("User id {} has no passwd entry, davmail.runAsGroup is required", uid)))?,
+        };
+        Ok(Some(RunAs { name, uid, gid }))
+    }
+
+    // Switch group, supplementary groups and user for every thread. Listeners restarted later can no
+    // longer bind ports below 1024, and files the gateway writes must be writable by the new user.
+    pub fn apply(&self) -> io::Result<()> {
+        let euid = unsafe { libc::geteuid() };
+        if euid == self.uid {
+            return Ok(());
+        }
+        if euid != 0 {
+            warn!("Not running as root, ignoring davmail.runAsUser and keeping user id {}", euid);
+            return Ok(());
+        }
+
+        // Supplementary groups and the group first, they can only be changed while still root
+        let groups = match &self.name {
+            Some(name) => {
+                let name = std::ffi::CString::new(name.as_str()).map_err(io::Error::other)?;
+                unsafe { libc::initgroups(name.as_ptr(), self.gid as _) }
+            },
+            None => unsafe { libc::setgroups(1, &self.gid) },
+        };
+        if groups != 0 || unsafe { libc::setgid(self.gid) } != 0 || unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Root must be out of reach for good, not only the effective user id
+        if unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Root privileges could be regained after dropping them"));
+        }
+        info!("Dropped root privileges, running as {} (uid {}, gid {})", self.name.as_deref().unwrap_or("?"), self.uid, self.gid);
+        Ok(())
+    }
+}
+
+// Name or numeric id: (name, uid, primary gid), a numeric id doesn't need a passwd entry
+#[cfg(unix)]
+fn lookup_user(user: &str) -> io::Result<(Option<String>, libc::uid_t, Option<libc::gid_t>)> {
+    let entry = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = std::ffi::CString::new(user).map_err(io::Error::other)?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        },
+    };
+    if entry.is_null() {
+        return match user.parse::<libc::uid_t>() {
+            Ok(uid) => Ok((None, uid, None)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown user {} in davmail.runAsUser", user))),
+        };
+    }
+    let entry = unsafe { &*entry };
+    let name = unsafe { std::ffi::CStr::from_ptr(entry.pw_name) }.to_string_lossy().into_owned();
+    Ok((Some(name), entry.pw_uid, Some(entry.pw_gid)))
+}
+
+#[cfg(unix)]
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(io::Error::other)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown group {} in davmail.runAsGroup", group)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+// Services on Windows choose their account when installed
+#[cfg(not(unix))]
+pub struct RunAs;
+
+#[cfg(not(unix))]
+impl RunAs {
+    pub fn from_config(config: &Config) -> io::Result<Option<RunAs>> {
+        if config.get_string("davmail.runAsUser").is_ok_and(|user| !user.trim().is_empty()) {
+            warn!("davmail.runAsUser is only supported on Unix, ignoring it");
+        }
+        Ok(None)
+    }
+
+    pub fn apply(&self) -> io::Result<()> {
+        info!("Keeping the account the gateway was started with");
+        Ok(())
+    }
+}
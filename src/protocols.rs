@@ -1,5 +1,12 @@
 // protocols.rs
 // protocols  module for DavMail Rust
 
+pub mod access;
+pub mod gate;
 pub mod imap;
-pub mod pop;
+pub mod lockout;
+pub mod sasl;
+pub mod shutdown;
+pub mod timeouts;
+pub mod tls;
+pub mod utf7;
@@ -0,0 +1,107 @@
+// protocols/access.rs
+// Which client addresses the listeners accept
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use config::Config;
+
+// Address range in CIDR notation, a plain address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix)
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network = address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()
+            .map_err(|_| format!("invalid address range {}", value))?
+            .to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length in {}", value))?,
+            None => max_prefix,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+// Checked on accept() before anything is sent to the client:
+// - davmail.deniedAddresses: ranges always refused, they win over everything else
+// - davmail.allowedAddresses: when set, only these ranges are accepted
+// - davmail.allowRemote: without an allow list, false limits clients to the loopback interface
+// Ranges are separated by commas or spaces, e.g. "10.1.0.0/16, 192.168.50.0/24, fd00::/8"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessPolicy {
+    allow_remote: bool,
+    allowed: Vec<Cidr>,
+    denied: Vec<Cidr>,
+}
+
+impl AccessPolicy {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        Ok(AccessPolicy {
+            allow_remote: config.get_bool("davmail.allowRemote").unwrap_or(true),
+            allowed: parse_ranges(config, "davmail.allowedAddresses")?,
+            denied: parse_ranges(config, "davmail.deniedAddresses")?,
+        })
+    }
+
+    pub fn allows(&self, address: IpAddr) -> bool {
+        // Dual stack listeners see IPv4 clients as ::ffff:a.b.c.d
+        let address = address.to_canonical();
+        if self.denied.iter().any(|range| range.contains(address)) {
+            return false;
+        }
+        if !self.allowed.is_empty() {
+            return self.allowed.iter().any(|range| range.contains(address));
+        }
+        self.allow_remote || address.is_loopback()
+    }
+}
+
+fn parse_ranges(config: &Config, key: &str) -> Result<Vec<Cidr>, String> {
+    let ranges = match config.get_array(key) {
+        Ok(values) => values.into_iter().filter_map(|value| value.into_string().ok()).collect(),
+        Err(_) => config.get_string(key).map(|value| vec![value]).unwrap_or_default(),
+    };
+    ranges.iter()
+        .flat_map(|value| value.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|range| !range.is_empty())
+        .map(|range| range.parse::<Cidr>().map_err(|e| format!("{}: {}", key, e)))
+        .collect()
+}
@@ -0,0 +1,109 @@
+// protocols/gate.rs
+// Caps the connections a listener accepts, in total and per client address
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use config::Config;
+
+// Mail clients keep a few connections per account, a single host rarely needs more
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 50;
+
+// davmail.<protocol>MaxConnections / davmail.<protocol>MaxConnectionsPerIp, falling back to
+// davmail.maxConnections / davmail.maxConnectionsPerIp, 0 means no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+}
+
+impl ConnectionLimits {
+    pub fn from_config(config: &Config, protocol: &str) -> Self {
+        let limit = |protocol_key: String, key: &str, default: usize| {
+            config.get_int(&protocol_key)
+                .or_else(|_| config.get_int(key))
+                .map(|limit| limit.max(0) as usize)
+                .unwrap_or(default)
+        };
+        ConnectionLimits {
+            max_connections: limit(format!("davmail.{}MaxConnections", protocol), "davmail.maxConnections", DEFAULT_MAX_CONNECTIONS),
+            max_connections_per_ip: limit(format!("davmail.{}MaxConnectionsPerIp", protocol), "davmail.maxConnectionsPerIp", DEFAULT_MAX_CONNECTIONS_PER_IP),
+        }
+    }
+}
+
+// Why a connection was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    ListenerFull,
+    AddressFull,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ListenerFull => write!(f, "Too many connections"),
+            Rejection::AddressFull => write!(f, "Too many connections from your address"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// One per listener, shared with the connection threads through their permits
+#[derive(Clone, Default)]
+pub struct ConnectionGate {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionGate {
+    pub fn new() -> Self {
+        ConnectionGate::default()
+    }
+
+    // Open connections of the listener
+    pub fn connections(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+
+    // Limits are passed on each call so a configuration reload applies to the next connection
+    pub fn try_acquire(&self, address: IpAddr, limits: &ConnectionLimits) -> Result<ConnectionPermit, Rejection> {
+        let mut counts = self.counts.lock().unwrap();
+        if limits.max_connections > 0 && counts.total >= limits.max_connections {
+            return Err(Rejection::ListenerFull);
+        }
+        let from_address = counts.per_ip.get(&address).copied().unwrap_or(0);
+        if limits.max_connections_per_ip > 0 && from_address >= limits.max_connections_per_ip {
+            return Err(Rejection::AddressFull);
+        }
+
+        counts.total += 1;
+        counts.per_ip.insert(address, from_address + 1);
+        Ok(ConnectionPermit { gate: self.clone(), address })
+    }
+}
+
+// Held by the connection thread, frees the slot when the connection ends
+pub struct ConnectionPermit {
+    gate: ConnectionGate,
+    address: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.gate.counts.lock().unwrap();
+        counts.total = counts.total.saturating_sub(1);
+        if let Some(count) = counts.per_ip.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.address);
+            }
+        }
+    }
+}
@@ -2,62 +2,260 @@
 // IMAP protocol implementation for DavMail Rust
 
 use std::sync::{Arc, Mutex};
-use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write, BufReader, BufRead};
-use std::thread;
+use std::net::IpAddr;
+use std::io::{self, Write};
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use futures_util::future::{self, Either};
 use log::{info, error, warn, debug};
 use config::Config;
+use reqwest::Client;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch};
+use zeroize::Zeroizing;
 
-use crate::exchange::client::ExchangeClient;
-use crate::auth::Credentials;
+use crate::audit::{self, AuditEvent, AuditSession};
+use crate::error::ProtocolStatus;
+use crate::exchange::ExchangeClient;
+use crate::exchange::archive::ARCHIVE_NAMESPACE;
+use crate::exchange::{ExchangeError, FetchItem};
+use crate::exchange::http::{HttpClientConfig, RequestTimeouts};
+use crate::exchange::html::HtmlConversion;
+use crate::exchange::foldercache::FolderCache;
+use crate::exchange::limiter::RequestLimiter;
+use crate::exchange::names::FolderNames;
+use crate::exchange::receipts::ReadReceipts;
+use crate::exchange::request::distinguished_folder;
+use crate::exchange::spool::BodySection;
+use crate::exchange::sessions::{SessionCache, SessionKey};
+use crate::exchange::sync::{MetadataSync, DEFAULT_METADATA_REFRESH};
+use crate::headers::HeaderRules;
+use crate::hooks::{Hooks, LoginInfo, MessageContext};
+use crate::logformat;
+use crate::systemd;
+use crate::telemetry::{self, Span};
+use crate::uidmap::UidMap;
+use crate::users::{UserLimits, UserPermit, UserRegistry};
+use crate::mailstore::{self, MailStore};
+use crate::metadata::MetadataCache;
+use crate::protocols::access::AccessPolicy;
+use crate::protocols::gate::{ConnectionGate, ConnectionLimits};
+use crate::protocols::lockout::{LockoutSettings, LoginGuard, REFUSAL_DELAY};
+use crate::protocols::sasl;
+use crate::rules::{Rules, RulesPass};
+use crate::protocols::shutdown::{self, ConnectionTracker};
+use crate::protocols::timeouts::{set_tcp_keepalive, ConnectionTimeouts};
+use crate::protocols::tls::{self, ClientStream, TlsAcceptor};
+use crate::protocols::utf7;
+use crate::configuration::{LiveSettings, SharedSettings, UserOverrides};
+use crate::wirelog::{self, Direction};
+use crate::auth::{Credentials, OAuth2Auth, OAuth2Client, OAuth2Config, TokenManager, TokenStore};
 
+// Flags STORE can add or remove, see the STORE command
+const STORE_FLAGS: [&str; 6] = ["\\Seen", "\\Answered", "\\Deleted", "$Junk", "$NotJunk", "$MDNSent"];
+
+/// IMAP listener serving Exchange mailboxes, on every bind address for one port.
+/// Built with `new` and the `with_*` options, then driven by `run` on a tokio runtime.
 pub struct ImapServer {
-    config: Arc<Config>,
+    // Read for each new connection, so configuration reloads apply without restarting the listener
+    settings: SharedSettings,
+    bind_addresses: Vec<String>,
     port: u16,
+    token_manager: Arc<TokenManager>,
+    session_cache: Arc<SessionCache>,
+    login_guard: Arc<LoginGuard>,
+    metadata_cache: Option<Arc<MetadataCache>>,
+    request_limiter: Option<Arc<RequestLimiter>>,
+    // Counts connections across all addresses of the listener
+    gate: ConnectionGate,
+    // Told once every address has been tried, see with_bound_signal
+    bound: Option<oneshot::Sender<()>>,
+    // Registered by embedders, none by default
+    hooks: Arc<Hooks>,
+    // Connections and UID maps of each user, shared with the profile's other listeners
+    users: UserRegistry,
 }
 
 impl ImapServer {
-    pub fn new(config: Arc<Config>, port: u16) -> Self {
-        ImapServer { config, port }
+    /// `settings` are shared with the caller so a configuration reload reaches new connections,
+    /// the token manager, session cache and login guard can be shared between listeners
+    pub fn new(settings: SharedSettings, bind_addresses: Vec<String>, port: u16, token_manager: Arc<TokenManager>, session_cache: Arc<SessionCache>,
+               login_guard: Arc<LoginGuard>) -> Self {
+        ImapServer { settings, bind_addresses, port, token_manager, session_cache, login_guard, metadata_cache: None, request_limiter: None,
+            gate: ConnectionGate::new(), bound: None, hooks: Arc::new(Hooks::new()), users: UserRegistry::new() }
+    }
+
+    /// Sessions keep folder metadata in this cache, see davmail.ews.metadataCacheFile
+    pub fn with_metadata_cache(mut self, metadata_cache: Option<Arc<MetadataCache>>) -> Self {
+        self.metadata_cache = metadata_cache;
+        self
+    }
+    
+    /// New sessions share this limit on concurrent EWS requests
+    pub fn with_request_limiter(mut self, request_limiter: Arc<RequestLimiter>) -> Self {
+        self.request_limiter = Some(request_limiter);
+        self
+    }
+    
+    /// Sent after binding, whether or not an address could be bound, so privileges can be dropped.
+    /// Dropped without sending when the server fails before binding.
+    pub fn with_bound_signal(mut self, bound: oneshot::Sender<()>) -> Self {
+        self.bound = Some(bound);
+        self
+    }
+    
+    /// Per-user limits and UID maps, shared with other listeners serving the same users
+    pub fn with_user_registry(mut self, users: UserRegistry) -> Self {
+        self.users = users;
+        self
+    }
+    
+    /// Logins and fetched messages of every connection go through these hooks
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
     }
     
-    pub fn run(&self, shutdown_signal: Arc<Mutex<bool>>) {
-        // Bind to the IMAP port
-        let listener = match TcpListener::bind(format!("0.0.0.0:{}", self.port)) {
-            Ok(listener) => listener,
-            Err(e) => {
-                error!("Failed to bind IMAP server to port {}: {}", self.port, e);
-                return;
+    /// Shares the connection counts of the listener, to report them while it runs
+    pub fn connection_gate(&self) -> ConnectionGate {
+        self.gate.clone()
+    }
+    
+    // What the listener shares with each of its connections
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            token_manager: self.token_manager.clone(),
+            session_cache: self.session_cache.clone(),
+            login_guard: self.login_guard.clone(),
+            metadata_cache: self.metadata_cache.clone(),
+            request_limiter: self.request_limiter.clone(),
+            hooks: self.hooks.clone(),
+            users: self.users.clone(),
+        }
+    }
+    
+    /// Accept connections until the shutdown signal is set, each client is served by its own task
+    pub async fn run(mut self, shutdown_signal: watch::Receiver<bool>) {
+        // IMAPS when davmail.imapSsl is set or a certificate is configured
+        let tls = {
+            let config = self.settings.read().unwrap().config.clone();
+            if tls::enabled(&config, "imap") {
+                match TlsAcceptor::from_config(&config) {
+                    Ok(acceptor) => Some(Arc::new(acceptor)),
+                    Err(e) => {
+                        error!("Failed to start IMAP server with TLS: {}", e);
+                        return;
+                    }
+                }
+            } else {
+                None
             }
         };
         
-        // Set timeout for accept operations to allow checking shutdown signal
-        listener.set_nonblocking(true).unwrap();
+        // Bind to the IMAP port on every address, a (host, port) pair also accepts IPv6 addresses and host names.
+        // An address that can't be bound is skipped, the server runs as long as one listener is up.
+        let mut listeners = Vec::new();
+        // Sockets passed by systemd for the port replace the configured bind addresses
+        for inherited in systemd::listeners(self.port) {
+            match inherited.set_nonblocking(true).and_then(|_| TcpListener::from_std(inherited)) {
+                Ok(listener) => {
+                    if let Ok(address) = listener.local_addr() {
+                        info!("IMAP server listening on {} (socket activation){}", address, if tls.is_some() { " with TLS" } else { "" });
+                    }
+                    listeners.push(listener);
+                },
+                Err(e) => error!("Failed to use the IMAP socket passed by systemd: {}", e),
+            }
+        }
+        let bind_addresses = if listeners.is_empty() { self.bind_addresses.as_slice() } else { &[] };
+        for bind_address in bind_addresses {
+            match TcpListener::bind((bind_address.as_str(), self.port)).await {
+                Ok(listener) => {
+                    match listener.local_addr() {
+                        Ok(address) => info!("IMAP server listening on {}{}", address, if tls.is_some() { " with TLS" } else { "" }),
+                        Err(_) => info!("IMAP server listening on {} port {}", bind_address, self.port),
+                    }
+                    listeners.push(listener);
+                },
+                Err(e) => error!("Failed to bind IMAP server to {} port {}: {}", bind_address, self.port, e),
+            }
+        }
+        if let Some(bound) = self.bound.take() {
+            let _ = bound.send(());
+        }
+        if listeners.is_empty() {
+            return;
+        }
+        
+        let connections = ConnectionTracker::new();
+        future::join_all(listeners.iter().map(|listener| self.accept_loop(listener, &self.gate, &connections, tls.clone(), shutdown_signal.clone()))).await;
         
-        info!("IMAP server listening on port {}", self.port);
+        // Clients get a BYE once their current command is answered
+        let grace_period = shutdown::grace_period(&self.settings.read().unwrap().config);
+        connections.close_all("IMAP", grace_period).await;
         
+        info!("IMAP server stopped");
+    }
+    
+    async fn accept_loop(&self, listener: &TcpListener, gate: &ConnectionGate, connections: &ConnectionTracker, tls: Option<Arc<TlsAcceptor>>,
+                         mut shutdown_signal: watch::Receiver<bool>) {
         loop {
-            // Check if shutdown was requested
-            if *shutdown_signal.lock().unwrap() {
-                info!("IMAP server shutdown requested");
-                break;
-            }
+            // Wait for a client or for shutdown to be requested
+            let accepted = match future::select(pin!(listener.accept()), pin!(shutdown_signal.wait_for(|shutdown| *shutdown))).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => {
+                    info!("IMAP server shutdown requested");
+                    break;
+                }
+            };
             
-            // Accept new connections
-            match listener.accept() {
-                Ok((stream, addr)) => {
+            match accepted {
+                Ok((mut stream, addr)) => {
+                    let settings = self.settings.read().unwrap().clone();
+                    // An invalid range refuses every connection rather than letting unwanted clients in
+                    let allowed = AccessPolicy::from_config(&settings.config)
+                        .map_err(|e| error!("Refusing IMAP connections, invalid access lists: {}", e))
+                        .is_ok_and(|policy| policy.allows(addr.ip()));
+                    if !allowed {
+                        warn!("Refusing IMAP connection from {}, address not allowed", addr);
+                        if tls.is_none() {
+                            let _ = stream.write_all(b"* BYE Access denied\n").await;
+                        }
+                        continue;
+                    }
+                    let permit = match gate.try_acquire(addr.ip(), &ConnectionLimits::from_config(&settings.config, "imap")) {
+                        Ok(permit) => permit,
+                        Err(rejection) => {
+                            warn!("Rejecting IMAP connection from {}: {}", addr, rejection);
+                            // A TLS client can't read anything before the handshake, it just sees the connection close
+                            if tls.is_none() {
+                                let _ = stream.write_all(format!("* BYE [UNAVAILABLE] {}\n", rejection).as_bytes()).await;
+                            }
+                            continue;
+                        }
+                    };
                     info!("New IMAP connection from {}", addr);
-                    let config = self.config.clone();
-                    thread::spawn(move || {
-                        if let Err(e) = handle_imap_client(stream, config) {
+                    let context = self.connection_context();
+                    let tls = tls.clone();
+                    let connection_shutdown = shutdown_signal.clone();
+                    connections.spawn(logformat::scope("IMAP", addr.to_string(), telemetry::scope(async move {
+                        let _permit = permit;
+                        let stream = match &tls {
+                            Some(tls) => tls.accept(stream).await,
+                            None => Ok(ClientStream::Plain(stream)),
+                        };
+                        let stream = match stream {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                warn!("TLS handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                        };
+                        if let Err(e) = handle_imap_client(stream, addr.ip(), settings, context, connection_shutdown).await {
                             error!("Error handling IMAP client: {}", e);
                         }
-                    });
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No connection available, wait a bit
-                    thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
+                    })));
                 }
                 Err(e) => {
                     error!("Error accepting IMAP connection: {}", e);
@@ -65,52 +263,423 @@ impl ImapServer {
                 }
             }
         }
-        
-        info!("IMAP server stopped");
     }
 }
 
-fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
+// Client connection, responses written with writeln! are buffered and sent before the next read
+struct ImapConnection {
+    stream: BufReader<ClientStream>,
+    responses: Vec<u8>,
+    // How long a read waits before giving up with TimedOut, see set_keepalive_timeout
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // Client address and port in the wire log
+    peer: String,
+    // The next line is a credential sent as a literal, see wirelog::continues_with_secret
+    secret_literal: bool,
+}
+
+impl ImapConnection {
+    fn new(stream: ClientStream, peer: String) -> Self {
+        ImapConnection { stream: BufReader::new(stream), responses: Vec::new(), read_timeout: None, write_timeout: None, peer, secret_literal: false }
+    }
+    
+    fn allows_user(&self, username: &str) -> bool {
+        self.stream.get_ref().allows_user(username)
+    }
+    
+    async fn send(&mut self) -> io::Result<()> {
+        if self.responses.is_empty() {
+            return Ok(());
+        }
+        let ImapConnection { stream, responses, write_timeout, peer, .. } = self;
+        if wirelog::enabled() {
+            for line in String::from_utf8_lossy(responses).lines() {
+                wirelog::log("IMAP", peer, Direction::Sent, line);
+            }
+        }
+        let write = async {
+            stream.write_all(responses).await?;
+            stream.flush().await
+        };
+        with_timeout(*write_timeout, write).await?;
+        responses.clear();
+        Ok(())
+    }
+    
+    // Message content goes from its spool to the socket a chunk at a time, the write timeout applies to each chunk
+    async fn send_literal(&mut self, section: &BodySection) -> io::Result<()> {
+        self.send().await?;
+        wirelog::log("IMAP", &self.peer, Direction::Sent, &format!("[{} bytes of message content]", section.len()));
+        let mut reader = section.reader().await?;
+        while let Some(chunk) = reader.next_chunk().await? {
+            with_timeout(self.write_timeout, self.stream.write_all(chunk)).await?;
+        }
+        Ok(())
+    }
+    
+    // Pending responses go out first, the client waits for them before sending more. On a timeout
+    // the part of the line received so far stays in `line`.
+    async fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        self.send().await?;
+        let start = line.len();
+        let read = with_timeout(self.read_timeout, self.stream.read_line(line)).await?;
+        if line.ends_with('\n') {
+            let secret = self.secret_literal;
+            self.secret_literal = wirelog::continues_with_secret(line, secret);
+            // A line completed after a read timeout is logged whole
+            if secret {
+                wirelog::log("IMAP", &self.peer, Direction::Received, wirelog::redacted());
+            } else if wirelog::enabled() {
+                wirelog::log("IMAP", &self.peer, Direction::Received, &wirelog::redact_imap_command(line));
+            }
+        } else if start == 0 && read == 0 {
+            wirelog::log("IMAP", &self.peer, Direction::Received, "[connection closed]");
+        }
+        Ok(read)
+    }
+    
+    // SASL response to an authentication challenge, kept out of the wire log
+    async fn read_secret_line(&mut self, line: &mut String) -> io::Result<usize> {
+        self.send().await?;
+        let read = with_timeout(self.read_timeout, self.stream.read_line(line)).await?;
+        wirelog::log("IMAP", &self.peer, Direction::Received, wirelog::redacted());
+        Ok(read)
+    }
+}
+
+impl Write for ImapConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.responses.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn with_timeout<T>(timeout: Option<Duration>, operation: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, operation).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => operation.await,
+    }
+}
+
+// Broker mode (davmail.oauth.broker): sessions run on refresh tokens imported by the administrator, who sets
+// the password each user logs in to the gateway with alongside the token. None for users without a token.
+async fn broker_session(config: &Config, username: &str, password: &str, http_client: &Client) -> Result<Option<OAuth2Auth>, ExchangeError> {
+    if !config.get_bool("davmail.oauth.broker").unwrap_or(false) {
+        return Ok(None);
+    }
+    let (Some(oauth2_config), Ok(token_file)) = (OAuth2Config::for_user(config, username), config.get_string("davmail.oauth.tokenFile")) else {
+        return Ok(None);
+    };
+    
+    // Read on each login so tokens imported since the gateway started are found. The file read and the
+    // PBKDF2 check of the password stay off the connection's task.
+    let lookup_key = OAuth2Client::token_key_for(&oauth2_config, username);
+    let (path, candidate) = (token_file.clone(), Zeroizing::new(password.to_string()));
+    let opened = tokio::task::spawn_blocking(move || TokenStore::open(&path).map(|token_store| {
+        let imported = token_store.get(&lookup_key).is_some();
+        let verified = imported && token_store.verify_password(&lookup_key, &candidate);
+        (token_store, imported, verified)
+    })).await;
+    let (token_store, imported, verified) = match opened {
+        Ok(Ok(opened)) => opened,
+        Ok(Err(e)) => {
+            error!("Failed to open token store {}: {}", token_file, e);
+            return Ok(None);
+        },
+        Err(e) => return Err(ExchangeError::AuthError(format!("Broker mode password check failed: {}", e))),
+    };
+    if !imported {
+        warn!("No imported refresh token for {}", username);
+        return Ok(None);
+    }
+    // The token opens the mailbox whatever the password, the gateway password is all that stands in front of it
+    if !verified {
+        return Err(ExchangeError::AuthError(format!("Wrong broker mode password for {}, or none was imported with the token", username)));
+    }
+    
+    let oauth2_auth = OAuth2Auth::new(oauth2_config, http_client.clone())
+        .map_err(|e| ExchangeError::ConfigError(e.to_string()))?;
+    Ok(Some(oauth2_auth.with_token_store(Arc::new(Mutex::new(token_store)), username)))
+}
+
+// OAuth2 sessions get their token from the shared renewal task instead of refreshing it on each request
+async fn connect_renewed(token_manager: &TokenManager, exchange_url: &str, oauth2_auth: OAuth2Auth, http_client: &Client) -> Result<ExchangeClient, ExchangeError> {
+    let token_updates = token_manager.subscribe(oauth2_auth).await
+        .map_err(|e| ExchangeError::AuthError(e.to_string()))?;
+    ExchangeClient::new_with_token_updates(exchange_url, token_updates, http_client.clone()).await
+}
+
+// New session, sharing the profile's metadata cache and request limits and the user's UID map and folder cache
+fn configure_session(client: ExchangeClient, metadata_cache: &Option<Arc<MetadataCache>>, request_limiter: &Option<Arc<RequestLimiter>>,
+                     uid_map: Option<Arc<Mutex<UidMap>>>, folder_cache: Option<Arc<FolderCache>>, config: &Config, username: &str) -> Arc<dyn MailStore> {
+    let client = match uid_map {
+        Some(uid_map) => client.with_uid_map(username, uid_map),
+        None => client,
+    };
+    let client = match folder_cache {
+        Some(folder_cache) => client.with_folder_cache(folder_cache),
+        None => client,
+    };
+    let client = match metadata_cache {
+        Some(metadata_cache) => {
+            let refresh_interval = config.get_int("davmail.ews.metadataRefreshInterval")
+                .map_or(DEFAULT_METADATA_REFRESH, |seconds| seconds.max(0) as u64);
+            client.with_metadata_cache(username, metadata_cache.clone(), Duration::from_secs(refresh_interval))
+        },
+        None => client,
+    };
+    let client = match request_limiter {
+        Some(request_limiter) => client.with_request_limiter(username, request_limiter.clone()),
+        None => client,
+    };
+    Arc::new(client.with_read_receipts(ReadReceipts::from_config(config))
+        .with_html_conversion(HtmlConversion::from_config(config))
+        .with_utf8_messages(config.get_bool("davmail.utf8Messages").unwrap_or(false))
+        .with_folder_names(FolderNames::from_config(config))
+        .with_header_rules(HeaderRules::from_config(config))
+        .with_request_timeouts(RequestTimeouts::from_config(config)))
+}
+
+// Keep a verifier of the password for offline logins, PBKDF2 runs off the runtime threads
+fn remember_login(metadata_cache: &Option<Arc<MetadataCache>>, username: &str, password: &str) {
+    if let Some(metadata_cache) = metadata_cache.clone() {
+        let (mailbox, password) = (username.to_lowercase(), Zeroizing::new(password.to_string()));
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = metadata_cache.remember_login(&mailbox, &password) {
+                warn!("Failed to keep offline login of {}: {}", mailbox, e);
+            }
+        });
+    }
+}
+
+// Read-only session on the cached folders, when the password matches the last one Exchange accepted
+async fn offline_login(metadata_cache: &Option<Arc<MetadataCache>>, username: &str, password: &str) -> Option<MetadataSync> {
+    let metadata_cache = metadata_cache.clone()?;
+    let cache = metadata_cache.clone();
+    let (mailbox, password) = (username.to_lowercase(), Zeroizing::new(password.to_string()));
+    match tokio::task::spawn_blocking(move || cache.verify_login(&mailbox, &password)).await {
+        Ok(Ok(true)) => Some(MetadataSync::offline(metadata_cache, username)),
+        Ok(Err(e)) => {
+            warn!("Failed to check offline login of {}: {}", username, e);
+            None
+        },
+        _ => None,
+    }
+}
+
+// Where the mail rules of the user's folder stopped, kept in the metadata cache so a restart doesn't lose it,
+// or in the user registry without one
+fn rule_watermark(metadata_cache: &Option<Arc<MetadataCache>>, users: &UserRegistry, username: &str, folder: &str) -> Option<String> {
+    match metadata_cache {
+        Some(metadata_cache) => metadata_cache.rule_watermark(&username.to_lowercase(), folder).unwrap_or_else(|e| {
+            warn!("Failed to read where the mail rules of {} stopped: {}", folder, e);
+            None
+        }),
+        None => users.rule_watermark(username, folder),
+    }
+}
+
+fn set_rule_watermark(metadata_cache: &Option<Arc<MetadataCache>>, users: &UserRegistry, username: &str, folder: &str, received: &str) {
+    match metadata_cache {
+        Some(metadata_cache) => if let Err(e) = metadata_cache.set_rule_watermark(&username.to_lowercase(), folder, received) {
+            warn!("Failed to keep where the mail rules of {} stopped: {}", folder, e);
+        },
+        None => users.set_rule_watermark(username, folder, received),
+    }
+}
+
+// Shared configuration with the user's [users."login"] overrides
+fn user_config(user_overrides: &UserOverrides, shared_config: &Arc<Config>, username: &str) -> Arc<Config> {
+    user_overrides.config_for(shared_config, username).unwrap_or_else(|e| {
+        error!("Ignoring configuration overrides of {}: {}", username, e);
+        shared_config.clone()
+    })
+}
+
+// Wake up when the client stays idle to keep the Exchange session alive, and often enough to
+// close the connection once davmail.imapIdleTimeout or davmail.imapReadTimeout is reached
+fn set_keepalive_timeout(stream: &mut ImapConnection, config: &Config) -> ConnectionTimeouts {
+    let timeouts = ConnectionTimeouts::from_config(config, "imap");
+    stream.read_timeout = [keepalive_interval(config), timeouts.idle, timeouts.read].into_iter().flatten().min();
+    stream.write_timeout = timeouts.write;
+    timeouts
+}
+
+// davmail.ews.keepAliveInterval, None when keepalives are off
+fn keepalive_interval(config: &Config) -> Option<Duration> {
+    let keepalive_interval = config.get_int("davmail.ews.keepAliveInterval").unwrap_or(300).max(0) as u64;
+    Some(Duration::from_secs(keepalive_interval)).filter(|interval| !interval.is_zero())
+}
+
+// Session cache bucket of a LOGIN, follows the order in which LOGIN picks the authentication
+fn login_mode(config: &Config) -> &'static str {
+    if config.get_bool("davmail.enableNtlm").unwrap_or(false) {
+        "ntlm"
+    } else if config.get_bool("davmail.oauth.broker").unwrap_or(false) {
+        "broker"
+    } else if config.get_bool("davmail.oauth.ropc").unwrap_or(false) {
+        "ropc"
+    } else {
+        "basic"
+    }
+}
+
+// Tag, command name and the rest of the line, None without a command name
+pub(crate) fn split_command(line: &str) -> Option<Vec<&str>> {
+    let parts: Vec<&str> = line.trim().splitn(3, ' ').collect();
+    (parts.len() >= 2 && !parts[0].is_empty() && !parts[1].is_empty()).then_some(parts)
+}
+
+// Tagged response of a command Exchange failed, with the response code of the error (see error.rs). Over
+// the rate limits it says how long to wait, so a script looping on errors at least gets told to slow down.
+fn command_failed(command: &str, error: &ExchangeError) -> String {
+    match error {
+        ExchangeError::RateLimited(wait) => error.imap_response(&format!("Too many requests, slow down and retry {} in {}s", command, wait.as_secs() + 1)),
+        _ => error.imap_response(&format!("{} failed", command)),
+    }
+}
+
+// Mailbox name sent by the client, in modified UTF-7 unless it enabled UTF8=ACCEPT. Names that
+// aren't valid modified UTF-7 are taken as they are, as older clients send UTF-8 anyway.
+fn mailbox_from_client(name: &str, utf8_accept: bool) -> String {
+    if utf8_accept {
+        return name.to_string();
+    }
+    utf7::decode(name).unwrap_or_else(|| name.to_string())
+}
+
+fn mailbox_to_client(name: &str, utf8_accept: bool) -> String {
+    if utf8_accept { name.to_string() } else { utf7::encode(name) }
+}
+
+// Listener state a connection works with, see ImapServer::connection_context
+struct ConnectionContext {
+    token_manager: Arc<TokenManager>,
+    session_cache: Arc<SessionCache>,
+    login_guard: Arc<LoginGuard>,
+    metadata_cache: Option<Arc<MetadataCache>>,
+    request_limiter: Option<Arc<RequestLimiter>>,
+    hooks: Arc<Hooks>,
+    users: UserRegistry,
+}
+
+async fn handle_imap_client(stream: ClientStream, client_address: IpAddr, settings: LiveSettings, context: ConnectionContext,
+                            mut shutdown_signal: watch::Receiver<bool>) -> crate::error::Result<()> {
+    let LiveSettings { config: shared_config, http_client, user_overrides } = settings;
+    let ConnectionContext { token_manager, session_cache, login_guard, metadata_cache, request_limiter, hooks, users } = context;
+    // Replaced by the user's own configuration at login
+    let mut config = shared_config.clone();
+    
     // Set TCP keepalive
-    stream.set_keepalive(Some(std::time::Duration::from_secs(60)))?;
+    set_tcp_keepalive(stream.socket(), &config)?;
+    let peer = stream.socket().peer_addr().map_or_else(|_| client_address.to_string(), |peer| peer.to_string());
+    let mut stream = ImapConnection::new(stream, peer);
     
     // Send greeting
-    writeln!(stream, "* OK [CAPABILITY IMAP4rev1 LITERAL+ SASL-IR LOGIN-REFERRALS AUTH=PLAIN AUTH=LOGIN] DavMail Rust IMAP ready")?;
+    writeln!(stream, "* OK [CAPABILITY IMAP4rev1 NAMESPACE LITERAL+ MOVE ENABLE UTF8=ACCEPT SASL-IR LOGIN-REFERRALS AUTH=XOAUTH2 AUTH=OAUTHBEARER] DavMail Rust IMAP ready")?;
     
-    let mut reader = BufReader::new(stream.try_clone()?);
     let mut line = String::new();
     let mut authenticated = false;
     let mut selected_mailbox: Option<String> = None;
-    let mut exchange_client: Option<ExchangeClient> = None;
+    // \Deleted was stored on every message of the selected Trash, the next EXPUNGE empties it
+    let mut all_deleted = false;
+    // RFC 6855, mailbox names are UTF-8 instead of modified UTF-7 once the client enables it
+    let mut utf8_accept = false;
+    // Set at login, for the message hooks
+    let mut login_user = String::new();
+    // Counts the connection against the user's limits while it is logged in
+    let mut _user_permit: Option<UserPermit> = None;
+    // Records the logout when the connection ends, see audit.rs
+    let mut audit: Option<AuditSession> = None;
+    let mut mail_store: Option<Box<dyn MailStore>> = None;
+    
+    let mut timeouts = set_keepalive_timeout(&mut stream, &config);
+    let mut partial_line = false;
+    let mut last_command = Instant::now();
+    let mut last_keepalive = Instant::now();
     
     // Process client commands
     loop {
-        line.clear();
-        let bytes_read = reader.read_line(&mut line)?;
+        // A read timeout can leave part of a command in the buffer, complete it instead of discarding it
+        if !partial_line {
+            line.clear();
+        }
+        partial_line = false;
+        // Shutdown only interrupts the wait for the next command, a command in progress is answered first
+        let read = match future::select(pin!(stream.read_line(&mut line)), pin!(shutdown_signal.wait_for(|shutdown| *shutdown))).await {
+            Either::Left((read, _)) => Some(read),
+            Either::Right(_) => None,
+        };
+        let Some(read) = read else {
+            info!("Closing IMAP connection from {}, server shutting down", client_address);
+            writeln!(stream, "* BYE Server shutting down")?;
+            stream.send().await?;
+            break;
+        };
+        let bytes_read = match read {
+            Ok(bytes_read) => bytes_read,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                partial_line = true;
+                if timeouts.expired(last_command.elapsed(), !line.is_empty()) {
+                    info!("Closing IMAP connection from {}, silent for {:?}", client_address, last_command.elapsed());
+                    writeln!(stream, "* BYE Autologout; idle for too long")?;
+                    break;
+                }
+                if let Some(client) = &mail_store {
+                    if keepalive_interval(&config).is_some_and(|interval| last_keepalive.elapsed() >= interval) {
+                        last_keepalive = Instant::now();
+                        if let Err(e) = client.keepalive().await {
+                            warn!("Exchange keepalive failed: {}", e);
+                        }
+                    }
+                }
+                continue;
+            },
+            Err(e) => return Err(e.into()),
+        };
         if bytes_read == 0 {
             // Connection closed
             break;
         }
+        last_command = Instant::now();
         
         debug!("IMAP received: {}", line.trim());
         
-        // Parse IMAP command
-        let parts: Vec<&str> = line.trim().splitn(3, ' ').collect();
-        if parts.len() < 2 {
+        let Some(parts) = split_command(&line) else {
             writeln!(stream, "* BAD Invalid command")?;
             continue;
-        }
+        };
         
         let tag = parts[0];
         let command = parts[1].to_uppercase();
         
+        // One trace per command, its EWS calls included
+        let mut span = match (command.as_str(), parts.get(2)) {
+            ("UID", Some(arguments)) => Span::command("IMAP", &format!("UID {}", arguments.split(' ').next().unwrap_or_default().to_uppercase())),
+            _ => Span::command("IMAP", &command),
+        };
+        if let Some(mailbox) = &selected_mailbox {
+            span.set_attribute("imap.mailbox", mailbox);
+        }
+        
         match command.as_str() {
             "CAPABILITY" => {
-                writeln!(stream, "* CAPABILITY IMAP4rev1 LITERAL+ SASL-IR LOGIN-REFERRALS AUTH=PLAIN AUTH=LOGIN")?;
+                writeln!(stream, "* CAPABILITY IMAP4rev1 NAMESPACE LITERAL+ MOVE ENABLE UTF8=ACCEPT SASL-IR LOGIN-REFERRALS AUTH=XOAUTH2 AUTH=OAUTHBEARER")?;
                 writeln!(stream, "{} OK CAPABILITY completed", tag)?;
             },
             
             "LOGIN" => {
+                // RFC 3501 6.2, only valid before authentication
+                if authenticated {
+                    writeln!(stream, "{} BAD Already authenticated", tag)?;
+                    continue;
+                }
                 if parts.len() < 3 {
                     writeln!(stream, "{} BAD Missing credentials", tag)?;
                     continue;
@@ -126,19 +695,267 @@ fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(),
                 let username = auth_parts[0].trim_matches('"');
                 let password = auth_parts[1].trim_matches('"');
                 
+                if !stream.allows_user(username) {
+                    warn!("Refusing LOGIN as {}, the client certificate belongs to another user", username);
+                    audit::record("IMAP", username, &AuditEvent::LoginFailed { client_address, reason: "client certificate" });
+                    writeln!(stream, "{} NO LOGIN failed", tag)?;
+                    continue;
+                }
+                
+                // The connection keeps the shared configuration until the login succeeds
+                let login_config = user_config(&user_overrides, &shared_config, username);
+                let lockout = LockoutSettings::from_config(&login_config);
+                if let Err(remaining) = login_guard.check(client_address, username, &lockout) {
+                    warn!("Refusing LOGIN as {} from {}, locked out for another {:?}", username, client_address, remaining);
+                    audit::record("IMAP", username, &AuditEvent::LoginFailed { client_address, reason: "locked out" });
+                    tokio::time::sleep(REFUSAL_DELAY).await;
+                    writeln!(stream, "{} NO [UNAVAILABLE] Too many failed logins, try again later", tag)?;
+                    continue;
+                }
+                let permit = match users.try_acquire(username, &UserLimits::from_config(&login_config)) {
+                    Ok(permit) => permit,
+                    Err(rejection) => {
+                        warn!("Refusing LOGIN as {}: {}", username, rejection);
+                        audit::record("IMAP", username, &AuditEvent::LoginFailed { client_address, reason: &rejection.to_string() });
+                        writeln!(stream, "{} NO [LIMIT] {}", tag, rejection)?;
+                        continue;
+                    }
+                };
+                
                 // Create Exchange client and authenticate
                 let credentials = Credentials::new(username.to_string(), password.to_string());
                 let exchange_url = config.get_string("davmail.url").unwrap_or_default();
                 
-                match ExchangeClient::new(&exchange_url, credentials) {
+                let uid_map = users.uid_map(username, &login_config);
+                let folder_cache = users.folder_cache(username, &login_config);
+                let new_session = |client| configure_session(client, &metadata_cache, &request_limiter, uid_map.clone(), folder_cache.clone(), &login_config, username);
+                
+                // Reuse a session this user opened recently with the same password
+                let session_key = SessionKey { username: username.to_string(), mode: login_mode(&login_config) };
+                let reused = session_cache.get(&session_key, password);
+                let new_login = reused.is_none();
+                let connected = if let Some(client) = reused {
+                    Ok(client)
+                } else if config.get_bool("davmail.enableNtlm").unwrap_or(false) {
+                    // NTLM needs its own connection, so it gets a dedicated client instead of the shared one
+                    let http_config = HttpClientConfig::from_config(&login_config);
+                    ExchangeClient::new_with_ntlm(&exchange_url, username, password, &http_config).await.map(new_session)
+                } else if let Some(broker) = broker_session(&login_config, username, password, &http_client).await.transpose() {
+                    match broker {
+                        // The broker keeps refresh tokens for EWS only
+                        Ok(oauth2_auth) => {
+                            let ews = async { connect_renewed(&token_manager, &exchange_url, oauth2_auth, &http_client).await.map(new_session) };
+                            mailstore::connect_oauth2(&login_config, ews, None, http_client.clone()).await
+                        },
+                        Err(e) => Err(e),
+                    }
+                } else if config.get_bool("davmail.oauth.ropc").unwrap_or(false) {
+                    // Basic auth is disabled on the server, trade the password for an OAuth token
+                    match OAuth2Config::for_user(&login_config, username).map(|oauth2_config| OAuth2Auth::new(oauth2_config, http_client.clone())) {
+                        Some(Ok(oauth2_auth)) => {
+                            let oauth2_auth = oauth2_auth.with_password_credentials(username, password);
+                            let graph_auth = mailstore::graph_auth(&login_config, username, &http_client)
+                                .map(|graph_auth| graph_auth.with_password_credentials(username, password));
+                            let ews = async { connect_renewed(&token_manager, &exchange_url, oauth2_auth, &http_client).await.map(new_session) };
+                            mailstore::connect_oauth2(&login_config, ews, graph_auth, http_client.clone()).await
+                        },
+                        Some(Err(e)) => Err(ExchangeError::ConfigError(e.to_string())),
+                        None => Err(ExchangeError::ConfigError("davmail.oauth.clientId is required for davmail.oauth.ropc".to_string())),
+                    }
+                } else {
+                    ExchangeClient::new_with_basic_auth(&exchange_url, credentials, http_client.clone()).await.map(new_session)
+                };
+                
+                match connected {
                     Ok(client) => {
-                        exchange_client = Some(client);
+                        let login = LoginInfo { protocol: "IMAP", username, client_address, offline: false };
+                        if let Err(reason) = hooks.login(&login).await {
+                            warn!("Refusing LOGIN as {}, rejected by a hook: {}", username, reason);
+                            audit::record("IMAP", username, &AuditEvent::LoginFailed { client_address, reason: "rejected by a hook" });
+                            writeln!(stream, "{} NO LOGIN failed", tag)?;
+                            continue;
+                        }
+                        login_guard.record_success(username);
+                        login_user = username.to_string();
+                        logformat::set_user(username);
+                        if new_login {
+                            remember_login(&metadata_cache, username, password);
+                        }
+                        session_cache.insert(session_key, password, client.clone());
+                        _user_permit = Some(permit);
+                        audit = Some(AuditSession::login("IMAP", username, client_address, false));
+                        mail_store = Some(Box::new(client));
                         authenticated = true;
+                        config = login_config;
+                        timeouts = set_keepalive_timeout(&mut stream, &config);
                         writeln!(stream, "{} OK LOGIN completed", tag)?;
                     },
                     Err(e) => {
+                        // A password that worked before opens the cached folders while Exchange is unreachable
+                        if e.is_unreachable() {
+                            if let Some(offline) = offline_login(&metadata_cache, username, password).await {
+                                let login = LoginInfo { protocol: "IMAP", username, client_address, offline: true };
+                                if let Err(reason) = hooks.login(&login).await {
+                                    warn!("Refusing offline LOGIN as {}, rejected by a hook: {}", username, reason);
+                                    audit::record("IMAP", username, &AuditEvent::LoginFailed { client_address, reason: "rejected by a hook" });
+                                    writeln!(stream, "{} NO LOGIN failed", tag)?;
+                                    continue;
+                                }
+                                login_user = username.to_string();
+                                warn!("Exchange is unreachable, {} logged in to the metadata cache in read-only mode", username);
+                                logformat::set_user(username);
+                                _user_permit = Some(permit);
+                                audit = Some(AuditSession::login("IMAP", username, client_address, true));
+                                mail_store = Some(Box::new(offline));
+                                authenticated = true;
+                                config = login_config;
+                        timeouts = set_keepalive_timeout(&mut stream, &config);
+                                writeln!(stream, "{} OK LOGIN completed, read-only until Exchange is reachable", tag)?;
+                                continue;
+                            }
+                        }
                         error!("Authentication failed: {}", e);
-                        writeln!(stream, "{} NO LOGIN failed", tag)?;
+                        if matches!(e, ExchangeError::AuthError(_)) {
+                            login_guard.record_failure(client_address, username, &lockout);
+                        }
+                        audit::record("IMAP", username, &AuditEvent::LoginFailed { client_address, reason: "authentication failed" });
+                        writeln!(stream, "{} {}", tag, e.imap_response("LOGIN failed"))?;
+                    }
+                }
+            },
+            
+            "AUTHENTICATE" => {
+                if authenticated {
+                    writeln!(stream, "{} BAD Already authenticated", tag)?;
+                    continue;
+                }
+                if parts.len() < 3 {
+                    writeln!(stream, "{} BAD Missing authentication mechanism", tag)?;
+                    continue;
+                }
+                
+                let auth_args: Vec<&str> = parts[2].splitn(2, ' ').collect();
+                let mechanism = auth_args[0].to_uppercase();
+                if mechanism != "XOAUTH2" && mechanism != "OAUTHBEARER" {
+                    writeln!(stream, "{} NO Unsupported authentication mechanism", tag)?;
+                    continue;
+                }
+                
+                // SASL-IR clients send the response inline, others get an empty continuation
+                let initial_response = match auth_args.get(1) {
+                    Some(response) => response.to_string(),
+                    None => {
+                        writeln!(stream, "+ ")?;
+                        let mut response = String::new();
+                        stream.read_secret_line(&mut response).await?;
+                        response.trim().to_string()
+                    }
+                };
+                
+                let credentials = match sasl::parse_bearer_response(&mechanism, &initial_response) {
+                    Ok(credentials) => credentials,
+                    Err(e) => {
+                        warn!("Invalid {} response: {}", mechanism, e);
+                        writeln!(stream, "{} BAD Invalid {} response", tag, mechanism)?;
+                        continue;
+                    }
+                };
+                
+                if !stream.allows_user(&credentials.username) {
+                    warn!("Refusing AUTHENTICATE as {}, the client certificate belongs to another user", credentials.username);
+                    audit::record("IMAP", &credentials.username, &AuditEvent::LoginFailed { client_address, reason: "client certificate" });
+                    writeln!(stream, "{} NO AUTHENTICATE failed", tag)?;
+                    continue;
+                }
+                
+                let login_config = user_config(&user_overrides, &shared_config, &credentials.username);
+                let lockout = LockoutSettings::from_config(&login_config);
+                if let Err(remaining) = login_guard.check(client_address, &credentials.username, &lockout) {
+                    warn!("Refusing AUTHENTICATE as {} from {}, locked out for another {:?}", credentials.username, client_address, remaining);
+                    audit::record("IMAP", &credentials.username, &AuditEvent::LoginFailed { client_address, reason: "locked out" });
+                    tokio::time::sleep(REFUSAL_DELAY).await;
+                    writeln!(stream, "{} NO [UNAVAILABLE] Too many failed logins, try again later", tag)?;
+                    continue;
+                }
+                let permit = match users.try_acquire(&credentials.username, &UserLimits::from_config(&login_config)) {
+                    Ok(permit) => permit,
+                    Err(rejection) => {
+                        warn!("Refusing AUTHENTICATE as {}: {}", credentials.username, rejection);
+                        audit::record("IMAP", &credentials.username, &AuditEvent::LoginFailed { client_address, reason: &rejection.to_string() });
+                        writeln!(stream, "{} NO [LIMIT] {}", tag, rejection)?;
+                        continue;
+                    }
+                };
+                let exchange_url = config.get_string("davmail.url").unwrap_or_default();
+                let uid_map = users.uid_map(&credentials.username, &login_config);
+                let folder_cache = users.folder_cache(&credentials.username, &login_config);
+                let new_session = |client| configure_session(client, &metadata_cache, &request_limiter, uid_map.clone(), folder_cache.clone(),
+                                                             &login_config, &credentials.username);
+                let on_behalf_of = config.get_bool("davmail.oauth.onBehalfOf").unwrap_or(false);
+                let session_key = SessionKey {
+                    username: credentials.username.clone(),
+                    mode: if on_behalf_of { "obo" } else { "bearer" },
+                };
+                // Everything kept per user is keyed by the name the client asserts, it must be the token's owner
+                let owner = sasl::token_owner(&credentials.access_token);
+                let connected = if !owner.as_ref().is_some_and(|owner| owner.eq_ignore_ascii_case(&credentials.username)) {
+                    Err(ExchangeError::AuthError(format!("The token of {} was presented as {}",
+                        owner.as_deref().unwrap_or("an unknown user"), credentials.username)))
+                } else if let Some(client) = session_cache.get(&session_key, &credentials.access_token) {
+                    Ok(client)
+                } else if on_behalf_of {
+                    // Token issued to a front-end service, exchanged for an EWS token
+                    match OAuth2Config::for_user(&login_config, &credentials.username).map(|oauth2_config| OAuth2Auth::new(oauth2_config, http_client.clone())) {
+                        Some(Ok(oauth2_auth)) => {
+                            let oauth2_auth = oauth2_auth.with_user_assertion(&credentials.username, &credentials.access_token);
+                            let graph_auth = mailstore::graph_auth(&login_config, &credentials.username, &http_client)
+                                .map(|graph_auth| graph_auth.with_user_assertion(&credentials.username, &credentials.access_token));
+                            let ews = async { connect_renewed(&token_manager, &exchange_url, oauth2_auth, &http_client).await.map(new_session) };
+                            mailstore::connect_oauth2(&login_config, ews, graph_auth, http_client.clone()).await
+                        },
+                        Some(Err(e)) => Err(ExchangeError::ConfigError(e.to_string())),
+                        None => Err(ExchangeError::ConfigError("davmail.oauth.clientId is required for davmail.oauth.onBehalfOf".to_string())),
+                    }
+                } else {
+                    // The client's token goes straight to EWS as bearer token
+                    ExchangeClient::new_with_access_token(&exchange_url, &credentials.access_token, http_client.clone()).await.map(new_session)
+                };
+                
+                match connected {
+                    Ok(client) => {
+                        let login = LoginInfo { protocol: "IMAP", username: &credentials.username, client_address, offline: false };
+                        if let Err(reason) = hooks.login(&login).await {
+                            warn!("Refusing AUTHENTICATE as {}, rejected by a hook: {}", credentials.username, reason);
+                            audit::record("IMAP", &credentials.username, &AuditEvent::LoginFailed { client_address, reason: "rejected by a hook" });
+                            writeln!(stream, "{} NO AUTHENTICATE failed", tag)?;
+                            continue;
+                        }
+                        info!("User {} authenticated with {}", credentials.username, mechanism);
+                        login_user = credentials.username.clone();
+                        login_guard.record_success(&credentials.username);
+                        logformat::set_user(&credentials.username);
+                        session_cache.insert(session_key, &credentials.access_token, client.clone());
+                        _user_permit = Some(permit);
+                        audit = Some(AuditSession::login("IMAP", &credentials.username, client_address, false));
+                        mail_store = Some(Box::new(client));
+                        authenticated = true;
+                        config = login_config;
+                        timeouts = set_keepalive_timeout(&mut stream, &config);
+                        writeln!(stream, "{} OK AUTHENTICATE completed", tag)?;
+                    },
+                    Err(e) => {
+                        error!("Authentication failed: {}", e);
+                        if matches!(e, ExchangeError::AuthError(_)) {
+                            login_guard.record_failure(client_address, &credentials.username, &lockout);
+                        }
+                        audit::record("IMAP", &credentials.username, &AuditEvent::LoginFailed { client_address, reason: "authentication failed" });
+                        if mechanism == "OAUTHBEARER" {
+                            // The client must answer the error challenge with a dummy response
+                            writeln!(stream, "+ {}", sasl::oauthbearer_error("https://outlook.office365.com/.default"))?;
+                            let mut dummy = String::new();
+                            stream.read_secret_line(&mut dummy).await?;
+                        }
+                        writeln!(stream, "{} {}", tag, e.imap_response("AUTHENTICATE failed"))?;
                     }
                 }
             },
@@ -156,21 +973,21 @@ fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(),
                     vec!["", ""]
                 };
                 
-                let reference = list_args.get(0).unwrap_or(&"").trim_matches('"');
-                let mailbox_pattern = list_args.get(1).unwrap_or(&"*").trim_matches('"');
+                let reference = mailbox_from_client(list_args.first().unwrap_or(&"").trim_matches('"'), utf8_accept);
+                let mailbox_pattern = mailbox_from_client(list_args.get(1).unwrap_or(&"*").trim_matches('"'), utf8_accept);
                 
                 // List mailboxes from Exchange
-                if let Some(client) = &exchange_client {
-                    match client.list_folders(reference, mailbox_pattern) {
+                if let Some(client) = &mail_store {
+                    match client.list_folders(&reference, &mailbox_pattern).await {
                         Ok(folders) => {
                             for folder in folders {
-                                writeln!(stream, "* LIST (\\HasNoChildren) \"/\" \"{}\"", folder)?;
+                                writeln!(stream, "* LIST (\\HasNoChildren) \"/\" \"{}\"", mailbox_to_client(&folder, utf8_accept))?;
                             }
                             writeln!(stream, "{} OK LIST completed", tag)?;
                         },
                         Err(e) => {
                             error!("LIST command failed: {}", e);
-                            writeln!(stream, "{} NO LIST failed", tag)?;
+                            writeln!(stream, "{} {}", tag, command_failed("LIST", &e))?;
                         }
                     }
                 } else {
@@ -178,6 +995,29 @@ fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(),
                 }
             },
             
+            "ENABLE" => {
+                if !authenticated {
+                    writeln!(stream, "{} NO Not authenticated", tag)?;
+                    continue;
+                }
+                
+                // RFC 5161, extensions this server doesn't know are left out of the ENABLED response
+                let requested = parts.get(2).copied().unwrap_or_default();
+                if requested.split_whitespace().any(|capability| capability.eq_ignore_ascii_case("UTF8=ACCEPT")) {
+                    utf8_accept = true;
+                    writeln!(stream, "* ENABLED UTF8=ACCEPT")?;
+                } else {
+                    writeln!(stream, "* ENABLED")?;
+                }
+                writeln!(stream, "{} OK ENABLE completed", tag)?;
+            },
+            
+            "NAMESPACE" => {
+                // Online archive folders live under their own prefix next to the personal mailbox
+                writeln!(stream, "* NAMESPACE ((\"\" \"/\")(\"{}\" \"/\")) NIL NIL", ARCHIVE_NAMESPACE)?;
+                writeln!(stream, "{} OK NAMESPACE completed", tag)?;
+            },
+            
             "SELECT" => {
                 if !authenticated {
                     writeln!(stream, "{} NO Not authenticated", tag)?;
@@ -189,25 +1029,46 @@ fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(),
                     continue;
                 }
                 
-                let mailbox = parts[2].trim_matches('"');
+                let mailbox = mailbox_from_client(parts[2].trim_matches('"'), utf8_accept);
+                let mailbox = mailbox.as_str();
                 
-                if let Some(client) = &exchange_client {
-                    match client.select_folder(mailbox) {
+                if let Some(client) = &mail_store {
+                    // Localized names of the well-known folders select the same folder as their English names
+                    let mailbox = client.canonical_folder_name(mailbox).await;
+                    let mailbox = mailbox.as_str();
+                    // Mail rules run before the folder is listed, so the client never sees what they move away
+                    let rules = Rules::from_config(&config);
+                    let folder_rules = rules.incoming(mailbox);
+                    if !folder_rules.is_empty() {
+                        let received_after = rule_watermark(&metadata_cache, &users, &login_user, mailbox);
+                        match client.apply_rules(mailbox, &folder_rules, received_after.as_deref()).await {
+                            Ok(RulesPass { watermark: Some(watermark), .. }) => set_rule_watermark(&metadata_cache, &users, &login_user, mailbox, &watermark),
+                            Ok(_) => {},
+                            Err(ExchangeError::Unsupported(_)) => debug!("Mail rules are not supported by this backend"),
+                            Err(e) => warn!("Mail rules on {} failed: {}", mailbox, e),
+                        }
+                    }
+                    match client.select_folder(mailbox).await {
                         Ok(stats) => {
                             selected_mailbox = Some(mailbox.to_string());
+                            all_deleted = false;
+                            if let Some(audit) = &audit {
+                                audit.record(&AuditEvent::Select { folder: mailbox });
+                            }
                             
                             writeln!(stream, "* {} EXISTS", stats.exists)?;
                             writeln!(stream, "* {} RECENT", stats.recent)?;
                             writeln!(stream, "* OK [UNSEEN {}] First unseen message", stats.unseen)?;
                             writeln!(stream, "* OK [UIDVALIDITY {}] UIDs valid", stats.uid_validity)?;
                             writeln!(stream, "* OK [UIDNEXT {}] Predicted next UID", stats.uid_next)?;
-                            writeln!(stream, "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)")?;
+                            writeln!(stream, "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft $MDNSent)")?;
                             writeln!(stream, "* OK [PERMANENTFLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft \\*)]")?;
-                            writeln!(stream, "{} OK [READ-WRITE] SELECT completed", tag)?;
+                            let access = if stats.read_only { "READ-ONLY" } else { "READ-WRITE" };
+                            writeln!(stream, "{} OK [{}] SELECT completed", tag, access)?;
                         },
                         Err(e) => {
                             error!("SELECT command failed: {}", e);
-                            writeln!(stream, "{} NO SELECT failed", tag)?;
+                            writeln!(stream, "{} {}", tag, command_failed("SELECT", &e))?;
                         }
                     }
                 } else {
@@ -241,17 +1102,293 @@ fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(),
                 let sequence_set = fetch_args[0];
                 let items = fetch_args[1];
                 
-                if let Some(client) = &exchange_client {
-                    match client.fetch_messages(selected_mailbox.as_ref().unwrap(), sequence_set, items) {
+                if let Some(client) = &mail_store {
+                    match client.fetch_messages(selected_mailbox.as_ref().unwrap(), sequence_set, items).await {
                         Ok(messages) => {
-                            for message in messages {
-                                writeln!(stream, "* {} FETCH {}", message.sequence, message.data)?;
+                            let context = MessageContext { protocol: "IMAP", username: &login_user, folder: selected_mailbox.as_deref() };
+                            for mut message in messages {
+                                hooks.incoming_message(&context, &mut message).await;
+                                write!(stream, "* {} FETCH (", message.sequence)?;
+                                for (index, item) in message.items.iter().enumerate() {
+                                    if index > 0 {
+                                        write!(stream, " ")?;
+                                    }
+                                    match item {
+                                        FetchItem::Value(value) => write!(stream, "{}", value)?,
+                                        FetchItem::Literal(name, section) => {
+                                            write!(stream, "{} {{{}}}\r\n", name, section.len())?;
+                                            stream.send_literal(section).await?;
+                                        },
+                                    }
+                                }
+                                writeln!(stream, ")")?;
                             }
                             writeln!(stream, "{} OK FETCH completed", tag)?;
                         },
                         Err(e) => {
                             error!("FETCH command failed: {}", e);
-                            writeln!(stream, "{} NO FETCH failed", tag)?;
+                            writeln!(stream, "{} {}", tag, command_failed("FETCH", &e))?;
+                        }
+                    }
+                } else {
+                    writeln!(stream, "{} NO Exchange client not initialized", tag)?;
+                }
+            },
+            
+            "SEARCH" => {
+                if !authenticated {
+                    writeln!(stream, "{} NO Not authenticated", tag)?;
+                    continue;
+                }
+                
+                let Some(mailbox) = &selected_mailbox else {
+                    writeln!(stream, "{} NO No mailbox selected", tag)?;
+                    continue;
+                };
+                
+                let criteria = parts.get(2).copied().unwrap_or("ALL");
+                // Strings are UTF-8 either way, the line was read as UTF-8
+                let criteria = match criteria.split_once(' ') {
+                    Some((keyword, rest)) if keyword.eq_ignore_ascii_case("CHARSET") => {
+                        let (charset, rest) = rest.split_once(' ').unwrap_or((rest, "ALL"));
+                        let charset = charset.trim_matches('"');
+                        if !charset.eq_ignore_ascii_case("UTF-8") && !charset.eq_ignore_ascii_case("US-ASCII") {
+                            writeln!(stream, "{} NO [BADCHARSET (US-ASCII UTF-8)] Charset not supported", tag)?;
+                            continue;
+                        }
+                        rest
+                    },
+                    _ => criteria,
+                };
+                
+                if let Some(client) = &mail_store {
+                    match client.search(mailbox, criteria).await {
+                        Ok(sequences) => {
+                            let sequences: Vec<String> = sequences.iter().map(u32::to_string).collect();
+                            if sequences.is_empty() {
+                                writeln!(stream, "* SEARCH")?;
+                            } else {
+                                writeln!(stream, "* SEARCH {}", sequences.join(" "))?;
+                            }
+                            writeln!(stream, "{} OK SEARCH completed", tag)?;
+                        },
+                        Err(e) => {
+                            error!("SEARCH command failed: {}", e);
+                            writeln!(stream, "{} {}", tag, command_failed("SEARCH", &e))?;
+                        }
+                    }
+                } else {
+                    writeln!(stream, "{} NO Exchange client not initialized", tag)?;
+                }
+            },
+            
+            "STATUS" => {
+                if !authenticated {
+                    writeln!(stream, "{} NO Not authenticated", tag)?;
+                    continue;
+                }
+                
+                // Mailbox name and the parenthesized list of status items
+                let status_args = match parts.get(2).and_then(|args| args.rsplit_once('(')) {
+                    Some((mailbox, items)) => (mailbox.trim().trim_matches('"'), items.trim_end_matches(')')),
+                    None => {
+                        writeln!(stream, "{} BAD Missing status arguments", tag)?;
+                        continue;
+                    }
+                };
+                let (mailbox, items) = status_args;
+                let mailbox = mailbox_from_client(mailbox, utf8_accept);
+                let mailbox = mailbox.as_str();
+                
+                if let Some(client) = &mail_store {
+                    match client.folder_status(&client.canonical_folder_name(mailbox).await).await {
+                        Ok(stats) => {
+                            let values: Vec<String> = items.split_whitespace()
+                                .filter_map(|item| {
+                                    let value = match item.to_uppercase().as_str() {
+                                        "MESSAGES" => stats.exists,
+                                        "RECENT" => stats.recent,
+                                        "UNSEEN" => stats.unseen,
+                                        "UIDNEXT" => stats.uid_next,
+                                        "UIDVALIDITY" => stats.uid_validity,
+                                        _ => return None,
+                                    };
+                                    Some(format!("{} {}", item.to_uppercase(), value))
+                                })
+                                .collect();
+                            writeln!(stream, "* STATUS \"{}\" ({})", mailbox_to_client(mailbox, utf8_accept), values.join(" "))?;
+                            writeln!(stream, "{} OK STATUS completed", tag)?;
+                        },
+                        Err(e) => {
+                            error!("STATUS command failed: {}", e);
+                            writeln!(stream, "{} {}", tag, command_failed("STATUS", &e))?;
+                        }
+                    }
+                } else {
+                    writeln!(stream, "{} NO Exchange client not initialized", tag)?;
+                }
+            },
+            
+            "STORE" => {
+                if !authenticated {
+                    writeln!(stream, "{} NO Not authenticated", tag)?;
+                    continue;
+                }
+                
+                if selected_mailbox.is_none() {
+                    writeln!(stream, "{} NO No mailbox selected", tag)?;
+                    continue;
+                }
+                
+                if parts.len() < 3 {
+                    writeln!(stream, "{} BAD Missing store arguments", tag)?;
+                    continue;
+                }
+                
+                let store_args = parts[2].splitn(3, ' ').collect::<Vec<&str>>();
+                if store_args.len() != 3 {
+                    writeln!(stream, "{} BAD Invalid store arguments", tag)?;
+                    continue;
+                }
+                
+                // \Seen only changes on the whole mailbox, as a single MarkAllItemsAsRead call. \Answered
+                // changes on any messages, as the replied state Outlook shows. $Junk and $NotJunk are
+                // junk reports, removing $NotJunk has nothing to report. $MDNSent after the client's own
+                // read receipt keeps Exchange from sending another, it can't be taken back. \Deleted is only
+                // kept for the whole of Trash, which EXPUNGE then empties.
+                let add = match store_args[1].to_uppercase().as_str() {
+                    "+FLAGS" | "+FLAGS.SILENT" => true,
+                    "-FLAGS" | "-FLAGS.SILENT" => false,
+                    _ => {
+                        writeln!(stream, "{} NO STORE not supported", tag)?;
+                        continue;
+                    }
+                };
+                let flags: Vec<&str> = store_args[2].trim_matches(|c| c == '(' || c == ')').split_whitespace().collect();
+                let has = |name: &str| flags.iter().any(|flag| flag.eq_ignore_ascii_case(name));
+                let seen = has("\\Seen");
+                let answered = has("\\Answered");
+                let mdn_sent = has("$MDNSent") && add;
+                let junk = if has("$Junk") { Some(add) } else if has("$NotJunk") && add { Some(false) } else { None };
+                let deleted = has("\\Deleted");
+                let supported = flags.iter().all(|flag| STORE_FLAGS.iter().any(|name| flag.eq_ignore_ascii_case(name)));
+                let in_trash = selected_mailbox.as_deref().and_then(distinguished_folder) == Some("deleteditems");
+                if flags.is_empty() || !supported || ((seen || deleted) && store_args[0] != "1:*") || (deleted && !in_trash) {
+                    writeln!(stream, "{} NO STORE not supported", tag)?;
+                    continue;
+                }
+                
+                if let Some(client) = &mail_store {
+                    let mailbox = selected_mailbox.as_ref().unwrap();
+                    let result = async {
+                        if seen {
+                            client.mark_all_read(mailbox, add).await?;
+                        }
+                        if answered {
+                            client.set_answered(mailbox, store_args[0], add).await?;
+                        }
+                        if let Some(junk) = junk {
+                            client.set_junk(mailbox, store_args[0], junk).await?;
+                        }
+                        if mdn_sent {
+                            client.set_mdn_sent(mailbox, store_args[0]).await?;
+                        }
+                        Ok::<_, ExchangeError>(())
+                    }.await;
+                    match result {
+                        Ok(_) => {
+                            if deleted {
+                                all_deleted = add;
+                            }
+                            writeln!(stream, "{} OK STORE completed", tag)?;
+                        },
+                        Err(e) => {
+                            error!("STORE command failed: {}", e);
+                            writeln!(stream, "{} {}", tag, command_failed("STORE", &e))?;
+                        }
+                    }
+                } else {
+                    writeln!(stream, "{} NO Exchange client not initialized", tag)?;
+                }
+            },
+            
+            "MOVE" => {
+                if !authenticated {
+                    writeln!(stream, "{} NO Not authenticated", tag)?;
+                    continue;
+                }
+                
+                let Some(mailbox) = selected_mailbox.clone() else {
+                    writeln!(stream, "{} NO No mailbox selected", tag)?;
+                    continue;
+                };
+                
+                let Some((sequence_set, target)) = parts.get(2).and_then(|arguments| arguments.split_once(' ')) else {
+                    writeln!(stream, "{} BAD Missing move arguments", tag)?;
+                    continue;
+                };
+                let target = mailbox_from_client(target.trim().trim_matches('"'), utf8_accept);
+                let target = target.as_str();
+                
+                // Into Junk it is a junk report, out of Junk a not junk report (see exchange/junk.rs)
+                if let Some(client) = &mail_store {
+                    let target = client.canonical_folder_name(target).await;
+                    match client.move_messages(&mailbox, sequence_set, &target).await {
+                        Ok(mut moved) => {
+                            // Moving to Deleted Items is how clients delete
+                            if let (Some(audit), Some("deleteditems")) = (&audit, distinguished_folder(&target)) {
+                                audit.record(&AuditEvent::Delete { folder: &mailbox, count: moved.len() });
+                            }
+                            // RFC 6851: an EXPUNGE for each moved message, from the highest number down so the others stay valid
+                            moved.sort_unstable_by(|a, b| b.cmp(a));
+                            for seq in moved {
+                                writeln!(stream, "* {} EXPUNGE", seq)?;
+                            }
+                            writeln!(stream, "{} OK MOVE completed", tag)?;
+                        },
+                        Err(e) => {
+                            error!("MOVE command failed: {}", e);
+                            writeln!(stream, "{} {}", tag, command_failed("MOVE", &e))?;
+                        }
+                    }
+                } else {
+                    writeln!(stream, "{} NO Exchange client not initialized", tag)?;
+                }
+            },
+            
+            "EXPUNGE" => {
+                if !authenticated {
+                    writeln!(stream, "{} NO Not authenticated", tag)?;
+                    continue;
+                }
+                
+                let mailbox = match &selected_mailbox {
+                    Some(mailbox) => mailbox.clone(),
+                    None => {
+                        writeln!(stream, "{} NO No mailbox selected", tag)?;
+                        continue;
+                    }
+                };
+                
+                // Expunging Trash after flagging all of it \Deleted empties Deleted Items in one EmptyFolder call,
+                // otherwise nothing was flagged and there is nothing to expunge
+                if !all_deleted || distinguished_folder(&mailbox) != Some("deleteditems") {
+                    writeln!(stream, "{} OK EXPUNGE completed", tag)?;
+                    continue;
+                }
+                
+                if let Some(client) = &mail_store {
+                    match client.empty_folder(&mailbox).await {
+                        Ok(_) => {
+                            all_deleted = false;
+                            if let Some(audit) = &audit {
+                                audit.record(&AuditEvent::EmptyFolder { folder: &mailbox });
+                            }
+                            writeln!(stream, "{} OK EXPUNGE completed", tag)?;
+                        },
+                        Err(e) => {
+                            error!("EXPUNGE command failed: {}", e);
+                            writeln!(stream, "{} {}", tag, command_failed("EXPUNGE", &e))?;
                         }
                     }
                 } else {
@@ -270,8 +1407,10 @@ fn handle_imap_client(mut stream: TcpStream, config: Arc<Config>) -> Result<(),
             }
         }
         
-        stream.flush()?;
+        stream.send().await?;
     }
     
+    // Deliver the goodbye of LOGOUT or of the autologout
+    stream.send().await?;
     Ok(())
 }
@@ -0,0 +1,106 @@
+// protocols/lockout.rs
+// Refuses logins for a while after repeated failures from one address or for one user
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use config::Config;
+use log::warn;
+
+// A client retrying with a stale password must not run into Exchange's own account lockout
+pub const DEFAULT_MAX_LOGIN_FAILURES: usize = 5;
+pub const DEFAULT_LOGIN_FAILURE_WINDOW: u64 = 300;
+pub const DEFAULT_LOGIN_LOCKOUT_TIME: u64 = 300;
+// Refused attempts are answered this late, so a client hammering LOGIN slows down
+pub const REFUSAL_DELAY: Duration = Duration::from_secs(2);
+
+// davmail.maxLoginFailures within davmail.loginFailureWindow seconds lock the address or user
+// out for davmail.loginLockoutTime seconds, a maximum of 0 turns the lockout off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutSettings {
+    pub max_failures: usize,
+    pub window: Duration,
+    pub lockout_time: Duration,
+}
+
+impl LockoutSettings {
+    pub fn from_config(config: &Config) -> Self {
+        LockoutSettings {
+            max_failures: config.get_int("davmail.maxLoginFailures").unwrap_or(DEFAULT_MAX_LOGIN_FAILURES as i64).max(0) as usize,
+            window: Duration::from_secs(config.get_int("davmail.loginFailureWindow").unwrap_or(DEFAULT_LOGIN_FAILURE_WINDOW as i64).max(0) as u64),
+            lockout_time: Duration::from_secs(config.get_int("davmail.loginLockoutTime").unwrap_or(DEFAULT_LOGIN_LOCKOUT_TIME as i64).max(0) as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Address(IpAddr),
+    // Lower case, clients don't agree on the case of the login name
+    User(String),
+}
+
+#[derive(Default)]
+struct Failures {
+    recent: Vec<Instant>,
+    locked_until: Option<Instant>,
+}
+
+// Shared by every listener, a user is locked out whichever protocol the failures came through
+#[derive(Default)]
+pub struct LoginGuard {
+    failures: Mutex<HashMap<Subject, Failures>>,
+}
+
+impl LoginGuard {
+    pub fn new() -> Self {
+        LoginGuard::default()
+    }
+
+    // Time left when the address or the user is locked out
+    pub fn check(&self, address: IpAddr, username: &str, settings: &LockoutSettings) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let remaining = [Subject::Address(address), Subject::User(username.to_lowercase())].iter()
+            .filter_map(|subject| failures.get(subject)?.locked_until)
+            .filter_map(|locked_until| locked_until.checked_duration_since(now))
+            .max();
+        // Entries whose lockout is over and whose failures left the window are forgotten lazily
+        failures.retain(|_, entry| entry.locked_until.is_some_and(|until| until > now)
+            || entry.recent.iter().any(|failure| now.duration_since(*failure) < settings.window));
+        match remaining {
+            Some(remaining) if !remaining.is_zero() => Err(remaining),
+            _ => Ok(()),
+        }
+    }
+
+    // Only failures where the server rejected the credentials count, not outages
+    pub fn record_failure(&self, address: IpAddr, username: &str, settings: &LockoutSettings) {
+        if settings.max_failures == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        for subject in [Subject::Address(address), Subject::User(username.to_lowercase())] {
+            let entry = failures.entry(subject.clone()).or_default();
+            entry.recent.retain(|failure| now.duration_since(*failure) < settings.window);
+            entry.recent.push(now);
+            if entry.recent.len() >= settings.max_failures {
+                entry.recent.clear();
+                entry.locked_until = Some(now + settings.lockout_time);
+                match subject {
+                    Subject::Address(address) => warn!("Locking out {} for {:?} after {} failed logins", address, settings.lockout_time, settings.max_failures),
+                    Subject::User(username) => warn!("Locking out user {} for {:?} after {} failed logins", username, settings.lockout_time, settings.max_failures),
+                }
+            }
+        }
+    }
+
+    // A successful login clears the user's failures, the address keeps its own so one valid
+    // account can't be used to keep guessing others
+    pub fn record_success(&self, username: &str) {
+        self.failures.lock().unwrap().remove(&Subject::User(username.to_lowercase()));
+    }
+}
@@ -0,0 +1,54 @@
+// protocols/shutdown.rs
+// Live client connections of a server, given time to say goodbye when it shuts down
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use config::Config;
+use log::{info, warn};
+use tokio::task::JoinSet;
+
+// Long enough for a FETCH in progress to finish, short enough not to hold up a service restart
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 10;
+
+// davmail.shutdownGracePeriod in seconds
+pub fn grace_period(config: &Config) -> Duration {
+    let seconds = config.get_int("davmail.shutdownGracePeriod").unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD as i64).max(0) as u64;
+    Duration::from_secs(seconds)
+}
+
+// Connection tasks of a server. They watch the same shutdown signal as the listener and close
+// themselves once the current command is answered.
+#[derive(Default)]
+pub struct ConnectionTracker {
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        ConnectionTracker::default()
+    }
+
+    pub fn spawn(&self, connection: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        // Forget connections that already ended
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(connection);
+    }
+
+    // Wait up to `grace_period` for the connections to close, then drop the ones still running
+    pub async fn close_all(&self, protocol: &str, grace_period: Duration) {
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        if tasks.is_empty() {
+            return;
+        }
+        info!("Waiting up to {:?} for {} {} connections to close", grace_period, tasks.len(), protocol);
+        let closed = tokio::time::timeout(grace_period, async {
+            while tasks.join_next().await.is_some() {}
+        }).await;
+        if closed.is_err() {
+            warn!("Dropping {} {} connections still busy after {:?}", tasks.len(), protocol, grace_period);
+            tasks.shutdown().await;
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// protocols/timeouts.rs
+// How long a protocol connection may stay silent before it is closed
+
+use std::io;
+use std::time::Duration;
+use config::Config;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+// Longest time a client may take to write the rest of a command it started
+pub const DEFAULT_READ_TIMEOUT: u64 = 120;
+// Longest time a blocked write to the client may take
+pub const DEFAULT_WRITE_TIMEOUT: u64 = 60;
+
+// Autologout timers of the protocol RFCs, IMAP clients legitimately sit idle for long
+fn default_idle_timeout(protocol: &str) -> u64 {
+    match protocol {
+        "imap" => 1800,
+        "pop" => 600,
+        "smtp" => 300,
+        _ => 300,
+    }
+}
+
+// davmail.<protocol>IdleTimeout, davmail.<protocol>ReadTimeout and davmail.<protocol>WriteTimeout
+// in seconds, falling back to davmail.idleTimeout and so on, 0 disables a timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionTimeouts {
+    // Between two commands
+    pub idle: Option<Duration>,
+    // Within a command
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+impl ConnectionTimeouts {
+    pub fn from_config(config: &Config, protocol: &str) -> Self {
+        let timeout = |name: &str, default: u64| {
+            let seconds = config.get_int(&format!("davmail.{}{}Timeout", protocol, name))
+                .or_else(|_| config.get_int(&format!("davmail.{}Timeout", name.to_lowercase())))
+                .map(|seconds| seconds.max(0) as u64)
+                .unwrap_or(default);
+            Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero())
+        };
+        ConnectionTimeouts {
+            idle: timeout("Idle", default_idle_timeout(protocol)),
+            read: timeout("Read", DEFAULT_READ_TIMEOUT),
+            write: timeout("Write", DEFAULT_WRITE_TIMEOUT),
+        }
+    }
+
+    // Whether a connection silent for `silent` has to be closed, `in_command` when part of a command was received
+    pub fn expired(&self, silent: Duration, in_command: bool) -> bool {
+        let limit = if in_command { self.read } else { self.idle };
+        limit.is_some_and(|limit| silent >= limit)
+    }
+}
+
+// TCP keepalive probes keep NAT and VPN gateways from dropping connections that sit idle, for
+// instance an IMAP client waiting for new mail, and detect clients that vanished
+pub const DEFAULT_TCP_KEEPALIVE_TIME: u64 = 60;
+pub const DEFAULT_TCP_KEEPALIVE_INTERVAL: u64 = 15;
+pub const DEFAULT_TCP_KEEPALIVE_PROBES: u32 = 4;
+
+// davmail.tcpKeepAlive (default true), davmail.tcpKeepAliveTime seconds of silence before the first
+// probe, davmail.tcpKeepAliveInterval seconds between probes and davmail.tcpKeepAliveProbes
+// unanswered probes before the connection is dropped. Platforms without a setting ignore it.
+pub fn set_tcp_keepalive(socket: &TcpStream, config: &Config) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    if !config.get_bool("davmail.tcpKeepAlive").unwrap_or(true) {
+        return socket.set_keepalive(false);
+    }
+
+    let seconds = |key: &str, default: u64| Duration::from_secs(config.get_int(key).map(|value| value.max(1) as u64).unwrap_or(default));
+    let keepalive = TcpKeepalive::new().with_time(seconds("davmail.tcpKeepAliveTime", DEFAULT_TCP_KEEPALIVE_TIME));
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "netbsd", target_os = "windows"))]
+    let keepalive = keepalive.with_interval(seconds("davmail.tcpKeepAliveInterval", DEFAULT_TCP_KEEPALIVE_INTERVAL));
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "netbsd"))]
+    let keepalive = keepalive.with_retries(
+        config.get_int("davmail.tcpKeepAliveProbes").map(|value| value.max(1) as u32).unwrap_or(DEFAULT_TCP_KEEPALIVE_PROBES));
+
+    socket.set_tcp_keepalive(&keepalive)
+}
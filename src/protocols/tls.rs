@@ -0,0 +1,389 @@
+// protocols/tls.rs
+// Implicit TLS for the protocol listeners (IMAPS, POP3S, SMTPS, HTTPS, LDAPS)
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use chrono::{Datelike, Utc};
+use config::Config;
+use log::{debug, info};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+// A client that connects and sends nothing must not hold a connection forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Generated when TLS is on without a certificate, reused on later starts
+pub const DEFAULT_SELF_SIGNED_FILE: &str = "davmail-selfsigned.pem";
+// Names the generated certificate is valid for unless davmail.ssl.hostnames lists others
+const DEFAULT_HOSTNAMES: &str = "localhost,127.0.0.1,::1";
+// Apple clients refuse server certificates valid for longer
+const SELF_SIGNED_VALIDITY_DAYS: i64 = 825;
+
+// Listeners of several profiles may start at once, only one of them generates the certificate
+static SELF_SIGNED_LOCK: Mutex<()> = Mutex::new(());
+
+// Subject attributes a client certificate can be mapped to a user with
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_EMAIL_ADDRESS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01];
+
+// Certificate settings, DavMail's davmail.ssl.keystore* keys plus PEM files:
+// - davmail.ssl.certFile and davmail.ssl.keyFile: PEM certificate chain and private key,
+//   keyFile can be left out when certFile holds the key too
+// - davmail.ssl.keystoreFile with davmail.ssl.keystoreType PKCS12 (default) or PEM,
+//   davmail.ssl.keystorePass (or davmail.ssl.keyPass) unlocks a PKCS#12 file
+// - neither: a self-signed certificate for davmail.ssl.hostnames, kept in davmail.ssl.selfSignedFile
+// Client certificates (mutual TLS) are checked against the CA bundle in davmail.ssl.clientCaFile,
+// davmail.ssl.needClientAuth makes them mandatory instead of optional
+pub fn certificate_configured(config: &Config) -> bool {
+    config.get_string("davmail.ssl.certFile").is_ok_and(|path| !path.is_empty())
+        || config.get_string("davmail.ssl.keystoreFile").is_ok_and(|path| !path.is_empty())
+}
+
+// davmail.<protocol>Ssl, listeners use TLS by default once a certificate is configured, as in DavMail.
+// Turning it on without one makes the listener use a generated self-signed certificate.
+pub fn enabled(config: &Config, protocol: &str) -> bool {
+    config.get_bool(&format!("davmail.{}Ssl", protocol)).unwrap_or_else(|_| certificate_configured(config))
+}
+
+// Server side TLS settings, loaded once per listener
+pub struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+    // Subject attribute naming the user of a client certificate (davmail.ssl.clientUserAttribute)
+    client_user_attribute: ClientUserAttribute,
+}
+
+// Which subject attribute holds the login name, email falls back to the common name when missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientUserAttribute {
+    Email,
+    CommonName,
+}
+
+impl TlsAcceptor {
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let (certificates, key) = if let Ok(cert_file) = config.get_string("davmail.ssl.certFile") {
+            let key_file = config.get_string("davmail.ssl.keyFile").unwrap_or_else(|_| cert_file.clone());
+            (read_pem_certificates(&cert_file)?, read_pem_key(&key_file)?)
+        } else if let Ok(keystore_file) = config.get_string("davmail.ssl.keystoreFile") {
+            let keystore_type = config.get_string("davmail.ssl.keystoreType").unwrap_or_else(|_| "PKCS12".to_string());
+            match keystore_type.to_uppercase().as_str() {
+                "PEM" => (read_pem_certificates(&keystore_file)?, read_pem_key(&keystore_file)?),
+                "PKCS12" => {
+                    let password = config.get_string("davmail.ssl.keystorePass")
+                        .or_else(|_| config.get_string("davmail.ssl.keyPass"))
+                        .unwrap_or_default();
+                    read_pkcs12(&keystore_file, &password)?
+                },
+                other => return Err(invalid_data(format!("Unsupported davmail.ssl.keystoreType {}, use PKCS12 or PEM", other))),
+            }
+        } else {
+            self_signed(config)?
+        };
+
+        // ring is the provider reqwest already builds rustls with
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid_data(e.to_string()))?;
+
+        let need_client_auth = config.get_bool("davmail.ssl.needClientAuth").unwrap_or(false);
+        let builder = match config.get_string("davmail.ssl.clientCaFile") {
+            Ok(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in read_pem_certificates(&ca_file)? {
+                    roots.add(certificate).map_err(|e| invalid_data(format!("Invalid CA certificate in {}: {}", ca_file, e)))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if need_client_auth { verifier } else { verifier.allow_unauthenticated() };
+                let verifier = verifier.build().map_err(|e| invalid_data(format!("Invalid client CA bundle {}: {}", ca_file, e)))?;
+                builder.with_client_cert_verifier(verifier)
+            },
+            Err(_) if need_client_auth => {
+                return Err(invalid_data("davmail.ssl.needClientAuth requires davmail.ssl.clientCaFile".to_string()));
+            },
+            Err(_) => builder.with_no_client_auth(),
+        };
+        let server_config = builder
+            .with_single_cert(certificates, key)
+            .map_err(|e| invalid_data(format!("Invalid TLS certificate or key: {}", e)))?;
+
+        let client_user_attribute = match config.get_string("davmail.ssl.clientUserAttribute").unwrap_or_default().to_lowercase().as_str() {
+            "" | "email" => ClientUserAttribute::Email,
+            "cn" => ClientUserAttribute::CommonName,
+            other => return Err(invalid_data(format!("Unsupported davmail.ssl.clientUserAttribute {}, use email or cn", other))),
+        };
+
+        Ok(TlsAcceptor { acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)), client_user_attribute })
+    }
+
+    // Complete the handshake, giving up on clients that don't finish it in time
+    pub async fn accept(&self, socket: TcpStream) -> io::Result<ClientStream> {
+        let peer_address = socket.peer_addr()?;
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(socket)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        let (_, connection) = stream.get_ref();
+        debug!("TLS handshake with {} completed ({:?})", peer_address, connection.protocol_version());
+
+        // The verifier has already checked the chain, only the user name is left to read
+        let client_user = connection.peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(|certificate| self.client_user(certificate));
+        if let Some(client_user) = &client_user {
+            info!("Client {} presented a certificate for {}", peer_address, client_user);
+        }
+
+        Ok(ClientStream::Tls { stream: Box::new(stream), client_user })
+    }
+
+    fn client_user(&self, certificate: &CertificateDer) -> Option<String> {
+        let attributes = subject_attributes(certificate.as_ref())?;
+        let find = |oid: &[u8]| attributes.iter().find(|(attribute, _)| *attribute == oid).map(|(_, value)| value.clone());
+        match self.client_user_attribute {
+            ClientUserAttribute::Email => find(OID_EMAIL_ADDRESS).or_else(|| find(OID_COMMON_NAME)),
+            ClientUserAttribute::CommonName => find(OID_COMMON_NAME),
+        }
+    }
+}
+
+// Connection of a protocol client, plain or TLS
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls {
+        stream: Box<TlsStream<TcpStream>>,
+        // User named by the client certificate, logins as anyone else are refused
+        client_user: Option<String>,
+    },
+}
+
+impl ClientStream {
+    // A login is allowed when the client sent no certificate or the certificate names the same user
+    pub fn allows_user(&self, username: &str) -> bool {
+        match self {
+            ClientStream::Tls { client_user: Some(client_user), .. } => client_user.eq_ignore_ascii_case(username),
+            _ => true,
+        }
+    }
+
+    // Underlying socket, for socket options such as keepalive
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls { stream, .. } => stream.get_ref().0,
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls { stream, .. } => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls { stream, .. } => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls { stream, .. } => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls { stream, .. } => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+// Load the generated certificate, creating it on first start, and log its fingerprint for users to
+// compare with what their mail client shows before trusting it
+fn self_signed(config: &Config) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let _guard = SELF_SIGNED_LOCK.lock().unwrap();
+    let path = config.get_string("davmail.ssl.selfSignedFile").unwrap_or_else(|_| DEFAULT_SELF_SIGNED_FILE.to_string());
+
+    if !Path::new(&path).is_file() {
+        let hostnames: Vec<String> = config.get_string("davmail.ssl.hostnames")
+            .unwrap_or_else(|_| DEFAULT_HOSTNAMES.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|hostname| !hostname.is_empty())
+            .map(String::from)
+            .collect();
+        generate_self_signed(&path, hostnames)?;
+    }
+
+    let certificates = read_pem_certificates(&path)?;
+    let key = read_pem_key(&path)?;
+    info!("Using self-signed certificate {}, SHA-256 fingerprint {}", path, fingerprint(&certificates[0]));
+    Ok((certificates, key))
+}
+
+fn generate_self_signed(path: &str, hostnames: Vec<String>) -> io::Result<()> {
+    let to_io = |e: rcgen::Error| invalid_data(format!("Cannot generate a self-signed certificate: {}", e));
+    let mut params = rcgen::CertificateParams::new(hostnames.clone()).map_err(to_io)?;
+    let mut subject = rcgen::DistinguishedName::new();
+    subject.push(rcgen::DnType::CommonName, hostnames.first().map(String::as_str).unwrap_or("localhost"));
+    subject.push(rcgen::DnType::OrganizationName, "DavMail Rust");
+    params.distinguished_name = subject;
+
+    let today = Utc::now().date_naive();
+    let expiry = today + chrono::Duration::days(SELF_SIGNED_VALIDITY_DAYS);
+    params.not_before = rcgen::date_time_ymd(today.year(), today.month() as u8, today.day() as u8);
+    params.not_after = rcgen::date_time_ymd(expiry.year(), expiry.month() as u8, expiry.day() as u8);
+
+    let key_pair = rcgen::KeyPair::generate().map_err(to_io)?;
+    let certificate = params.self_signed(&key_pair).map_err(to_io)?;
+
+    // Written to a temporary file first so an interrupted start never leaves half a certificate behind
+    let temporary = format!("{}.tmp", path);
+    let mut file = private_file(&temporary)?;
+    file.write_all(certificate.pem().as_bytes())?;
+    file.write_all(key_pair.serialize_pem().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+
+    info!("Generated a self-signed certificate for {} in {}, valid until {}, delete it to generate a new one",
+        hostnames.join(", "), path, expiry);
+    Ok(())
+}
+
+// The file holds the private key, only the owner may read it
+#[cfg(unix)]
+fn private_file(path: &str) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &str) -> io::Result<File> {
+    File::create(path)
+}
+
+// Colon separated SHA-256, the form certificate dialogs display
+fn fingerprint(certificate: &CertificateDer) -> String {
+    ring::digest::digest(&ring::digest::SHA256, certificate.as_ref()).as_ref().iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Next DER element as (tag, contents, rest)
+fn der_next(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, remaining) = rest.split_at(count);
+        rest = remaining;
+        bytes.iter().fold(0, |length, &byte| (length << 8) | byte as usize)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(length);
+    Some((tag, contents, rest))
+}
+
+// Subject of an X.509 certificate as (attribute OID, value) pairs
+fn subject_attributes(certificate: &[u8]) -> Option<Vec<(&[u8], String)>> {
+    let (_, certificate, _) = der_next(certificate)?;
+    let (_, mut tbs_certificate, _) = der_next(certificate)?;
+    // Optional [0] version, then serial number, signature algorithm, issuer and validity come before the subject
+    if tbs_certificate.first() == Some(&0xa0) {
+        tbs_certificate = der_next(tbs_certificate)?.2;
+    }
+    for _ in 0..4 {
+        tbs_certificate = der_next(tbs_certificate)?.2;
+    }
+    let (_, mut subject, _) = der_next(tbs_certificate)?;
+
+    let mut attributes = Vec::new();
+    while !subject.is_empty() {
+        let (_, mut relative_name, rest) = der_next(subject)?;
+        subject = rest;
+        while !relative_name.is_empty() {
+            let (_, attribute, rest) = der_next(relative_name)?;
+            relative_name = rest;
+            let (_, oid, value) = der_next(attribute)?;
+            let (_, value, _) = der_next(value)?;
+            attributes.push((oid, String::from_utf8_lossy(value).into_owned()));
+        }
+    }
+    Some(attributes)
+}
+
+fn read_pem_certificates(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certificates.is_empty() {
+        return Err(invalid_data(format!("No certificate found in {}", path)));
+    }
+    Ok(certificates)
+}
+
+fn read_pem_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_data(format!("No private key found in {}", path)))
+}
+
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+fn read_pkcs12(path: &str, password: &str) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    let mut der = Vec::new();
+    open(path)?.read_to_end(&mut der)?;
+    let keystore = openssl::pkcs12::Pkcs12::from_der(&der)
+        .and_then(|pkcs12| pkcs12.parse2(password))
+        .map_err(|e| invalid_data(format!("Cannot read PKCS#12 keystore {}: {}", path, e)))?;
+
+    let missing = |what: &str| invalid_data(format!("No {} found in {}", what, path));
+    let certificate = keystore.cert.ok_or_else(|| missing("certificate"))?;
+    let key = keystore.pkey.ok_or_else(|| missing("private key"))?;
+
+    let to_io = |e: openssl::error::ErrorStack| invalid_data(format!("Invalid PKCS#12 keystore {}: {}", path, e));
+    let mut certificates = vec![CertificateDer::from(certificate.to_der().map_err(to_io)?)];
+    for intermediate in keystore.ca.into_iter().flatten() {
+        certificates.push(CertificateDer::from(intermediate.to_der().map_err(to_io)?));
+    }
+    let key = PrivatePkcs8KeyDer::from(key.private_key_to_pkcs8().map_err(to_io)?);
+    Ok((certificates, PrivateKeyDer::Pkcs8(key)))
+}
+
+#[cfg(any(target_os = "windows", target_vendor = "apple"))]
+fn read_pkcs12(path: &str, _password: &str) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    Err(invalid_data(format!(
+        "PKCS#12 keystores are not supported on this platform, convert {} to PEM and set davmail.ssl.certFile and davmail.ssl.keyFile", path)))
+}
+
+fn open(path: &str) -> io::Result<File> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("Cannot open {}: {}", path, e)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,72 @@
+// protocols/utf7.rs
+// Modified UTF-7 mailbox names (RFC 3501 5.1.3), for IMAP clients that haven't enabled UTF8=ACCEPT
+
+use base64::alphabet::IMAP_MUTF7;
+use base64::engine::general_purpose::NO_PAD;
+use base64::engine::GeneralPurpose;
+use base64::Engine;
+
+const MUTF7: GeneralPurpose = GeneralPurpose::new(&IMAP_MUTF7, NO_PAD);
+
+// Printable ASCII other than & stands for itself
+fn is_direct(c: char) -> bool {
+    (' '..='~').contains(&c) && c != '&'
+}
+
+pub fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    let mut pending: Vec<u16> = Vec::new();
+    let flush = |pending: &mut Vec<u16>, encoded: &mut String| {
+        if !pending.is_empty() {
+            let bytes: Vec<u8> = pending.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+            encoded.push('&');
+            encoded.push_str(&MUTF7.encode(bytes));
+            encoded.push('-');
+            pending.clear();
+        }
+    };
+    for c in name.chars() {
+        if is_direct(c) {
+            flush(&mut pending, &mut encoded);
+            encoded.push(c);
+        } else if c == '&' {
+            flush(&mut pending, &mut encoded);
+            encoded.push_str("&-");
+        } else {
+            let mut units = [0; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut units));
+        }
+    }
+    flush(&mut pending, &mut encoded);
+    encoded
+}
+
+// None when the name isn't valid modified UTF-7, e.g. a UTF-8 name or a stray &
+pub fn decode(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        if !rest[..start].chars().all(is_direct) {
+            return None;
+        }
+        decoded.push_str(&rest[..start]);
+        let end = start + 1 + rest[start + 1..].find('-')?;
+        let shifted = &rest[start + 1..end];
+        if shifted.is_empty() {
+            decoded.push('&');
+        } else {
+            let bytes = MUTF7.decode(shifted).ok()?;
+            if bytes.len() % 2 != 0 {
+                return None;
+            }
+            let units: Vec<u16> = bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+            decoded.push_str(&String::from_utf16(&units).ok()?);
+        }
+        rest = &rest[end + 1..];
+    }
+    if !rest.chars().all(is_direct) {
+        return None;
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
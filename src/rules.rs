@@ -0,0 +1,108 @@
+// rules.rs
+// Mail rules evaluated by the gateway: davmail.rules lists the rules, each configured under davmail.rule.<name>.*
+
+use config::Config;
+use log::warn;
+
+#[derive(Debug, Clone, Default)]
+pub struct RuleActions {
+    pub move_to: Option<String>,
+    pub mark_read: bool,
+    pub category: Option<String>,
+    // The item goes to Deleted Items
+    pub drop: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    // Case-insensitive substrings, of the sender name or address and of the subject
+    from: Option<String>,
+    subject: Option<String>,
+    // IMAP mailbox name, INBOX by default
+    folder: String,
+    pub actions: RuleActions,
+}
+
+impl Rule {
+    // davmail.rule.<name>.from / subject / folder, and the actions moveTo, markRead, category and drop
+    fn from_config(config: &Config, name: &str) -> Result<Rule, String> {
+        let setting = |key: &str| config.get_string(&format!("davmail.rule.{}.{}", name, key)).ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+        let flag = |key: &str| config.get_bool(&format!("davmail.rule.{}.{}", name, key)).unwrap_or(false);
+
+        let from = setting("from").map(|from| from.to_lowercase());
+        let subject = setting("subject").map(|subject| subject.to_lowercase());
+        // A rule without conditions would apply to every message
+        if from.is_none() && subject.is_none() {
+            return Err("from or subject is required".to_string());
+        }
+        let actions = RuleActions {
+            move_to: setting("moveTo"),
+            mark_read: flag("markRead"),
+            category: setting("category"),
+            drop: flag("drop"),
+        };
+        if actions.drop && actions.move_to.is_some() {
+            return Err("drop and moveTo can't be combined".to_string());
+        }
+        if !actions.drop && actions.move_to.is_none() && !actions.mark_read && actions.category.is_none() {
+            return Err("no action, set moveTo, markRead, category or drop".to_string());
+        }
+        Ok(Rule {
+            name: name.to_string(),
+            from,
+            subject,
+            folder: setting("folder").unwrap_or_else(|| "INBOX".to_string()),
+            actions,
+        })
+    }
+
+    // Every condition that is set must match
+    pub fn matches(&self, from: &str, subject: &str) -> bool {
+        self.from.as_ref().is_none_or(|needle| from.to_lowercase().contains(needle))
+            && self.subject.as_ref().is_none_or(|needle| subject.to_lowercase().contains(needle))
+    }
+}
+
+// Rules in the order of davmail.rules, the first one matching a message is applied
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    // Invalid rules are logged and left out, the others still apply
+    pub fn from_config(config: &Config) -> Rules {
+        let names = config.get_string("davmail.rules").unwrap_or_default();
+        let rules = names.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| Rule::from_config(config, name)
+                .map_err(|e| warn!("Ignoring mail rule {}: {}", name, e))
+                .ok())
+            .collect::<Vec<Rule>>();
+        Rules { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Rules watching the folder
+    pub fn incoming(&self, folder: &str) -> Vec<&Rule> {
+        self.rules.iter()
+            .filter(|rule| rule.folder.eq_ignore_ascii_case(folder))
+            .collect()
+    }
+}
+
+// What a pass of the incoming rules over a folder did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RulesPass {
+    // Items a rule changed
+    pub applied: usize,
+    // DateTimeReceived of the newest item looked at, the next pass only looks at items received later
+    pub watermark: Option<String>,
+}
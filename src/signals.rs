@@ -0,0 +1,81 @@
+// signals.rs
+// SIGINT and SIGTERM shut the gateway down gracefully, SIGHUP reloads the configuration and reopens the log files
+
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Terminate,
+    Reload,
+}
+
+#[cfg(unix)]
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+// Block the signals so that only the thread started by listen() receives them. Threads inherit the
+// mask, so this must run before any other thread is started.
+#[cfg(unix)]
+pub fn block() -> io::Result<()> {
+    let set = signal_set();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+// Call `handler` from a dedicated thread for each signal. A second termination signal exits
+// at once, for a shutdown stuck waiting on connections.
+#[cfg(unix)]
+pub fn listen(handler: impl Fn(Signal) + Send + 'static) -> io::Result<()> {
+    use log::{info, warn};
+
+    std::thread::Builder::new().name("signals".to_string()).spawn(move || {
+        let set = signal_set();
+        let mut terminating = false;
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                continue;
+            }
+            match signal {
+                libc::SIGHUP => {
+                    info!("Received SIGHUP, reloading the configuration");
+                    handler(Signal::Reload);
+                },
+                _ if terminating => {
+                    warn!("Received a second termination signal, exiting immediately");
+                    std::process::exit(1);
+                },
+                _ => {
+                    info!("Received {}, shutting down", if signal == libc::SIGTERM { "SIGTERM" } else { "SIGINT" });
+                    terminating = true;
+                    handler(Signal::Terminate);
+                },
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn block() -> io::Result<()> {
+    Ok(())
+}
+
+// Ctrl-C and console close on Windows, there is no reload signal
+#[cfg(not(unix))]
+pub fn listen(handler: impl Fn(Signal) + Send + 'static) -> io::Result<()> {
+    ctrlc::set_handler(move || {
+        log::info!("Received termination signal");
+        handler(Signal::Terminate);
+    }).map_err(io::Error::other)
+}
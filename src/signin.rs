@@ -0,0 +1,80 @@
+// signin.rs
+// Exchange sign-in for the command line tools (check, import), the way an IMAP login would do it
+
+use std::error::Error;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+use config::Config;
+use reqwest::Client;
+
+use davmail_core::auth::{Credentials, OAuth2Auth, OAuth2Client, OAuth2Config, TokenStore};
+use davmail_core::exchange::http::HttpClientConfig;
+use davmail_core::exchange::ExchangeClient;
+
+// How a command line tool signs in, chosen like an IMAP login of the user would be
+pub enum Login {
+    // A token saved with --token or --import-refresh-token
+    StoredToken(Box<OAuth2Auth>, String),
+    Password(String),
+}
+
+// Saved token of the user, or the password from standard input
+pub fn login_for(config: &Config, username: &str) -> Result<Login, Box<dyn Error>> {
+    if let Some(login) = stored_token(config, username)? {
+        return Ok(login);
+    }
+    if std::io::stdin().is_terminal() {
+        eprint!("Password for {} (echoed, or pipe it to standard input): ", username);
+    }
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(format!("No password for {} on standard input", username).into());
+    }
+    Ok(Login::Password(password.to_string()))
+}
+
+fn stored_token(config: &Config, username: &str) -> Result<Option<Login>, Box<dyn Error>> {
+    let (Some(oauth2_config), Ok(token_file)) = (OAuth2Config::for_user(config, username), config.get_string("davmail.oauth.tokenFile")) else {
+        return Ok(None);
+    };
+    let token_store = TokenStore::open(&token_file)?;
+    if token_store.get(&OAuth2Client::token_key_for(&oauth2_config, username)).is_none() {
+        return Ok(None);
+    }
+    let http_client = HttpClientConfig::from_config(config).build()?;
+    let oauth2_auth = OAuth2Auth::new(oauth2_config, http_client)?
+        .with_token_store(Arc::new(Mutex::new(token_store)), username);
+    Ok(Some(Login::StoredToken(Box::new(oauth2_auth), token_file)))
+}
+
+// Same choice of method as an IMAP LOGIN, returns the client and how it signed in
+pub async fn authenticate(config: &Config, base_url: &str, http_config: &HttpClientConfig, client: &Client, username: &str, login: Login)
+    -> Result<(ExchangeClient, String), String> {
+    let password = match login {
+        Login::StoredToken(oauth2_auth, token_file) => {
+            let exchange_client = ExchangeClient::new_with_oauth2_auth(base_url, *oauth2_auth, client.clone()).await
+                .map_err(|e| format!("OAuth2 token from {}: {}", token_file, e))?;
+            return Ok((exchange_client, format!("OAuth2 token from {}", token_file)));
+        },
+        Login::Password(password) => password,
+    };
+
+    if config.get_bool("davmail.enableNtlm").unwrap_or(false) {
+        let exchange_client = ExchangeClient::new_with_ntlm(base_url, username, &password, http_config).await.map_err(|e| e.to_string())?;
+        return Ok((exchange_client, "NTLM".to_string()));
+    }
+    if config.get_bool("davmail.oauth.ropc").unwrap_or(false) {
+        let oauth2_config = OAuth2Config::for_user(config, username)
+            .ok_or("davmail.oauth.clientId is required for davmail.oauth.ropc")?;
+        let oauth2_auth = OAuth2Auth::new(oauth2_config, client.clone()).map_err(|e| e.to_string())?
+            .with_password_credentials(username, &password);
+        let exchange_client = ExchangeClient::new_with_oauth2_auth(base_url, oauth2_auth, client.clone()).await
+            .map_err(|e| format!("OAuth2 password grant: {}", e))?;
+        return Ok((exchange_client, "OAuth2 password grant".to_string()));
+    }
+    let credentials = Credentials::new(username.to_string(), password);
+    let exchange_client = ExchangeClient::new_with_basic_auth(base_url, credentials, client.clone()).await.map_err(|e| e.to_string())?;
+    Ok((exchange_client, "Basic authentication".to_string()))
+}
@@ -0,0 +1,166 @@
+// syslog.rs
+// Copies log records to the local syslog daemon or a remote syslog server, next to the stderr or file output
+
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use chrono::Utc;
+use config::Config;
+use log::{error, info, Level, Log, Metadata, Record};
+
+const APP_NAME: &str = "gatewayrs563";
+// Longer messages are truncated, many syslog daemons drop bigger datagrams
+const MAX_MESSAGE_LEN: usize = 2048;
+
+static SYSLOG: Mutex<Option<Syslog>> = Mutex::new(None);
+
+enum Target {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Remote(UdpSocket),
+}
+
+struct Syslog {
+    target: Target,
+    facility: u8,
+    hostname: String,
+}
+
+// env_logger, whose filter decides for every output, the syslog copy and the dashboard's recent errors
+struct DaemonLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for DaemonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        send(record);
+        crate::admin::remember(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Replaces env_logger::Builder::init
+pub fn init(logger: env_logger::Logger) -> Result<(), log::SetLoggerError> {
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(DaemonLogger { inner: logger }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+// davmail.syslog, "local" for the daemon behind /dev/log or host[:port] for a remote server over UDP,
+// davmail.syslogFacility one of daemon (the default), mail, user or local0 to local7
+pub fn configure(config: &Config) {
+    let target = config.get_string("davmail.syslog").ok().filter(|target| !target.trim().is_empty());
+    let Some(target) = target else {
+        *SYSLOG.lock().unwrap() = None;
+        return;
+    };
+    let target = target.trim();
+
+    let facility = match config.get_string("davmail.syslogFacility") {
+        Ok(name) => facility(&name).unwrap_or_else(|| {
+            error!("Unknown davmail.syslogFacility value '{}'", name);
+            facility("daemon").unwrap()
+        }),
+        Err(_) => facility("daemon").unwrap(),
+    };
+
+    let connected = if target.eq_ignore_ascii_case("local") {
+        connect_local()
+    } else {
+        let address = if target.contains(':') && !target.ends_with(']') { target.to_string() } else { format!("{}:514", target) };
+        UdpSocket::bind(if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })
+            .and_then(|socket| socket.connect(&address).map(|_| Target::Remote(socket)))
+    };
+    match connected {
+        Ok(connected) => {
+            *SYSLOG.lock().unwrap() = Some(Syslog { target: connected, facility, hostname: hostname() });
+            info!("Logging to syslog {}", target);
+        },
+        Err(e) => {
+            *SYSLOG.lock().unwrap() = None;
+            error!("Failed to connect to syslog {}: {}", target, e);
+        },
+    }
+}
+
+#[cfg(unix)]
+fn connect_local() -> std::io::Result<Target> {
+    let socket = UnixDatagram::unbound()?;
+    // /var/run/syslog on macOS
+    socket.connect("/dev/log").or_else(|_| socket.connect("/var/run/syslog"))?;
+    Ok(Target::Local(socket))
+}
+
+#[cfg(not(unix))]
+fn connect_local() -> std::io::Result<Target> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "no local syslog daemon on this platform, use host[:port]"))
+}
+
+fn facility(name: &str) -> Option<u8> {
+    let name = name.trim().to_lowercase();
+    match name.as_str() {
+        "user" => Some(1),
+        "mail" => Some(2),
+        "daemon" => Some(3),
+        _ => name.strip_prefix("local")
+            .and_then(|index| index.parse::<u8>().ok())
+            .filter(|index| *index <= 7)
+            .map(|index| 16 + index),
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn send(record: &Record) {
+    let syslog = SYSLOG.lock().unwrap();
+    let Some(syslog) = syslog.as_ref() else {
+        return;
+    };
+    let priority = syslog.facility * 8 + severity(record.level());
+    let mut message = format!("{}: {}", record.target(), record.args());
+    if message.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    // Errors are not logged, that would come back here
+    let _ = match &syslog.target {
+        // The local daemon takes the traditional BSD format and adds the hostname itself
+        #[cfg(unix)]
+        Target::Local(socket) => socket.send(format!("<{}>{} {}[{}]: {}", priority, chrono::Local::now().format("%b %e %H:%M:%S"),
+            APP_NAME, std::process::id(), message).as_bytes()),
+        Target::Remote(socket) => socket.send(format!("<{}>1 {} {} {} {} - - {}", priority, Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            syslog.hostname, APP_NAME, std::process::id(), message).as_bytes()),
+    };
+}
@@ -0,0 +1,115 @@
+// systemd.rs
+// Running as a Type=notify service: readiness and watchdog notifications, and listening sockets passed by socket activation
+
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::time::Duration;
+use log::{debug, info, warn};
+
+// First file descriptor passed by socket activation, see sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+static INHERITED: Mutex<Vec<TcpListener>> = Mutex::new(Vec::new());
+
+// Take the sockets systemd passed to this process, before anything else opens files
+pub fn init() {
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+
+        let for_us = std::env::var("LISTEN_PID").ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == std::process::id());
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok()).unwrap_or(0);
+        // Child processes must not take them for their own
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+        if !for_us || count <= 0 {
+            return;
+        }
+
+        let mut inherited = INHERITED.lock().unwrap();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            // Only TCP listening sockets are expected, with ListenStream= in the socket unit
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(address) => {
+                    debug!("Socket activation passed a listener on {}", address);
+                    inherited.push(listener);
+                },
+                Err(e) => warn!("Ignoring file descriptor {} passed by systemd: {}", fd, e),
+            }
+        }
+    }
+}
+
+// Sockets passed by systemd listening on `port`, copies so a restarted listener can use them again
+pub fn listeners(port: u16) -> Vec<TcpListener> {
+    INHERITED.lock().unwrap().iter()
+        .filter(|listener| listener.local_addr().is_ok_and(|address| address.port() == port))
+        .filter_map(|listener| listener.try_clone().ok())
+        .collect()
+}
+
+// sd_notify(3), does nothing unless started by systemd with NotifyAccess
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        let sent = UnixDatagram::unbound().and_then(|socket| {
+            let path = path.to_string_lossy();
+            match path.strip_prefix('@') {
+                // Abstract socket namespace
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+                    socket.send_to_addr(state.as_bytes(), &address)
+                },
+                _ => socket.send_to(state.as_bytes(), path.as_ref()),
+            }
+        });
+        if let Err(e) = sent {
+            debug!("Failed to notify systemd of {}: {}", state.trim(), e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+pub fn ready() {
+    notify("READY=1\nSTATUS=Running");
+}
+
+// Sent before a configuration reload, followed by ready() once it is applied
+pub fn reloading() {
+    notify("RELOADING=1\nSTATUS=Reloading configuration");
+}
+
+pub fn stopping() {
+    notify("STOPPING=1\nSTATUS=Shutting down");
+}
+
+// Half of WatchdogSec=, None when the service has no watchdog
+pub fn watchdog_interval() -> Option<Duration> {
+    let for_us = std::env::var("WATCHDOG_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == std::process::id());
+    let microseconds = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    if !for_us {
+        return None;
+    }
+    let interval = Duration::from_micros(microseconds / 2);
+    info!("Sending systemd watchdog keep-alives every {:?}", interval);
+    Some(interval)
+}
+
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
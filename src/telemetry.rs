@@ -0,0 +1,281 @@
+// telemetry.rs
+// Optional OpenTelemetry traces, a span per client command with the EWS calls it made as children,
+// exported in batches to an OTLP/HTTP collector
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use config::Config;
+use log::{debug, info, warn};
+use reqwest::header::CONTENT_TYPE;
+use ring::rand::{SecureRandom, SystemRandom};
+use crate::logformat::push_json_string;
+
+pub const DEFAULT_SERVICE_NAME: &str = "gatewayrs563";
+// Spans finished beyond this many waiting for export are dropped
+const QUEUE_SIZE: usize = 4096;
+const BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static EXPORTER: Mutex<Option<SyncSender<FinishedSpan>>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+tokio::task_local! {
+    // The command span of the connection task, parent of the EWS spans started while it runs
+    static CURRENT: RefCell<Option<SpanContext>>;
+}
+
+#[derive(Clone, Copy)]
+enum SpanKind {
+    Server = 2,
+    Client = 3,
+}
+
+struct FinishedSpan {
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+struct ActiveSpan {
+    span: FinishedSpan,
+    started: Instant,
+    // Current span before this one was entered, restored when it ends
+    previous: Option<Option<SpanContext>>,
+}
+
+// Ends and queues the span when dropped, does nothing while tracing is off
+pub struct Span {
+    active: Option<ActiveSpan>,
+}
+
+// davmail.otlpEndpoint, the collector's OTLP/HTTP address (http://collector:4318), and
+// davmail.otlpServiceName, service.name of the exported spans
+pub fn configure(config: &Config) {
+    let endpoint = config.get_string("davmail.otlpEndpoint").ok().filter(|endpoint| !endpoint.trim().is_empty());
+    let Some(endpoint) = endpoint else {
+        ENABLED.store(false, Ordering::Relaxed);
+        // Dropping the sender lets the exporter send what it has and stop
+        *EXPORTER.lock().unwrap() = None;
+        return;
+    };
+    let endpoint = endpoint.trim().trim_end_matches('/');
+    let url = if endpoint.ends_with("/v1/traces") { endpoint.to_string() } else { format!("{}/v1/traces", endpoint) };
+    let service_name = config.get_string("davmail.otlpServiceName").unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string());
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let exporter_url = url.clone();
+    let spawned = std::thread::Builder::new()
+        .name("otlp-exporter".to_string())
+        .spawn(move || export(receiver, exporter_url, service_name));
+    match spawned {
+        Ok(_) => {
+            *EXPORTER.lock().unwrap() = Some(sender);
+            ENABLED.store(true, Ordering::Relaxed);
+            info!("Exporting traces to {}", url);
+        },
+        Err(e) => warn!("Failed to start the trace exporter: {}", e),
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Run a client connection with its own current span
+pub fn scope<F: Future>(connection: F) -> impl Future<Output = F::Output> {
+    CURRENT.scope(RefCell::new(None), connection)
+}
+
+impl Span {
+    // A client command, started as a new trace and current until it ends
+    pub fn command(protocol: &str, command: &str) -> Span {
+        if !enabled() {
+            return Span { active: None };
+        }
+        let context = SpanContext { trace_id: random_id(), span_id: random_id() };
+        let previous = CURRENT.try_with(|current| current.replace(Some(context))).ok();
+        Span::start(context, None, format!("{} {}", protocol, command), SpanKind::Server, previous)
+    }
+
+    // A call to a backend, child of the current command span if there is one
+    pub fn call(service: &str, operation: &str) -> Span {
+        if !enabled() {
+            return Span { active: None };
+        }
+        let parent = CURRENT.try_with(|current| *current.borrow()).ok().flatten();
+        let context = SpanContext {
+            trace_id: parent.map_or_else(random_id, |parent| parent.trace_id),
+            span_id: random_id(),
+        };
+        Span::start(context, parent.map(|parent| parent.span_id), format!("{} {}", service, operation), SpanKind::Client, None)
+    }
+
+    fn start(context: SpanContext, parent: Option<[u8; 8]>, name: String, kind: SpanKind, previous: Option<Option<SpanContext>>) -> Span {
+        let span = FinishedSpan { context, parent, name, kind, start: unix_nanos(), end: 0, attributes: Vec::new(), error: None };
+        Span { active: Some(ActiveSpan { span, started: Instant::now(), previous }) }
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(active) = &mut self.active {
+            active.span.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn set_error(&mut self, error: impl ToString) {
+        if let Some(active) = &mut self.active {
+            active.span.error = Some(error.to_string());
+        }
+    }
+
+    // Pass a result through, marking the span failed if it is an error
+    pub fn record<T, E: std::fmt::Display>(&mut self, result: Result<T, E>) -> Result<T, E> {
+        if let Err(e) = &result {
+            self.set_error(e);
+        }
+        result
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(ActiveSpan { mut span, started, previous }) = self.active.take() else {
+            return;
+        };
+        if let Some(previous) = previous {
+            let _ = CURRENT.try_with(|current| current.replace(previous));
+        }
+        span.end = span.start + started.elapsed().as_nanos() as u64;
+        if let Some(exporter) = EXPORTER.lock().unwrap().as_ref() {
+            if let Err(TrySendError::Full(_)) = exporter.try_send(span) {
+                debug!("Trace export queue full, dropping a span");
+            }
+        }
+    }
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0u8; N];
+    let _ = SystemRandom::new().fill(&mut id);
+    id
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+// Batches spans until BATCH_SIZE or EXPORT_INTERVAL, until the sender is dropped
+fn export(receiver: Receiver<FinishedSpan>, url: String, service_name: String) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            warn!("Failed to start the trace exporter: {}", e);
+            return;
+        }
+    };
+    let client = reqwest::Client::new();
+    let mut batch = Vec::new();
+    let mut failing = false;
+    let mut next_export = Instant::now() + EXPORT_INTERVAL;
+    loop {
+        let disconnected = match receiver.recv_timeout(next_export.saturating_duration_since(Instant::now())) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if !batch.is_empty() && (disconnected || batch.len() >= BATCH_SIZE || Instant::now() >= next_export) {
+            let body = encode(&batch, &service_name);
+            let sent = runtime.block_on(client.post(&url).header(CONTENT_TYPE, "application/json").body(body).send());
+            match sent.and_then(|response| response.error_for_status()) {
+                Ok(_) => failing = false,
+                // Once per outage, not for every batch
+                Err(e) if !failing => {
+                    warn!("Failed to export {} spans to {}: {}", batch.len(), url, e);
+                    failing = true;
+                },
+                Err(_) => {},
+            }
+            batch.clear();
+        }
+        if Instant::now() >= next_export {
+            next_export = Instant::now() + EXPORT_INTERVAL;
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+// OTLP/HTTP JSON encoding of an ExportTraceServiceRequest
+fn encode(spans: &[FinishedSpan], service_name: &str) -> String {
+    let mut body = String::with_capacity(256 * spans.len());
+    body.push_str("{\"resourceSpans\":[{\"resource\":{\"attributes\":[");
+    push_attribute(&mut body, "service.name", service_name);
+    body.push_str("]},\"scopeSpans\":[{\"scope\":{\"name\":");
+    push_json_string(&mut body, DEFAULT_SERVICE_NAME);
+    body.push_str("},\"spans\":[");
+    for (index, span) in spans.iter().enumerate() {
+        if index > 0 {
+            body.push(',');
+        }
+        let _ = write!(body, "{{\"traceId\":\"{}\",\"spanId\":\"{}\",", hex(&span.context.trace_id), hex(&span.context.span_id));
+        if let Some(parent) = &span.parent {
+            let _ = write!(body, "\"parentSpanId\":\"{}\",", hex(parent));
+        }
+        body.push_str("\"name\":");
+        push_json_string(&mut body, &span.name);
+        let _ = write!(body, ",\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+            span.kind as u8, span.start, span.end);
+        for (index, (key, value)) in span.attributes.iter().enumerate() {
+            if index > 0 {
+                body.push(',');
+            }
+            push_attribute(&mut body, key, value);
+        }
+        body.push_str("],\"status\":");
+        match &span.error {
+            Some(error) => {
+                body.push_str("{\"code\":2,\"message\":");
+                push_json_string(&mut body, error);
+                body.push('}');
+            },
+            None => body.push_str("{\"code\":1}"),
+        }
+        body.push('}');
+    }
+    body.push_str("]}]}]}");
+    body
+}
+
+fn push_attribute(body: &mut String, key: &str, value: &str) {
+    body.push_str("{\"key\":");
+    push_json_string(body, key);
+    body.push_str(",\"value\":{\"stringValue\":");
+    push_json_string(body, value);
+    body.push_str("}}");
+}
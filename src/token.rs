@@ -0,0 +1,144 @@
+// token.rs
+// davmail-rust token login|refresh|show|clear: manage davmail.oauth.tokenFile without starting the servers
+
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use config::Config;
+use log::info;
+use tokio::runtime::Runtime;
+use zeroize::Zeroizing;
+
+use davmail_core::auth::{OAuth2Auth, OAuth2Client, OAuth2Config, OAuth2Token, SharedTokenStore, TokenKey, TokenStore};
+use davmail_core::exchange::http::HttpClientConfig;
+
+use crate::cli::TokenAction;
+
+pub fn run(config: &Config, action: &TokenAction) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        TokenAction::Login { user, device_code } => login(config, user, *device_code),
+        TokenAction::Refresh { user } => refresh(config, user),
+        TokenAction::Show { user } => show(config, user.as_deref()),
+        TokenAction::Clear { user, all } => clear(config, user.as_deref(), *all),
+    }
+}
+
+fn token_file(config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(config.get_string("davmail.oauth.tokenFile").map_err(|_| "davmail.oauth.tokenFile is not configured")?)
+}
+
+// Registration the gateway uses for the user, with the token file behind it
+fn oauth2_auth(config: &Config, username: &str) -> Result<(OAuth2Auth, SharedTokenStore), Box<dyn std::error::Error>> {
+    let oauth2_config = OAuth2Config::for_user(config, username)
+        .ok_or("davmail.oauth.clientId is not configured")?;
+    let token_store = Arc::new(Mutex::new(TokenStore::open(token_file(config)?)?));
+    let http_client = HttpClientConfig::from_config(config).build()?;
+    let oauth2_auth = OAuth2Auth::new(oauth2_config, http_client)?
+        .with_token_store(token_store.clone(), username);
+    Ok((oauth2_auth, token_store))
+}
+
+// Sign in through the browser, or with a code entered on another device, and keep the tokens in the token file
+pub fn login(config: &Config, username: &str, device_code: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut oauth2_auth, _) = oauth2_auth(config, username)?;
+    let runtime = Runtime::new()?;
+    let token = if device_code {
+        runtime.block_on(oauth2_auth.async_acquire_token_device_code(|code| println!("{}", code.message)))?
+    } else {
+        runtime.block_on(oauth2_auth.async_acquire_token_interactive())?
+    };
+
+    info!("Saved OAuth2 tokens for {} into {}", username, token_file(config)?);
+    print_token(username, &token);
+    Ok(())
+}
+
+// Redeem the saved refresh token, which also proves it still works
+fn refresh(config: &Config, username: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut oauth2_auth, token_store) = oauth2_auth(config, username)?;
+    if token_store.lock().unwrap().get(&oauth2_auth.token_key()).is_none() {
+        return Err(format!("No saved token for {}, sign in with: token login {}", username, username).into());
+    }
+    let token = Runtime::new()?.block_on(oauth2_auth.async_renew_token())?;
+    print_token(username, &token);
+    Ok(())
+}
+
+// Only refresh tokens are saved, expiry and scopes are known once one is redeemed
+fn show(config: &Config, username: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let token_file = token_file(config)?;
+    let token_store = TokenStore::open(&token_file)?;
+    let mut keys: Vec<&TokenKey> = token_store.keys()
+        .filter(|key| username.is_none_or(|username| key.username.eq_ignore_ascii_case(username)))
+        .collect();
+    keys.sort_by(|a, b| (&a.username, &a.tenant_id, &a.client_id).cmp(&(&b.username, &b.tenant_id, &b.client_id)));
+
+    if keys.is_empty() {
+        println!("No saved tokens{} in {}", username.map(|username| format!(" for {}", username)).unwrap_or_default(), token_file);
+        return Ok(());
+    }
+    for key in keys {
+        let name = if key.username.is_empty() { "(application)" } else { key.username.as_str() };
+        // Tokens of another tenant or application are left over from an earlier configuration
+        let in_use = OAuth2Config::for_user(config, &key.username)
+            .is_some_and(|oauth2_config| OAuth2Client::token_key_for(&oauth2_config, &key.username) == *key);
+        println!("{}  tenant {}  client {}  refresh token saved{}", name, key.tenant_id, key.client_id,
+            if in_use { "" } else { ", not used by the current configuration" });
+    }
+    Ok(())
+}
+
+// Forget the tokens of a user, e.g. before signing in with another account
+fn clear(config: &Config, username: Option<&str>, all: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if username.is_none() && !all {
+        return Err("Name the user whose tokens to clear, or pass --all".into());
+    }
+    let token_file = token_file(config)?;
+    let mut token_store = TokenStore::open(&token_file)?;
+    let keys: Vec<TokenKey> = token_store.keys()
+        .filter(|key| username.is_none_or(|username| key.username.eq_ignore_ascii_case(username)))
+        .cloned()
+        .collect();
+    for key in &keys {
+        token_store.remove(key);
+    }
+    token_store.save()?;
+    println!("Removed {} token(s) from {}", keys.len(), token_file);
+    Ok(())
+}
+
+fn print_token(username: &str, token: &OAuth2Token) {
+    let minutes = token.expires_at.duration_since(SystemTime::now()).unwrap_or_default().as_secs() / 60;
+    println!("Access token for {} valid for {} more minute(s)", username, minutes);
+    println!("Scopes: {}", token.scope.as_deref().unwrap_or("(not reported)"));
+    println!("Refresh token: {}", if token.refresh_token.is_some() { "saved" } else { "none, add offline_access to davmail.oauth.scope" });
+}
+
+// Refresh token obtained elsewhere, for tenants whose policies block every flow the gateway can run
+pub fn import_refresh_token(config: &Config, username: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let oauth2_config = OAuth2Config::for_user(config, username)
+        .ok_or("davmail.oauth.clientId is not configured")?;
+    let token_file = token_file(config)?;
+
+    // The refresh token on the first line, the password the user will log in to the gateway with on the second
+    let mut refresh_token = String::new();
+    std::io::stdin().read_line(&mut refresh_token)?;
+    let refresh_token = refresh_token.trim();
+    if refresh_token.is_empty() {
+        return Err("No refresh token on standard input".into());
+    }
+    let mut password = Zeroizing::new(String::new());
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("No gateway password for the user on the second line of standard input".into());
+    }
+
+    let key = OAuth2Client::token_key_for(&oauth2_config, username);
+    let mut token_store = TokenStore::open(&token_file)?;
+    token_store.import_refresh_token(key.clone(), refresh_token);
+    token_store.set_password(&key, password)?;
+    token_store.save()?;
+
+    info!("Imported refresh token for {} into {}", username, token_file);
+    Ok(())
+}
@@ -0,0 +1,185 @@
+// tray.rs
+// System tray icon for desktop installs (cargo feature "tray"): gateway status, admin page, signing in again and quit
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use log::{error, info, warn};
+use tray_icon::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+use tray_icon::{Icon, TrayIcon, TrayIconBuilder};
+
+use davmail_core::auth::oauth2::open_browser;
+
+const TOOLTIP: &str = "gatewayrs563";
+const ICON_SIZE: u32 = 32;
+// How often the tray thread handles platform and menu events
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Menu choices the main thread carries out, opening the admin page is handled by the tray itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayAction {
+    SignIn,
+    Quit,
+}
+
+// Removes the icon when dropped
+pub struct Tray {
+    status: Sender<String>,
+}
+
+impl Tray {
+    // Shown as the tooltip and the first, disabled, menu item
+    pub fn set_status(&self, status: String) {
+        let _ = self.status.send(status);
+    }
+}
+
+// Run the icon on its own thread, `admin_url` enables the admin page item. None on macOS, where
+// the icon would need the main thread, or when the desktop has no tray.
+pub fn start(admin_url: Option<String>, actions: impl Fn(TrayAction) + Send + 'static) -> Option<Tray> {
+    if cfg!(target_os = "macos") {
+        warn!("The tray icon is not supported on macOS, running without it");
+        return None;
+    }
+    let (status, statuses) = mpsc::channel();
+    let (started, result) = mpsc::channel();
+    let spawned = std::thread::Builder::new().name("tray".to_string()).spawn(move || {
+        if let Err(e) = init_platform() {
+            let _ = started.send(Err(e));
+            return;
+        }
+        match TrayMenu::new(admin_url.is_some()) {
+            Ok(menu) => {
+                let _ = started.send(Ok(()));
+                menu.run(admin_url, statuses, actions);
+            },
+            Err(e) => {
+                let _ = started.send(Err(e));
+            },
+        }
+    });
+    if let Err(e) = spawned {
+        error!("Failed to start the tray icon: {}", e);
+        return None;
+    }
+    match result.recv() {
+        Ok(Ok(())) => {
+            info!("Tray icon started");
+            Some(Tray { status })
+        },
+        Ok(Err(e)) => {
+            warn!("Running without a tray icon: {}", e);
+            None
+        },
+        Err(_) => None,
+    }
+}
+
+struct TrayMenu {
+    // Kept alive for as long as the icon is shown
+    icon: TrayIcon,
+    status: MenuItem,
+    open_admin: MenuItem,
+    sign_in: MenuItem,
+    quit: MenuItem,
+}
+
+impl TrayMenu {
+    fn new(admin_page: bool) -> Result<Self, String> {
+        let status = MenuItem::new("Starting", false, None);
+        let open_admin = MenuItem::new("Open admin page", admin_page, None);
+        let sign_in = MenuItem::new("Sign in again", true, None);
+        let quit = MenuItem::new("Quit", true, None);
+        let menu = Menu::new();
+        menu.append_items(&[&status, &PredefinedMenuItem::separator(), &open_admin, &sign_in, &PredefinedMenuItem::separator(), &quit])
+            .map_err(|e| e.to_string())?;
+
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip(TOOLTIP)
+            .with_icon(icon()?)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(TrayMenu { icon, status, open_admin, sign_in, quit })
+    }
+
+    fn run(self, admin_url: Option<String>, statuses: Receiver<String>, actions: impl Fn(TrayAction)) {
+        loop {
+            pump_platform_events();
+
+            while let Ok(event) = MenuEvent::receiver().try_recv() {
+                if event.id == *self.open_admin.id() {
+                    if let Some(url) = &admin_url {
+                        open_browser(url, "the admin page");
+                    }
+                } else if event.id == *self.sign_in.id() {
+                    actions(TrayAction::SignIn);
+                } else if event.id == *self.quit.id() {
+                    actions(TrayAction::Quit);
+                }
+            }
+
+            loop {
+                match statuses.try_recv() {
+                    Ok(status) => {
+                        self.status.set_text(&status);
+                        let _ = self.icon.set_tooltip(Some(format!("{}: {}", TOOLTIP, status)));
+                    },
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    // The Tray handle was dropped, the gateway is shutting down
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+// A filled blue disc, so no image file has to be shipped
+fn icon() -> Result<Icon, String> {
+    let center = (ICON_SIZE as f32 - 1.0) / 2.0;
+    let radius = ICON_SIZE as f32 / 2.0 - 1.0;
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt();
+            let alpha = if distance <= radius { 255 } else { 0 };
+            rgba.extend_from_slice(&[0x1e, 0x64, 0xc8, alpha]);
+        }
+    }
+    Icon::from_rgba(rgba, ICON_SIZE, ICON_SIZE).map_err(|e| e.to_string())
+}
+
+// The icon lives in a GTK main loop on Linux and a window message loop on Windows, both
+// tied to the thread that created it
+#[cfg(target_os = "linux")]
+fn init_platform() -> Result<(), String> {
+    gtk::init().map_err(|e| e.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn init_platform() -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn pump_platform_events() {
+    while gtk::events_pending() {
+        gtk::main_iteration_do(false);
+    }
+}
+
+#[cfg(windows)]
+fn pump_platform_events() {
+    use windows_sys::Win32::UI::WindowsAndMessaging::{DispatchMessageW, PeekMessageW, TranslateMessage, MSG, PM_REMOVE};
+
+    let mut message: MSG = unsafe { std::mem::zeroed() };
+    while unsafe { PeekMessageW(&mut message, std::ptr::null_mut(), 0, 0, PM_REMOVE) } != 0 {
+        unsafe {
+            TranslateMessage(&message);
+            DispatchMessageW(&message);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn pump_platform_events() {}
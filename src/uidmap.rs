@@ -0,0 +1,215 @@
+// uidmap.rs
+// Persistent mapping of Exchange item ids to IMAP UIDs
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{debug, warn};
+
+#[derive(Debug, Default)]
+struct FolderUids {
+    uid_next: u32,
+    uids: HashMap<String, u32>,
+    // Replaces the map's seed once the folder used up every UID and started over
+    seed: Option<u32>,
+}
+
+// UIDs are assigned once per (folder, item) and never reused, so they stay valid across sessions.
+// Keys must be stable ids (see ExchangeClient::convert_ids), raw EWS ids change representation.
+// Folders are keyed by their EWS FolderId once selected, so a renamed folder keeps its UIDs.
+#[derive(Debug, Default)]
+pub struct UidMap {
+    path: Option<PathBuf>,
+    // Mixed into every UIDVALIDITY, a map started over gives its folders new ones
+    seed: u32,
+    // FolderId of each folder name selected, folders never selected are keyed by name
+    folder_ids: HashMap<String, String>,
+    folders: HashMap<String, FolderUids>,
+    dirty: bool,
+}
+
+// UIDVALIDITY of a folder, a hash of its FolderId that doesn't depend on the name or case it is selected
+// with. FNV-1a, which unlike the std hashers is guaranteed to give the same value in every release.
+pub fn uid_validity(seed: u32, folder_id: &str) -> u32 {
+    let hash = seed.to_le_bytes().iter().chain(folder_id.as_bytes())
+        .fold(0x811c_9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193));
+    hash.max(1)
+}
+
+// Seconds since the epoch, a map started again later never reuses the seed of the one it replaces
+fn new_seed() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_secs() as u32)
+}
+
+impl UidMap {
+    pub fn in_memory() -> Self {
+        UidMap { seed: new_seed(), ..UidMap::default() }
+    }
+
+    // Load the map from a tab separated file (folder, item, uid), a missing file is an empty map. Lines
+    // starting with a tab hold the seed, the seed of folders that started over and the FolderId of
+    // each folder name.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut map = UidMap {
+            path: Some(path.as_ref().to_path_buf()),
+            ..UidMap::default()
+        };
+
+        let file = match File::open(path.as_ref()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                map.seed = new_seed();
+                map.dirty = true;
+                return Ok(map);
+            },
+            Err(e) => return Err(e),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["", "seed", seed] => match seed.parse::<u32>() {
+                    Ok(seed) => map.seed = seed,
+                    Err(_) => warn!("Ignoring invalid UID map line: {}", line),
+                },
+                ["", "seed", seed, folder] => match seed.parse::<u32>() {
+                    Ok(seed) => map.folders.entry(folder.to_string()).or_default().seed = Some(seed),
+                    Err(_) => warn!("Ignoring invalid UID map line: {}", line),
+                },
+                ["", "folder", name, folder_id] => {
+                    map.folder_ids.insert(name.to_string(), folder_id.to_string());
+                },
+                // The last UID leaves no UIDNEXT to report
+                [folder, item, uid] => match uid.parse::<u32>().ok().filter(|uid| uid.checked_add(1).is_some()) {
+                    Some(uid) => map.insert(folder, item, uid),
+                    None => warn!("Ignoring invalid UID map line: {}", line),
+                },
+                _ => warn!("Ignoring invalid UID map line: {}", line),
+            }
+        }
+
+        // Maps written before folders had a UIDVALIDITY of their own
+        if map.seed == 0 {
+            map.seed = new_seed();
+            map.dirty = true;
+        }
+        debug!("Loaded UID map for {} folders from {:?}", map.folders.len(), map.path);
+        Ok(map)
+    }
+
+    fn insert(&mut self, folder: &str, item: &str, uid: u32) {
+        let folder_uids = self.folders.entry(folder.to_string()).or_default();
+        folder_uids.uids.insert(item.to_string(), uid);
+        folder_uids.uid_next = folder_uids.uid_next.max(uid + 1);
+    }
+
+    // The FolderId of a folder name once it was selected, the name itself before
+    fn key<'a>(&'a self, folder: &'a str) -> &'a str {
+        self.folder_ids.get(folder).map_or(folder, String::as_str)
+    }
+
+    // UIDVALIDITY of `folder`, whose FolderId is `folder_id`. The UIDs assigned under the name before its
+    // FolderId was known are kept. Only a folder whose UIDs start over, one deleted and created again
+    // under the same name or a map started over, gets a new UIDVALIDITY.
+    pub fn uid_validity(&mut self, folder: &str, folder_id: &str) -> u32 {
+        if self.folder_ids.get(folder).map(String::as_str) != Some(folder_id) {
+            if !self.folder_ids.values().any(|id| id == folder_id) {
+                if let Some(folder_uids) = self.folders.remove(folder) {
+                    self.folders.entry(folder_id.to_string()).or_insert(folder_uids);
+                }
+            }
+            self.folder_ids.insert(folder.to_string(), folder_id.to_string());
+            self.dirty = true;
+        }
+        let seed = self.folders.get(folder_id).and_then(|folder_uids| folder_uids.seed).unwrap_or(self.seed);
+        uid_validity(seed, folder_id)
+    }
+
+    // UID of an item, assigning the next free one the first time the item is seen
+    pub fn uid_for(&mut self, folder: &str, item: &str) -> u32 {
+        let key = self.key(folder).to_string();
+        let map_seed = self.seed;
+        let folder_uids = self.folders.entry(key).or_insert_with(|| FolderUids {
+            uid_next: 1,
+            ..FolderUids::default()
+        });
+        if let Some(uid) = folder_uids.uids.get(item) {
+            return *uid;
+        }
+
+        let mut uid = folder_uids.uid_next.max(1);
+        if uid.checked_add(1).is_none() {
+            // Every UID is used up, the folder starts over under a new UIDVALIDITY
+            let seed = new_seed().max(folder_uids.seed.unwrap_or(map_seed).saturating_add(1));
+            warn!("No UIDs left in {}, assigning them again under a new UIDVALIDITY", folder);
+            *folder_uids = FolderUids { uid_next: 1, uids: HashMap::new(), seed: Some(seed) };
+            uid = 1;
+        }
+        folder_uids.uid_next = uid + 1;
+        folder_uids.uids.insert(item.to_string(), uid);
+        self.dirty = true;
+        uid
+    }
+
+    // Forget the items of the folder that are not in `present`, its complete list of items. UIDNEXT
+    // stays where it is, the UIDs of deleted and moved items are not given out again.
+    pub fn retain_items(&mut self, folder: &str, present: &[String]) {
+        let key = self.key(folder).to_string();
+        let Some(folder_uids) = self.folders.get_mut(&key) else {
+            return;
+        };
+        let present: HashSet<&str> = present.iter().map(String::as_str).collect();
+        let count = folder_uids.uids.len();
+        folder_uids.uids.retain(|item, _| present.contains(item.as_str()));
+        if folder_uids.uids.len() != count {
+            debug!("Removed {} items gone from {} from the UID map", count - folder_uids.uids.len(), folder);
+            self.dirty = true;
+        }
+    }
+
+    // Assignments not written to disk yet
+    pub fn needs_save(&self) -> bool {
+        self.dirty && self.path.is_some()
+    }
+
+    pub fn uid_next(&self, folder: &str) -> u32 {
+        self.folders.get(self.key(folder)).map_or(1, |folder_uids| folder_uids.uid_next.max(1))
+    }
+
+    // Write pending assignments back to disk, through a temporary file so a crash can't truncate the map
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) if self.dirty => path.clone(),
+            _ => return Ok(()),
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            writeln!(file, "# DavMail Rust UID map")?;
+            writeln!(file, "\tseed\t{}", self.seed)?;
+            for (name, folder_id) in &self.folder_ids {
+                writeln!(file, "\tfolder\t{}\t{}", name, folder_id)?;
+            }
+            for (folder, folder_uids) in &self.folders {
+                if let Some(seed) = folder_uids.seed {
+                    writeln!(file, "\tseed\t{}\t{}", seed, folder)?;
+                }
+                for (item, uid) in &folder_uids.uids {
+                    writeln!(file, "{}\t{}\t{}", folder, item, uid)?;
+                }
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+// users.rs
+// Per-user state of a gateway serving a whole team: the connections each user has open, the number of
+// users logged in at once, and each user's UID map and folder cache, shared by all of that user's sessions
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use config::Config;
+use log::{debug, error};
+
+use crate::exchange::foldercache::FolderCache;
+use crate::uidmap::UidMap;
+
+// A user with a desktop client, a phone and a tablet rarely keeps more than a dozen connections open
+pub const DEFAULT_MAX_CONNECTIONS_PER_USER: usize = 20;
+
+// davmail.maxConnectionsPerUser and davmail.maxUsers, 0 means no limit. Read from the user's own
+// configuration, so a [users."login"] section can give one account more connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserLimits {
+    pub max_connections_per_user: usize,
+    pub max_users: usize,
+}
+
+impl UserLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let limit = |key: &str, default: usize| config.get_int(key).map_or(default, |limit| limit.max(0) as usize);
+        UserLimits {
+            max_connections_per_user: limit("davmail.maxConnectionsPerUser", DEFAULT_MAX_CONNECTIONS_PER_USER),
+            max_users: limit("davmail.maxUsers", 0),
+        }
+    }
+}
+
+// Why a login was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserRejection {
+    TooManyConnections,
+    TooManyUsers,
+}
+
+impl fmt::Display for UserRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserRejection::TooManyConnections => write!(f, "Too many connections for this user"),
+            UserRejection::TooManyUsers => write!(f, "Too many users logged in"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct UserState {
+    connections: usize,
+    // Kept once opened, sessions in the session cache still write to it after the user's connections close
+    uid_map: Option<Arc<Mutex<UidMap>>>,
+    folder_cache: Option<Arc<FolderCache>>,
+    // Where the mail rules of each folder stopped, when there is no metadata cache to keep it
+    rule_watermarks: HashMap<String, String>,
+}
+
+// One per profile, shared by its listeners like the session cache
+#[derive(Clone, Default)]
+pub struct UserRegistry {
+    users: Arc<Mutex<HashMap<String, UserState>>>,
+}
+
+impl UserRegistry {
+    pub fn new() -> Self {
+        UserRegistry::default()
+    }
+
+    // Users with at least one connection logged in
+    pub fn active_users(&self) -> usize {
+        self.users.lock().unwrap().values().filter(|state| state.connections > 0).count()
+    }
+
+    // Limits are passed on each call so a configuration reload or the user's overrides apply to the next login
+    pub fn try_acquire(&self, username: &str, limits: &UserLimits) -> Result<UserPermit, UserRejection> {
+        let key = username.to_lowercase();
+        let mut users = self.users.lock().unwrap();
+        let active_users = users.values().filter(|state| state.connections > 0).count();
+        let connections = users.get(&key).map_or(0, |state| state.connections);
+        if connections == 0 && limits.max_users > 0 && active_users >= limits.max_users {
+            return Err(UserRejection::TooManyUsers);
+        }
+        if limits.max_connections_per_user > 0 && connections >= limits.max_connections_per_user {
+            return Err(UserRejection::TooManyConnections);
+        }
+
+        users.entry(key.clone()).or_default().connections += 1;
+        Ok(UserPermit { registry: self.clone(), username: key })
+    }
+
+    // The user's UID map in davmail.uidMapDirectory, None when it isn't set or the map can't be read
+    pub fn uid_map(&self, username: &str, config: &Config) -> Option<Arc<Mutex<UidMap>>> {
+        let directory = config.get_string("davmail.uidMapDirectory").ok()
+            .filter(|directory| !directory.trim().is_empty())?;
+        let key = username.to_lowercase();
+        let mut users = self.users.lock().unwrap();
+        let state = users.entry(key.clone()).or_default();
+        if let Some(uid_map) = &state.uid_map {
+            return Some(uid_map.clone());
+        }
+
+        let path = Path::new(directory.trim()).join(format!("{}.uids", file_name(&key)));
+        match UidMap::open(&path) {
+            Ok(uid_map) => {
+                debug!("Opened UID map of {} at {}", username, path.display());
+                let uid_map = Arc::new(Mutex::new(uid_map));
+                state.uid_map = Some(uid_map.clone());
+                Some(uid_map)
+            },
+            Err(e) => {
+                error!("Failed to open UID map {}: {}", path.display(), e);
+                None
+            },
+        }
+    }
+
+    // The user's folder list and counts cache, None when davmail.folderCacheTtl is 0
+    pub fn folder_cache(&self, username: &str, config: &Config) -> Option<Arc<FolderCache>> {
+        let mut users = self.users.lock().unwrap();
+        let state = users.entry(username.to_lowercase()).or_default();
+        if state.folder_cache.is_none() {
+            state.folder_cache = FolderCache::from_config(config).map(Arc::new);
+        }
+        state.folder_cache.clone()
+    }
+
+    pub fn rule_watermark(&self, username: &str, folder: &str) -> Option<String> {
+        self.users.lock().unwrap().get(&username.to_lowercase())
+            .and_then(|state| state.rule_watermarks.get(folder).cloned())
+    }
+
+    pub fn set_rule_watermark(&self, username: &str, folder: &str, received: &str) {
+        let mut users = self.users.lock().unwrap();
+        let state = users.entry(username.to_lowercase()).or_default();
+        state.rule_watermarks.insert(folder.to_string(), received.to_string());
+    }
+}
+
+// Login names as file names, anything but letters, digits and @ . - _ is replaced
+fn file_name(username: &str) -> String {
+    username.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '@' | '.' | '-' | '_') { c } else { '_' })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string()
+}
+
+// Held by the connection while its user is logged in, frees the slot when the connection ends
+pub struct UserPermit {
+    registry: UserRegistry,
+    username: String,
+}
+
+impl Drop for UserPermit {
+    fn drop(&mut self) {
+        let mut users = self.registry.users.lock().unwrap();
+        if let Some(state) = users.get_mut(&self.username) {
+            state.connections = state.connections.saturating_sub(1);
+            if state.connections == 0 && state.uid_map.is_none() && state.folder_cache.is_none()
+                && state.rule_watermarks.is_empty() {
+                users.remove(&self.username);
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// validate.rs
+// davmail-rust --validate USERNAME: start up as far as serving, sign in once and exit, for deployment pipelines
+
+use std::error::Error;
+use std::net::TcpListener;
+use config::Config;
+
+use davmail_core::admin::{self, AdminServer};
+use davmail_core::configuration::{self, UserOverrides};
+use davmail_core::exchange::http::HttpClientConfig;
+use davmail_core::protocols::tls::TlsAcceptor;
+use davmail_core::{audit, exchange, telemetry, wirelog};
+
+use crate::check::{Outcome, Report};
+use crate::privileges::RunAs;
+use crate::signin;
+use crate::DavMailRust;
+
+// Exit status 0 only when no step failed
+pub fn run(config: Config, user_overrides: UserOverrides, profile: &str, username: &str) -> Result<(), Box<dyn Error>> {
+    // Asked before the report starts, like the check command
+    let login = signin::login_for(&configuration::profile_config(&config, profile)?, username)?;
+    let mut report = Report::default();
+
+    // Profiles, caches, HTTP clients and the rest of what a start initializes
+    exchange::spool::configure(&config);
+    wirelog::configure(&config);
+    audit::configure(&config);
+    telemetry::configure(&config);
+    let davmail = match DavMailRust::new(config.clone(), user_overrides) {
+        Ok(davmail) => {
+            let names: Vec<&str> = davmail.profiles.iter().map(|profile| profile.name.as_str()).collect();
+            report.add("Configuration", Outcome::Pass, format!("profile(s) {}", names.join(", ")));
+            davmail
+        },
+        Err(e) => {
+            report.add("Configuration", Outcome::Fail, e.to_string());
+            return finish(report);
+        }
+    };
+
+    match RunAs::from_config(&config) {
+        Ok(Some(_)) => report.add("Privileges", Outcome::Pass,
+            format!("davmail.runAsUser {} exists", config.get_string("davmail.runAsUser").unwrap_or_default().trim())),
+        Ok(None) => report.add("Privileges", Outcome::Skip, "davmail.runAsUser is not set"),
+        Err(e) => report.add("Privileges", Outcome::Fail, e.to_string()),
+    }
+
+    // Bound and released again, so the ports are free for the gateway this one replaces
+    for profile in &davmail.profiles {
+        let step = format!("IMAP {}", profile.name);
+        let Some((bind_addresses, port, tls)) = crate::imap_listener(&profile.config) else {
+            report.add(&step, Outcome::Skip, "davmail.imapEnabled is not set");
+            continue;
+        };
+        if tls {
+            if let Err(e) = TlsAcceptor::from_config(&profile.config) {
+                report.add(&step, Outcome::Fail, format!("TLS: {}", e));
+                continue;
+            }
+        }
+        let (outcome, detail) = bind_all(&bind_addresses, port);
+        report.add(&step, outcome, format!("{}{}", detail, if tls { " with TLS" } else { "" }));
+    }
+
+    match admin::admin_address(&config) {
+        Some((bind_address, port)) => {
+            if AdminServer::from_config(&config, |_| {}).is_none() {
+                report.add("Admin", Outcome::Fail, "davmail.adminPassword is required off the loopback interface");
+            } else {
+                let (outcome, detail) = bind_all(&[bind_address], port);
+                report.add("Admin", outcome, detail);
+            }
+        },
+        None => report.add("Admin", Outcome::Skip, "davmail.adminPort is not set"),
+    }
+
+    // One sign-in and one EWS request with the settings of --profile
+    let profile_config = configuration::profile_config(&config, profile)?;
+    let outcome = davmail.runtime.block_on(async {
+        let url = profile_config.get_string("davmail.url").map_err(|_| "davmail.url is not configured".to_string())?;
+        let http_config = HttpClientConfig::from_config(&profile_config);
+        let client = http_config.build().map_err(|e| format!("HTTP client: {}", e))?;
+        let (exchange_client, method) = signin::authenticate(&profile_config, url.trim_end_matches('/'), &http_config, &client, username, login).await?;
+        let folders = exchange_client.list_folders("", "*").await.map_err(|e| format!("{} as {}, then FindFolder: {}", method, username, e))?;
+        Ok::<_, String>(format!("{} as {}, {} folder(s) listed", method, username, folders.len()))
+    });
+    match outcome {
+        Ok(detail) => report.add("Exchange", Outcome::Pass, detail),
+        Err(e) => report.add("Exchange", Outcome::Fail, e),
+    }
+
+    finish(report)
+}
+
+// Every address on the port, or the ones that couldn't be bound
+fn bind_all(bind_addresses: &[String], port: u16) -> (Outcome, String) {
+    let failed: Vec<String> = bind_addresses.iter()
+        .filter_map(|address| TcpListener::bind((address.as_str(), port)).err().map(|e| format!("{} port {}: {}", address, port, e)))
+        .collect();
+    if failed.is_empty() {
+        (Outcome::Pass, format!("{} port {} can be bound", bind_addresses.join(", "), port))
+    } else {
+        (Outcome::Fail, failed.join(", "))
+    }
+}
+
+fn finish(report: Report) -> Result<(), Box<dyn Error>> {
+    if report.failed > 0 {
+        return Err(format!("Validation failed, {} step(s) failed", report.failed).into());
+    }
+    println!("Validation passed");
+    Ok(())
+}
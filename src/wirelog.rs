@@ -0,0 +1,116 @@
+// wirelog.rs
+// Opt-in log of the protocol lines exchanged with clients and Exchange, with credentials masked
+
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use chrono::Utc;
+use config::Config;
+use log::{error, info};
+
+const REDACTED: &str = "***";
+
+// Checked before formatting anything, so a disabled wire log costs one atomic load per line
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static WIRE_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // Written by the gateway, to a client or to Exchange
+    Sent,
+    // Read by the gateway
+    Received,
+}
+
+// davmail.wireLogFile, appended to. Mail content is never written, only commands, responses and headers.
+pub fn configure(config: &Config) {
+    let path = config.get_string("davmail.wireLogFile").ok().filter(|path| !path.trim().is_empty());
+    let file = path.and_then(|path| {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(path.trim()) {
+            Ok(file) => {
+                info!("Logging protocol traffic to {}", path.trim());
+                Some(file)
+            },
+            Err(e) => {
+                error!("Failed to open wire log {}: {}", path.trim(), e);
+                None
+            },
+        }
+    });
+    ENABLED.store(file.is_some(), Ordering::Relaxed);
+    *WIRE_LOG.lock().unwrap() = file;
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// One line per call, `connection` tells connections of the same protocol apart
+pub fn log(protocol: &str, connection: &str, direction: Direction, line: &str) {
+    if !enabled() {
+        return;
+    }
+    let arrow = match direction {
+        Direction::Sent => ">>",
+        Direction::Received => "<<",
+    };
+    if let Some(file) = WIRE_LOG.lock().unwrap().as_mut() {
+        let _ = writeln!(file, "{} {} {} {} {}", Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"), protocol, connection, arrow,
+            line.trim_end_matches(['\r', '\n']));
+    }
+}
+
+// IMAP command with the LOGIN password and the AUTHENTICATE initial response masked
+pub fn redact_imap_command(line: &str) -> Cow<'_, str> {
+    let mut parts = line.trim_end_matches(['\r', '\n']).splitn(4, ' ');
+    let (Some(tag), Some(command)) = (parts.next(), parts.next()) else {
+        return Cow::Borrowed(line);
+    };
+    match command.to_uppercase().as_str() {
+        "LOGIN" => match parts.next() {
+            Some(username) => Cow::Owned(format!("{} {} {} {}", tag, command, username, REDACTED)),
+            None => Cow::Borrowed(line),
+        },
+        "AUTHENTICATE" => match (parts.next(), parts.next()) {
+            (Some(mechanism), Some(_)) => Cow::Owned(format!("{} {} {} {}", tag, command, mechanism, REDACTED)),
+            _ => Cow::Borrowed(line),
+        },
+        _ => Cow::Borrowed(line),
+    }
+}
+
+// Whether the line after `line` is the content of a literal sent with LOGIN or AUTHENTICATE, a password
+// or a SASL response. `continued` tells `line` is itself such a literal, with the next literal announced
+// at its end (LOGIN {4} / user {8} / password).
+pub fn continues_with_secret(line: &str, continued: bool) -> bool {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let literal = line.strip_suffix('}')
+        .and_then(|rest| rest.rsplit_once('{'))
+        .map(|(_, size)| size.strip_suffix('+').unwrap_or(size))
+        .is_some_and(|size| !size.is_empty() && size.bytes().all(|byte| byte.is_ascii_digit()));
+    literal && (continued || line.split(' ').nth(1)
+        .is_some_and(|command| command.eq_ignore_ascii_case("LOGIN") || command.eq_ignore_ascii_case("AUTHENTICATE")))
+}
+
+// HTTP header as it can go to the wire log, credentials and session cookies masked
+pub fn redact_header(name: &str, value: &str) -> String {
+    match name.to_lowercase().as_str() {
+        "authorization" | "proxy-authorization" => {
+            // Keep the scheme (Basic, Bearer, NTLM), it matters when debugging authentication
+            let scheme = value.split_whitespace().next().unwrap_or_default();
+            format!("{}: {} {}", name, scheme, REDACTED)
+        },
+        "cookie" | "set-cookie" => format!("{}: {}", name, REDACTED),
+        _ => format!("{}: {}", name, value),
+    }
+}
+
+pub fn redacted() -> &'static str {
+    REDACTED
+}
@@ -0,0 +1,196 @@
+// tests/common/mod.rs
+// Protocol test harness: a listener of the gateway in front of a mock EWS endpoint, driven by scripted client sessions
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use config::Config;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, watch};
+
+use davmail_core::auth::TokenManager;
+use davmail_core::exchange::http::HttpClientConfig;
+use davmail_core::exchange::limiter::RequestLimiter;
+use davmail_core::exchange::sessions::SessionCache;
+use davmail_core::mock_ews::MockEws;
+use davmail_core::protocols::lockout::LoginGuard;
+use davmail_core::{ImapServer, LiveSettings, UserOverrides};
+
+pub const USERNAME: &str = "alice@example.com";
+pub const PASSWORD: &str = "secret";
+
+// A conforming server answers well within this, a slower answer fails the test instead of hanging it
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Run a test against a fresh gateway, `settings` are davmail.* keys on top of the mock's URL
+pub fn run<F, T>(settings: &[(&str, &str)], test: F)
+where
+    F: FnOnce(Gateway) -> T,
+    T: Future<Output = ()>,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let handle = runtime.handle().clone();
+    runtime.block_on(async move {
+        let gateway = Gateway::start(handle, settings).await;
+        test(gateway).await;
+    });
+}
+
+pub struct Gateway {
+    pub mock: MockEws,
+    port: u16,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Gateway {
+    async fn start(handle: tokio::runtime::Handle, settings: &[(&str, &str)]) -> Gateway {
+        let mock = MockEws::start().await.unwrap();
+        mock.require_basic_auth(USERNAME, PASSWORD);
+
+        let mut builder = Config::builder().set_override("davmail.url", mock.url()).unwrap();
+        for (key, value) in settings {
+            builder = builder.set_override(*key, *value).unwrap();
+        }
+        let config = Arc::new(builder.build().unwrap());
+        let http_client = HttpClientConfig::from_config(&config).build().unwrap();
+        let request_limiter = Arc::new(RequestLimiter::from_config(&config));
+        let settings = Arc::new(RwLock::new(LiveSettings { config, http_client, user_overrides: Arc::new(UserOverrides::default()) }));
+
+        // A port that was free a moment ago, the server binds it again
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (bound, bound_receiver) = oneshot::channel();
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let server = ImapServer::new(settings, vec!["127.0.0.1".to_string()], port, Arc::new(TokenManager::new(handle)),
+            Arc::new(SessionCache::new(Duration::ZERO)), Arc::new(LoginGuard::new()))
+            .with_request_limiter(request_limiter)
+            .with_bound_signal(bound);
+        tokio::spawn(server.run(shutdown_receiver));
+        bound_receiver.await.expect("IMAP server failed to start");
+
+        Gateway { mock, port, shutdown }
+    }
+
+    // Connected session that has read the greeting
+    pub async fn connect(&self) -> Session {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).await.unwrap();
+        let mut session = Session { stream: BufReader::new(stream), greeting: String::new() };
+        session.greeting = session.read_line().await.expect("connection closed before the greeting");
+        session
+    }
+
+    // Connected and logged in with the mock's credentials
+    pub async fn login(&self) -> Session {
+        let mut session = self.connect().await;
+        session.command("login", &format!("LOGIN {} {}", USERNAME, PASSWORD)).await.assert_ok();
+        session
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(true);
+    }
+}
+
+// Untagged lines of a command and its tagged completion, line endings removed
+#[derive(Debug)]
+pub struct Response {
+    pub untagged: Vec<String>,
+    pub tagged: String,
+}
+
+impl Response {
+    // OK, NO or BAD
+    pub fn status(&self) -> &str {
+        self.tagged.split(' ').nth(1).unwrap_or_default()
+    }
+
+    pub fn assert_ok(&self) -> &Self {
+        self.assert_status("OK")
+    }
+
+    pub fn assert_status(&self, status: &str) -> &Self {
+        assert_eq!(self.status(), status, "unexpected completion: {:?}", self);
+        self
+    }
+
+    // The untagged response starting with `prefix`, e.g. "* CAPABILITY"
+    pub fn untagged(&self, prefix: &str) -> Option<&str> {
+        self.untagged.iter().find(|line| line.starts_with(prefix)).map(String::as_str)
+    }
+}
+
+pub struct Session {
+    stream: BufReader<TcpStream>,
+    pub greeting: String,
+}
+
+impl Session {
+    // Sent as is, for pipelining, partial lines and oversized input
+    pub async fn send(&mut self, data: &[u8]) {
+        self.stream.get_mut().write_all(data).await.unwrap();
+    }
+
+    pub async fn command(&mut self, tag: &str, command: &str) -> Response {
+        self.send(format!("{} {}\r\n", tag, command).as_bytes()).await;
+        self.response(tag).await
+    }
+
+    // Lines up to the completion with this tag
+    pub async fn response(&mut self, tag: &str) -> Response {
+        let prefix = format!("{} ", tag);
+        let mut untagged = Vec::new();
+        loop {
+            let line = self.read_line().await.unwrap_or_else(|| panic!("connection closed before the completion of {}", tag));
+            if line.starts_with(&prefix) {
+                return Response { untagged, tagged: line };
+            }
+            untagged.push(line);
+        }
+    }
+
+    // Next line without its line ending, None once the server closed the connection
+    pub async fn read_line(&mut self) -> Option<String> {
+        self.read_raw_line().await.map(|line| line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    // Next line as sent, with its line ending
+    pub async fn read_raw_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        let read = tokio::time::timeout(RESPONSE_TIMEOUT, self.stream.read_line(&mut line)).await
+            .expect("no response from the server")
+            .unwrap_or(0);
+        (read > 0).then_some(line)
+    }
+
+    // Content of the single literal in the response to a FETCH, exactly as sent
+    pub async fn fetch_literal(&mut self, tag: &str, command: &str) -> Vec<u8> {
+        self.send(format!("{} {}\r\n", tag, command).as_bytes()).await;
+        let line = self.read_raw_line().await.expect("connection closed before the FETCH response");
+        let length = line.trim_end().strip_suffix('}')
+            .and_then(|line| line.rsplit_once('{'))
+            .and_then(|(_, length)| length.parse().ok())
+            .unwrap_or_else(|| panic!("no literal in {:?}", line));
+        let mut content = vec![0; length];
+        tokio::time::timeout(RESPONSE_TIMEOUT, self.stream.read_exact(&mut content)).await
+            .expect("no response from the server")
+            .unwrap();
+        self.response(tag).await.assert_ok();
+        content
+    }
+
+    // Whether the server closes the connection, anything it still sends is skipped
+    pub async fn closed(&mut self) -> bool {
+        let mut buffer = [0; 4096];
+        loop {
+            match tokio::time::timeout(RESPONSE_TIMEOUT, self.stream.read(&mut buffer)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => return true,
+                Ok(Ok(_)) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,643 @@
+// tests/imap_conformance.rs
+// Scripted IMAP sessions against the gateway and a mock EWS endpoint, checking the responses RFC 3501 requires.
+// Run with: cargo test --features test-support
+
+mod common;
+
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use davmail_core::mock_ews::{Fault, MockMessage};
+
+use common::{run, PASSWORD, USERNAME};
+
+#[test]
+fn greeting_is_untagged_ok_with_capabilities() {
+    run(&[], |gateway| async move {
+        let session = gateway.connect().await;
+        assert!(session.greeting.starts_with("* OK [CAPABILITY IMAP4rev1"), "greeting: {}", session.greeting);
+    });
+}
+
+#[test]
+fn capability_lists_imap4rev1() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        let response = session.command("a1", "CAPABILITY").await;
+        response.assert_ok();
+        let capabilities = response.untagged("* CAPABILITY ").expect("no CAPABILITY response");
+        assert!(capabilities.split(' ').any(|capability| capability == "IMAP4rev1"), "capabilities: {}", capabilities);
+    });
+}
+
+#[test]
+fn commands_are_case_insensitive() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.command("a1", "capability").await.assert_ok();
+        session.command("a2", "CaPaBiLiTy").await.assert_ok();
+    });
+}
+
+#[test]
+fn unknown_command_is_bad() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.command("a1", "XYZZY").await.assert_status("BAD");
+        // The session goes on after a BAD
+        session.command("a2", "CAPABILITY").await.assert_ok();
+    });
+}
+
+#[test]
+fn line_without_command_is_untagged_bad() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.send(b"a1\r\n").await;
+        assert_eq!(session.read_line().await.as_deref(), Some("* BAD Invalid command"));
+    });
+}
+
+#[test]
+fn missing_arguments_are_bad() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.command("a1", "LOGIN").await.assert_status("BAD");
+        session.command("a2", &format!("LOGIN {}", USERNAME)).await.assert_status("BAD");
+        let mut session = gateway.login().await;
+        session.command("a3", "SELECT").await.assert_status("BAD");
+    });
+}
+
+#[test]
+fn mailbox_commands_need_authentication() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.command("a1", "LIST \"\" \"*\"").await.assert_status("NO");
+        session.command("a2", "SELECT INBOX").await.assert_status("NO");
+        session.command("a3", "FETCH 1 (FLAGS)").await.assert_status("NO");
+        assert_eq!(gateway.mock.requests().len(), 0, "Exchange was called before a login");
+    });
+}
+
+#[test]
+fn login_with_valid_credentials() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.command("a1", &format!("LOGIN \"{}\" \"{}\"", USERNAME, PASSWORD)).await.assert_ok();
+    });
+}
+
+#[test]
+fn login_with_wrong_password_is_no() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.command("a1", &format!("LOGIN {} wrong", USERNAME)).await.assert_status("NO");
+        // Still not authenticated
+        session.command("a2", "SELECT INBOX").await.assert_status("NO");
+    });
+}
+
+#[test]
+fn login_after_login_is_bad() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", &format!("LOGIN {} wrong", USERNAME)).await.assert_status("BAD");
+        // The first login's session goes on
+        session.command("a2", "SELECT INBOX").await.assert_ok();
+    });
+}
+
+#[test]
+fn connections_per_user_are_limited() {
+    run(&[("davmail.maxConnectionsPerUser", "1")], |gateway| async move {
+        let _first = gateway.login().await;
+        let mut second = gateway.connect().await;
+        let response = second.command("a1", &format!("LOGIN {} {}", USERNAME, PASSWORD)).await;
+        response.assert_status("NO");
+        assert!(response.tagged.starts_with("a1 NO [LIMIT]"), "completion: {}", response.tagged);
+        // Still not authenticated
+        second.command("a2", "SELECT INBOX").await.assert_status("NO");
+    });
+}
+
+#[test]
+fn ews_requests_per_minute_are_limited() {
+    run(&[("davmail.ews.maxRequestsPerMinute", "2")], |gateway| async move {
+        let mut session = gateway.login().await;
+        let mut responses = Vec::new();
+        for tag in ["a1", "a2", "a3", "a4"] {
+            responses.push(session.command(tag, "SELECT INBOX").await);
+        }
+        let limited = responses.iter().find(|response| !response.tagged.contains(" OK "))
+            .expect("no SELECT was refused");
+        assert!(limited.tagged.contains(" NO [LIMIT] "), "completion: {}", limited.tagged);
+    });
+}
+
+#[test]
+fn list_includes_inbox() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        let response = session.command("a1", "LIST \"\" \"*\"").await;
+        response.assert_ok();
+        assert!(response.untagged.iter().all(|line| line.starts_with("* LIST (")), "unexpected lines: {:?}", response.untagged);
+        assert!(response.untagged.iter().any(|line| line.ends_with(" \"INBOX\"")), "no INBOX in {:?}", response.untagged);
+    });
+}
+
+#[test]
+fn select_reports_the_required_responses() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        let response = session.command("a1", "SELECT INBOX").await;
+        response.assert_ok();
+        assert!(response.tagged.starts_with("a1 OK [READ-WRITE]") || response.tagged.starts_with("a1 OK [READ-ONLY]"),
+            "completion: {}", response.tagged);
+        // RFC 3501 6.3.1: FLAGS, EXISTS, RECENT, UIDVALIDITY are required
+        for required in ["* FLAGS (", "* OK [UIDVALIDITY "] {
+            assert!(response.untagged(required).is_some(), "no {} in {:?}", required, response.untagged);
+        }
+        for required in [" EXISTS", " RECENT"] {
+            assert!(response.untagged.iter().any(|line| line.starts_with("* ") && line.ends_with(required)),
+                "no{} in {:?}", required, response.untagged);
+        }
+    });
+}
+
+#[test]
+fn uidvalidity_does_not_depend_on_the_folder_name_case() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        let mut uid_validities = Vec::new();
+        for (tag, folder) in [("a1", "INBOX"), ("a2", "inbox"), ("a3", "Inbox")] {
+            let response = session.command(tag, &format!("SELECT {}", folder)).await;
+            response.assert_ok();
+            uid_validities.push(response.untagged("* OK [UIDVALIDITY ").expect("no UIDVALIDITY").to_string());
+        }
+        assert!(uid_validities.iter().all(|uid_validity| *uid_validity == uid_validities[0]), "{:?}", uid_validities);
+    });
+}
+
+#[test]
+fn fetch_needs_a_selected_mailbox() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "FETCH 1 (FLAGS)").await.assert_status("NO");
+    });
+}
+
+#[test]
+fn logout_sends_bye_and_closes() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        let response = session.command("a1", "LOGOUT").await;
+        response.assert_ok();
+        assert!(response.untagged("* BYE").is_some(), "no BYE in {:?}", response.untagged);
+        assert!(session.closed().await, "connection left open after LOGOUT");
+    });
+}
+
+#[test]
+fn pipelined_commands_are_answered_in_order() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.send(b"p1 CAPABILITY\r\np2 NAMESPACE\r\np3 SELECT INBOX\r\np4 XYZZY\r\np5 CAPABILITY\r\n").await;
+        session.response("p1").await.assert_ok();
+        session.response("p2").await.assert_ok();
+        session.response("p3").await.assert_ok();
+        session.response("p4").await.assert_status("BAD");
+        session.response("p5").await.assert_ok();
+    });
+}
+
+#[test]
+fn long_line_does_not_take_the_listener_down() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        let mut line = b"a1 ".to_vec();
+        line.resize(1024 * 1024, b'x');
+        line.extend_from_slice(b"\r\n");
+        session.send(&line).await;
+        // Either refused or answered, as long as the server is still there for the next client
+        drop(session);
+        let mut session = gateway.connect().await;
+        session.command("a2", "CAPABILITY").await.assert_ok();
+    });
+}
+
+#[test]
+fn disconnect_in_the_middle_of_a_command() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.send(b"a1 SEL").await;
+        drop(session);
+        let mut session = gateway.login().await;
+        session.command("a2", "SELECT INBOX").await.assert_ok();
+    });
+}
+
+#[test]
+fn disconnect_while_exchange_is_answering() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        gateway.mock.fail("GetFolder", Fault::Delay(Duration::from_millis(500)), Some(1));
+        session.send(b"a1 SELECT INBOX\r\n").await;
+        drop(session);
+        let mut session = gateway.login().await;
+        session.command("a2", "SELECT INBOX").await.assert_ok();
+    });
+}
+
+#[test]
+fn stored_answered_flag_is_fetched() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        session.command("a2", "STORE 1 +FLAGS (\\Answered)").await.assert_ok();
+        let response = session.command("a3", "FETCH 1:2 (FLAGS)").await;
+        response.assert_ok();
+        assert!(response.untagged("* 1 FETCH (FLAGS (\\Answered))").is_some(), "unexpected lines: {:?}", response.untagged);
+        assert!(response.untagged("* 2 FETCH (FLAGS (\\Seen))").is_some(), "unexpected lines: {:?}", response.untagged);
+        session.command("a4", "STORE 1 -FLAGS.SILENT (\\Answered)").await.assert_ok();
+        let response = session.command("a5", "FETCH 1 (FLAGS)").await;
+        assert!(response.untagged("* 1 FETCH (FLAGS ())").is_some(), "unexpected lines: {:?}", response.untagged);
+    });
+}
+
+#[test]
+fn mdn_sent_clears_the_read_receipt_request() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        session.command("a2", "STORE 1 +FLAGS.SILENT ($MDNSent)").await.assert_ok();
+        assert_eq!(gateway.mock.count("UpdateItem"), 1);
+    });
+}
+
+#[test]
+fn exchange_read_receipts_show_mdn_sent() {
+    run(&[("davmail.readReceipts", "exchange")], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        let response = session.command("a2", "FETCH 2 (FLAGS)").await;
+        assert!(response.untagged("* 2 FETCH (FLAGS (\\Seen $MDNSent))").is_some(), "unexpected lines: {:?}", response.untagged);
+        session.command("a3", "STORE 2 +FLAGS.SILENT ($MDNSent)").await.assert_ok();
+        assert_eq!(gateway.mock.count("UpdateItem"), 0);
+    });
+}
+
+// Signed content with what a normalizing gateway would change: trailing whitespace, a folded header,
+// a bare LF and 8-bit text. Any change to it breaks the signature.
+const SIGNED_MESSAGE: &str = concat!(
+    "From: alice@example.com\r\n",
+    "Subject: Signed\r\n",
+    "MIME-Version: 1.0\r\n",
+    "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\";\r\n",
+    "\tmicalg=sha-256; boundary=\"sig\"\r\n",
+    "\r\n",
+    "--sig\r\n",
+    "Content-Type: text/plain; charset=utf-8\r\n",
+    "Content-Transfer-Encoding: 8bit\r\n",
+    "\r\n",
+    "Grüße \t\r\n",
+    "bare line feed\n",
+    "\r\n",
+    "--sig\r\n",
+    "Content-Type: application/pkcs7-signature; name=smime.p7s\r\n",
+    "Content-Transfer-Encoding: base64\r\n",
+    "\r\n",
+    "MIAGCSqGSIb3DQEHAqCAMIACAQExDzANBglghkgBZQMEAgEFADCABgkqhkiG9w0BBwEAAKCAMIIB\r\n",
+    "--sig--\r\n",
+);
+
+#[test]
+fn signed_message_is_fetched_byte_for_byte() {
+    run(&[], |gateway| async move {
+        let mut signed = MockMessage::new("mock-signed", "Signed", "alice@example.com");
+        signed.mime = SIGNED_MESSAGE.to_string();
+        gateway.mock.set_messages(vec![signed]);
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        assert_eq!(session.fetch_literal("a2", "FETCH 1 (BODY[])").await, SIGNED_MESSAGE.as_bytes());
+        let header_end = SIGNED_MESSAGE.find("\r\n\r\n").unwrap() + 4;
+        assert_eq!(session.fetch_literal("a3", "FETCH 1 (BODY[TEXT])").await, &SIGNED_MESSAGE.as_bytes()[header_end..]);
+    });
+}
+
+#[test]
+fn html_only_message_gets_a_plain_text_alternative() {
+    run(&[("davmail.htmlToText", "true")], |gateway| async move {
+        let mut html = MockMessage::new("mock-html", "Newsletter", "news@example.com");
+        html.mime = concat!(
+            "From: news@example.com\r\n",
+            "Subject: Newsletter\r\n",
+            "Content-Type: text/html; charset=utf-8\r\n",
+            "\r\n",
+            "<html><head><title>News</title></head><body><p>Hello <b>world</b></p>",
+            "<p><a href=\"https://example.com/\">Example</a></p></body></html>\r\n",
+        ).to_string();
+        gateway.mock.set_messages(vec![html]);
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        let content = String::from_utf8(session.fetch_literal("a2", "FETCH 1 (BODY[])").await).unwrap();
+        assert!(content.contains("Content-Type: multipart/alternative;"), "not converted: {}", content);
+        assert!(content.contains("Content-Type: text/plain; charset=\"utf-8\""), "no text part: {}", content);
+        assert!(content.contains(&STANDARD.encode("Hello world\r\n\r\nExample <https://example.com/>\r\n")), "unexpected text: {}", content);
+        assert!(content.contains("<p>Hello <b>world</b></p>"), "HTML part changed: {}", content);
+    });
+}
+
+#[test]
+fn legacy_charsets_are_transcoded_to_utf8() {
+    run(&[("davmail.utf8Messages", "true")], |gateway| async move {
+        let mut legacy = MockMessage::new("mock-legacy", "Legacy", "alice@example.com");
+        legacy.mime = concat!(
+            "From: alice@example.com\r\n",
+            "Subject: =?iso-8859-1?Q?Gr=FC=DFe?=\r\n",
+            "Content-Type: text/plain; charset=windows-1252\r\n",
+            "Content-Transfer-Encoding: quoted-printable\r\n",
+            "\r\n",
+            "Gr=FC=DFe =80\r\n",
+        ).to_string();
+        gateway.mock.set_messages(vec![legacy]);
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        let content = String::from_utf8(session.fetch_literal("a2", "FETCH 1 (BODY[])").await).unwrap();
+        assert!(content.contains("Subject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n"), "subject not transcoded: {}", content);
+        assert!(content.contains("Content-Type: text/plain; charset=utf-8\r\n"), "charset not replaced: {}", content);
+        assert!(content.contains(&STANDARD.encode("Gr\u{fc}\u{df}e \u{20ac}\r\n")), "body not transcoded: {}", content);
+    });
+}
+
+#[test]
+fn header_rules_rewrite_fetched_messages() {
+    let settings = [
+        ("davmail.headerRules", "routing, tag, relay"),
+        ("davmail.headerRule.routing.header", "X-MS-Exchange-*"),
+        ("davmail.headerRule.routing.action", "remove"),
+        ("davmail.headerRule.tag.header", "X-Classification"),
+        ("davmail.headerRule.tag.action", "add"),
+        ("davmail.headerRule.tag.value", "Internal"),
+        ("davmail.headerRule.relay.header", "Received"),
+        ("davmail.headerRule.relay.action", "replace"),
+        ("davmail.headerRule.relay.match", r"mx\d+\.corp\.example"),
+        ("davmail.headerRule.relay.value", "relay.example"),
+    ];
+    run(&settings, |gateway| async move {
+        let mut routed = MockMessage::new("mock-routed", "Routed", "alice@example.com");
+        routed.mime = concat!(
+            "Received: from mx12.corp.example by mail.example.com\r\n",
+            "X-MS-Exchange-Organization-AuthSource: mx12.corp.example\r\n",
+            "X-MS-Exchange-Organization-Network-Message-Id:\r\n",
+            "\t0123456789abcdef\r\n",
+            "From: alice@example.com\r\n",
+            "Subject: Routed\r\n",
+            "\r\n",
+            "Body\r\n",
+        ).to_string();
+        gateway.mock.set_messages(vec![routed]);
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        let content = String::from_utf8(session.fetch_literal("a2", "FETCH 1 (BODY[])").await).unwrap();
+        assert_eq!(content, concat!(
+            "Received: from relay.example by mail.example.com\r\n",
+            "From: alice@example.com\r\n",
+            "Subject: Routed\r\n",
+            "X-Classification: Internal\r\n",
+            "\r\n",
+            "Body\r\n",
+        ));
+    });
+}
+
+#[test]
+fn enable_utf8_accept() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        let response = session.command("a1", "ENABLE UTF8=ACCEPT CONDSTORE").await;
+        response.assert_ok();
+        assert_eq!(response.untagged, ["* ENABLED UTF8=ACCEPT"]);
+    });
+}
+
+#[test]
+fn search_with_an_unknown_charset_is_badcharset() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        let response = session.command("a2", "SEARCH CHARSET KOI8-R SUBJECT test").await;
+        response.assert_status("NO");
+        assert!(response.tagged.contains("[BADCHARSET (US-ASCII UTF-8)]"), "completion: {}", response.tagged);
+    });
+}
+
+#[test]
+fn expunge_empties_trash_only_once_all_of_it_is_deleted() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT Trash").await.assert_ok();
+        session.command("a2", "EXPUNGE").await.assert_ok();
+        assert_eq!(gateway.mock.count("EmptyFolder"), 0);
+        session.command("a3", "STORE 1 +FLAGS (\\Deleted)").await.assert_status("NO");
+        session.command("a4", "STORE 1:* +FLAGS.SILENT (\\Deleted)").await.assert_ok();
+        session.command("a5", "EXPUNGE").await.assert_ok();
+        assert_eq!(gateway.mock.count("EmptyFolder"), 1);
+        session.command("a6", "EXPUNGE").await.assert_ok();
+        assert_eq!(gateway.mock.count("EmptyFolder"), 1);
+    });
+}
+
+#[test]
+fn move_into_junk_reports_junk() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        let response = session.command("a2", "MOVE 1:2 Junk").await;
+        response.assert_ok();
+        assert_eq!(response.untagged, ["* 2 EXPUNGE", "* 1 EXPUNGE"]);
+        assert_eq!((gateway.mock.count("MarkAsJunk"), gateway.mock.count("MoveItem")), (1, 0));
+    });
+}
+
+#[test]
+fn move_out_of_junk_reports_not_junk() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT Junk").await.assert_ok();
+        session.command("a2", "MOVE 1 INBOX").await.assert_ok();
+        assert_eq!((gateway.mock.count("MarkAsJunk"), gateway.mock.count("MoveItem")), (1, 1));
+        session.command("a3", "STORE 1 +FLAGS.SILENT ($Junk)").await.assert_ok();
+        assert_eq!(gateway.mock.count("MarkAsJunk"), 2);
+    });
+}
+
+// GetFolder answer for the well-known folders of a German mailbox
+fn german_folders() -> String {
+    let folders: String = ["Posteingang", "Gesendete Elemente", "Entwürfe", "Gelöschte Elemente", "Junk-E-Mail"].iter().enumerate()
+        .map(|(index, name)| format!(concat!(
+            r#"<m:GetFolderResponseMessage ResponseClass="Success"><m:ResponseCode>NoError</m:ResponseCode><m:Folders><t:Folder>"#,
+            r#"<t:FolderId Id="mock-{}" ChangeKey="1"/><t:DisplayName>{}</t:DisplayName></t:Folder></m:Folders></m:GetFolderResponseMessage>"#),
+            index, name))
+        .collect();
+    format!("<m:GetFolderResponse><m:ResponseMessages>{}</m:ResponseMessages></m:GetFolderResponse>", folders)
+}
+
+#[test]
+fn localized_folder_names_are_listed() {
+    run(&[("davmail.folderNames", "localized")], |gateway| async move {
+        gateway.mock.respond("GetFolder", german_folders());
+        let mut session = gateway.login().await;
+        let response = session.command("a1", "LIST \"\" \"*\"").await;
+        response.assert_ok();
+        for name in ["INBOX", "Gesendete Elemente", "Entw&APw-rfe", "Gel&APY-schte Elemente", "Junk-E-Mail"] {
+            assert!(response.untagged.iter().any(|line| line.ends_with(&format!(" \"{}\"", name))), "no {} in {:?}", name, response.untagged);
+        }
+        assert!(response.untagged.iter().all(|line| !line.ends_with(" \"Sent Items\"")), "English name in {:?}", response.untagged);
+    });
+}
+
+#[test]
+fn localized_and_english_names_select_the_same_folder() {
+    run(&[("davmail.folderNames", "localized")], |gateway| async move {
+        gateway.mock.respond("GetFolder", german_folders());
+        let mut session = gateway.login().await;
+        let lookups = gateway.mock.count("FindFolder");
+        session.command("a1", "SELECT \"Sent Items\"").await.assert_ok();
+        session.command("a2", "SELECT \"Gesendete Elemente\"").await.assert_ok();
+        // Both are the distinguished sentitems folder, neither is looked up by display name
+        assert_eq!(gateway.mock.count("FindFolder"), lookups);
+        session.command("a3", "SELECT INBOX").await.assert_ok();
+        session.command("a4", "MOVE 1 \"Junk-E-Mail\"").await.assert_ok();
+        assert_eq!(gateway.mock.count("MarkAsJunk"), 1);
+    });
+}
+
+#[test]
+fn status_and_list_are_served_from_the_folder_cache() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "STATUS INBOX (MESSAGES UNSEEN)").await.assert_ok();
+        session.command("a2", "LIST \"\" \"*\"").await.assert_ok();
+        let requests = gateway.mock.requests().len();
+        let status = session.command("a3", "STATUS INBOX (MESSAGES UNSEEN)").await;
+        status.assert_ok();
+        assert!(status.untagged("* STATUS").is_some(), "untagged: {:?}", status.untagged);
+        session.command("a4", "LIST \"\" \"*\"").await.assert_ok();
+        assert_eq!(gateway.mock.requests().len(), requests, "Exchange was asked again");
+        // SELECT always asks Exchange
+        session.command("a5", "SELECT INBOX").await.assert_ok();
+        assert!(gateway.mock.requests().len() > requests);
+    });
+}
+
+#[test]
+fn exchange_fault_is_no_and_the_session_recovers() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        gateway.mock.fail("GetFolder", Fault::Soap("Invalid request".to_string()), Some(1));
+        session.command("a1", "SELECT INBOX").await.assert_status("NO");
+        session.command("a2", "SELECT INBOX").await.assert_ok();
+    });
+}
+
+#[test]
+fn exchange_going_away_is_no() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        gateway.mock.fail("*", Fault::Disconnect, None);
+        session.command("a1", "SELECT INBOX").await.assert_status("NO");
+        session.command("a2", "CAPABILITY").await.assert_ok();
+    });
+}
+
+#[test]
+fn authenticate_refuses_the_token_of_another_user() {
+    run(&[], |gateway| async move {
+        let claims = URL_SAFE_NO_PAD.encode(r#"{"upn":"bob@example.com"}"#);
+        let token = format!("eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl", claims);
+        let mut session = gateway.connect().await;
+        let response = STANDARD.encode(format!("user={}\x01auth=Bearer {}\x01\x01", USERNAME, token));
+        let response = session.command("a1", &format!("AUTHENTICATE XOAUTH2 {}", response)).await;
+        assert!(response.tagged.starts_with("a1 NO [AUTHENTICATIONFAILED]"), "completion: {}", response.tagged);
+        assert!(gateway.mock.requests().is_empty(), "the token was sent to Exchange");
+        let response = STANDARD.encode(format!("n,,\x01auth=Bearer {}\x01\x01", token));
+        session.command("a2", &format!("AUTHENTICATE OAUTHBEARER {}", response)).await.assert_status("BAD");
+    });
+}
+
+#[test]
+fn failures_carry_response_codes() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        let response = session.command("a1", &format!("LOGIN {} wrong", USERNAME)).await;
+        assert!(response.tagged.starts_with("a1 NO [AUTHENTICATIONFAILED]"), "completion: {}", response.tagged);
+        let mut session = gateway.login().await;
+        gateway.mock.fail("*", Fault::Disconnect, None);
+        let response = session.command("a2", "SELECT INBOX").await;
+        assert!(response.tagged.starts_with("a2 NO [UNAVAILABLE]"), "completion: {}", response.tagged);
+    });
+}
+
+#[test]
+fn folder_lookups_time_out_before_downloads() {
+    run(&[("davmail.http.timeout", "1"), ("davmail.http.bulkTimeout", "30")], |gateway| async move {
+        let mut session = gateway.login().await;
+        session.command("a1", "SELECT INBOX").await.assert_ok();
+        gateway.mock.fail("GetItem", Fault::Delay(Duration::from_millis(1500)), Some(1));
+        assert!(!session.fetch_literal("a2", "FETCH 1 (BODY[])").await.is_empty());
+        gateway.mock.fail("GetFolder", Fault::Delay(Duration::from_millis(1500)), Some(1));
+        let response = session.command("a3", "SELECT INBOX").await;
+        assert!(response.tagged.starts_with("a3 NO [UNAVAILABLE]"), "completion: {}", response.tagged);
+    });
+}
+
+#[test]
+fn throttled_exchange_is_no() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.login().await;
+        gateway.mock.throttle(0, Duration::from_secs(60), Duration::from_secs(1));
+        session.command("a1", "SELECT INBOX").await.assert_status("NO");
+    });
+}
+
+// Known gaps, run with --ignored to see where the server stands
+
+#[test]
+#[ignore = "responses end with LF, RFC 3501 requires CRLF"]
+fn responses_end_with_crlf() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.send(b"a1 CAPABILITY\r\n").await;
+        for _ in 0..2 {
+            let line = session.read_raw_line().await.expect("connection closed");
+            assert!(line.ends_with("\r\n"), "line without CRLF: {:?}", line);
+        }
+    });
+}
+
+#[test]
+#[ignore = "literals are not parsed yet"]
+fn login_with_synchronizing_literals() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.send(format!("a1 LOGIN {{{}}}\r\n", USERNAME.len()).as_bytes()).await;
+        let continuation = session.read_line().await.expect("connection closed");
+        assert!(continuation.starts_with("+"), "no continuation request: {}", continuation);
+        session.send(format!("{} {{{}}}\r\n", USERNAME, PASSWORD.len()).as_bytes()).await;
+        let continuation = session.read_line().await.expect("connection closed");
+        assert!(continuation.starts_with("+"), "no continuation request: {}", continuation);
+        session.send(format!("{}\r\n", PASSWORD).as_bytes()).await;
+        session.response("a1").await.assert_ok();
+    });
+}
+
+#[test]
+#[ignore = "literals are not parsed yet"]
+fn login_with_non_synchronizing_literals() {
+    run(&[], |gateway| async move {
+        let mut session = gateway.connect().await;
+        session.send(format!("a1 LOGIN {{{}+}}\r\n{} {{{}+}}\r\n{}\r\n", USERNAME.len(), USERNAME, PASSWORD.len(), PASSWORD).as_bytes()).await;
+        session.response("a1").await.assert_ok();
+    });
+}